@@ -2,9 +2,11 @@
 //!
 //! The key component is the trait `Config`: anything that provides configuration for
 //! the application to run, must implement it.
-//! At the moment, only `CLI` implements it, as the configuration is provided as command line
-//! interface arguments.
+//! It's implemented by `CLI`, for configuration provided as command line interface arguments,
+//! and by `FileConfig`, for configuration provided as a TOML file. `CLI` can load a `FileConfig`
+//! (see `--config`), and layer its own arguments on top.
 
 pub mod cli;
+pub mod file;
 pub mod config;
 mod defaults;
\ No newline at end of file
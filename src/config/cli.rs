@@ -1,13 +1,17 @@
 //! Command Line Interface implementation of `Config`
 
-use super::{defaults, config::Config};
-use crate::core::{protocol::DoHProtocol, provider::DoHProvider};
+use super::{defaults, config::{self, Config, CustomProvider}, file::FileConfig};
+use crate::core::{protocol::DoHProtocol, mode::DoHRequestMode, provider::{DoHProvider, parse_provider_url}, strategy::DoHUpstreamStrategy, filter::{block::DoHBlockResponse, source::DomainListSource}, local::record::LocalRecord, routing::DoHRoute};
 use crate::doh_json::provider::DoHJsonProvider;
+use crate::doh_wire::provider::DoHWireProvider;
+use crate::dns::client;
+use crate::querylog::QueryLogDestination;
+use crate::dnstap::DnstapDestination;
 
 use clap::*;
 use log::*;
 
-use std::{net::{Ipv4Addr, Ipv6Addr}, fmt};
+use std::{collections::HashMap, env, ffi::OsString, path::PathBuf, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, fmt};
 
 const ARG_IPV4: &'static str = "ipv4";
 const ARG_IPV4_SHORT: &'static str = "4";
@@ -17,30 +21,76 @@ const ARG_PORT: &'static str = "port";
 const ARG_PORT_SHORT: &'static str = "p";
 const ARG_PROTOCOL: &'static str = "protocol";
 const ARG_PROVIDER: &'static str = "provider";
+const ARG_PROVIDER_URL: &'static str = "provider-url";
+const ARG_PROVIDER_HEADER: &'static str = "provider-header";
+const ARG_PROVIDER_BOOTSTRAP: &'static str = "provider-bootstrap";
+const ARG_PROVIDER_WEIGHT: &'static str = "provider-weight";
+const ARG_STRATEGY: &'static str = "strategy";
+const ARG_RACE_COUNT: &'static str = "race-count";
+const ARG_REQUEST_MODE: &'static str = "request-mode";
+const ARG_CACHE_SIZE: &'static str = "cache-size";
+const ARG_BOOTSTRAP_DNS: &'static str = "bootstrap-dns";
+const ARG_FALLBACK_DNS: &'static str = "fallback-dns";
+const ARG_HEALTH_CHECK_INTERVAL: &'static str = "health-check-interval";
+const ARG_METRICS: &'static str = "metrics";
+const ARG_QUERY_LOG: &'static str = "query-log";
+const ARG_QUERY_LOG_ANONYMIZE: &'static str = "query-log-anonymize";
+const ARG_DNSTAP: &'static str = "dnstap";
+const ARG_DNSTAP_IDENTITY: &'static str = "dnstap-identity";
+const ARG_BLOCKLIST: &'static str = "blocklist";
+const ARG_ALLOWLIST: &'static str = "allowlist";
+const ARG_BLOCK_RESPONSE: &'static str = "block-response";
+const ARG_BLOCK_TTL: &'static str = "block-ttl";
+const ARG_LIST_REFRESH_INTERVAL: &'static str = "list-refresh-interval";
+const ARG_LIST_CACHE_DIR: &'static str = "list-cache-dir";
+const ARG_LOCAL_RECORD: &'static str = "local-record";
+const ARG_HOSTS_FILE: &'static str = "hosts-file";
+const ARG_LOCAL_TTL: &'static str = "local-ttl";
+const ARG_ROUTE: &'static str = "route";
 const ARG_VERBOSE: &'static str = "verbose";
 const ARG_VERBOSE_SHORT: &'static str = "v";
 const ARG_QUIET: &'static str = "quiet";
 const ARG_QUIET_SHORT: &'static str = "q";
+const ARG_CONFIG: &'static str = "config";
+const ARG_CONFIG_SHORT: &'static str = "c";
 const SUBCOMMAND_LIST_PROVIDERS: &'static str = "list-providers";
 const SUBCOMMAND_ALIAS_LIST_PROVIDERS: &'static str = "lsprov";
 
 /// Command Line Interface
 ///
 /// This implements `Config` by parsing the arguments passed to the executable at launch.
+///
+/// If a configuration file is given (see `FileConfig`), its values are used for all the arguments
+/// that were not explicitly passed. Otherwise, the defaults are used.
 #[derive(Clone)]
 pub struct CLI<'a> {
-  arg_matches: ArgMatches<'a>
+  arg_matches: ArgMatches<'a>,
+  file_config: Option<FileConfig>,
 }
 
 impl<'a> CLI<'a> {
   /// Constructor
   ///
-  /// Will automatically get the arguments received from the command line and parse them
+  /// Will automatically get the arguments received from the command line and parse them.
+  /// If a configuration file was given but can't be loaded, it exits with an error.
   pub fn new() -> CLI<'a> {
+    CLI::from_args(env::args_os())
+  }
+
+  /// Constructor, parsing the given arguments (the first being the name of the executable)
+  fn from_args<I, T>(args: I) -> CLI<'a> where I: IntoIterator<Item = T>, T: Into<OsString> + Clone {
     let matches = App::new(crate_name!())
       .version(crate_version!())
       .about(crate_description!())
       .author(crate_authors!("\n"))
+      .arg(Arg::with_name(ARG_CONFIG)
+        .long(ARG_CONFIG)
+        .short(ARG_CONFIG_SHORT)
+        .required(false)
+        .multiple(false)
+        .value_name("FILE")
+        .help("Configuration file (TOML): arguments passed on the command line take precedence")
+      )
       .arg(Arg::with_name(ARG_IPV4)
         .long(ARG_IPV4)
         .short(ARG_IPV4_SHORT)
@@ -70,15 +120,236 @@ impl<'a> CLI<'a> {
         .required(false)
         .multiple(false)
         .possible_values(&[DoHProtocol::JSON.into(), DoHProtocol::WIRE.into()])
-        .default_value(DoHProtocol::JSON.into())
+        .default_value(defaults::PROTOCOL_DEFAULT.into())
         .help(&format!("DoH Protocol (see subcommand '{}')", SUBCOMMAND_LIST_PROVIDERS))
       )
       .arg(Arg::with_name(ARG_PROVIDER)
         .long(ARG_PROVIDER)
         .required(false)
-        .multiple(false)
+        .multiple(true)
+        .number_of_values(1)
         .value_name(ARG_PROVIDER)
-        .help(&format!("DoH Provider (see subcommand '{}')", SUBCOMMAND_LIST_PROVIDERS))
+        .help(&format!("DoH Provider (see subcommand '{}'): use multiple times for failover, in order of preference", SUBCOMMAND_LIST_PROVIDERS))
+      )
+      .arg(Arg::with_name(ARG_PROVIDER_URL)
+        .long(ARG_PROVIDER_URL)
+        .required(false)
+        .multiple(false)
+        .value_name("URL")
+        .validator(|v| parse_provider_url(&v).map(|_| ()).map_err(|err| err.to_string()))
+        .help(&format!("User-defined DoH Provider URL (uses the '{}' explicitly given, or '{}', and it's preferred to any '{}')", ARG_PROTOCOL, defaults::CUSTOM_PROVIDER_PROTOCOL_DEFAULT, ARG_PROVIDER))
+      )
+      .arg(Arg::with_name(ARG_PROVIDER_HEADER)
+        .long(ARG_PROVIDER_HEADER)
+        .required(false)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("NAME: VALUE")
+        .requires(ARG_PROVIDER_URL)
+        .validator(|v| parse_header_arg(&v).map(|_| ()))
+        .help("Extra header for the user-defined DoH Provider (can use multiple times)")
+      )
+      .arg(Arg::with_name(ARG_PROVIDER_BOOTSTRAP)
+        .long(ARG_PROVIDER_BOOTSTRAP)
+        .required(false)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("IP")
+        .requires(ARG_PROVIDER_URL)
+        .validator(|v| v.parse::<IpAddr>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("IP the user-defined DoH Provider can be reached at (can use multiple times)")
+      )
+      .arg(Arg::with_name(ARG_STRATEGY)
+        .long(ARG_STRATEGY)
+        .required(false)
+        .multiple(false)
+        .possible_values(&[
+          DoHUpstreamStrategy::FAILOVER.into(),
+          DoHUpstreamStrategy::ROUND_ROBIN.into(),
+          DoHUpstreamStrategy::WEIGHTED.into(),
+          DoHUpstreamStrategy::FASTEST.into(),
+          DoHUpstreamStrategy::RACE.into(),
+        ])
+        .default_value(defaults::STRATEGY_DEFAULT.into())
+        .help("Strategy to spread queries across multiple DoH Providers")
+      )
+      .arg(Arg::with_name(ARG_RACE_COUNT)
+        .long(ARG_RACE_COUNT)
+        .required(false)
+        .multiple(false)
+        .default_value(defaults::RACE_COUNT_DEFAULT)
+        .help(&format!("How many DoH Providers to query at once (only for strategy '{}')", DoHUpstreamStrategy::RACE))
+      )
+      .arg(Arg::with_name(ARG_PROVIDER_WEIGHT)
+        .long(ARG_PROVIDER_WEIGHT)
+        .required(false)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("PROVIDER=WEIGHT")
+        .validator(|v| parse_weight_arg(&v).map(|_| ()))
+        .help(&format!("Weight of a DoH Provider (only for strategy '{}': can use multiple times)", DoHUpstreamStrategy::WEIGHTED))
+      )
+      .arg(Arg::with_name(ARG_REQUEST_MODE)
+        .long(ARG_REQUEST_MODE)
+        .required(false)
+        .multiple(false)
+        .possible_values(&[DoHRequestMode::GET.into(), DoHRequestMode::POST.into()])
+        .help(&format!("DoH Request Mode (only for protocol '{}': default is preferred by Provider)", DoHProtocol::WIRE))
+      )
+      .arg(Arg::with_name(ARG_CACHE_SIZE)
+        .long(ARG_CACHE_SIZE)
+        .required(false)
+        .multiple(false)
+        .default_value(defaults::CACHE_SIZE_DEFAULT)
+        .help("Maximum number of responses to cache (0 disables caching)")
+      )
+      .arg(Arg::with_name(ARG_HEALTH_CHECK_INTERVAL)
+        .long(ARG_HEALTH_CHECK_INTERVAL)
+        .required(false)
+        .multiple(false)
+        .value_name("SECONDS")
+        .default_value(defaults::HEALTH_CHECK_INTERVAL_DEFAULT)
+        .help("How often to probe the DoH Providers, marking them up or down (0 disables health checking)")
+      )
+      .arg(Arg::with_name(ARG_METRICS)
+        .long(ARG_METRICS)
+        .required(false)
+        .multiple(false)
+        .value_name("IP:PORT")
+        .validator(|v| v.parse::<SocketAddr>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("Address to serve metrics on, over HTTP at '/metrics' (Prometheus text format)")
+      )
+      .arg(Arg::with_name(ARG_QUERY_LOG)
+        .long(ARG_QUERY_LOG)
+        .required(false)
+        .multiple(false)
+        .value_name("DESTINATION")
+        .validator(|v| v.parse::<QueryLogDestination>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("Where to write the query log, one JSON per line: 'stdout', 'file:PATH' (or just 'PATH') or 'unix:PATH'")
+      )
+      .arg(Arg::with_name(ARG_QUERY_LOG_ANONYMIZE)
+        .long(ARG_QUERY_LOG_ANONYMIZE)
+        .required(false)
+        .multiple(false)
+        .help("Anonymize client IPs in the query log (IPv4 truncated to /24, IPv6 to /48)")
+      )
+      .arg(Arg::with_name(ARG_DNSTAP)
+        .long(ARG_DNSTAP)
+        .required(false)
+        .multiple(false)
+        .value_name("DESTINATION")
+        .validator(|v| v.parse::<DnstapDestination>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("Where to write dnstap output (Frame Streams): 'file:PATH' (or just 'PATH') or 'unix:PATH'")
+      )
+      .arg(Arg::with_name(ARG_DNSTAP_IDENTITY)
+        .long(ARG_DNSTAP_IDENTITY)
+        .required(false)
+        .multiple(false)
+        .value_name("IDENTITY")
+        .help("Identity of this server, added to every dnstap message (ex. hostname)")
+      )
+      .arg(Arg::with_name(ARG_BLOCKLIST)
+        .long(ARG_BLOCKLIST)
+        .required(false)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("PATH|URL")
+        .validator(|v| v.parse::<DomainListSource>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("List of domains to block, with their subdomains (hosts file, plain domains or Adblock-style '||domain^'), from a file or HTTP(S) URL: can be repeated")
+      )
+      .arg(Arg::with_name(ARG_ALLOWLIST)
+        .long(ARG_ALLOWLIST)
+        .required(false)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("PATH|URL")
+        .validator(|v| v.parse::<DomainListSource>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("List of domains to never block, with their subdomains (same formats and sources as blocklists): can be repeated")
+      )
+      .arg(Arg::with_name(ARG_BLOCK_RESPONSE)
+        .long(ARG_BLOCK_RESPONSE)
+        .required(false)
+        .multiple(false)
+        .value_name("RESPONSE")
+        .default_value(defaults::BLOCK_RESPONSE_DEFAULT)
+        .validator(|v| v.parse::<DoHBlockResponse>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("How to respond to blocked queries: 'nxdomain', 'refused', 'nodata', 'null' (0.0.0.0/::) or IP[,IP] (ex. of a block page)")
+      )
+      .arg(Arg::with_name(ARG_BLOCK_TTL)
+        .long(ARG_BLOCK_TTL)
+        .required(false)
+        .multiple(false)
+        .value_name("SECONDS")
+        .default_value(defaults::BLOCK_TTL_DEFAULT)
+        .validator(|v| v.parse::<u32>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("TTL of the responses to blocked queries")
+      )
+      .arg(Arg::with_name(ARG_LIST_REFRESH_INTERVAL)
+        .long(ARG_LIST_REFRESH_INTERVAL)
+        .required(false)
+        .multiple(false)
+        .value_name("SECONDS")
+        .default_value(defaults::LIST_REFRESH_INTERVAL_DEFAULT)
+        .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("How often to reload the blocklists and allowlists, if changed (0 disables reloading)")
+      )
+      .arg(Arg::with_name(ARG_LIST_CACHE_DIR)
+        .long(ARG_LIST_CACHE_DIR)
+        .required(false)
+        .multiple(false)
+        .value_name("PATH")
+        .help("Where to cache the lists downloaded from URLs, to use when offline (default: '$XDG_CACHE_HOME/mooncell/lists')")
+      )
+      .arg(Arg::with_name(ARG_LOCAL_RECORD)
+        .long(ARG_LOCAL_RECORD)
+        .required(false)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("NAME TYPE DATA")
+        .validator(|v| v.parse::<LocalRecord>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("Record to answer with locally, never resolving the name upstream (ex. 'nas.home.lan A 192.168.1.10'): can be repeated")
+      )
+      .arg(Arg::with_name(ARG_HOSTS_FILE)
+        .long(ARG_HOSTS_FILE)
+        .required(false)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("PATH")
+        .help("Hosts file to answer with locally (ex. '/etc/hosts'): can be repeated")
+      )
+      .arg(Arg::with_name(ARG_LOCAL_TTL)
+        .long(ARG_LOCAL_TTL)
+        .required(false)
+        .multiple(false)
+        .value_name("SECONDS")
+        .default_value(defaults::LOCAL_TTL_DEFAULT)
+        .validator(|v| v.parse::<u32>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("TTL of the local records (and of the reverse records generated for them)")
+      )
+      .arg(Arg::with_name(ARG_ROUTE)
+        .long(ARG_ROUTE)
+        .required(false)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("SUFFIX=TARGET")
+        .validator(|v| v.parse::<DoHRoute>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("Send queries for a domain suffix to a DoH Provider or to a plain DNS server (ex. 'corp.example=tcp:10.0.0.53', 'lan=192.168.1.1', 'example.org=quad9'): can be repeated")
+      )
+      .arg(Arg::with_name(ARG_BOOTSTRAP_DNS)
+        .long(ARG_BOOTSTRAP_DNS)
+        .required(false)
+        .multiple(false)
+        .value_name("IP[:PORT]")
+        .validator(|v| client::parse_server_address(&v).map(|_| ()).map_err(|err| err.to_string()))
+        .help("Plain DNS server to resolve the Provider hostname with, at launch (default is to use built-in addresses)")
+      )
+      .arg(Arg::with_name(ARG_FALLBACK_DNS)
+        .long(ARG_FALLBACK_DNS)
+        .required(false)
+        .multiple(false)
+        .value_name("IP[:PORT]")
+        .validator(|v| client::parse_server_address(&v).map(|_| ()).map_err(|err| err.to_string()))
+        .help("Plain DNS server to resolve with as a last resort, when all the DoH Providers fail (ex. behind a captive portal)")
       )
       .arg(Arg::with_name(ARG_VERBOSE)
         .long(ARG_VERBOSE)
@@ -98,13 +369,47 @@ impl<'a> CLI<'a> {
         .visible_alias(SUBCOMMAND_ALIAS_LIST_PROVIDERS)
         .about("Available Providers, by DoH Protocol")
       )
-      .get_matches();
+      .get_matches_from(args);
+
+    let file_config = matches.value_of(ARG_CONFIG).map(|path| {
+      FileConfig::from_file(path).unwrap_or_else(|err| Error::with_description(&err.to_string(), ErrorKind::InvalidValue).exit())
+    });
 
     CLI {
-      arg_matches: matches
+      arg_matches: matches,
+      file_config,
     }
   }
 
+  /// Returns `true` if the given argument was explicitly passed on the command line (i.e. not a default value)
+  fn is_explicit(&self, arg_name: &str) -> bool {
+    self.arg_matches.occurrences_of(arg_name) > 0
+  }
+
+  /// Returns a value from the configuration file (if any)
+  ///
+  /// # Parameters
+  ///
+  /// * `getter` - Picks the value out of the `FileConfig`
+  fn from_file<T, F>(&self, getter: F) -> Option<T> where F: FnOnce(&FileConfig) -> Option<T> {
+    self.file_config.as_ref().and_then(getter)
+  }
+
+  /// Identifiers of the Providers to use, in order of preference: empty means the default for the protocol
+  ///
+  /// A Provider given via URL is identified by the URL itself, and comes first.
+  fn provider_ids(&self) -> Vec<String> {
+    let mut provider_ids: Vec<String> = self.arg_matches.value_of(ARG_PROVIDER_URL).map(String::from).into_iter().collect();
+
+    if self.is_explicit(ARG_PROVIDER) {
+      provider_ids.extend(self.arg_matches.values_of(ARG_PROVIDER).unwrap().map(String::from));
+    } else if !self.arg_matches.is_present(ARG_PROVIDER_URL) {
+      provider_ids.extend(self.from_file(|file_config| file_config.resolver.provider.clone()).unwrap_or_default());
+    }
+
+    provider_ids
+  }
+
   pub fn is_list_providers(&self) -> bool {
     match self.arg_matches.subcommand_name() {
       Some(SUBCOMMAND_LIST_PROVIDERS) => true,
@@ -120,38 +425,219 @@ impl<'a> CLI<'a> {
       {}     : {} ({})
     "#,
              DoHProtocol::JSON, DoHJsonProvider::available_ids().join(", "), DoHJsonProvider::default_id(),
-             DoHProtocol::WIRE, "NONE", "NONE"
+             DoHProtocol::WIRE, DoHWireProvider::available_ids().join(", "), DoHWireProvider::default_id()
     );
+
+    let custom_providers = self.custom_providers();
+    if !custom_providers.is_empty() {
+      println!(r#"
+      user-defined providers (protocol) : url
+      ============================================================ = = =
+      {}
+    "#,
+               custom_providers.iter()
+                 .map(|custom_provider| format!("{} ({}) : {}", custom_provider.id, custom_provider.protocol, custom_provider.url))
+                 .collect::<Vec<String>>()
+                 .join("\n      ")
+      );
+    }
   }
 
+
 }
 
 impl<'a> Config for CLI<'a> {
   fn ipv4(&self) -> Vec<Ipv4Addr> {
+    if !self.is_explicit(ARG_IPV4) {
+      if let Some(ipv4) = self.from_file(|file_config| file_config.listen.ipv4.clone()) {
+        return ipv4;
+      }
+    }
+
     let arg_matches_ref = &self.arg_matches;
     values_t_or_exit!(arg_matches_ref, ARG_IPV4, Ipv4Addr)
   }
 
   fn ipv6(&self) -> Vec<Ipv6Addr> {
+    if !self.is_explicit(ARG_IPV6) {
+      if let Some(ipv6) = self.from_file(|file_config| file_config.listen.ipv6.clone()) {
+        return ipv6;
+      }
+    }
+
     let arg_matches_ref = &self.arg_matches;
     values_t_or_exit!(arg_matches_ref, ARG_IPV6, Ipv6Addr)
   }
 
   fn port(&self) -> u16 {
+    if !self.is_explicit(ARG_PORT) {
+      if let Some(port) = self.from_file(|file_config| file_config.listen.port) {
+        return port;
+      }
+    }
+
     let arg_matches_ref = &self.arg_matches;
     value_t_or_exit!(arg_matches_ref, ARG_PORT, u16)
   }
 
+  fn cache_size(&self) -> usize {
+    if !self.is_explicit(ARG_CACHE_SIZE) {
+      if let Some(cache_size) = self.from_file(|file_config| file_config.cache.size) {
+        return cache_size;
+      }
+    }
+
+    let arg_matches_ref = &self.arg_matches;
+    value_t_or_exit!(arg_matches_ref, ARG_CACHE_SIZE, usize)
+  }
+
+  fn health_check_interval(&self) -> u64 {
+    if !self.is_explicit(ARG_HEALTH_CHECK_INTERVAL) {
+      if let Some(interval) = self.from_file(|file_config| file_config.health_check.interval) {
+        return interval;
+      }
+    }
+
+    let arg_matches_ref = &self.arg_matches;
+    value_t_or_exit!(arg_matches_ref, ARG_HEALTH_CHECK_INTERVAL, u64)
+  }
+
+  fn metrics_address(&self) -> Option<SocketAddr> {
+    self.arg_matches.value_of(ARG_METRICS)
+      .map(|raw_address| raw_address.parse().unwrap())
+      .or_else(|| self.from_file(|file_config| file_config.metrics.address))
+  }
+
+  fn query_log(&self) -> Option<QueryLogDestination> {
+    self.arg_matches.value_of(ARG_QUERY_LOG)
+      .map(|raw_destination| raw_destination.parse().unwrap())
+      .or_else(|| self.from_file(|file_config| file_config.query_log.destination.clone()))
+  }
+
+  fn query_log_anonymize(&self) -> bool {
+    self.arg_matches.is_present(ARG_QUERY_LOG_ANONYMIZE) || self.from_file(|file_config| file_config.query_log.anonymize).unwrap_or(false)
+  }
+
+  fn dnstap(&self) -> Option<DnstapDestination> {
+    self.arg_matches.value_of(ARG_DNSTAP)
+      .map(|raw_destination| raw_destination.parse().unwrap())
+      .or_else(|| self.from_file(|file_config| file_config.dnstap.destination.clone()))
+  }
+
+  fn dnstap_identity(&self) -> Option<String> {
+    self.arg_matches.value_of(ARG_DNSTAP_IDENTITY)
+      .map(String::from)
+      .or_else(|| self.from_file(|file_config| file_config.dnstap.identity.clone()))
+  }
+
+  fn blocklists(&self) -> Vec<DomainListSource> {
+    match self.arg_matches.values_of(ARG_BLOCKLIST) {
+      Some(blocklists) => blocklists.map(|raw_source| raw_source.parse().unwrap()).collect(),
+      None => self.from_file(|file_config| file_config.filter.blocklists.clone()).unwrap_or_default(),
+    }
+  }
+
+  fn allowlists(&self) -> Vec<DomainListSource> {
+    match self.arg_matches.values_of(ARG_ALLOWLIST) {
+      Some(allowlists) => allowlists.map(|raw_source| raw_source.parse().unwrap()).collect(),
+      None => self.from_file(|file_config| file_config.filter.allowlists.clone()).unwrap_or_default(),
+    }
+  }
+
+  fn list_refresh_interval(&self) -> u64 {
+    if !self.is_explicit(ARG_LIST_REFRESH_INTERVAL) {
+      if let Some(interval) = self.from_file(|file_config| file_config.filter.refresh_interval) {
+        return interval;
+      }
+    }
+
+    let arg_matches_ref = &self.arg_matches;
+    value_t_or_exit!(arg_matches_ref, ARG_LIST_REFRESH_INTERVAL, u64)
+  }
+
+  fn list_cache_dir(&self) -> PathBuf {
+    self.arg_matches.value_of(ARG_LIST_CACHE_DIR)
+      .map(PathBuf::from)
+      .or_else(|| self.from_file(|file_config| file_config.filter.cache_dir.clone()))
+      .unwrap_or_else(defaults::list_cache_dir_default)
+  }
+
+  fn block_response(&self) -> DoHBlockResponse {
+    if !self.is_explicit(ARG_BLOCK_RESPONSE) {
+      if let Some(block_response) = self.from_file(|file_config| file_config.filter.block_response.clone()) {
+        return block_response;
+      }
+    }
+
+    let arg_matches_ref = &self.arg_matches;
+    value_t_or_exit!(arg_matches_ref, ARG_BLOCK_RESPONSE, DoHBlockResponse)
+  }
+
+  fn block_ttl(&self) -> u32 {
+    if !self.is_explicit(ARG_BLOCK_TTL) {
+      if let Some(block_ttl) = self.from_file(|file_config| file_config.filter.block_ttl) {
+        return block_ttl;
+      }
+    }
+
+    let arg_matches_ref = &self.arg_matches;
+    value_t_or_exit!(arg_matches_ref, ARG_BLOCK_TTL, u32)
+  }
+
+  fn local_records(&self) -> Vec<LocalRecord> {
+    match self.arg_matches.values_of(ARG_LOCAL_RECORD) {
+      Some(local_records) => local_records.map(|raw_record| raw_record.parse().unwrap()).collect(),
+      None => self.from_file(|file_config| file_config.local.records.clone()).unwrap_or_default(),
+    }
+  }
+
+  fn hosts_files(&self) -> Vec<PathBuf> {
+    match self.arg_matches.values_of(ARG_HOSTS_FILE) {
+      Some(hosts_files) => hosts_files.map(PathBuf::from).collect(),
+      None => self.from_file(|file_config| file_config.local.hosts_files.clone()).unwrap_or_default(),
+    }
+  }
+
+  fn local_ttl(&self) -> u32 {
+    if !self.is_explicit(ARG_LOCAL_TTL) {
+      if let Some(local_ttl) = self.from_file(|file_config| file_config.local.ttl) {
+        return local_ttl;
+      }
+    }
+
+    let arg_matches_ref = &self.arg_matches;
+    value_t_or_exit!(arg_matches_ref, ARG_LOCAL_TTL, u32)
+  }
+
+  fn routes(&self) -> Vec<DoHRoute> {
+    match self.arg_matches.values_of(ARG_ROUTE) {
+      Some(routes) => routes.map(|raw_route| raw_route.parse().unwrap()).collect(),
+      None => self.from_file(|file_config| file_config.routing.routes.clone()).unwrap_or_default(),
+    }
+  }
+
+  fn bootstrap_dns(&self) -> Option<SocketAddr> {
+    self.arg_matches.value_of(ARG_BOOTSTRAP_DNS)
+      .map(|raw_server| client::parse_server_address(raw_server).unwrap())
+      .or_else(|| self.from_file(|file_config| file_config.resolver.bootstrap_dns))
+  }
+
+  fn fallback_dns(&self) -> Option<SocketAddr> {
+    self.arg_matches.value_of(ARG_FALLBACK_DNS)
+      .map(|raw_server| client::parse_server_address(raw_server).unwrap())
+      .or_else(|| self.from_file(|file_config| file_config.resolver.fallback_dns))
+  }
+
   fn log_filter(&self) -> LevelFilter {
     // Here we take 2 parameters, `quiet` and `verbose` and work out
     // how to map their use to a logging level.
     //
-    // `quiet` has priority over `verbose`.
+    // `quiet` has priority over `verbose`, and both have priority over the configuration file.
     if self.arg_matches.occurrences_of(ARG_QUIET) == 1 {
       LevelFilter::Off
     } else {
       match self.arg_matches.occurrences_of(ARG_VERBOSE) {
-        0 => defaults::LOG_FILTER_DEFAULT,
+        0 => self.from_file(|file_config| file_config.log_level).unwrap_or(defaults::LOG_FILTER_DEFAULT),
         1 => LevelFilter::Warn,
         2 => LevelFilter::Info,
         3 => LevelFilter::Debug,
@@ -161,36 +647,168 @@ impl<'a> Config for CLI<'a> {
   }
 
   fn protocol(&self) -> DoHProtocol {
+    if !self.is_explicit(ARG_PROTOCOL) {
+      if let Some(protocol) = self.from_file(|file_config| file_config.resolver.protocol) {
+        return protocol;
+      }
+    }
+
     let arg_matches_ref = &self.arg_matches;
     value_t_or_exit!(arg_matches_ref, ARG_PROTOCOL, DoHProtocol)
   }
 
-  fn provider(&self) -> Option<Box<dyn DoHProvider>> {
-    match self.protocol() {
-      DoHProtocol::JSON => {
-        let provider_id = self.arg_matches.value_of(ARG_PROVIDER).unwrap_or(DoHJsonProvider::default_id());
+  fn request_mode(&self) -> Option<DoHRequestMode> {
+    if self.arg_matches.is_present(ARG_REQUEST_MODE) {
+      let arg_matches_ref = &self.arg_matches;
+      Some(value_t_or_exit!(arg_matches_ref, ARG_REQUEST_MODE, DoHRequestMode))
+    } else {
+      self.from_file(|file_config| file_config.resolver.request_mode)
+    }
+  }
 
-        match DoHJsonProvider::available().remove(provider_id) {
-          Some(provider) => Some(Box::new(provider)),
-          None => {
-            error!("Unknown provider '{}': see subcommand '{}'", provider_id, SUBCOMMAND_LIST_PROVIDERS);
-            None
-          }
-        }
-      },
-      DoHProtocol::WIRE => {
-        error!("No providers available for protocol '{}': see subcommand '{}'", DoHProtocol::WIRE, SUBCOMMAND_LIST_PROVIDERS);
-        None
-      },
+  fn providers(&self) -> Vec<Box<dyn DoHProvider>> {
+    let providers = config::providers_for(self.protocol(), &self.provider_ids(), self.request_mode(), &self.custom_providers());
+    if providers.is_empty() {
+      error!("See subcommand '{}' for the available providers", SUBCOMMAND_LIST_PROVIDERS);
     }
+
+    providers
   }
 
+  fn strategy(&self) -> DoHUpstreamStrategy {
+    if !self.is_explicit(ARG_STRATEGY) {
+      if let Some(strategy) = self.from_file(|file_config| file_config.resolver.strategy) {
+        return strategy;
+      }
+    }
+
+    let arg_matches_ref = &self.arg_matches;
+    value_t_or_exit!(arg_matches_ref, ARG_STRATEGY, DoHUpstreamStrategy)
+  }
+
+  fn race_count(&self) -> usize {
+    if !self.is_explicit(ARG_RACE_COUNT) {
+      if let Some(race_count) = self.from_file(|file_config| file_config.resolver.race_count) {
+        return race_count;
+      }
+    }
+
+    let arg_matches_ref = &self.arg_matches;
+    value_t_or_exit!(arg_matches_ref, ARG_RACE_COUNT, usize)
+  }
+
+  fn provider_weights(&self) -> HashMap<String, u32> {
+    let mut provider_weights = self.from_file(|file_config| Some(file_config.provider_weights())).unwrap_or_default();
+
+    // Weights passed on the command line take precedence, one by one
+    if let Some(values) = self.arg_matches.values_of(ARG_PROVIDER_WEIGHT) {
+      provider_weights.extend(values.map(|v| parse_weight_arg(v).unwrap()));
+    }
+
+    provider_weights
+  }
+
+  fn custom_providers(&self) -> Vec<CustomProvider> {
+    let mut custom_providers = self.from_file(|file_config| Some(file_config.custom_providers())).unwrap_or_default();
+
+    if let Some(provider_url) = self.arg_matches.value_of(ARG_PROVIDER_URL) {
+      let raw_headers: Vec<(String, String)> = self.arg_matches.values_of(ARG_PROVIDER_HEADER)
+        .map(|values| values.map(|v| parse_header_arg(v).unwrap()).collect())
+        .unwrap_or_default();
+      let arg_matches_ref = &self.arg_matches;
+
+      custom_providers.push(CustomProvider {
+        id: provider_url.to_string(),
+        url: provider_url.to_string(),
+        // Like the ones in the configuration file, unless a protocol is explicitly given
+        protocol: if self.is_explicit(ARG_PROTOCOL) { self.protocol() } else { defaults::CUSTOM_PROVIDER_PROTOCOL_DEFAULT },
+        headers: config::parse_headers(raw_headers.iter().map(|(name, value)| (name.as_str(), value.as_str()))).unwrap(),
+        bootstrap: if self.arg_matches.is_present(ARG_PROVIDER_BOOTSTRAP) {
+          values_t_or_exit!(arg_matches_ref, ARG_PROVIDER_BOOTSTRAP, IpAddr)
+        } else {
+          Vec::new()
+        },
+      });
+    }
+
+    custom_providers
+  }
+
+
 }
 
 impl<'a> fmt::Debug for CLI<'a> {
   fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
     write!(fmtr,
-           "CLI (ConfigProvider) {{ ipv4: {:?}, ipv6: {:?}, port: {}, protocol: {}, provider: {:?}, log_filter: {} }}",
-           self.ipv4(), self.ipv6(), self.port(), self.protocol(), self.provider(), self.log_filter())
+           "CLI (ConfigProvider) {{ ipv4: {:?}, ipv6: {:?}, port: {}, protocol: {}, request_mode: {:?}, providers: {:?}, strategy: {}, bootstrap_dns: {:?}, fallback_dns: {:?}, cache_size: {}, health_check_interval: {}, metrics_address: {:?}, query_log: {:?}, dnstap: {:?}, blocklists: {:?}, allowlists: {:?}, block_response: {}, list_refresh_interval: {}, list_cache_dir: {:?}, local_records: {:?}, hosts_files: {:?}, routes: {:?}, log_filter: {} }}",
+           self.ipv4(), self.ipv6(), self.port(), self.protocol(), self.request_mode(), self.providers(), self.strategy(), self.bootstrap_dns(), self.fallback_dns(), self.cache_size(), self.health_check_interval(), self.metrics_address(), self.query_log(), self.dnstap(), self.blocklists(), self.allowlists(), self.block_response(), self.list_refresh_interval(), self.list_cache_dir(), self.local_records(), self.hosts_files(), self.routes(), self.log_filter())
+  }
+}
+
+/// Parses a `--provider-header` argument, in the format `NAME: VALUE`
+fn parse_header_arg(raw_header: &str) -> std::result::Result<(String, String), String> {
+  let mut parts = raw_header.splitn(2, ':');
+  match (parts.next(), parts.next()) {
+    (Some(name), Some(value)) => config::parse_headers(vec![(name, value)])
+      .map(|_| (name.trim().to_string(), value.trim().to_string()))
+      .map_err(|err| format!("Invalid header '{}': {}", raw_header, err)),
+    _ => Err(format!("Invalid header '{}': expected format is 'NAME: VALUE'", raw_header)),
+  }
+}
+
+/// Parses a `--provider-weight` argument, in the format `PROVIDER=WEIGHT`
+fn parse_weight_arg(raw_weight: &str) -> std::result::Result<(String, u32), String> {
+  // The identifier can be a URL (that could contain `=`): the weight is after the last one
+  let mut parts = raw_weight.rsplitn(2, '=');
+  match (parts.next(), parts.next()) {
+    (Some(weight), Some(provider_id)) if !provider_id.trim().is_empty() => weight.trim().parse::<u32>()
+      .map(|weight| (provider_id.trim().to_string(), weight))
+      .map_err(|err| format!("Invalid weight '{}': {}", raw_weight, err)),
+    _ => Err(format!("Invalid weight '{}': expected format is 'PROVIDER=WEIGHT'", raw_weight)),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::{str::FromStr, time::Instant};
+
+  const PROVIDER_URL: &'static str = "https://doh.example.com/dns-query";
+
+  #[test]
+  fn should_default_custom_provider_protocol_consistently() {
+    let cli = CLI::from_args(vec!["mooncell", "--provider-url", PROVIDER_URL]);
+    let cli_provider = cli.custom_providers().remove(0);
+
+    let file_config = FileConfig::from_str(&format!("[[providers]]\nid = \"custom\"\nurl = \"{}\"", PROVIDER_URL)).unwrap();
+    let file_provider = file_config.custom_providers().remove(0);
+
+    assert_eq!(cli_provider.protocol.to_string(), "wire");
+    assert_eq!(file_provider.protocol.to_string(), "wire");
+
+    // Unless a protocol is explicitly given
+    let cli = CLI::from_args(vec!["mooncell", "--provider-url", PROVIDER_URL, "--protocol", "json"]);
+    assert_eq!(cli.custom_providers().remove(0).protocol.to_string(), "json");
+
+    let file_config = FileConfig::from_str(&format!("[[providers]]\nid = \"custom\"\nurl = \"{}\"\nprotocol = \"json\"", PROVIDER_URL)).unwrap();
+    assert_eq!(file_config.custom_providers().remove(0).protocol.to_string(), "json");
+  }
+
+  #[test]
+  fn should_share_upstreams_between_route_groups() {
+    let cli = CLI::from_args(vec!["mooncell", "--provider", "quad9", "--route", "corp.example=quad9", "--route", "lan=192.168.1.1", "--route", "home.arpa=192.168.1.1"]);
+    let upstream_group = cli.upstream_group();
+    let route_upstream_groups = cli.route_upstream_groups(&upstream_group);
+
+    let ids: Vec<&str> = route_upstream_groups.iter().map(|(_, group)| group.upstreams()[0].id()).collect();
+    assert_eq!(ids, vec!["quad9", "udp:192.168.1.1:53", "udp:192.168.1.1:53"]);
+
+    // A failure recorded by one group is seen by the others sharing the upstream
+    upstream_group.upstreams()[0].state().lock().unwrap().record_failure(Instant::now());
+    assert!(!route_upstream_groups[0].1.upstreams()[0].state().lock().unwrap().is_up());
+    assert!(route_upstream_groups[1].1.upstreams()[0].state().lock().unwrap().is_up());
+
+    route_upstream_groups[1].1.upstreams()[0].state().lock().unwrap().record_failure(Instant::now());
+    assert!(!route_upstream_groups[2].1.upstreams()[0].state().lock().unwrap().is_up());
   }
 }
@@ -1,11 +1,16 @@
 //! Configuration Provider trait (schema)
 
-use crate::core::{protocol::DoHProtocol, provider::DoHProvider, resolver::DoHResolver};
+use crate::core::{protocol::DoHProtocol, mode::DoHRequestMode, provider::{DoHProvider, DoHProviderUrlError}, resolver::DoHResolver, cache::DoHCachingResolver, filter::{DoHFilteringResolver, SharedDomainLists, block::DoHBlockResponse, source::{DomainListSource, DomainListLoader}}, local::{self, DoHLocalResolver, record::LocalRecord}, routing::{DoHRoute, DoHRouteTarget, DoHRoutingResolver}, upstream::{DoHUpstream, DoHUpstreamGroup}, strategy::DoHUpstreamStrategy};
 use crate::doh_json::{resolver::DoHJsonResolver, provider::DoHJsonProvider};
+use crate::doh_wire::{resolver::DoHWireResolver, provider::DoHWireProvider};
+use crate::dns::resolver::{DnsPlainResolver, DnsTransport};
+use crate::querylog::QueryLogDestination;
+use crate::dnstap::DnstapDestination;
 
-use log::LevelFilter;
+use log::*;
+use http::{HeaderMap, header::{HeaderName, HeaderValue}, Error as HttpError};
 
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::{collections::HashMap, path::PathBuf, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}};
 
 /// This trait is implemented by types that _provide configuration_ to the rest of the application.
 pub trait Config {
@@ -24,18 +29,359 @@ pub trait Config {
   /// The DNS-over-HTTPS Protocol to use
   fn protocol(&self) -> DoHProtocol;
 
-  /// The DNS-over-HTTPS Provider to use
-  fn provider(&self) -> Option<Box<dyn DoHProvider>>;
+  /// The DNS-over-HTTPS Request Mode to use, overriding the one preferred by the Provider (if any)
+  fn request_mode(&self) -> Option<DoHRequestMode>;
 
-  /// The DNS-over-HTTPS Resolver to use
-  fn resolver(&self) -> Box<DoHResolver + Send> {
-    match self.protocol() {
-      DoHProtocol::JSON => match self.provider() {
-        Some(provider) => Box::new(DoHJsonResolver::new(*provider.downcast::<DoHJsonProvider>().unwrap())),
-        None => panic!("Unable to determine DoH JSON Provider: this should never be reached!"),
+  /// The DNS-over-HTTPS Providers to use, in order of preference (empty if any is unknown)
+  fn providers(&self) -> Vec<Box<dyn DoHProvider>>;
+
+  /// The strategy to spread queries across the Providers with
+  fn strategy(&self) -> DoHUpstreamStrategy;
+
+  /// How many Providers to query at once (only for `DoHUpstreamStrategy::RACE`)
+  fn race_count(&self) -> usize;
+
+  /// The weights of the Providers, by identifier (only for `DoHUpstreamStrategy::WEIGHTED`)
+  ///
+  /// Providers without a weight get the default one.
+  fn provider_weights(&self) -> HashMap<String, u32>;
+
+  /// The user-defined DNS-over-HTTPS Providers, available alongside the built-in ones
+  fn custom_providers(&self) -> Vec<CustomProvider>;
+
+  /// The "plain" DNS server to resolve the Provider hostname with, at launch (if any)
+  ///
+  /// If not set, the Provider is reached via its built-in bootstrap addresses.
+  fn bootstrap_dns(&self) -> Option<SocketAddr>;
+
+  /// The "plain" DNS server to resolve with as a last resort, when all the Providers fail (if any)
+  fn fallback_dns(&self) -> Option<SocketAddr>;
+
+  /// The maximum number of responses to cache (`0` disables caching)
+  fn cache_size(&self) -> usize;
+
+  /// How often to probe the Providers, in seconds (`0` disables health checking)
+  fn health_check_interval(&self) -> u64;
+
+  /// The address to serve metrics on, over HTTP (if any)
+  fn metrics_address(&self) -> Option<SocketAddr>;
+
+  /// Where to write the query log to (if anywhere)
+  fn query_log(&self) -> Option<QueryLogDestination>;
+
+  /// Whether to anonymize the client IPs in the query log
+  fn query_log_anonymize(&self) -> bool;
+
+  /// Where to write the dnstap output to (if anywhere)
+  fn dnstap(&self) -> Option<DnstapDestination>;
+
+  /// The identity to add to dnstap messages (if any)
+  fn dnstap_identity(&self) -> Option<String>;
+
+  /// The lists of domain names to block (see `filter::list` for the supported formats)
+  fn blocklists(&self) -> Vec<DomainListSource>;
+
+  /// The lists of domain names to never block, even if in the blocklists
+  fn allowlists(&self) -> Vec<DomainListSource>;
+
+  /// How often to reload the lists, in seconds (`0` disables reloading)
+  fn list_refresh_interval(&self) -> u64;
+
+  /// Where to cache the lists downloaded from URLs
+  fn list_cache_dir(&self) -> PathBuf;
+
+  /// How to respond to blocked queries
+  fn block_response(&self) -> DoHBlockResponse;
+
+  /// The TTL of the responses to blocked queries, in seconds
+  fn block_ttl(&self) -> u32;
+
+  /// The records to answer with locally
+  fn local_records(&self) -> Vec<LocalRecord>;
+
+  /// The hosts files to answer with locally (see `local::hosts`)
+  fn hosts_files(&self) -> Vec<PathBuf>;
+
+  /// The TTL of the local records, in seconds
+  fn local_ttl(&self) -> u32;
+
+  /// The routes that send queries for some domain suffixes to a different upstream
+  fn routes(&self) -> Vec<DoHRoute>;
+
+  /// `true` if the Providers the routes send queries to all exist
+  fn has_valid_routes(&self) -> bool {
+    self.routes().iter().all(|route| match &route.target {
+      DoHRouteTarget::Provider(id) => provider_for(self.protocol(), Some(id), self.request_mode(), &self.custom_providers()).is_some(),
+      DoHRouteTarget::Plain(_, _) => true,
+    })
+  }
+
+  /// The group of upstreams to resolve with, one per Provider
+  ///
+  /// Each Provider gets its own resolver, and they are grouped in a `DoHUpstreamGroup` that spreads
+  /// the queries according to the strategy, failing over from one to the next. If a bootstrap DNS
+  /// server is set, the Providers hostnames are resolved with it first. If a fallback DNS server is
+  /// set, it's the upstream of last resort.
+  fn upstream_group(&self) -> DoHUpstreamGroup {
+    let providers = self.providers();
+    if providers.is_empty() {
+      panic!("Unable to determine DoH Providers: this should never be reached!");
+    }
+
+    let provider_weights = self.provider_weights();
+    let upstreams = providers.into_iter()
+      .map(|mut provider| {
+        if let Some(bootstrap_dns) = self.bootstrap_dns() {
+          provider.resolve_bootstrap_addresses(&bootstrap_dns);
+        }
+
+        let id = provider.id().to_string();
+        let resolver = resolver_for(provider);
+
+        match provider_weights.get(&id) {
+          Some(weight) => DoHUpstream::with_weight(&id, resolver, *weight),
+          None => DoHUpstream::new(&id, resolver),
+        }
+      })
+      .collect();
+
+    let mut upstream_group = DoHUpstreamGroup::with_strategy(upstreams, self.strategy(), self.race_count());
+    if let Some(fallback_dns) = self.fallback_dns() {
+      let target = DoHRouteTarget::Plain(fallback_dns, DnsTransport::Udp);
+      upstream_group.set_fallback(DoHUpstream::new(&target.to_string(), Box::new(DnsPlainResolver::new(fallback_dns, DnsTransport::Udp))));
+    }
+
+    upstream_group
+  }
+
+  /// The groups of upstreams to send the queries of the routes to (see `routes()`), one per route
+  ///
+  /// Routes to the same target share the same upstream, and so does a route to a Provider of the given
+  /// group: each upstream has a single state, even if it is used by more than one group.
+  ///
+  /// # Parameters
+  ///
+  /// * `upstream_group` - The group of upstreams the other queries are sent to (see `upstream_group()`)
+  fn route_upstream_groups(&self, upstream_group: &DoHUpstreamGroup) -> Vec<(DoHRoute, DoHUpstreamGroup)> {
+    let mut upstreams = upstream_group.upstreams().to_vec();
+
+    self.routes().into_iter()
+      .map(|route| {
+        let id = route.target.to_string();
+        let upstream = match upstreams.iter().find(|upstream| upstream.id() == id) {
+          Some(upstream) => upstream.clone(),
+          None => {
+            let upstream = self.route_upstream(&route.target);
+            upstreams.push(upstream.clone());
+            upstream
+          },
+        };
+
+        (route, DoHUpstreamGroup::new(vec![upstream]))
+      })
+      .collect()
+  }
+
+  /// The upstream to send the queries of a route to (see `routes()`)
+  ///
+  /// # Parameters
+  ///
+  /// * `target` - Where the route sends queries to
+  fn route_upstream(&self, target: &DoHRouteTarget) -> DoHUpstream {
+    let resolver: Box<DoHResolver + Send> = match target {
+      DoHRouteTarget::Provider(id) => {
+        let mut provider = provider_for(self.protocol(), Some(id), self.request_mode(), &self.custom_providers())
+          .expect("Unable to determine DoH Provider of a route: this should never be reached!");
+        if let Some(bootstrap_dns) = self.bootstrap_dns() {
+          provider.resolve_bootstrap_addresses(&bootstrap_dns);
+        }
+
+        resolver_for(provider)
       },
-      // TODO Rewrite the following once support for WIRE protocol is implemented
-      DoHProtocol::WIRE => panic!("Unable to determine DoH WIRE Provider: this should never be reached!"),
+      DoHRouteTarget::Plain(server, transport) => Box::new(DnsPlainResolver::new(*server, *transport)),
+    };
+
+    DoHUpstream::new(&target.to_string(), resolver)
+  }
+
+  /// The loader of the domain lists to filter queries with (`None` if there are no blocklists)
+  fn domain_list_loader(&self) -> Option<DomainListLoader> {
+    let blocklists = self.blocklists();
+    if blocklists.is_empty() {
+      return None;
+    }
+
+    Some(DomainListLoader::new(blocklists, self.allowlists(), Some(self.list_cache_dir())))
+  }
+
+  /// The DNS-over-HTTPS Resolver to use, on top of the given group of upstreams
+  ///
+  /// If there are routes, the group is the default of a `DoHRoutingResolver`, alongside the groups
+  /// of the routes. If caching is enabled, that is wrapped by a `DoHCachingResolver`. If there are domain lists,
+  /// that is in turn wrapped by a `DoHFilteringResolver`, so blocked queries never reach the cache.
+  /// If there are local records, the outermost is a `DoHLocalResolver`, so local names are never
+  /// blocked nor resolved upstream.
+  ///
+  /// # Parameters
+  ///
+  /// * `upstream_group` - The group of upstreams (see `upstream_group()`)
+  /// * `route_upstream_groups` - The groups of upstreams of the routes (see `route_upstream_groups()`)
+  /// * `domain_lists` - The domain lists to filter queries with (see `domain_list_loader()`), if any
+  fn resolver(&self, upstream_group: DoHUpstreamGroup, route_upstream_groups: Vec<(DoHRoute, DoHUpstreamGroup)>, domain_lists: Option<SharedDomainLists>) -> Box<DoHResolver + Send> {
+    let resolver: Box<DoHResolver + Send> = Box::new(upstream_group);
+
+    let resolver: Box<DoHResolver + Send> = if route_upstream_groups.is_empty() {
+      resolver
+    } else {
+      let mut routing_resolver = DoHRoutingResolver::new(resolver);
+      for (route, route_upstream_group) in route_upstream_groups {
+        info!("Routing queries for '{}' to '{}'", route.suffix, route.target);
+        routing_resolver.add_route(route.suffix, Box::new(route_upstream_group));
+      }
+      Box::new(routing_resolver)
+    };
+
+    let resolver: Box<DoHResolver + Send> = match self.cache_size() {
+      0 => resolver,
+      cache_size => Box::new(DoHCachingResolver::new(resolver, cache_size)),
+    };
+
+    let resolver: Box<DoHResolver + Send> = match domain_lists {
+      Some(domain_lists) => Box::new(DoHFilteringResolver::new(resolver, domain_lists, self.block_response(), self.block_ttl())),
+      None => resolver,
+    };
+
+    let local_records = local::load_local_records(self.local_records(), &self.hosts_files(), self.local_ttl());
+    if local_records.is_empty() {
+      return resolver;
     }
+
+    info!("Answering locally for {} names", local_records.len());
+    Box::new(DoHLocalResolver::new(resolver, local_records))
+  }
+}
+
+/// Definition of a user-defined DNS-over-HTTPS Provider
+#[derive(Debug, Clone)]
+pub struct CustomProvider {
+  /// Identifier of the Provider
+  pub id: String,
+  /// URL of the Provider (ex. "https://doh.example.com/dns-query")
+  pub url: String,
+  /// Protocol supported by the Provider
+  pub protocol: DoHProtocol,
+  /// Extra headers to send with every request
+  pub headers: HeaderMap,
+  /// IP addresses the Provider can be reached at (if empty, its hostname is resolved as usual)
+  pub bootstrap: Vec<IpAddr>,
+}
+
+impl CustomProvider {
+  /// Builds the `DoHProvider` described by this definition
+  pub fn build(&self) -> Result<Box<dyn DoHProvider>, DoHProviderUrlError> {
+    Ok(match self.protocol {
+      DoHProtocol::JSON => Box::new(DoHJsonProvider::from_url(&self.id, &self.url, self.headers.clone(), self.bootstrap.clone())?),
+      DoHProtocol::WIRE => Box::new(DoHWireProvider::from_url(&self.id, &self.url, self.headers.clone(), self.bootstrap.clone())?),
+    })
   }
 }
+
+/// Builds the `DoHResolver` bound to the given DNS-over-HTTPS Provider
+///
+/// The protocol is the one of the Provider, as user-defined Providers have their own.
+fn resolver_for(provider: Box<dyn DoHProvider>) -> Box<DoHResolver + Send> {
+  match provider.protocol() {
+    DoHProtocol::JSON => Box::new(DoHJsonResolver::new(*provider.downcast::<DoHJsonProvider>().unwrap())),
+    DoHProtocol::WIRE => Box::new(DoHWireResolver::new(*provider.downcast::<DoHWireProvider>().unwrap())),
+  }
+}
+
+/// Parses a list of header names and values into an `HeaderMap`
+///
+/// # Parameters
+///
+/// * `raw_headers` - Pairs of `&str` representing header name and value (ex. `("X-Api-Key", "secret")`)
+pub fn parse_headers<'a, I>(raw_headers: I) -> Result<HeaderMap, HttpError> where I: IntoIterator<Item = (&'a str, &'a str)> {
+  let mut headers = HeaderMap::new();
+  for (raw_name, raw_value) in raw_headers {
+    headers.append(HeaderName::from_bytes(raw_name.trim().as_bytes())?, HeaderValue::from_str(raw_value.trim())?);
+  }
+
+  Ok(headers)
+}
+
+/// Looks up DNS-over-HTTPS Providers, by identifiers (see `provider_for()`)
+///
+/// Returns an empty `Vec` if any of the Providers doesn't exist.
+///
+/// # Parameters
+///
+/// * `protocol` - The DNS-over-HTTPS Protocol the (built-in) Providers should support
+/// * `provider_ids` - Identifiers of the Providers, in order of preference: if empty, the default for the protocol is used
+/// * `request_mode` - Request Mode that overrides the one preferred by the Providers (if any)
+/// * `custom_providers` - The user-defined Providers
+pub fn providers_for(protocol: DoHProtocol, provider_ids: &[String], request_mode: Option<DoHRequestMode>, custom_providers: &[CustomProvider]) -> Vec<Box<dyn DoHProvider>> {
+  if provider_ids.is_empty() {
+    return provider_for(protocol, None, request_mode, custom_providers).into_iter().collect();
+  }
+
+  provider_ids.iter()
+    .map(|provider_id| provider_for(protocol, Some(provider_id), request_mode, custom_providers))
+    .collect::<Option<Vec<Box<dyn DoHProvider>>>>()
+    .unwrap_or_default()
+}
+
+/// Looks up a DNS-over-HTTPS Provider, by identifier, among the user-defined and the built-in ones
+///
+/// User-defined Providers take precedence, and come with their own protocol. Built-in Providers
+/// are looked up among the ones for the given protocol.
+/// Returns `None` (and logs an error) if no such Provider exists.
+///
+/// # Parameters
+///
+/// * `protocol` - The DNS-over-HTTPS Protocol the (built-in) Provider should support
+/// * `provider_id` - Identifier of the Provider: if `None`, the default for the protocol is used
+/// * `request_mode` - Request Mode that overrides the one preferred by the Provider (if any)
+/// * `custom_providers` - The user-defined Providers
+pub fn provider_for(protocol: DoHProtocol, provider_id: Option<&str>, request_mode: Option<DoHRequestMode>, custom_providers: &[CustomProvider]) -> Option<Box<dyn DoHProvider>> {
+  let custom_provider = provider_id.and_then(|id| custom_providers.iter().find(|custom_provider| custom_provider.id == id));
+
+  let mut provider: Box<dyn DoHProvider> = match custom_provider {
+    Some(custom_provider) => match custom_provider.build() {
+      Ok(provider) => provider,
+      Err(err) => {
+        error!("Unable to build provider '{}': {}", custom_provider.id, err);
+        return None;
+      }
+    },
+    None => {
+      let builtin_provider: Option<Box<dyn DoHProvider>> = match protocol {
+        DoHProtocol::JSON => DoHJsonProvider::available()
+          .remove(provider_id.unwrap_or(DoHJsonProvider::default_id()))
+          .map(|provider| Box::new(provider) as Box<dyn DoHProvider>),
+        DoHProtocol::WIRE => DoHWireProvider::available()
+          .remove(provider_id.unwrap_or(DoHWireProvider::default_id()))
+          .map(|provider| Box::new(provider) as Box<dyn DoHProvider>),
+      };
+
+      match builtin_provider {
+        Some(provider) => provider,
+        None => {
+          error!("Unknown provider '{}' for protocol '{}'", provider_id.unwrap_or_default(), protocol);
+          return None;
+        }
+      }
+    }
+  };
+
+  if let Some(request_mode) = request_mode {
+    match provider.downcast_mut::<DoHWireProvider>() {
+      Some(wire_provider) => wire_provider.set_request_mode(request_mode),
+      None => if request_mode == DoHRequestMode::POST {
+        warn!("Protocol '{}' only supports request mode '{}': ignoring '{}'", DoHProtocol::JSON, DoHRequestMode::GET, DoHRequestMode::POST);
+      },
+    }
+  }
+
+  Some(provider)
+}
@@ -2,9 +2,34 @@
 //!
 //! This is here to keep things DRY
 
+use crate::core::{protocol::DoHProtocol, strategy::DoHUpstreamStrategy};
+
 use log::LevelFilter;
 
+use std::{env, path::PathBuf};
+
 pub const IPV4_DEFAULT: &'static str = "127.0.0.1";
 pub const IPV6_DEFAULT: &'static str = "::1";
 pub const PORT_DEFAULT: &'static str = "53";
-pub const LOG_FILTER_DEFAULT: LevelFilter = LevelFilter::Error;
\ No newline at end of file
+pub const PROTOCOL_DEFAULT: DoHProtocol = DoHProtocol::JSON;
+pub const CUSTOM_PROVIDER_PROTOCOL_DEFAULT: DoHProtocol = DoHProtocol::WIRE;
+pub const STRATEGY_DEFAULT: DoHUpstreamStrategy = DoHUpstreamStrategy::FAILOVER;
+pub const RACE_COUNT_DEFAULT: &'static str = "2";
+pub const CACHE_SIZE_DEFAULT: &'static str = "4096";
+pub const HEALTH_CHECK_INTERVAL_DEFAULT: &'static str = "30";
+pub const BLOCK_RESPONSE_DEFAULT: &'static str = "nxdomain";
+pub const BLOCK_TTL_DEFAULT: &'static str = "60";
+pub const LIST_REFRESH_INTERVAL_DEFAULT: &'static str = "86400";
+pub const LOCAL_TTL_DEFAULT: &'static str = "300";
+pub const LOG_FILTER_DEFAULT: LevelFilter = LevelFilter::Error;
+
+/// Where to cache the lists downloaded from URLs, if not configured
+///
+/// That is `$XDG_CACHE_HOME/mooncell/lists`, falling back to `$HOME/.cache` and then to the temporary directory.
+pub fn list_cache_dir_default() -> PathBuf {
+  env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+    .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+    .unwrap_or_else(env::temp_dir)
+    .join("mooncell")
+    .join("lists")
+}
\ No newline at end of file
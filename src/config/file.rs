@@ -0,0 +1,660 @@
+//! Configuration File (TOML) implementation of `Config`
+//!
+//! An example of configuration file, with all the supported settings:
+//!
+//! ```toml
+//! log_level = "info"
+//!
+//! [listen]
+//! ipv4 = ["127.0.0.1"]
+//! ipv6 = ["::1"]
+//! port = 53
+//!
+//! [resolver]
+//! protocol = "wire"
+//! provider = ["cloudflare", "quad9"]
+//! request_mode = "post"
+//! bootstrap_dns = "9.9.9.9:53"
+//! fallback_dns = "192.168.1.1"
+//! strategy = "weighted"
+//! race_count = 2
+//! weights = { cloudflare = 3, quad9 = 1 }
+//!
+//! [cache]
+//! size = 4096
+//!
+//! [health_check]
+//! interval = 30
+//!
+//! [metrics]
+//! address = "127.0.0.1:9153"
+//!
+//! [query_log]
+//! destination = "/var/log/mooncell/queries.log"
+//! anonymize = true
+//!
+//! [dnstap]
+//! destination = "unix:/run/dnstap.sock"
+//! identity = "resolver-1"
+//!
+//! [filter]
+//! blocklists = ["/etc/mooncell/ads.txt", "https://lists.example.com/malware.hosts"]
+//! allowlists = ["/etc/mooncell/allowed.txt"]
+//! block_response = "null"
+//! block_ttl = 60
+//! refresh_interval = 86400
+//! cache_dir = "/var/cache/mooncell/lists"
+//!
+//! [local]
+//! records = ["nas.home.lan A 192.168.1.10", "www.home.lan CNAME nas.home.lan", "home.lan MX 10 mail.home.lan"]
+//! hosts_files = ["/etc/hosts"]
+//! ttl = 300
+//!
+//! [routing]
+//! routes = ["corp.example=tcp:10.0.0.53", "10.in-addr.arpa=10.0.0.53", "lan=192.168.1.1", "example.org=quad9"]
+//!
+//! [[providers]]
+//! id = "internal"
+//! url = "https://doh.internal.example/dns-query"
+//! protocol = "wire"
+//! headers = { "X-Api-Key" = "secret" }
+//! bootstrap = ["10.0.0.53"]
+//! ```
+//!
+//! Every setting is optional: when missing, the default value is used.
+//! The `provider` can be a single identifier, or a list (in order of preference, for failover):
+//! how queries are spread across them is decided by the `strategy`.
+//! User-defined providers (`[[providers]]`) can be selected via `provider` like the built-in ones,
+//! and as the target of `routes`.
+
+use super::{defaults, config::{self, Config, CustomProvider}};
+use crate::core::{protocol::DoHProtocol, mode::DoHRequestMode, provider::DoHProvider, strategy::DoHUpstreamStrategy};
+use crate::dns::client;
+use crate::querylog::QueryLogDestination;
+use crate::core::filter::{block::DoHBlockResponse, source::DomainListSource};
+use crate::core::local::record::LocalRecord;
+use crate::core::routing::DoHRoute;
+use crate::dnstap::DnstapDestination;
+
+use log::{error, LevelFilter};
+use serde::{Deserialize, Deserializer, de::Error as DeError};
+use toml;
+
+use std::{fmt, fs, io, path::{Path, PathBuf}, str::FromStr, collections::{BTreeMap, HashMap}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}};
+
+/// Configuration File
+///
+/// This implements `Config` by parsing a TOML file. It's usually loaded via the `--config`
+/// command line argument, and then the other command line arguments are layered on top (see `CLI`).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+  #[serde(deserialize_with = "deserialize_option_from_str")]
+  pub log_level: Option<LevelFilter>,
+  pub listen: FileConfigListen,
+  pub resolver: FileConfigResolver,
+  pub cache: FileConfigCache,
+  pub health_check: FileConfigHealthCheck,
+  pub metrics: FileConfigMetrics,
+  pub query_log: FileConfigQueryLog,
+  pub dnstap: FileConfigDnstap,
+  pub filter: FileConfigFilter,
+  pub local: FileConfigLocal,
+  pub routing: FileConfigRouting,
+  pub providers: Vec<FileConfigProvider>,
+}
+
+/// Section `[listen]` of the Configuration File
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfigListen {
+  pub ipv4: Option<Vec<Ipv4Addr>>,
+  pub ipv6: Option<Vec<Ipv6Addr>>,
+  pub port: Option<u16>,
+}
+
+/// Section `[resolver]` of the Configuration File
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfigResolver {
+  #[serde(deserialize_with = "deserialize_option_from_str")]
+  pub protocol: Option<DoHProtocol>,
+  #[serde(deserialize_with = "deserialize_option_one_or_many")]
+  pub provider: Option<Vec<String>>,
+  #[serde(deserialize_with = "deserialize_option_from_str")]
+  pub request_mode: Option<DoHRequestMode>,
+  #[serde(deserialize_with = "deserialize_option_server_address")]
+  pub bootstrap_dns: Option<SocketAddr>,
+  #[serde(deserialize_with = "deserialize_option_server_address")]
+  pub fallback_dns: Option<SocketAddr>,
+  #[serde(deserialize_with = "deserialize_option_from_str")]
+  pub strategy: Option<DoHUpstreamStrategy>,
+  pub race_count: Option<usize>,
+  pub weights: BTreeMap<String, u32>,
+}
+
+/// Section `[cache]` of the Configuration File
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfigCache {
+  pub size: Option<usize>,
+}
+
+/// Section `[health_check]` of the Configuration File
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfigHealthCheck {
+  pub interval: Option<u64>,
+}
+
+/// Section `[metrics]` of the Configuration File
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfigMetrics {
+  pub address: Option<SocketAddr>,
+}
+
+/// Section `[query_log]` of the Configuration File
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfigQueryLog {
+  #[serde(deserialize_with = "deserialize_option_from_str")]
+  pub destination: Option<QueryLogDestination>,
+  pub anonymize: Option<bool>,
+}
+
+/// Section `[dnstap]` of the Configuration File
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfigDnstap {
+  #[serde(deserialize_with = "deserialize_option_from_str")]
+  pub destination: Option<DnstapDestination>,
+  pub identity: Option<String>,
+}
+
+/// Section `[filter]` of the Configuration File
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfigFilter {
+  #[serde(deserialize_with = "deserialize_option_vec_from_str")]
+  pub blocklists: Option<Vec<DomainListSource>>,
+  #[serde(deserialize_with = "deserialize_option_vec_from_str")]
+  pub allowlists: Option<Vec<DomainListSource>>,
+  #[serde(deserialize_with = "deserialize_option_from_str")]
+  pub block_response: Option<DoHBlockResponse>,
+  pub block_ttl: Option<u32>,
+  pub refresh_interval: Option<u64>,
+  pub cache_dir: Option<PathBuf>,
+}
+
+/// Section `[local]` of the Configuration File
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfigLocal {
+  #[serde(deserialize_with = "deserialize_option_vec_from_str")]
+  pub records: Option<Vec<LocalRecord>>,
+  pub hosts_files: Option<Vec<PathBuf>>,
+  pub ttl: Option<u32>,
+}
+
+/// Section `[routing]` of the Configuration File
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfigRouting {
+  #[serde(deserialize_with = "deserialize_option_vec_from_str")]
+  pub routes: Option<Vec<DoHRoute>>,
+}
+
+/// Section `[[providers]]` of the Configuration File (one per user-defined Provider)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfigProvider {
+  pub id: String,
+  pub url: String,
+  #[serde(default = "default_custom_provider_protocol", deserialize_with = "deserialize_from_str")]
+  pub protocol: DoHProtocol,
+  #[serde(default)]
+  pub headers: BTreeMap<String, String>,
+  #[serde(default)]
+  pub bootstrap: Vec<IpAddr>,
+}
+
+impl FileConfigProvider {
+  /// Converts to a `CustomProvider`, validating the headers and the URL
+  pub fn to_custom_provider(&self) -> Result<CustomProvider, FileConfigError> {
+    let headers = config::parse_headers(self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())))
+      .map_err(|err| FileConfigError::Provider(self.id.clone(), err.to_string()))?;
+
+    let custom_provider = CustomProvider {
+      id: self.id.clone(),
+      url: self.url.clone(),
+      protocol: self.protocol,
+      headers,
+      bootstrap: self.bootstrap.clone(),
+    };
+
+    custom_provider.build().map_err(|err| FileConfigError::Provider(self.id.clone(), err.to_string()))?;
+
+    Ok(custom_provider)
+  }
+}
+
+impl FileConfig {
+  /// Constructor from the path of a TOML file
+  ///
+  /// # Parameters
+  ///
+  /// * `path` - Path of the TOML file to read
+  pub fn from_file<P: AsRef<Path>>(path: P) -> Result<FileConfig, FileConfigError> {
+    let raw_file_config = fs::read_to_string(&path)
+      .map_err(|err| FileConfigError::Io(path.as_ref().display().to_string(), err))?;
+
+    FileConfig::from_str(&raw_file_config)
+  }
+}
+
+impl FromStr for FileConfig {
+  type Err = FileConfigError;
+
+  fn from_str(raw_file_config: &str) -> Result<Self, Self::Err> {
+    let file_config: FileConfig = toml::from_str(raw_file_config).map_err(FileConfigError::Toml)?;
+
+    // Validate the user-defined providers at load time, rather than when they are used
+    for provider in &file_config.providers {
+      provider.to_custom_provider()?;
+    }
+
+    Ok(file_config)
+  }
+}
+
+impl Config for FileConfig {
+  fn ipv4(&self) -> Vec<Ipv4Addr> {
+    self.listen.ipv4.clone().unwrap_or_else(|| vec![defaults::IPV4_DEFAULT.parse().unwrap()])
+  }
+
+  fn ipv6(&self) -> Vec<Ipv6Addr> {
+    self.listen.ipv6.clone().unwrap_or_else(|| vec![defaults::IPV6_DEFAULT.parse().unwrap()])
+  }
+
+  fn port(&self) -> u16 {
+    self.listen.port.unwrap_or_else(|| defaults::PORT_DEFAULT.parse().unwrap())
+  }
+
+  fn log_filter(&self) -> LevelFilter {
+    self.log_level.unwrap_or(defaults::LOG_FILTER_DEFAULT)
+  }
+
+  fn protocol(&self) -> DoHProtocol {
+    self.resolver.protocol.unwrap_or(defaults::PROTOCOL_DEFAULT)
+  }
+
+  fn request_mode(&self) -> Option<DoHRequestMode> {
+    self.resolver.request_mode
+  }
+
+  fn providers(&self) -> Vec<Box<dyn DoHProvider>> {
+    config::providers_for(self.protocol(), &self.resolver.provider.clone().unwrap_or_default(), self.request_mode(), &self.custom_providers())
+  }
+
+  fn strategy(&self) -> DoHUpstreamStrategy {
+    self.resolver.strategy.unwrap_or(defaults::STRATEGY_DEFAULT)
+  }
+
+  fn race_count(&self) -> usize {
+    self.resolver.race_count.unwrap_or_else(|| defaults::RACE_COUNT_DEFAULT.parse().unwrap())
+  }
+
+  fn provider_weights(&self) -> HashMap<String, u32> {
+    self.resolver.weights.iter().map(|(id, weight)| (id.clone(), *weight)).collect()
+  }
+
+  fn custom_providers(&self) -> Vec<CustomProvider> {
+    self.providers.iter()
+      .filter_map(|provider| provider.to_custom_provider().map_err(|err| error!("{}", err)).ok())
+      .collect()
+  }
+
+  fn bootstrap_dns(&self) -> Option<SocketAddr> {
+    self.resolver.bootstrap_dns
+  }
+
+  fn fallback_dns(&self) -> Option<SocketAddr> {
+    self.resolver.fallback_dns
+  }
+
+  fn cache_size(&self) -> usize {
+    self.cache.size.unwrap_or_else(|| defaults::CACHE_SIZE_DEFAULT.parse().unwrap())
+  }
+
+  fn health_check_interval(&self) -> u64 {
+    self.health_check.interval.unwrap_or_else(|| defaults::HEALTH_CHECK_INTERVAL_DEFAULT.parse().unwrap())
+  }
+
+  fn metrics_address(&self) -> Option<SocketAddr> {
+    self.metrics.address
+  }
+
+  fn query_log(&self) -> Option<QueryLogDestination> {
+    self.query_log.destination.clone()
+  }
+
+  fn query_log_anonymize(&self) -> bool {
+    self.query_log.anonymize.unwrap_or(false)
+  }
+
+  fn dnstap(&self) -> Option<DnstapDestination> {
+    self.dnstap.destination.clone()
+  }
+
+  fn dnstap_identity(&self) -> Option<String> {
+    self.dnstap.identity.clone()
+  }
+
+  fn blocklists(&self) -> Vec<DomainListSource> {
+    self.filter.blocklists.clone().unwrap_or_default()
+  }
+
+  fn allowlists(&self) -> Vec<DomainListSource> {
+    self.filter.allowlists.clone().unwrap_or_default()
+  }
+
+  fn list_refresh_interval(&self) -> u64 {
+    self.filter.refresh_interval.unwrap_or_else(|| defaults::LIST_REFRESH_INTERVAL_DEFAULT.parse().unwrap())
+  }
+
+  fn list_cache_dir(&self) -> PathBuf {
+    self.filter.cache_dir.clone().unwrap_or_else(defaults::list_cache_dir_default)
+  }
+
+  fn block_response(&self) -> DoHBlockResponse {
+    self.filter.block_response.clone().unwrap_or_else(|| defaults::BLOCK_RESPONSE_DEFAULT.parse().unwrap())
+  }
+
+  fn block_ttl(&self) -> u32 {
+    self.filter.block_ttl.unwrap_or_else(|| defaults::BLOCK_TTL_DEFAULT.parse().unwrap())
+  }
+
+  fn local_records(&self) -> Vec<LocalRecord> {
+    self.local.records.clone().unwrap_or_default()
+  }
+
+  fn hosts_files(&self) -> Vec<PathBuf> {
+    self.local.hosts_files.clone().unwrap_or_default()
+  }
+
+  fn local_ttl(&self) -> u32 {
+    self.local.ttl.unwrap_or_else(|| defaults::LOCAL_TTL_DEFAULT.parse().unwrap())
+  }
+
+  fn routes(&self) -> Vec<DoHRoute> {
+    self.routing.routes.clone().unwrap_or_default()
+  }
+}
+
+/// Error that happens when loading a `FileConfig` fails
+#[derive(Debug)]
+pub enum FileConfigError {
+  /// The file couldn't be read
+  Io(String, io::Error),
+  /// The file content isn't a valid configuration
+  Toml(toml::de::Error),
+  /// A user-defined provider isn't valid
+  Provider(String, String),
+}
+
+impl fmt::Display for FileConfigError {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      FileConfigError::Io(path, err) => write!(fmtr, "Unable to read configuration file '{}': {}", path, err),
+      FileConfigError::Toml(err) => write!(fmtr, "Invalid configuration file: {}", err),
+      FileConfigError::Provider(id, reason) => write!(fmtr, "Invalid provider '{}' in configuration file: {}", id, reason),
+    }
+  }
+}
+
+/// Protocol of user-defined providers, unless otherwise specified: the standard one (RFC 8484)
+fn default_custom_provider_protocol() -> DoHProtocol {
+  defaults::CUSTOM_PROVIDER_PROTOCOL_DEFAULT
+}
+
+/// Deserializes a `&str` into any type that implements `FromStr`
+fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+  where D: Deserializer<'de>, T: FromStr, T::Err: fmt::Display {
+  let raw_value: String = Deserialize::deserialize(deserializer)?;
+  T::from_str(&raw_value).map_err(DeError::custom)
+}
+
+/// Deserializes an optional `&str` into any type that implements `FromStr`
+fn deserialize_option_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+  where D: Deserializer<'de>, T: FromStr, T::Err: fmt::Display {
+  let raw_value: String = Deserialize::deserialize(deserializer)?;
+  T::from_str(&raw_value).map(Some).map_err(DeError::custom)
+}
+
+/// Deserializes an optional list of `&str` into a list of types that implement `FromStr`
+fn deserialize_option_vec_from_str<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+  where D: Deserializer<'de>, T: FromStr, T::Err: fmt::Display {
+  let raw_values: Vec<String> = Deserialize::deserialize(deserializer)?;
+  raw_values.iter()
+    .map(|raw_value| T::from_str(raw_value).map_err(DeError::custom))
+    .collect::<Result<Vec<T>, D::Error>>()
+    .map(Some)
+}
+
+/// Deserializes either a single `String` or a list of them
+fn deserialize_option_one_or_many<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> where D: Deserializer<'de> {
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+  }
+
+  Ok(Some(match OneOrMany::deserialize(deserializer)? {
+    OneOrMany::One(value) => vec![value],
+    OneOrMany::Many(values) => values,
+  }))
+}
+
+/// Deserializes an optional `&str` into the `SocketAddr` of a DNS server (see `client::parse_server_address()`)
+fn deserialize_option_server_address<'de, D>(deserializer: D) -> Result<Option<SocketAddr>, D::Error> where D: Deserializer<'de> {
+  let raw_value: String = Deserialize::deserialize(deserializer)?;
+  client::parse_server_address(&raw_value).map(Some).map_err(DeError::custom)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn should_parse_full_file_config() {
+    let file_config = FileConfig::from_str(r#"
+      log_level = "debug"
+
+      [listen]
+      ipv4 = ["127.0.0.1", "10.0.0.1"]
+      ipv6 = []
+      port = 5353
+
+      [resolver]
+      protocol = "wire"
+      provider = "google"
+      request_mode = "post"
+      bootstrap_dns = "9.9.9.9"
+      fallback_dns = "[fd00::1]:5353"
+      strategy = "race"
+      race_count = 3
+      weights = { google = 5 }
+
+      [cache]
+      size = 128
+
+      [health_check]
+      interval = 0
+
+      [metrics]
+      address = "127.0.0.1:9153"
+
+      [query_log]
+      destination = "unix:/run/collector.sock"
+      anonymize = true
+
+      [dnstap]
+      destination = "/var/log/mooncell.dnstap"
+      identity = "resolver-1"
+
+      [filter]
+      blocklists = ["/etc/mooncell/ads.txt", "https://lists.example.com/malware.hosts"]
+      allowlists = ["/etc/mooncell/allowed.txt"]
+      block_response = "10.0.0.80"
+      block_ttl = 5
+      refresh_interval = 3600
+      cache_dir = "/var/cache/mooncell/lists"
+
+      [local]
+      records = ["nas.home.lan A 192.168.1.10"]
+      hosts_files = ["/etc/hosts"]
+      ttl = 60
+
+      [routing]
+      routes = ["corp.example=tcp:10.0.0.53"]
+    "#).unwrap();
+
+    assert_eq!(file_config.log_filter(), LevelFilter::Debug);
+    assert_eq!(file_config.ipv4(), vec![Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 1)]);
+    assert!(file_config.ipv6().is_empty());
+    assert_eq!(file_config.port(), 5353);
+    assert_eq!(file_config.protocol().to_string(), "wire");
+    assert_eq!(file_config.request_mode(), Some(DoHRequestMode::POST));
+    assert_eq!(file_config.bootstrap_dns(), Some("9.9.9.9:53".parse().unwrap()));
+    assert_eq!(file_config.fallback_dns(), Some("[fd00::1]:5353".parse().unwrap()));
+    assert_eq!(file_config.cache_size(), 128);
+    assert_eq!(file_config.health_check_interval(), 0);
+    assert_eq!(file_config.metrics_address(), Some("127.0.0.1:9153".parse().unwrap()));
+    assert_eq!(file_config.query_log(), Some(QueryLogDestination::UnixSocket("/run/collector.sock".into())));
+    assert!(file_config.query_log_anonymize());
+    assert_eq!(file_config.dnstap(), Some(DnstapDestination::File("/var/log/mooncell.dnstap".into())));
+    assert_eq!(file_config.dnstap_identity(), Some("resolver-1".to_string()));
+    assert_eq!(file_config.blocklists(), vec![
+      DomainListSource::File(PathBuf::from("/etc/mooncell/ads.txt")),
+      DomainListSource::Url("https://lists.example.com/malware.hosts".into()),
+    ]);
+    assert_eq!(file_config.allowlists(), vec![DomainListSource::File(PathBuf::from("/etc/mooncell/allowed.txt"))]);
+    assert_eq!(file_config.block_response(), DoHBlockResponse::IP(vec!["10.0.0.80".parse().unwrap()]));
+    assert_eq!(file_config.block_ttl(), 5);
+    assert_eq!(file_config.list_refresh_interval(), 3600);
+    assert_eq!(file_config.list_cache_dir(), PathBuf::from("/var/cache/mooncell/lists"));
+    assert_eq!(file_config.local_records(), vec!["nas.home.lan A 192.168.1.10".parse::<LocalRecord>().unwrap()]);
+    assert_eq!(file_config.hosts_files(), vec![PathBuf::from("/etc/hosts")]);
+    assert_eq!(file_config.local_ttl(), 60);
+    assert_eq!(file_config.routes(), vec!["corp.example=tcp:10.0.0.53".parse::<DoHRoute>().unwrap()]);
+    assert_eq!(file_config.strategy(), DoHUpstreamStrategy::RACE);
+    assert_eq!(file_config.race_count(), 3);
+    assert_eq!(file_config.provider_weights().get("google"), Some(&5));
+
+    let providers = file_config.providers();
+    assert_eq!(providers.len(), 1);
+    assert_eq!(providers[0].id(), "google");
+    assert_eq!(providers[0].request_mode(), DoHRequestMode::POST);
+  }
+
+  #[test]
+  fn should_use_defaults_for_empty_file_config() {
+    let file_config = FileConfig::from_str("").unwrap();
+
+    assert_eq!(file_config.log_filter(), defaults::LOG_FILTER_DEFAULT);
+    assert_eq!(file_config.ipv4(), vec![Ipv4Addr::new(127, 0, 0, 1)]);
+    assert_eq!(file_config.ipv6(), vec![Ipv6Addr::LOCALHOST]);
+    assert_eq!(file_config.port(), 53);
+    assert_eq!(file_config.protocol().to_string(), "json");
+    assert_eq!(file_config.request_mode(), None);
+    assert_eq!(file_config.bootstrap_dns(), None);
+    assert_eq!(file_config.fallback_dns(), None);
+    assert_eq!(file_config.cache_size(), 4096);
+    assert_eq!(file_config.health_check_interval(), 30);
+    assert_eq!(file_config.metrics_address(), None);
+    assert_eq!(file_config.query_log(), None);
+    assert!(!file_config.query_log_anonymize());
+    assert_eq!(file_config.dnstap(), None);
+    assert_eq!(file_config.dnstap_identity(), None);
+    assert!(file_config.blocklists().is_empty());
+    assert!(file_config.allowlists().is_empty());
+    assert_eq!(file_config.block_response(), DoHBlockResponse::NXDOMAIN);
+    assert_eq!(file_config.block_ttl(), 60);
+    assert_eq!(file_config.list_refresh_interval(), 86400);
+    assert!(file_config.local_records().is_empty());
+    assert!(file_config.hosts_files().is_empty());
+    assert_eq!(file_config.local_ttl(), 300);
+    assert!(file_config.routes().is_empty());
+    assert_eq!(file_config.strategy(), DoHUpstreamStrategy::FAILOVER);
+    assert_eq!(file_config.race_count(), 2);
+    assert!(file_config.provider_weights().is_empty());
+    assert_eq!(file_config.providers().len(), 1);
+    assert_eq!(file_config.providers()[0].id(), "cloudflare");
+  }
+
+  #[test]
+  fn should_parse_custom_providers() {
+    let file_config = FileConfig::from_str(r#"
+      [resolver]
+      provider = "internal"
+
+      [[providers]]
+      id = "internal"
+      url = "https://doh.internal.example/dns-query"
+      headers = { "X-Api-Key" = "secret" }
+      bootstrap = ["10.0.0.53"]
+
+      [[providers]]
+      id = "internal-json"
+      url = "https://doh.internal.example/resolve"
+      protocol = "json"
+    "#).unwrap();
+
+    let custom_providers = file_config.custom_providers();
+    assert_eq!(custom_providers.len(), 2);
+    assert_eq!(custom_providers[0].protocol.to_string(), "wire");
+    assert_eq!(custom_providers[0].headers.get("x-api-key").unwrap(), &"secret");
+    assert_eq!(custom_providers[1].protocol.to_string(), "json");
+    assert!(custom_providers[1].bootstrap.is_empty());
+
+    // The protocol of the user-defined provider wins over the (default) one in `[resolver]`
+    let provider = file_config.providers().remove(0);
+    assert_eq!(provider.id(), "internal");
+    assert_eq!(provider.protocol().to_string(), "wire");
+    assert_eq!(provider.hostname(), "doh.internal.example");
+    assert_eq!(provider.bootstrap_addresses(), &["10.0.0.53".parse::<IpAddr>().unwrap()]);
+  }
+
+  #[test]
+  fn should_parse_multiple_providers() {
+    let file_config = FileConfig::from_str(r#"
+      [resolver]
+      protocol = "wire"
+      provider = ["quad9", "google", "cloudflare"]
+    "#).unwrap();
+
+    let provider_ids: Vec<String> = file_config.providers().iter().map(|provider| provider.id().to_string()).collect();
+    assert_eq!(provider_ids, vec!["quad9", "google", "cloudflare"]);
+
+    // Any unknown provider makes the whole list invalid
+    let file_config = FileConfig::from_str("[resolver]\nprovider = [\"quad9\", \"unknown\"]").unwrap();
+    assert!(file_config.providers().is_empty());
+  }
+
+  #[test]
+  fn should_reject_invalid_custom_providers() {
+    assert!(FileConfig::from_str("[[providers]]\nid = \"x\"\nurl = \"not-a-url\"").is_err());
+    assert!(FileConfig::from_str("[[providers]]\nid = \"x\"\nurl = \"https://x.example/\"\nheaders = { \"bad header\" = \"v\" }").is_err());
+    assert!(FileConfig::from_str("[[providers]]\nurl = \"https://x.example/\"").is_err());
+  }
+
+  #[test]
+  fn should_reject_invalid_file_config() {
+    assert!(FileConfig::from_str("[resolver]\nprotocol = \"carrier-pigeon\"").is_err());
+    assert!(FileConfig::from_str("[listen]\nport = \"fifty-three\"").is_err());
+    assert!(FileConfig::from_str("[unknown]\nkey = 1").is_err());
+    assert!(FileConfig::from_file("./test/fixtures/does-not-exist.toml").is_err());
+  }
+}
@@ -1,5 +1,14 @@
 pub mod processor;
 pub mod resolver;
+pub mod cache;
+pub mod filter;
+pub mod local;
+pub mod routing;
+pub mod upstream;
+pub mod strategy;
+pub mod health;
+pub mod context;
 pub mod protocol;
+pub mod mode;
 pub mod provider;
-pub mod response;
\ No newline at end of file
+pub mod response;
@@ -0,0 +1,396 @@
+//! In-memory, TTL-aware cache of DNS responses, in front of any `DoHResolver`
+
+use super::{resolver::{DoHResolver, DoHResolutionError}, context::{self, CacheStatus}};
+use crate::dns::protocol::*;
+use crate::metrics;
+
+use log::*;
+
+use std::{collections::{BTreeMap, HashMap}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+type Result<T> = std::result::Result<T, DoHResolutionError>;
+
+/// Key used to index cached responses
+///
+/// Responses depend not only on the question, but also on the DNSSEC-related bits of the query:
+/// `DO` (DNSSEC OK, in EDNS) and `CD` (Checking Disabled).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DoHCacheKey {
+  name: DnsDomainName,
+  query_type: DnsRecordType,
+  query_class: DnsClass,
+  dnssec_ok: bool,
+  checking_disabled: bool,
+}
+
+impl DoHCacheKey {
+
+  /// Builds the key for the given DNS Query message, if it can be cached
+  ///
+  /// Only messages carrying exactly 1 query can be cached.
+  fn from_query_message(dns_message: &DnsMessage) -> Option<DoHCacheKey> {
+    match dns_message.queries() {
+      [query] => Some(DoHCacheKey {
+        name: query.name().clone(),
+        query_type: query.query_type(),
+        query_class: query.query_class(),
+        dnssec_ok: dns_message.edns().map(|edns| edns.dnssec_ok()).unwrap_or(false),
+        checking_disabled: dns_message.checking_disabled(),
+      }),
+      _ => None,
+    }
+  }
+
+}
+
+/// A cached DNS response
+#[derive(Debug, Clone)]
+struct DoHCacheEntry {
+  response: DnsMessage,
+  inserted: Instant,
+  ttl: Duration,
+  seq: u64,
+}
+
+impl DoHCacheEntry {
+
+  fn expires(&self) -> Instant {
+    self.inserted + self.ttl
+  }
+
+  fn is_expired(&self, now: Instant) -> bool {
+    now >= self.expires()
+  }
+
+  /// Key of this entry in `DoHCache::expiries`: the sequence number tells apart entries expiring at the same time
+  fn expiry_key(&self) -> (Instant, u64) {
+    (self.expires(), self.seq)
+  }
+
+}
+
+/// The actual cache storage, with a maximum number of entries
+///
+/// Entries are also indexed by expiry, so that making room for a new entry doesn't require
+/// scanning the whole cache (while holding its lock).
+#[derive(Debug)]
+struct DoHCache {
+  capacity: usize,
+  entries: HashMap<DoHCacheKey, DoHCacheEntry>,
+  expiries: BTreeMap<(Instant, u64), DoHCacheKey>,
+  next_seq: u64,
+}
+
+impl DoHCache {
+
+  fn new(capacity: usize) -> DoHCache {
+    DoHCache {
+      capacity,
+      entries: HashMap::with_capacity(capacity),
+      expiries: BTreeMap::new(),
+      next_seq: 0,
+    }
+  }
+
+  /// Removes the entry for the given key (if any), from both the entries and their expiry index
+  fn remove(&mut self, key: &DoHCacheKey) {
+    if let Some(entry) = self.entries.remove(key) {
+      self.expiries.remove(&entry.expiry_key());
+    }
+  }
+
+  /// Returns the response cached for the given key, with TTLs counted down by the elapsed time
+  fn get(&mut self, key: &DoHCacheKey, now: Instant) -> Option<DnsMessage> {
+    let entry = match self.entries.get(key) {
+      Some(entry) if !entry.is_expired(now) => entry,
+      Some(_) => {
+        self.remove(key);
+        return None;
+      },
+      None => return None,
+    };
+
+    let elapsed = now.duration_since(entry.inserted).as_secs() as u32;
+    let mut response = entry.response.clone();
+    let answers = countdown_ttls(response.take_answers(), elapsed);
+    let name_servers = countdown_ttls(response.take_name_servers(), elapsed);
+    let additionals = countdown_ttls(response.take_additionals(), elapsed);
+    response.insert_answers(answers);
+    response.insert_name_servers(name_servers);
+    response.insert_additionals(additionals);
+
+    Some(response)
+  }
+
+  /// Stores the response for the given key, making room for it if the cache is full
+  ///
+  /// The entry evicted is the one closest to expire (or already expired).
+  fn insert(&mut self, key: DoHCacheKey, response: DnsMessage, ttl: Duration, now: Instant) {
+    self.remove(&key);
+
+    while self.entries.len() >= self.capacity {
+      let evict_key = match self.expiries.keys().next() {
+        Some(expiry_key) => *expiry_key,
+        None => break,
+      };
+      if let Some(key) = self.expiries.remove(&evict_key) {
+        self.entries.remove(&key);
+      }
+    }
+
+    let entry = DoHCacheEntry {
+      response,
+      inserted: now,
+      ttl,
+      seq: self.next_seq,
+    };
+    self.next_seq += 1;
+    self.expiries.insert(entry.expiry_key(), key.clone());
+    self.entries.insert(key, entry);
+  }
+
+}
+
+/// Decrements the TTL of the given records by the elapsed seconds
+fn countdown_ttls(mut records: Vec<DnsRecord>, elapsed: u32) -> Vec<DnsRecord> {
+  for record in records.iter_mut() {
+    let ttl = record.ttl().saturating_sub(elapsed);
+    record.set_ttl(ttl);
+  }
+
+  records
+}
+
+/// Builds a response to the given request, out of a cached response
+///
+/// The cached response might have been originated by a request with different ID and
+/// question case: those are taken from the request instead.
+fn response_from_cached(req_dns_msg: &DnsMessage, cached_dns_msg: DnsMessage) -> DnsMessage {
+  let mut res_dns_msg = DnsMessage::new();
+  res_dns_msg.set_id(req_dns_msg.id());
+  res_dns_msg.set_message_type(DnsMessageType::Response);
+  res_dns_msg.set_op_code(cached_dns_msg.op_code());
+  res_dns_msg.set_authoritative(cached_dns_msg.authoritative());
+  res_dns_msg.set_truncated(cached_dns_msg.truncated());
+  res_dns_msg.set_recursion_desired(cached_dns_msg.recursion_desired());
+  res_dns_msg.set_recursion_available(cached_dns_msg.recursion_available());
+  res_dns_msg.set_authentic_data(cached_dns_msg.authentic_data());
+  res_dns_msg.set_checking_disabled(cached_dns_msg.checking_disabled());
+  res_dns_msg.set_response_code(cached_dns_msg.response_code());
+  res_dns_msg.add_queries(req_dns_msg.queries().to_vec());
+  if let Some(edns) = cached_dns_msg.edns() {
+    res_dns_msg.set_edns(edns.clone());
+  }
+
+  let mut cached_dns_msg = cached_dns_msg;
+  res_dns_msg.insert_answers(cached_dns_msg.take_answers());
+  res_dns_msg.insert_name_servers(cached_dns_msg.take_name_servers());
+  res_dns_msg.insert_additionals(cached_dns_msg.take_additionals());
+
+  res_dns_msg
+}
+
+/// Determines for how long a response can be cached, if at all
+///
+/// Positive responses are cached for the smallest TTL of their records.
+/// Negative responses (`NXDOMAIN` or `NOERROR` with no answers) are cached following
+/// [RFC 2308](https://tools.ietf.org/html/rfc2308#section-5): for the smallest between the TTL
+/// of the `SOA` in the authority section and its `MINIMUM` field.
+/// Anything else (ex. `SERVFAIL`, truncated responses) is not cached.
+fn cacheable_ttl(response: &DnsMessage) -> Option<Duration> {
+  if response.truncated() {
+    return None;
+  }
+
+  let ttl = match response.response_code() {
+    DnsResponseCode::NoError if !response.answers().is_empty() => {
+      response.answers().iter()
+        .chain(response.name_servers().iter())
+        .chain(response.additionals().iter())
+        .map(|record| record.ttl())
+        .min()
+    },
+    DnsResponseCode::NoError | DnsResponseCode::NXDomain => {
+      response.name_servers().iter()
+        .filter_map(|record| match record.rdata() {
+          DnsRData::SOA(soa) => Some(std::cmp::min(record.ttl(), soa.minimum())),
+          _ => None,
+        })
+        .min()
+    },
+    _ => None,
+  };
+
+  match ttl {
+    Some(ttl) if ttl > 0 => Some(Duration::from_secs(ttl as u64)),
+    _ => None,
+  }
+}
+
+/// Resolver that caches responses of the `DoHResolver` it wraps
+///
+/// The cache is shared by all the clones of this resolver.
+#[derive(Clone)]
+pub struct DoHCachingResolver {
+  resolver: Box<DoHResolver + Send>,
+  cache: Arc<Mutex<DoHCache>>,
+}
+
+impl DoHCachingResolver {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  ///
+  /// * `resolver` - the `DoHResolver` to cache responses of
+  /// * `capacity` - maximum number of responses to keep in cache
+  pub fn new(resolver: Box<DoHResolver + Send>, capacity: usize) -> DoHCachingResolver {
+    DoHCachingResolver {
+      resolver,
+      cache: Arc::new(Mutex::new(DoHCache::new(capacity))),
+    }
+  }
+
+}
+
+impl DoHResolver for DoHCachingResolver {
+
+  fn resolve_query(&self, req_dns_msg: &DnsMessage) -> Result<DnsMessage> {
+    let key = match DoHCacheKey::from_query_message(req_dns_msg) {
+      Some(key) => key,
+      None => return self.resolver.resolve_query(req_dns_msg),
+    };
+
+    // Cache hit: respond right away, reusing the request ID and question
+    let cached_dns_msg = self.cache.lock().unwrap().get(&key, Instant::now());
+    if let Some(cached_dns_msg) = cached_dns_msg {
+      debug!("Cache hit: {:?}", key);
+      metrics::inc_counter(&metrics::CACHE_HITS, &[]);
+      context::set_cache_status(CacheStatus::Hit);
+      return Ok(response_from_cached(req_dns_msg, cached_dns_msg));
+    }
+
+    // Cache miss: resolve, then cache if possible
+    debug!("Cache miss: {:?}", key);
+    metrics::inc_counter(&metrics::CACHE_MISSES, &[]);
+    context::set_cache_status(CacheStatus::Miss);
+    let res_dns_msg = self.resolver.resolve_query(req_dns_msg)?;
+    if let Some(ttl) = cacheable_ttl(&res_dns_msg) {
+      self.cache.lock().unwrap().insert(key, res_dns_msg.clone(), ttl, Instant::now());
+    }
+
+    Ok(res_dns_msg)
+  }
+
+  fn box_clone(&self) -> Box<DoHResolver + Send> {
+    Box::new((*self).clone())
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_support::{CountingResolver, query_message};
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  fn caching_resolver(response_code: DnsResponseCode, ttl: u32) -> (DoHCachingResolver, Arc<AtomicUsize>) {
+    let resolver = CountingResolver::new(Some(response_code)).with_ttl(ttl);
+    let count = resolver.count();
+
+    (DoHCachingResolver::new(Box::new(resolver), 2), count)
+  }
+
+  #[test]
+  fn should_respond_from_cache() {
+    let (resolver, count) = caching_resolver(DnsResponseCode::NoError, 300);
+
+    let res = resolver.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap();
+    assert_eq!(res.id(), 1);
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+
+    // Same question (case-insensitive), different ID
+    let res = resolver.resolve(&query_message(2, "EXAMPLE.com.", DnsRecordType::A)).unwrap();
+    assert_eq!(res.id(), 2);
+    assert_eq!(res.answers().len(), 1);
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+
+    // Different question
+    resolver.resolve(&query_message(3, "example.org.", DnsRecordType::A)).unwrap();
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+  }
+
+  #[test]
+  fn should_count_down_ttls() {
+    let (resolver, _) = caching_resolver(DnsResponseCode::NoError, 300);
+    let req = query_message(1, "example.com.", DnsRecordType::A);
+    let key = DoHCacheKey::from_query_message(&req).unwrap();
+
+    let res = resolver.resolve(&req).unwrap();
+    let mut cache = resolver.cache.lock().unwrap();
+
+    let later = Instant::now() + Duration::from_secs(100);
+    let cached_res = cache.get(&key, later).unwrap();
+    assert!(cached_res.answers()[0].ttl() <= 200);
+    assert_eq!(cached_res.answers()[0].rdata(), res.answers()[0].rdata());
+
+    let expired = Instant::now() + Duration::from_secs(301);
+    assert!(cache.get(&key, expired).is_none());
+    assert!(cache.entries.is_empty());
+    assert!(cache.expiries.is_empty());
+  }
+
+  #[test]
+  fn should_not_cache_failures() {
+    let (resolver, count) = caching_resolver(DnsResponseCode::ServFail, 300);
+
+    resolver.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap();
+    resolver.resolve(&query_message(2, "example.com.", DnsRecordType::A)).unwrap();
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+  }
+
+  #[test]
+  fn should_evict_when_full() {
+    let (resolver, count) = caching_resolver(DnsResponseCode::NoError, 300);
+
+    resolver.resolve(&query_message(1, "a.example.com.", DnsRecordType::A)).unwrap();
+    resolver.resolve(&query_message(2, "b.example.com.", DnsRecordType::A)).unwrap();
+    resolver.resolve(&query_message(3, "c.example.com.", DnsRecordType::A)).unwrap();
+    assert_eq!(count.load(Ordering::SeqCst), 3);
+    assert_eq!(resolver.cache.lock().unwrap().entries.len(), 2);
+  }
+
+  #[test]
+  fn should_evict_closest_to_expire() {
+    let mut cache = DoHCache::new(2);
+    let keys: Vec<DoHCacheKey> = ["a.example.com.", "b.example.com.", "c.example.com."].iter()
+      .map(|name| DoHCacheKey::from_query_message(&query_message(1, name, DnsRecordType::A)).unwrap())
+      .collect();
+    let now = Instant::now();
+
+    cache.insert(keys[0].clone(), DnsMessage::new(), Duration::from_secs(300), now);
+    cache.insert(keys[1].clone(), DnsMessage::new(), Duration::from_secs(100), now);
+    // Replacing an entry doesn't evict anything
+    cache.insert(keys[1].clone(), DnsMessage::new(), Duration::from_secs(200), now);
+    assert_eq!((cache.entries.len(), cache.expiries.len()), (2, 2));
+
+    cache.insert(keys[2].clone(), DnsMessage::new(), Duration::from_secs(300), now);
+    assert_eq!((cache.entries.len(), cache.expiries.len()), (2, 2));
+    assert!(cache.entries.contains_key(&keys[0]));
+    assert!(!cache.entries.contains_key(&keys[1]));
+    assert!(cache.entries.contains_key(&keys[2]));
+  }
+
+  #[test]
+  fn should_key_on_dnssec_bits() {
+    let (resolver, count) = caching_resolver(DnsResponseCode::NoError, 300);
+
+    resolver.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap();
+
+    let mut req = query_message(2, "example.com.", DnsRecordType::A);
+    req.set_checking_disabled(true);
+    resolver.resolve(&req).unwrap();
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+  }
+
+}
@@ -0,0 +1,99 @@
+//! Context of the resolution in progress on the current thread
+//!
+//! `DoHResolver`s are layered (ex. cache, then group of upstreams), and each layer knows something
+//! about how a query was resolved that the others don't: the context collects it, so that it can be
+//! reported (ex. in the query log) once the resolution is done.
+//!
+//! The context is thread-local: it has to be `reset()` before resolving, and `take()`n right after,
+//! on the same thread.
+
+use std::cell::RefCell;
+
+/// How the cache was involved in a resolution
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CacheStatus {
+  /// The cache wasn't used (ex. disabled, or the query can't be cached)
+  None,
+  /// The response came from the cache
+  Hit,
+  /// The response wasn't in the cache
+  Miss,
+}
+
+impl CacheStatus {
+  /// Name of the status (ex. "hit")
+  pub fn name(self) -> &'static str {
+    match self {
+      CacheStatus::None => "none",
+      CacheStatus::Hit => "hit",
+      CacheStatus::Miss => "miss",
+    }
+  }
+}
+
+impl Default for CacheStatus {
+  fn default() -> Self {
+    CacheStatus::None
+  }
+}
+
+/// What is known about a resolution
+#[derive(Debug, Clone, Default)]
+pub struct ResolutionContext {
+  /// Identifier of the upstream that provided the response (if any)
+  pub upstream: Option<String>,
+  /// How the cache was involved
+  pub cache: CacheStatus,
+}
+
+thread_local! {
+  static CONTEXT: RefCell<ResolutionContext> = RefCell::new(ResolutionContext::default());
+}
+
+/// Resets the context of the current thread, before a new resolution
+pub fn reset() {
+  CONTEXT.with(|context| *context.borrow_mut() = ResolutionContext::default());
+}
+
+/// Takes the context of the current thread, leaving a blank one behind
+pub fn take() -> ResolutionContext {
+  CONTEXT.with(|context| context.replace(ResolutionContext::default()))
+}
+
+/// Records the upstream that provided the response
+pub fn set_upstream(upstream_id: &str) {
+  CONTEXT.with(|context| context.borrow_mut().upstream = Some(upstream_id.to_string()));
+}
+
+/// Records how the cache was involved
+pub fn set_cache_status(cache: CacheStatus) {
+  CONTEXT.with(|context| context.borrow_mut().cache = cache);
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::thread;
+
+  #[test]
+  fn should_collect_context_per_thread() {
+    reset();
+    set_upstream("cloudflare");
+    set_cache_status(CacheStatus::Miss);
+
+    // Other threads have their own context
+    thread::spawn(|| {
+      set_upstream("quad9");
+      assert_eq!(take().upstream, Some("quad9".to_string()));
+    }).join().unwrap();
+
+    let context = take();
+    assert_eq!(context.upstream, Some("cloudflare".to_string()));
+    assert_eq!(context.cache, CacheStatus::Miss);
+
+    // Taking leaves a blank context behind
+    let context = take();
+    assert_eq!(context.upstream, None);
+    assert_eq!(context.cache, CacheStatus::None);
+  }
+}
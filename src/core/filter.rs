@@ -0,0 +1,183 @@
+//! Filtering of DNS queries by domain name (ex. ad, tracker or malware blocking), in front of any `DoHResolver`
+
+pub mod trie;
+pub mod list;
+pub mod block;
+pub mod source;
+pub mod updater;
+
+use super::resolver::{DoHResolver, DoHResolutionError};
+use self::{trie::DomainTrie, block::DoHBlockResponse};
+use crate::dns::protocol::*;
+use crate::metrics;
+
+use log::*;
+
+use std::sync::{Arc, RwLock};
+
+type Result<T> = std::result::Result<T, DoHResolutionError>;
+
+/// The domain names to block, and the ones to never block
+#[derive(Debug, Default)]
+pub struct DomainLists {
+  pub blocklist: DomainTrie,
+  pub allowlist: DomainTrie,
+}
+
+/// `DomainLists` shared by resolvers and updater, that can be replaced while in use
+///
+/// Replacing is atomic: a lookup uses either the old lists or the new ones, never a mix.
+#[derive(Debug, Clone, Default)]
+pub struct SharedDomainLists {
+  lists: Arc<RwLock<Arc<DomainLists>>>,
+}
+
+impl SharedDomainLists {
+
+  /// Constructor
+  pub fn new(lists: DomainLists) -> SharedDomainLists {
+    SharedDomainLists {
+      lists: Arc::new(RwLock::new(Arc::new(lists))),
+    }
+  }
+
+  /// The current lists
+  pub fn current(&self) -> Arc<DomainLists> {
+    self.lists.read().unwrap().clone()
+  }
+
+  /// Replaces the lists: lookups already in progress finish with the old ones
+  pub fn replace(&self, lists: DomainLists) {
+    *self.lists.write().unwrap() = Arc::new(lists);
+  }
+
+}
+
+/// Resolver that blocks queries for the names in a blocklist (and their subdomains)
+///
+/// Names in the allowlist (and their subdomains) are never blocked, even if in the blocklist.
+/// Blocked queries are responded straight away, as per `DoHBlockResponse`: the wrapped
+/// `DoHResolver` is never contacted. The lists are shared by all the clones of this resolver
+/// (and with the `DomainListUpdater` that keeps them up to date).
+#[derive(Clone)]
+pub struct DoHFilteringResolver {
+  resolver: Box<DoHResolver + Send>,
+  lists: SharedDomainLists,
+  block_response: DoHBlockResponse,
+  block_ttl: u32,
+}
+
+impl DoHFilteringResolver {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  ///
+  /// * `resolver` - the `DoHResolver` to resolve the queries that are not blocked with
+  /// * `lists` - the domain names to block, and the ones to never block
+  /// * `block_response` - how to respond to blocked queries
+  /// * `block_ttl` - TTL of the responses to blocked queries, in seconds
+  pub fn new(resolver: Box<DoHResolver + Send>, lists: SharedDomainLists, block_response: DoHBlockResponse, block_ttl: u32) -> DoHFilteringResolver {
+    DoHFilteringResolver {
+      resolver,
+      lists,
+      block_response,
+      block_ttl,
+    }
+  }
+
+  /// Returns `true` if any of the queries is for a blocked name
+  fn is_blocked(&self, req_dns_msg: &DnsMessage) -> bool {
+    let lists = self.lists.current();
+    req_dns_msg.queries().iter().any(|query| {
+      let name = query.name().to_ascii();
+      lists.blocklist.matches(&name) && !lists.allowlist.matches(&name)
+    })
+  }
+
+}
+
+impl DoHResolver for DoHFilteringResolver {
+
+  fn resolve_query(&self, req_dns_msg: &DnsMessage) -> Result<DnsMessage> {
+    if self.is_blocked(req_dns_msg) {
+      debug!("Blocked: {:?}", req_dns_msg.queries());
+      metrics::inc_counter(&metrics::QUERIES_BLOCKED, &[]);
+      return Ok(self.block_response.respond(req_dns_msg, self.block_ttl));
+    }
+
+    self.resolver.resolve_query(req_dns_msg)
+  }
+
+  fn box_clone(&self) -> Box<DoHResolver + Send> {
+    Box::new((*self).clone())
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_support::{CountingResolver, query_message};
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  fn domain_lists(blocklist: &[&str], allowlist: &[&str]) -> DomainLists {
+    let mut lists = DomainLists::default();
+    blocklist.iter().for_each(|domain| { lists.blocklist.insert(domain); });
+    allowlist.iter().for_each(|domain| { lists.allowlist.insert(domain); });
+
+    lists
+  }
+
+  fn filtering_resolver(blocklist: &[&str], allowlist: &[&str]) -> (DoHFilteringResolver, SharedDomainLists, Arc<AtomicUsize>) {
+    let lists = SharedDomainLists::new(domain_lists(blocklist, allowlist));
+
+    let resolver = CountingResolver::new(Some(DnsResponseCode::NoError));
+    let count = resolver.count();
+    (DoHFilteringResolver::new(Box::new(resolver), lists.clone(), DoHBlockResponse::NXDOMAIN, 60), lists, count)
+  }
+
+  #[test]
+  fn should_block_listed_names_and_subdomains() {
+    let (resolver, _, count) = filtering_resolver(&["ads.example.com"], &[]);
+
+    let res = resolver.resolve(&query_message(1, "ads.example.com.", DnsRecordType::A)).unwrap();
+    assert_eq!(res.response_code(), DnsResponseCode::NXDomain);
+    assert_eq!(res.id(), 1);
+    assert_eq!(res.queries().len(), 1);
+
+    let res = resolver.resolve(&query_message(1, "x.ADS.example.com.", DnsRecordType::AAAA)).unwrap();
+    assert_eq!(res.response_code(), DnsResponseCode::NXDomain);
+    assert_eq!(count.load(Ordering::SeqCst), 0);
+
+    let res = resolver.resolve(&query_message(1, "www.example.com.", DnsRecordType::A)).unwrap();
+    assert_eq!(res.response_code(), DnsResponseCode::NoError);
+    assert_eq!(res.answers().len(), 1);
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn should_not_block_allowed_names_and_subdomains() {
+    let (resolver, _, count) = filtering_resolver(&["example.com"], &["cdn.example.com"]);
+
+    let res = resolver.resolve(&query_message(1, "img.cdn.example.com.", DnsRecordType::A)).unwrap();
+    assert_eq!(res.response_code(), DnsResponseCode::NoError);
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+
+    let res = resolver.resolve(&query_message(1, "www.example.com.", DnsRecordType::A)).unwrap();
+    assert_eq!(res.response_code(), DnsResponseCode::NXDomain);
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn should_use_replaced_lists() {
+    let (resolver, lists, _) = filtering_resolver(&["ads.example.com"], &[]);
+
+    lists.replace(domain_lists(&["tracker.example.com"], &[]));
+
+    let res = resolver.resolve(&query_message(1, "ads.example.com.", DnsRecordType::A)).unwrap();
+    assert_eq!(res.response_code(), DnsResponseCode::NoError);
+    let res = resolver.resolve(&query_message(1, "tracker.example.com.", DnsRecordType::A)).unwrap();
+    assert_eq!(res.response_code(), DnsResponseCode::NXDomain);
+  }
+}
@@ -0,0 +1,208 @@
+//! Enum of possible responses to blocked DNS queries
+
+use crate::dns::protocol::*;
+
+use std::{fmt, str::FromStr, net::{IpAddr, Ipv4Addr, Ipv6Addr}};
+
+const BLOCK_RESPONSE_NAME_NXDOMAIN: &'static str = "nxdomain";
+const BLOCK_RESPONSE_NAME_REFUSED: &'static str = "refused";
+const BLOCK_RESPONSE_NAME_NODATA: &'static str = "nodata";
+const BLOCK_RESPONSE_NAME_NULL: &'static str = "null";
+const BLOCK_RESPONSE_IPS_SEPARATOR: char = ',';
+
+/// Synthetic zone that negative responses come from (`.invalid` can never exist: see RFC 2606)
+const BLOCK_SOA_ZONE: &'static str = "blocked.mooncell.invalid.";
+const BLOCK_SOA_RNAME: &'static str = "hostmaster.blocked.mooncell.invalid.";
+const BLOCK_SOA_REFRESH_SEC: i32 = 3600;
+const BLOCK_SOA_RETRY_SEC: i32 = 600;
+const BLOCK_SOA_EXPIRE_SEC: i32 = 86400;
+
+/// How blocked DNS queries are responded
+///
+/// * `NXDOMAIN` - the name doesn't exist
+/// * `REFUSED` - the query is refused
+/// * `NODATA` - the name exists, but has no records of the type queried (`NOERROR` with no answers)
+/// * `NULL` - `A` queries are answered `0.0.0.0`, `AAAA` queries `::` ("sinkhole")
+/// * `IP` - `A`/`AAAA` queries are answered with the given IPv4/IPv6 (ex. of a local block page)
+///
+/// Queries for other types (and `A`/`AAAA` queries with no IP of the matching version) are
+/// responded as `NODATA` by `NULL` and `IP`.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum DoHBlockResponse {
+  NXDOMAIN,
+  REFUSED,
+  NODATA,
+  NULL,
+  IP(Vec<IpAddr>),
+}
+
+impl DoHBlockResponse {
+
+  /// Builds the response to a blocked DNS query
+  ///
+  /// Answers carry the given TTL. So do negative responses, via the `SOA` record of a synthetic zone
+  /// added to the authority section: both its TTL and its `MINIMUM` are the given TTL, as the
+  /// negative caching TTL is the lowest of the two (see [RFC 2308](https://tools.ietf.org/html/rfc2308#section-5)).
+  ///
+  /// # Parameters
+  ///
+  /// * `req_dns_msg` - The blocked DNS query
+  /// * `ttl` - TTL of the response, in seconds
+  pub fn respond(&self, req_dns_msg: &DnsMessage, ttl: u32) -> DnsMessage {
+    match self {
+      DoHBlockResponse::NXDOMAIN => negative_response(req_dns_msg, DnsResponseCode::NXDomain, ttl),
+      DoHBlockResponse::REFUSED => dns_error_response(req_dns_msg, DnsResponseCode::Refused),
+      DoHBlockResponse::NODATA => negative_response(req_dns_msg, DnsResponseCode::NoError, ttl),
+      DoHBlockResponse::NULL => answer_response(req_dns_msg, &[IpAddr::V4(Ipv4Addr::UNSPECIFIED), IpAddr::V6(Ipv6Addr::UNSPECIFIED)], ttl),
+      DoHBlockResponse::IP(ips) => answer_response(req_dns_msg, ips, ttl),
+    }
+  }
+
+}
+
+/// Builds a negative response, with the `SOA` of the synthetic zone `BLOCK_SOA_ZONE` in the authority section
+fn negative_response(req_dns_msg: &DnsMessage, response_code: DnsResponseCode, ttl: u32) -> DnsMessage {
+  let zone = DnsDomainName::from_str(BLOCK_SOA_ZONE).unwrap();
+  let rname = DnsDomainName::from_str(BLOCK_SOA_RNAME).unwrap();
+  let soa = DnsRDataSOA::new(zone.clone(), rname, 1, BLOCK_SOA_REFRESH_SEC, BLOCK_SOA_RETRY_SEC, BLOCK_SOA_EXPIRE_SEC, ttl);
+
+  let mut res_dns_msg = dns_error_response(req_dns_msg, response_code);
+  res_dns_msg.add_name_server(DnsRecord::from_rdata(zone, ttl, DnsRData::SOA(soa)));
+
+  res_dns_msg
+}
+
+/// Builds a response that answers `A`/`AAAA` queries with the IPs of the matching version
+///
+/// If there is nothing to answer with, it's a negative response (`NODATA`) instead.
+fn answer_response(req_dns_msg: &DnsMessage, ips: &[IpAddr], ttl: u32) -> DnsMessage {
+  let answers: Vec<DnsRecord> = req_dns_msg.queries().iter()
+    .flat_map(|query| ips.iter().filter_map(move |ip| match (query.query_type(), ip) {
+      (DnsRecordType::A, IpAddr::V4(ipv4)) => Some(DnsRecord::from_rdata(query.name().clone(), ttl, DnsRData::A(*ipv4))),
+      (DnsRecordType::AAAA, IpAddr::V6(ipv6)) => Some(DnsRecord::from_rdata(query.name().clone(), ttl, DnsRData::AAAA(*ipv6))),
+      _ => None,
+    }))
+    .collect();
+
+  if answers.is_empty() {
+    return negative_response(req_dns_msg, DnsResponseCode::NoError, ttl);
+  }
+
+  let mut res_dns_msg = dns_error_response(req_dns_msg, DnsResponseCode::NoError);
+  res_dns_msg.insert_answers(answers);
+
+  res_dns_msg
+}
+
+impl FromStr for DoHBlockResponse {
+  type Err = DoHBlockResponseParseError;
+
+  fn from_str(raw_block_response: &str) -> Result<Self, Self::Err> {
+    match raw_block_response {
+      BLOCK_RESPONSE_NAME_NXDOMAIN => Ok(DoHBlockResponse::NXDOMAIN),
+      BLOCK_RESPONSE_NAME_REFUSED => Ok(DoHBlockResponse::REFUSED),
+      BLOCK_RESPONSE_NAME_NODATA => Ok(DoHBlockResponse::NODATA),
+      BLOCK_RESPONSE_NAME_NULL => Ok(DoHBlockResponse::NULL),
+      _ => raw_block_response.split(BLOCK_RESPONSE_IPS_SEPARATOR)
+        .map(|raw_ip| raw_ip.trim().parse::<IpAddr>())
+        .collect::<Result<Vec<IpAddr>, _>>()
+        .map(DoHBlockResponse::IP)
+        .map_err(|_| DoHBlockResponseParseError::new(raw_block_response)),
+    }
+  }
+}
+
+impl fmt::Display for DoHBlockResponse {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DoHBlockResponse::NXDOMAIN => write!(fmtr, "{}", BLOCK_RESPONSE_NAME_NXDOMAIN),
+      DoHBlockResponse::REFUSED => write!(fmtr, "{}", BLOCK_RESPONSE_NAME_REFUSED),
+      DoHBlockResponse::NODATA => write!(fmtr, "{}", BLOCK_RESPONSE_NAME_NODATA),
+      DoHBlockResponse::NULL => write!(fmtr, "{}", BLOCK_RESPONSE_NAME_NULL),
+      DoHBlockResponse::IP(ips) => {
+        let ips: Vec<String> = ips.iter().map(IpAddr::to_string).collect();
+        write!(fmtr, "{}", ips.join(&BLOCK_RESPONSE_IPS_SEPARATOR.to_string()))
+      },
+    }
+  }
+}
+
+/// Error that happens when parsing a `DoHBlockResponse` fails
+#[derive(Debug, Clone)]
+pub struct DoHBlockResponseParseError {
+  token: String
+}
+
+impl DoHBlockResponseParseError {
+  fn new(token: &str) -> Self {
+    Self {
+      token: token.to_string()
+    }
+  }
+}
+
+impl fmt::Display for DoHBlockResponseParseError {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmtr, "Invalid Block Response (expected '{}', '{}', '{}', '{}' or IP[,IP]): {}",
+           BLOCK_RESPONSE_NAME_NXDOMAIN, BLOCK_RESPONSE_NAME_REFUSED, BLOCK_RESPONSE_NAME_NODATA, BLOCK_RESPONSE_NAME_NULL, self.token)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_support::query_message;
+
+  #[test]
+  fn should_parse_block_response() {
+    assert_eq!(DoHBlockResponse::from_str("nxdomain").unwrap(), DoHBlockResponse::NXDOMAIN);
+    assert_eq!(DoHBlockResponse::from_str("null").unwrap(), DoHBlockResponse::NULL);
+    assert_eq!(DoHBlockResponse::from_str("192.168.1.2, fd00::2").unwrap(), DoHBlockResponse::IP(vec!["192.168.1.2".parse().unwrap(), "fd00::2".parse().unwrap()]));
+    assert_eq!(DoHBlockResponse::from_str("192.168.1.2,fd00::2").unwrap().to_string(), "192.168.1.2,fd00::2");
+    assert!(DoHBlockResponse::from_str("blackhole").is_err());
+    assert!(DoHBlockResponse::from_str("").is_err());
+  }
+
+  #[test]
+  fn should_respond_to_blocked_query() {
+    let res = DoHBlockResponse::NXDOMAIN.respond(&query_message(1, "ads.example.com.", DnsRecordType::A), 60);
+    assert_eq!(res.response_code(), DnsResponseCode::NXDomain);
+    assert!(res.answers().is_empty());
+    assert_eq!(res.name_servers().len(), 1);
+    assert_eq!(res.name_servers()[0].name().to_utf8(), BLOCK_SOA_ZONE);
+    assert_eq!(res.name_servers()[0].ttl(), 60);
+    match res.name_servers()[0].rdata() {
+      DnsRData::SOA(soa) => {
+        assert_eq!(soa.mname().to_utf8(), BLOCK_SOA_ZONE);
+        assert_eq!(soa.minimum(), 60);
+      },
+      rdata => panic!("Not a SOA: {:?}", rdata),
+    }
+
+    // The negative caching TTL is the configured one, whatever the name blocked
+    let res = DoHBlockResponse::NODATA.respond(&query_message(1, "tracker.example.net.", DnsRecordType::TXT), 300);
+    assert_eq!(res.response_code(), DnsResponseCode::NoError);
+    assert_eq!(res.name_servers()[0].name().to_utf8(), BLOCK_SOA_ZONE);
+    assert_eq!(res.name_servers()[0].ttl(), 300);
+
+    let res = DoHBlockResponse::REFUSED.respond(&query_message(1, "ads.example.com.", DnsRecordType::A), 60);
+    assert_eq!(res.response_code(), DnsResponseCode::Refused);
+    assert!(res.name_servers().is_empty());
+
+    let res = DoHBlockResponse::NULL.respond(&query_message(1, "ads.example.com.", DnsRecordType::AAAA), 60);
+    assert_eq!(res.response_code(), DnsResponseCode::NoError);
+    assert_eq!(res.answers()[0].rdata(), &DnsRData::AAAA(Ipv6Addr::UNSPECIFIED));
+    assert_eq!(res.answers()[0].ttl(), 60);
+
+    let block_page = DoHBlockResponse::IP(vec!["192.168.1.2".parse().unwrap()]);
+    let res = block_page.respond(&query_message(1, "ads.example.com.", DnsRecordType::A), 60);
+    assert_eq!(res.answers()[0].rdata(), &DnsRData::A(Ipv4Addr::new(192, 168, 1, 2)));
+
+    // Nothing to answer with: NODATA
+    let res = block_page.respond(&query_message(1, "ads.example.com.", DnsRecordType::AAAA), 60);
+    assert_eq!(res.response_code(), DnsResponseCode::NoError);
+    assert!(res.answers().is_empty());
+    assert_eq!(res.name_servers().len(), 1);
+  }
+}
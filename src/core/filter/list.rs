@@ -0,0 +1,165 @@
+//! Parsing of domain lists, in the formats commonly used by blocklists
+//!
+//! The format is detected line by line, so lists mixing formats are fine:
+//!
+//! * hosts file (ex. `0.0.0.0 ads.example.com`), with any number of names per line
+//! * plain domain (ex. `ads.example.com`)
+//! * Adblock-style (ex. `||ads.example.com^`): rules with options (ex. `$third-party`) and
+//!   exception rules (ex. `@@||example.com^`) can't be applied to DNS, and are skipped
+//!
+//! Comments (`#` and `!`), empty lines and anything else that doesn't parse as a domain are skipped.
+
+use super::trie::DomainTrie;
+use crate::core::local::hosts;
+
+use std::io::{self, BufRead};
+
+const ADBLOCK_PREFIX: &'static str = "||";
+const ADBLOCK_SUFFIX: &'static str = "^";
+
+/// Names found in hosts files that are never meant to be blocked
+const HOSTS_RESERVED_NAMES: &[&str] = &[
+  "localhost",
+  "localhost.localdomain",
+  "local",
+  "broadcasthost",
+  "ip6-localhost",
+  "ip6-loopback",
+  "ip6-localnet",
+  "ip6-mcastprefix",
+  "ip6-allnodes",
+  "ip6-allrouters",
+  "ip6-allhosts",
+  "0.0.0.0",
+];
+
+/// Parses a line of a domain list, returning the domain names it contains (if any)
+///
+/// # Parameters
+///
+/// * `line` - A line of the list
+pub fn parse_line(line: &str) -> Vec<String> {
+  let line = line.trim();
+  if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+    return Vec::new();
+  }
+
+  // Adblock-style
+  if let Some(rule) = line.strip_prefix(ADBLOCK_PREFIX) {
+    return match rule.find(ADBLOCK_SUFFIX) {
+      Some(end) if end + ADBLOCK_SUFFIX.len() == rule.len() => parse_domain(&rule[..end]).into_iter().collect(),
+      _ => Vec::new(),
+    };
+  }
+
+  // Hosts file
+  if let Some((_, names)) = hosts::split_line(line) {
+    return names.into_iter()
+      .filter(|name| !HOSTS_RESERVED_NAMES.contains(&name.to_ascii_lowercase().as_str()))
+      .filter_map(parse_domain)
+      .collect();
+  }
+
+  // Plain domain (a comment can follow it)
+  let entries = line.split('#').next().unwrap_or_default();
+  let mut tokens = entries.split_whitespace();
+  match (tokens.next(), tokens.next()) {
+    (Some(domain), None) => parse_domain(domain).into_iter().collect(),
+    _ => Vec::new(),
+  }
+}
+
+/// Validates a domain name, returning it normalized (lowercase, no trailing dot)
+fn parse_domain(raw_domain: &str) -> Option<String> {
+  let domain = raw_domain.trim_end_matches('.').to_ascii_lowercase();
+
+  let is_valid = !domain.is_empty()
+    && domain.len() <= 253
+    && domain.split('.').all(|label| {
+      !label.is_empty()
+        && label.len() <= 63
+        && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    });
+
+  if is_valid { Some(domain) } else { None }
+}
+
+/// Reads domain names from a list, into the given trie
+///
+/// Returns how many lines were read. Lines that aren't valid UTF-8 don't fail the whole list:
+/// they are read lossily, so they'll just not parse as a domain (unless the invalid bytes are in a comment).
+///
+/// # Parameters
+///
+/// * `reader` - The list
+/// * `trie` - Where to add the domain names to
+pub fn read_list<R: BufRead>(mut reader: R, trie: &mut DomainTrie) -> io::Result<usize> {
+  let mut lines = 0;
+  let mut buf = Vec::new();
+  while reader.read_until(b'\n', &mut buf)? > 0 {
+    for domain in parse_line(String::from_utf8_lossy(&buf).trim_end_matches(&['\r', '\n'][..])) {
+      trie.insert(&domain);
+    }
+    lines += 1;
+    buf.clear();
+  }
+
+  Ok(lines)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn should_parse_lines() {
+    // Hosts file
+    assert_eq!(parse_line("0.0.0.0 ads.example.com"), vec!["ads.example.com"]);
+    assert_eq!(parse_line("127.0.0.1\tads.example.com tracker.example.com # trackers"), vec!["ads.example.com", "tracker.example.com"]);
+    assert_eq!(parse_line(":: ads.example.com"), vec!["ads.example.com"]);
+    assert!(parse_line("127.0.0.1 localhost").is_empty());
+    assert!(parse_line("0.0.0.0 0.0.0.0").is_empty());
+
+    // Plain domain
+    assert_eq!(parse_line("Ads.Example.com."), vec!["ads.example.com"]);
+    assert_eq!(parse_line("  ads.example.com  # comment"), vec!["ads.example.com"]);
+
+    // Adblock-style
+    assert_eq!(parse_line("||ads.example.com^"), vec!["ads.example.com"]);
+    assert!(parse_line("||ads.example.com^$third-party").is_empty());
+    assert!(parse_line("@@||example.com^").is_empty());
+    assert!(parse_line("||example.com/ads/*").is_empty());
+    assert!(parse_line("[Adblock Plus 2.0]").is_empty());
+    assert!(parse_line("! Title: Example list").is_empty());
+
+    // Anything else
+    assert!(parse_line("").is_empty());
+    assert!(parse_line("# comment").is_empty());
+    assert!(parse_line("*.example.com").is_empty());
+    assert!(parse_line("example..com").is_empty());
+    assert!(parse_line("not a domain").is_empty());
+  }
+
+  #[test]
+  fn should_read_list() {
+    let list = "# Mixed list\n0.0.0.0 ads.example.com\ntracker.example.net\n||malware.example.org^\n";
+
+    let mut trie = DomainTrie::new();
+    assert_eq!(read_list(list.as_bytes(), &mut trie).unwrap(), 4);
+    assert_eq!(trie.len(), 3);
+    assert!(trie.matches("x.ads.example.com"));
+    assert!(trie.matches("tracker.example.net"));
+    assert!(trie.matches("malware.example.org"));
+  }
+
+  #[test]
+  fn should_skip_lines_that_are_not_utf8() {
+    let list = b"0.0.0.0 ads.example.com\r\n0.0.0.0 caf\xe9.example.com\n# Liste publicit\xe9\ntracker.example.net";
+
+    let mut trie = DomainTrie::new();
+    assert_eq!(read_list(&list[..], &mut trie).unwrap(), 4);
+    assert_eq!(trie.len(), 2);
+    assert!(trie.matches("ads.example.com"));
+    assert!(trie.matches("tracker.example.net"));
+  }
+}
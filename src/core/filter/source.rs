@@ -0,0 +1,336 @@
+//! Sources of domain lists (local files and HTTP(S) URLs), and their loading
+//!
+//! Lists downloaded from URLs are cached on disk, with the validators of the response (`ETag` and
+//! `Last-Modified`): this way, reloading only downloads lists that have changed (via conditional
+//! requests), and lists are available at startup even if their URL is not reachable.
+
+use super::{DomainLists, trie::DomainTrie, list};
+use crate::net::http::{fetch_http_response, HttpFollowRedirects};
+
+use log::*;
+use http::{Request as HttpRequest, Uri as HttpUri, StatusCode as HttpStatusCode, header::{ETAG, LAST_MODIFIED, IF_NONE_MATCH, IF_MODIFIED_SINCE}};
+
+use std::{fmt, fs, io, str::FromStr, collections::HashMap, path::{Path, PathBuf}, time::SystemTime};
+
+const SOURCE_PREFIX_HTTP: &'static str = "http://";
+const SOURCE_PREFIX_HTTPS: &'static str = "https://";
+const CACHE_FILE_NAME_PREFIX_MAX_LEN: usize = 64;
+const CACHE_FILE_EXT_LIST: &'static str = "list";
+const CACHE_FILE_EXT_META: &'static str = "meta";
+const CACHE_FILE_EXT_TMP: &'static str = "tmp";
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Where a domain list comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum DomainListSource {
+  /// A local file (format: `PATH`)
+  File(PathBuf),
+  /// An HTTP(S) URL (format: `http://...` or `https://...`)
+  Url(String),
+}
+
+impl FromStr for DomainListSource {
+  type Err = DomainListSourceParseError;
+
+  fn from_str(raw_source: &str) -> Result<Self, Self::Err> {
+    if raw_source.starts_with(SOURCE_PREFIX_HTTP) || raw_source.starts_with(SOURCE_PREFIX_HTTPS) {
+      match raw_source.parse::<HttpUri>() {
+        Ok(_) => Ok(DomainListSource::Url(raw_source.to_string())),
+        Err(_) => Err(DomainListSourceParseError::new(raw_source)),
+      }
+    } else if raw_source.is_empty() {
+      Err(DomainListSourceParseError::new(raw_source))
+    } else {
+      Ok(DomainListSource::File(PathBuf::from(raw_source)))
+    }
+  }
+}
+
+impl fmt::Display for DomainListSource {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DomainListSource::File(path) => write!(fmtr, "{}", path.display()),
+      DomainListSource::Url(url) => write!(fmtr, "{}", url),
+    }
+  }
+}
+
+/// Error that happens when parsing a `DomainListSource` fails
+#[derive(Debug, Clone)]
+pub struct DomainListSourceParseError {
+  token: String
+}
+
+impl DomainListSourceParseError {
+  fn new(token: &str) -> Self {
+    Self {
+      token: token.to_string()
+    }
+  }
+}
+
+impl fmt::Display for DomainListSourceParseError {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmtr, "Invalid domain list (expected a path, or an HTTP(S) URL): {}", self.token)
+  }
+}
+
+/// Outcome of downloading a list
+enum Fetched {
+  Modified(Vec<u8>),
+  NotModified,
+  Failed,
+}
+
+/// Loads the blocklist and the allowlist from their sources, keeping track of changes
+///
+/// Files are considered changed when their modification time changes; URLs when the server
+/// responds with a new version (i.e. not with `304 Not Modified`).
+#[derive(Debug, Clone)]
+pub struct DomainListLoader {
+  blocklists: Vec<DomainListSource>,
+  allowlists: Vec<DomainListSource>,
+  cache_dir: Option<PathBuf>,
+  file_mtimes: HashMap<PathBuf, SystemTime>,
+  loaded: bool,
+}
+
+impl DomainListLoader {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  ///
+  /// * `blocklists` - the sources of the blocklist
+  /// * `allowlists` - the sources of the allowlist
+  /// * `cache_dir` - where to cache the lists downloaded from URLs (if anywhere)
+  pub fn new(blocklists: Vec<DomainListSource>, allowlists: Vec<DomainListSource>, cache_dir: Option<PathBuf>) -> DomainListLoader {
+    DomainListLoader {
+      blocklists,
+      allowlists,
+      cache_dir,
+      file_mtimes: HashMap::new(),
+      loaded: false,
+    }
+  }
+
+  /// Loads the lists, if any of the sources has changed since the last time (always, the first time)
+  ///
+  /// Sources that fail are logged: a URL that fails is loaded from its cached copy (if any).
+  pub fn load(&mut self) -> Option<DomainLists> {
+    let mut changed = !self.loaded;
+    let mut downloaded: HashMap<String, Vec<u8>> = HashMap::new();
+
+    for source in self.blocklists.iter().chain(self.allowlists.iter()) {
+      match source {
+        DomainListSource::File(path) => {
+          let mtime = fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+          if mtime != self.file_mtimes.get(path).cloned() {
+            changed = true;
+            match mtime {
+              Some(mtime) => self.file_mtimes.insert(path.clone(), mtime),
+              None => self.file_mtimes.remove(path),
+            };
+          }
+        },
+        DomainListSource::Url(url) if !downloaded.contains_key(url) => {
+          if let Fetched::Modified(body) = fetch_url(url, self.cache_dir.as_ref().map(PathBuf::as_path)) {
+            changed = true;
+            downloaded.insert(url.clone(), body);
+          }
+        },
+        DomainListSource::Url(_) => {},
+      }
+    }
+
+    if !changed {
+      debug!("Domain lists have not changed");
+      return None;
+    }
+
+    self.loaded = true;
+    Some(DomainLists {
+      blocklist: self.load_trie(&self.blocklists, &downloaded),
+      allowlist: self.load_trie(&self.allowlists, &downloaded),
+    })
+  }
+
+  /// Loads the given sources into a `DomainTrie`, using the lists just downloaded or the cached ones
+  fn load_trie(&self, sources: &[DomainListSource], downloaded: &HashMap<String, Vec<u8>>) -> DomainTrie {
+    let mut trie = DomainTrie::new();
+    for source in sources {
+      let before = trie.len();
+      let res = match source {
+        DomainListSource::File(path) => fs::File::open(path).and_then(|file| list::read_list(io::BufReader::new(file), &mut trie)),
+        DomainListSource::Url(url) => match downloaded.get(url) {
+          Some(body) => list::read_list(body.as_slice(), &mut trie),
+          None => self.cache_dir.as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not downloaded, and not cached"))
+            .and_then(|cache_dir| fs::File::open(cache_paths(cache_dir, url).0))
+            .and_then(|file| list::read_list(io::BufReader::new(file), &mut trie)),
+        },
+      };
+
+      match res {
+        Ok(lines) => info!("Loaded list '{}': {} lines, {} new domains", source, lines, trie.len() - before),
+        Err(err) => error!("Unable to load list '{}': {}", source, err),
+      }
+    }
+
+    trie
+  }
+
+}
+
+/// Paths of the cached copy of the list at the given URL, and of its validators
+///
+/// Names are a readable (but lossy) prefix of the URL, followed by a hash of the whole URL:
+/// URLs that only differ past the prefix, or in characters that can't be in a name, don't collide.
+fn cache_paths(cache_dir: &Path, url: &str) -> (PathBuf, PathBuf) {
+  let prefix: String = url.chars()
+    .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+    .take(CACHE_FILE_NAME_PREFIX_MAX_LEN)
+    .collect();
+  let name = format!("{}-{:016x}", prefix, fnv1a_hash(url.as_bytes()));
+
+  (cache_dir.join(format!("{}.{}", name, CACHE_FILE_EXT_LIST)), cache_dir.join(format!("{}.{}", name, CACHE_FILE_EXT_META)))
+}
+
+/// 64 bits FNV-1a hash: unlike `std::collections::hash_map::DefaultHasher`, it's stable across
+/// Rust releases, so cached copies are found again after an upgrade
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME))
+}
+
+/// Downloads the list at the given URL, if it has changed since the cached copy (if any)
+fn fetch_url(url: &str, cache_dir: Option<&Path>) -> Fetched {
+  let cache_paths = cache_dir.map(|cache_dir| cache_paths(cache_dir, url));
+
+  let mut req_builder = HttpRequest::get(url);
+  req_builder.extension(HttpFollowRedirects);
+  if let Some((list_path, meta_path)) = &cache_paths {
+    // Conditional request, only if there is a cached copy to fall back to
+    if list_path.is_file() {
+      for line in fs::read_to_string(meta_path).unwrap_or_default().lines() {
+        match line.find(':').map(|colon| (line[..colon].trim(), line[colon + 1..].trim())) {
+          Some((name, value)) if name.eq_ignore_ascii_case(ETAG.as_str()) => { req_builder.header(IF_NONE_MATCH, value); },
+          Some((name, value)) if name.eq_ignore_ascii_case(LAST_MODIFIED.as_str()) => { req_builder.header(IF_MODIFIED_SINCE, value); },
+          _ => {},
+        }
+      }
+    }
+  }
+
+  let res_http = req_builder.body(Vec::new())
+    .map_err(|err| err.to_string())
+    .and_then(|req_http| fetch_http_response(&req_http).map_err(|err| err.to_string()));
+  let res_http = match res_http {
+    Ok(res_http) => res_http,
+    Err(err) => {
+      warn!("Unable to download list '{}': {}", url, err);
+      return Fetched::Failed;
+    }
+  };
+
+  match res_http.status() {
+    HttpStatusCode::NOT_MODIFIED => {
+      debug!("List '{}' not modified", url);
+      Fetched::NotModified
+    },
+    status if status.is_success() => {
+      if let Some((list_path, meta_path)) = &cache_paths {
+        let meta: String = [ETAG, LAST_MODIFIED].iter()
+          .filter_map(|name| res_http.headers().get(name).and_then(|value| value.to_str().ok()).map(|value| format!("{}: {}\n", name, value)))
+          .collect();
+
+        if let Err(err) = write_atomically(list_path, res_http.body()).and_then(|_| write_atomically(meta_path, meta.as_bytes())) {
+          warn!("Unable to cache list '{}' at '{}': {}", url, list_path.display(), err);
+        }
+      }
+      Fetched::Modified(res_http.into_body())
+    },
+    status => {
+      warn!("Unable to download list '{}': HTTP status {}", url, status);
+      Fetched::Failed
+    },
+  }
+}
+
+/// Writes a file via a temporary one, so that it's never read half-written
+fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+
+  let tmp_path = path.with_extension(CACHE_FILE_EXT_TMP);
+  fs::write(&tmp_path, content)?;
+  fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::{env, thread, time::Duration};
+
+  #[test]
+  fn should_parse_source() {
+    assert_eq!(DomainListSource::from_str("/etc/mooncell/ads.txt").unwrap(), DomainListSource::File("/etc/mooncell/ads.txt".into()));
+    assert_eq!(DomainListSource::from_str("https://lists.example.com/ads.txt").unwrap(), DomainListSource::Url("https://lists.example.com/ads.txt".into()));
+    assert!(DomainListSource::from_str("https://lists example.com").is_err());
+    assert!(DomainListSource::from_str("").is_err());
+  }
+
+  #[test]
+  fn should_not_share_cache_paths_between_urls() {
+    let dir = Path::new("/var/cache/mooncell");
+    let long_path = "a".repeat(CACHE_FILE_NAME_PREFIX_MAX_LEN);
+
+    // Same name once sanitized, or once truncated
+    let urls = [
+      "https://lists.example.com/ads?v=1".to_string(),
+      "https://lists.example.com/ads&v=1".to_string(),
+      format!("https://lists.example.com/{}/ads.txt", long_path),
+      format!("https://lists.example.com/{}/trackers.txt", long_path),
+    ];
+    let paths: Vec<PathBuf> = urls.iter().map(|url| cache_paths(dir, url).0).collect();
+    for (i, path) in paths.iter().enumerate() {
+      assert_eq!(paths.iter().filter(|other| *other == path).count(), 1, "{} collides", urls[i]);
+    }
+
+    // Stable, and still readable
+    assert_eq!(cache_paths(dir, &urls[0]), cache_paths(dir, &urls[0]));
+    assert!(paths[0].file_name().unwrap().to_str().unwrap().starts_with("https___lists.example.com_ads_v_1-"));
+    assert_eq!(fnv1a_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+  }
+
+  #[test]
+  fn should_load_only_when_changed() {
+    let dir = env::temp_dir().join(format!("mooncell-lists-test-{}", std::process::id()));
+    let blocklist_path = dir.join("block.txt");
+    write_atomically(&blocklist_path, b"ads.example.com\n").unwrap();
+
+    // A URL that can't be downloaded, with a cached copy to fall back to
+    let url = "http://127.0.0.1:9/allow.txt";
+    write_atomically(&cache_paths(&dir, url).0, b"||cdn.example.com^\n").unwrap();
+
+    let blocklists = vec![DomainListSource::File(blocklist_path.clone())];
+    let allowlists = vec![DomainListSource::Url(url.into())];
+    let mut loader = DomainListLoader::new(blocklists, allowlists, Some(dir.clone()));
+
+    let lists = loader.load().unwrap();
+    assert!(lists.blocklist.matches("ads.example.com"));
+    assert!(lists.allowlist.matches("cdn.example.com"));
+    assert!(loader.load().is_none());
+
+    // Make sure the modification time changes
+    thread::sleep(Duration::from_millis(20));
+    fs::write(&blocklist_path, b"tracker.example.com\n").unwrap();
+    let lists = loader.load().unwrap();
+    assert!(!lists.blocklist.matches("ads.example.com"));
+    assert!(lists.blocklist.matches("tracker.example.com"));
+    assert!(lists.allowlist.matches("cdn.example.com"));
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,144 @@
+//! Trie of domain names, indexed by label from the TLD down, to match names and their subdomains
+
+use std::collections::HashMap;
+
+/// A node of the trie: one per label
+#[derive(Debug, Default)]
+struct DomainTrieNode {
+  children: HashMap<Box<str>, DomainTrieNode>,
+  terminal: bool,
+}
+
+impl DomainTrieNode {
+
+  /// Number of domain names added below this node
+  fn descendants(&self) -> usize {
+    self.children.values().map(|child| if child.terminal { 1 } else { child.descendants() }).sum()
+  }
+
+}
+
+/// Set of domain names, that matches the names it contains and all their subdomains
+///
+/// Names are case-insensitive, and the trailing dot is optional. Lookups cost one hash lookup
+/// per label of the name looked up, no matter how many names the trie contains.
+#[derive(Debug, Default)]
+pub struct DomainTrie {
+  root: DomainTrieNode,
+  len: usize,
+}
+
+impl DomainTrie {
+
+  /// Constructor
+  pub fn new() -> DomainTrie {
+    DomainTrie::default()
+  }
+
+  /// Adds a domain name
+  ///
+  /// Returns `false` if the name was already matched (i.e. it, or one of its parents, was added before).
+  ///
+  /// # Parameters
+  ///
+  /// * `domain` - The domain name (ex. `ads.example.com`)
+  pub fn insert(&mut self, domain: &str) -> bool {
+    let domain = normalize(domain);
+    if domain.is_empty() {
+      return false;
+    }
+
+    let mut node = &mut self.root;
+    for label in domain.rsplit('.') {
+      if node.terminal {
+        return false;
+      }
+      node = node.children.entry(label.into()).or_insert_with(DomainTrieNode::default);
+    }
+
+    if node.terminal {
+      return false;
+    }
+
+    // Subdomains are matched anyway, from now on: no need to keep them (nor to count them)
+    let pruned = node.descendants();
+    node.terminal = true;
+    node.children = HashMap::new();
+    self.len = self.len + 1 - pruned;
+
+    true
+  }
+
+  /// Returns `true` if the domain name, or one of its parents, was added
+  ///
+  /// # Parameters
+  ///
+  /// * `domain` - The domain name (ex. `tracker.ads.example.com.`)
+  pub fn matches(&self, domain: &str) -> bool {
+    let domain = normalize(domain);
+
+    let mut node = &self.root;
+    for label in domain.rsplit('.') {
+      node = match node.children.get(label) {
+        Some(child) if child.terminal => return true,
+        Some(child) => child,
+        None => return false,
+      };
+    }
+
+    false
+  }
+
+  /// Number of domain names added (excluding the ones already matched when added)
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+}
+
+/// Lowercases and drops the trailing dot
+fn normalize(domain: &str) -> String {
+  domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn should_match_domains_and_subdomains() {
+    let mut trie = DomainTrie::new();
+    assert!(trie.insert("ads.example.com"));
+    assert!(trie.insert("Tracker.NET."));
+    assert!(!trie.insert("x.ads.example.com"));
+    assert!(!trie.insert("tracker.net"));
+    assert_eq!(trie.len(), 2);
+
+    assert!(trie.matches("ads.example.com."));
+    assert!(trie.matches("a.b.ADS.example.com"));
+    assert!(trie.matches("tracker.net"));
+    assert!(!trie.matches("example.com."));
+    assert!(!trie.matches("bads.example.com"));
+    assert!(!trie.matches("net"));
+    assert!(!trie.matches(""));
+  }
+
+  #[test]
+  fn should_drop_subdomains_when_parent_is_added() {
+    let mut trie = DomainTrie::new();
+    trie.insert("a.example.com");
+    trie.insert("b.example.com");
+    trie.insert("example.com");
+
+    assert!(trie.matches("c.example.com"));
+    assert!(trie.root.children["com"].children["example"].children.is_empty());
+    assert_eq!(trie.len(), 1);
+
+    trie.insert("x.y.example.org");
+    trie.insert("z.example.org");
+    trie.insert("other.org");
+    assert_eq!(trie.len(), 4);
+    assert!(trie.insert("org"));
+    assert_eq!(trie.len(), 2);
+  }
+}
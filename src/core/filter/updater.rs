@@ -0,0 +1,110 @@
+//! Periodic reloading of the domain lists
+
+use super::{SharedDomainLists, source::DomainListLoader};
+
+use log::*;
+use srvzio;
+
+use std::{thread, time::{Duration, Instant}};
+
+const DOMAIN_LIST_UPDATER_SERVICE_NAME: &'static str = "DomainListUpdater";
+const DOMAIN_LIST_UPDATER_THREAD_NAME: &'static str = "domain_list_updater_thread";
+const DOMAIN_LIST_UPDATER_STOP_CHECK_INTERVAL_MS: u64 = 500;
+
+/// DomainListUpdater is a service that reloads the domain lists at regular intervals
+///
+/// Lists are only reloaded if any of their sources has changed (see `DomainListLoader`), and the
+/// new ones replace the `SharedDomainLists` in use by the resolvers, without interrupting them.
+/// The first load is expected to be done before the service starts.
+pub struct DomainListUpdater {
+  loader: DomainListLoader,
+  lists: SharedDomainLists,
+  interval: Duration,
+  status: srvzio::ServiceStatusFlag,
+  updater_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl DomainListUpdater {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  /// * `loader`: loads the lists from their sources
+  /// * `lists`: the lists to replace (shared with the resolvers using them)
+  /// * `interval`: how long to wait between reloads
+  pub fn new(loader: DomainListLoader, lists: SharedDomainLists, interval: Duration) -> DomainListUpdater {
+    DomainListUpdater {
+      loader,
+      lists,
+      interval,
+      status: srvzio::ServiceStatusFlag::default(),
+      updater_thread: None,
+    }
+  }
+
+}
+
+impl srvzio::Service for DomainListUpdater {
+
+  fn name(&self) -> &'static str {
+    DOMAIN_LIST_UPDATER_SERVICE_NAME
+  }
+
+  fn start(&mut self) {
+    self.status.starting();
+
+    let mut loader = self.loader.clone();
+    let lists = self.lists.clone();
+    let interval = self.interval;
+    let status = self.status.clone();
+
+    // Launch a 'list updating' thread
+    self.updater_thread = Some(thread::Builder::new()
+      .name(DOMAIN_LIST_UPDATER_THREAD_NAME.into())
+      .spawn(move || {
+        status.started();
+
+        // Reload at every interval, but interrupt at regular intervals to check if DomainListUpdater was stopped
+        let mut next_reload = Instant::now() + interval;
+        while !status.is_stopping() {
+          if Instant::now() >= next_reload {
+            if let Some(new_lists) = loader.load() {
+              info!("Domain lists updated: blocking {} domains, allowing {} domains", new_lists.blocklist.len(), new_lists.allowlist.len());
+              lists.replace(new_lists);
+            }
+            next_reload = Instant::now() + interval;
+          }
+
+          thread::sleep(Duration::from_millis(DOMAIN_LIST_UPDATER_STOP_CHECK_INTERVAL_MS));
+        }
+        trace!("{} is done running: stop reloading domain lists", DOMAIN_LIST_UPDATER_SERVICE_NAME);
+
+        status.stopped();
+      })
+      .expect(format!("Unable to spawn thread: {}", DOMAIN_LIST_UPDATER_THREAD_NAME).as_ref())
+    );
+  }
+
+  fn await_started(&mut self) {
+    while !self.status.is_started() {}
+  }
+
+  fn stop(&mut self) {
+    trace!("{} should now stop...", DOMAIN_LIST_UPDATER_SERVICE_NAME);
+    self.status.stopping();
+  }
+
+  fn await_stopped(&mut self) {
+    while !self.status.is_stopped() {}
+
+    // Wait for updater thread to stop (if it's actually set)
+    if self.updater_thread.is_some() {
+      self.updater_thread
+        .take()
+        .unwrap()
+        .join()
+        .expect(format!("Panicked upon termination: {}", DOMAIN_LIST_UPDATER_THREAD_NAME).as_ref());
+    }
+  }
+
+}
@@ -0,0 +1,204 @@
+//! Active health checking of upstreams
+
+use super::upstream::DoHUpstream;
+use crate::dns::protocol::{DnsMessage, DnsMessageType, DnsOpCode, DnsQuery, DnsDomainName, DnsRecordType};
+
+use log::*;
+use threadpool::{Builder as ThreadPoolBuilder, ThreadPool};
+use srvzio;
+
+use std::{cmp, thread, time::{Duration, Instant}};
+
+const HEALTH_CHECKER_SERVICE_NAME: &'static str = "HealthChecker";
+const HEALTH_CHECKER_THREAD_NAME: &'static str = "health_checker_thread";
+const HEALTH_CHECKER_PROBE_THREAD_NAME: &'static str = "health_checker_probe_thread";
+const HEALTH_CHECKER_STOP_CHECK_INTERVAL_MS: u64 = 500;
+const HEALTH_CHECKER_PROBE_ID: u16 = 0x4843;
+
+/// HealthChecker is a service that probes the upstreams at regular intervals
+///
+/// Each probe is a `. NS` query, sent via the upstream (i.e. via the HTTP request built by its
+/// `DoHProvider`), and its outcome is recorded in the (shared) state of the upstream: a failed
+/// probe takes the upstream out of rotation, a successful one puts it back (and updates its
+/// latency). This way, a broken provider is spotted before user queries are sent to it.
+///
+/// Changes of health are logged.
+pub struct HealthChecker {
+  upstreams: Vec<DoHUpstream>,
+  interval: Duration,
+  status: srvzio::ServiceStatusFlag,
+  checker_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl HealthChecker {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  /// * `upstreams`: the upstreams to probe (their state is shared with the resolver using them)
+  /// * `interval`: how long to wait between probes
+  pub fn new(upstreams: Vec<DoHUpstream>, interval: Duration) -> HealthChecker {
+    HealthChecker {
+      upstreams,
+      interval,
+      status: srvzio::ServiceStatusFlag::default(),
+      checker_thread: None,
+    }
+  }
+
+}
+
+impl srvzio::Service for HealthChecker {
+
+  fn name(&self) -> &'static str {
+    HEALTH_CHECKER_SERVICE_NAME
+  }
+
+  fn start(&mut self) {
+    self.status.starting();
+
+    let upstreams = self.upstreams.clone();
+    let interval = self.interval;
+    let status = self.status.clone();
+
+    // Launch a 'health checking' thread
+    self.checker_thread = Some(thread::Builder::new()
+      .name(HEALTH_CHECKER_THREAD_NAME.into())
+      .spawn(move || {
+        let pool = create_thread_pool(upstreams.len());
+
+        status.started();
+
+        // Probe at every interval, but interrupt at regular intervals to check if HealthChecker was stopped
+        let mut next_probe = Instant::now();
+        while !status.is_stopping() {
+          if Instant::now() >= next_probe {
+            probe_all(&pool, &upstreams);
+            next_probe = Instant::now() + interval;
+          }
+
+          thread::sleep(Duration::from_millis(HEALTH_CHECKER_STOP_CHECK_INTERVAL_MS));
+        }
+        trace!("{} is done running: stop probing upstreams", HEALTH_CHECKER_SERVICE_NAME);
+
+        status.stopped();
+      })
+      .expect(format!("Unable to spawn thread: {}", HEALTH_CHECKER_THREAD_NAME).as_ref())
+    );
+  }
+
+  fn await_started(&mut self) {
+    while !self.status.is_started() {}
+  }
+
+  fn stop(&mut self) {
+    trace!("{} should now stop...", HEALTH_CHECKER_SERVICE_NAME);
+    self.status.stopping();
+  }
+
+  fn await_stopped(&mut self) {
+    while !self.status.is_stopped() {}
+
+    // Wait for checker thread to stop (if it's actually set)
+    if self.checker_thread.is_some() {
+      self.checker_thread
+        .take()
+        .unwrap()
+        .join()
+        .expect(format!("Panicked upon termination: {}", HEALTH_CHECKER_THREAD_NAME).as_ref());
+    }
+  }
+
+}
+
+fn create_thread_pool(upstreams_count: usize) -> ThreadPool {
+  ThreadPoolBuilder::new()
+    .num_threads(cmp::max(upstreams_count, 1))
+    .thread_name(HEALTH_CHECKER_PROBE_THREAD_NAME.into())
+    .build()
+}
+
+/// Probes all the upstreams in parallel, and waits for all the probes to be done
+fn probe_all(pool: &ThreadPool, upstreams: &[DoHUpstream]) {
+  for upstream in upstreams {
+    let upstream = upstream.clone();
+    pool.execute(move || probe(&upstream));
+  }
+
+  pool.join();
+}
+
+/// Probes an upstream, logging if its health has changed
+fn probe(upstream: &DoHUpstream) {
+  let was_up = upstream.state().lock().unwrap().is_up();
+  let res = upstream.resolve_query(&probe_dns_message());
+
+  let state = upstream.state().lock().unwrap().clone();
+  match (was_up, state.is_up()) {
+    (true, false) => warn!("Upstream '{}' is down: {}", upstream.id(), res.err().map(|err| err.to_string()).unwrap_or_else(|| "SERVFAIL".into())),
+    (false, true) => info!("Upstream '{}' is up again", upstream.id()),
+    _ => debug!("Upstream '{}' probed: {:?}", upstream.id(), state),
+  }
+}
+
+/// Builds the DNS query used to probe upstreams: `. NS`
+fn probe_dns_message() -> DnsMessage {
+  let mut dns_msg = DnsMessage::new();
+  dns_msg.set_id(HEALTH_CHECKER_PROBE_ID);
+  dns_msg.set_message_type(DnsMessageType::Query);
+  dns_msg.set_op_code(DnsOpCode::Query);
+  dns_msg.set_recursion_desired(true);
+  dns_msg.add_query(DnsQuery::query(DnsDomainName::root(), DnsRecordType::NS));
+
+  dns_msg
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::resolver::{DoHResolver, DoHResolutionError, DoHResolutionErrorKind};
+  use crate::dns::protocol::DnsResponseCode;
+  use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+
+  /// Resolver that succeeds or fails, depending on a (shared) switch
+  #[derive(Debug, Clone)]
+  struct SwitchResolver {
+    up: Arc<AtomicBool>,
+  }
+
+  impl DoHResolver for SwitchResolver {
+    fn resolve_query(&self, req_dns_msg: &DnsMessage) -> Result<DnsMessage, DoHResolutionError> {
+      assert_eq!(req_dns_msg.queries()[0].query_type(), DnsRecordType::NS);
+
+      if self.up.load(Ordering::SeqCst) {
+        let mut res_dns_msg = DnsMessage::new();
+        res_dns_msg.set_id(req_dns_msg.id());
+        res_dns_msg.set_message_type(DnsMessageType::Response);
+        res_dns_msg.set_response_code(DnsResponseCode::NoError);
+        Ok(res_dns_msg)
+      } else {
+        Err(DoHResolutionError::new(DoHResolutionErrorKind::Transport, "Connection refused".into()))
+      }
+    }
+
+    fn box_clone(&self) -> Box<DoHResolver + Send> {
+      Box::new((*self).clone())
+    }
+  }
+
+  #[test]
+  fn should_mark_upstreams_up_and_down() {
+    let up = Arc::new(AtomicBool::new(false));
+    let upstream = DoHUpstream::new("switch", Box::new(SwitchResolver { up: up.clone() }));
+    let pool = create_thread_pool(1);
+
+    probe_all(&pool, &[upstream.clone()]);
+    assert!(!upstream.state().lock().unwrap().is_up());
+    assert!(!upstream.state().lock().unwrap().is_available(Instant::now()));
+
+    up.store(true, Ordering::SeqCst);
+    probe_all(&pool, &[upstream.clone()]);
+    assert!(upstream.state().lock().unwrap().is_up());
+    assert!(upstream.state().lock().unwrap().latency().is_some());
+  }
+}
@@ -0,0 +1,269 @@
+//! Local records (ex. internal hostnames), answered authoritatively in front of any `DoHResolver`
+
+pub mod record;
+pub mod hosts;
+
+use super::resolver::{DoHResolver, DoHResolutionError};
+use self::record::LocalRecord;
+use crate::dns::protocol::*;
+use crate::metrics;
+
+use log::*;
+
+use std::{fs::File, io::BufReader, path::PathBuf, collections::HashMap, sync::Arc};
+
+type Result<T> = std::result::Result<T, DoHResolutionError>;
+
+/// How many `CNAME` records to follow, before giving up on a chain
+const CNAME_CHAIN_MAX_LEN: usize = 8;
+
+/// The local records, by name
+///
+/// Names are case insensitive and fully qualified.
+#[derive(Debug, Clone, Default)]
+pub struct LocalRecords {
+  records: HashMap<DnsDomainName, Vec<DnsRecord>>,
+}
+
+impl LocalRecords {
+
+  /// Constructor
+  ///
+  /// A `PTR` record is generated for each `A`/`AAAA` record, unless the IP already has one
+  /// (so, the first name for an IP is the one it's reversed to).
+  ///
+  /// # Parameters
+  ///
+  /// * `records` - the local records
+  /// * `ttl` - TTL of the local records, in seconds
+  pub fn new(records: &[LocalRecord], ttl: u32) -> LocalRecords {
+    let mut local_records = LocalRecords::default();
+    for record in records {
+      local_records.insert(record.to_record(ttl));
+    }
+
+    for record in records {
+      let reverse_name = match record.rdata {
+        DnsRData::A(ipv4) => DnsDomainName::from(ipv4),
+        DnsRData::AAAA(ipv6) => DnsDomainName::from(ipv6),
+        _ => continue,
+      };
+      if !local_records.records.contains_key(&reverse_name) {
+        local_records.insert(DnsRecord::from_rdata(reverse_name, ttl, DnsRData::PTR(record.name.clone())));
+      }
+    }
+
+    local_records
+  }
+
+  /// Adds a record, unless it's already there
+  fn insert(&mut self, record: DnsRecord) {
+    let mut name = record.name().clone();
+    name.set_fqdn(true);
+
+    let records = self.records.entry(name).or_default();
+    if !records.iter().any(|existing| existing.rr_type() == record.rr_type() && existing.rdata() == record.rdata()) {
+      records.push(record);
+    }
+  }
+
+  /// How many names have local records
+  pub fn len(&self) -> usize {
+    self.records.len()
+  }
+
+  /// `true` if there are no local records
+  pub fn is_empty(&self) -> bool {
+    self.records.is_empty()
+  }
+
+  /// The records that answer the given query, or `None` if the name queried is not local
+  ///
+  /// If the name has a `CNAME` record, that is followed as long as the names are local.
+  /// An empty answer means the name is local, but has no records of the type queried.
+  pub fn answer(&self, query: &DnsQuery) -> Option<Vec<DnsRecord>> {
+    let mut name = query.name().clone();
+    name.set_fqdn(true);
+    self.records.get(&name)?;
+
+    let mut answers = Vec::new();
+    for _ in 0..CNAME_CHAIN_MAX_LEN {
+      let records = match self.records.get(&name) {
+        Some(records) => records,
+        None => break,
+      };
+
+      let matching: Vec<DnsRecord> = records.iter()
+        .filter(|record| query.query_type() == DnsRecordType::ANY || record.rr_type() == query.query_type())
+        .cloned()
+        .collect();
+      if !matching.is_empty() {
+        answers.extend(matching);
+        break;
+      }
+
+      match records.iter().find(|record| record.rr_type() == DnsRecordType::CNAME) {
+        Some(cname) => {
+          answers.push(cname.clone());
+          match cname.rdata() {
+            DnsRData::CNAME(target) => name = target.clone(),
+            _ => break,
+          }
+        },
+        None => break,
+      }
+    }
+
+    Some(answers)
+  }
+
+}
+
+/// Loads the local records from the configuration and from hosts files
+///
+/// Hosts files that can't be read are skipped (logging an error).
+///
+/// # Parameters
+///
+/// * `records` - the records defined in the configuration
+/// * `hosts_files` - paths to hosts files
+/// * `ttl` - TTL of the local records, in seconds
+pub fn load_local_records(records: Vec<LocalRecord>, hosts_files: &[PathBuf], ttl: u32) -> LocalRecords {
+  let mut records = records;
+  for path in hosts_files {
+    match File::open(path).and_then(|file| hosts::read_hosts(BufReader::new(file))) {
+      Ok(hosts_records) => {
+        info!("Loaded hosts file '{}': {} records", path.display(), hosts_records.len());
+        records.extend(hosts_records);
+      },
+      Err(err) => error!("Unable to load hosts file '{}': {}", path.display(), err),
+    }
+  }
+
+  LocalRecords::new(&records, ttl)
+}
+
+/// Resolver that answers the queries for local names authoritatively
+///
+/// Queries for names that have local records are never passed on to the wrapped `DoHResolver`,
+/// even when there is nothing to answer with (the response is then `NODATA`), so that local
+/// names never leak. The records are shared by all the clones of this resolver.
+#[derive(Clone)]
+pub struct DoHLocalResolver {
+  resolver: Box<DoHResolver + Send>,
+  records: Arc<LocalRecords>,
+}
+
+impl DoHLocalResolver {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  ///
+  /// * `resolver` - the `DoHResolver` to resolve the queries for other names with
+  /// * `records` - the local records
+  pub fn new(resolver: Box<DoHResolver + Send>, records: LocalRecords) -> DoHLocalResolver {
+    DoHLocalResolver {
+      resolver,
+      records: Arc::new(records),
+    }
+  }
+
+}
+
+impl DoHResolver for DoHLocalResolver {
+
+  fn resolve_query(&self, req_dns_msg: &DnsMessage) -> Result<DnsMessage> {
+    let answers: Vec<Option<Vec<DnsRecord>>> = req_dns_msg.queries().iter().map(|query| self.records.answer(query)).collect();
+    if answers.iter().all(Option::is_none) {
+      return self.resolver.resolve_query(req_dns_msg);
+    }
+
+    debug!("Answering locally: {:?}", req_dns_msg.queries());
+    metrics::inc_counter(&metrics::QUERIES_LOCAL, &[]);
+
+    let mut res_dns_msg = dns_error_response(req_dns_msg, DnsResponseCode::NoError);
+    res_dns_msg.set_authoritative(true);
+    res_dns_msg.insert_answers(answers.into_iter().flatten().flatten().collect());
+
+    Ok(res_dns_msg)
+  }
+
+  fn box_clone(&self) -> Box<DoHResolver + Send> {
+    Box::new((*self).clone())
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::resolver::DoHResolutionErrorKind;
+  use crate::test_support::query_message;
+  use std::{str::FromStr, net::Ipv4Addr};
+
+  /// Resolver that always fails: queries for local names must never reach it
+  #[derive(Debug, Clone)]
+  struct FailingResolver;
+
+  impl DoHResolver for FailingResolver {
+    fn resolve_query(&self, _: &DnsMessage) -> Result<DnsMessage> {
+      Err(DoHResolutionError::new(DoHResolutionErrorKind::Transport, "Not a local name".into()))
+    }
+
+    fn box_clone(&self) -> Box<DoHResolver + Send> {
+      Box::new((*self).clone())
+    }
+  }
+
+  fn local_resolver() -> DoHLocalResolver {
+    let records: Vec<LocalRecord> = [
+      "nas.home.lan A 192.168.1.10",
+      "nas.home.lan AAAA fd00::10",
+      "www.home.lan CNAME nas.home.lan",
+      "storage.home.lan A 192.168.1.10",
+      "home.lan MX 10 mail.home.lan",
+    ].iter().map(|raw_record| raw_record.parse().unwrap()).collect();
+
+    DoHLocalResolver::new(Box::new(FailingResolver), LocalRecords::new(&records, 300))
+  }
+
+  #[test]
+  fn should_answer_local_names() {
+    let resolver = local_resolver();
+
+    let res = resolver.resolve(&query_message(1, "NAS.home.lan.", DnsRecordType::A)).unwrap();
+    assert_eq!(res.response_code(), DnsResponseCode::NoError);
+    assert!(res.authoritative());
+    assert_eq!(res.answers().len(), 1);
+    assert_eq!(res.answers()[0].rdata(), &DnsRData::A(Ipv4Addr::new(192, 168, 1, 10)));
+    assert_eq!(res.answers()[0].ttl(), 300);
+
+    // CNAME is followed
+    let res = resolver.resolve(&query_message(1, "www.home.lan.", DnsRecordType::AAAA)).unwrap();
+    assert_eq!(res.answers().len(), 2);
+    assert_eq!(res.answers()[0].rr_type(), DnsRecordType::CNAME);
+    assert_eq!(res.answers()[1].rr_type(), DnsRecordType::AAAA);
+
+    // Local name, but no records of the type: NODATA
+    let res = resolver.resolve(&query_message(1, "home.lan.", DnsRecordType::A)).unwrap();
+    assert_eq!(res.response_code(), DnsResponseCode::NoError);
+    assert!(res.answers().is_empty());
+
+    // Not a local name
+    assert!(resolver.resolve(&query_message(1, "example.com.", DnsRecordType::A)).is_err());
+  }
+
+  #[test]
+  fn should_generate_reverse_records() {
+    let resolver = local_resolver();
+
+    // The first name for the IP is the one it's reversed to
+    let res = resolver.resolve(&query_message(1, "10.1.168.192.in-addr.arpa.", DnsRecordType::PTR)).unwrap();
+    assert_eq!(res.answers().len(), 1);
+    assert_eq!(res.answers()[0].rdata(), &DnsRData::PTR(DnsDomainName::from_str("nas.home.lan.").unwrap()));
+
+    let res = resolver.resolve(&query_message(1, "0.1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa.", DnsRecordType::PTR)).unwrap();
+    assert_eq!(res.answers().len(), 1);
+  }
+}
@@ -0,0 +1,90 @@
+//! Parsing of hosts files (ex. `/etc/hosts`), into local records
+
+use super::record::{LocalRecord, parse_name};
+use crate::dns::protocol::*;
+
+use std::{io::{self, BufRead}, net::IpAddr};
+
+/// Splits a line of a hosts file (`IP NAME [NAME...]`) into its IP and its names
+///
+/// Returns `None` for comments, empty lines and lines that don't start with an IP that can be
+/// parsed (ex. IPv6 with a zone index). Names are returned as they are, without any validation.
+///
+/// # Parameters
+///
+/// * `line` - A line of the hosts file
+pub fn split_line(line: &str) -> Option<(IpAddr, Vec<&str>)> {
+  let entries = line.split('#').next().unwrap_or_default();
+  let mut tokens = entries.split_whitespace();
+
+  let ip = tokens.next().and_then(|raw_ip| raw_ip.parse::<IpAddr>().ok())?;
+
+  Some((ip, tokens.collect()))
+}
+
+/// Parses a line of a hosts file (`IP NAME [NAME...]`), returning a local record for each name
+///
+/// Lines skipped by `split_line()`, and names that aren't valid, don't return any record.
+///
+/// # Parameters
+///
+/// * `line` - A line of the hosts file
+pub fn parse_line(line: &str) -> Vec<LocalRecord> {
+  let (ip, names) = match split_line(line) {
+    Some(entry) => entry,
+    None => return Vec::new(),
+  };
+
+  names.into_iter()
+    .filter_map(parse_name)
+    .map(|name| LocalRecord {
+      name,
+      rdata: match ip {
+        IpAddr::V4(ipv4) => DnsRData::A(ipv4),
+        IpAddr::V6(ipv6) => DnsRData::AAAA(ipv6),
+      },
+    })
+    .collect()
+}
+
+/// Reads the local records from a hosts file
+///
+/// # Parameters
+///
+/// * `reader` - The hosts file
+pub fn read_hosts<R: BufRead>(reader: R) -> io::Result<Vec<LocalRecord>> {
+  let mut records = Vec::new();
+  for line in reader.lines() {
+    records.extend(parse_line(&line?));
+  }
+
+  Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::{str::FromStr, net::{Ipv4Addr, Ipv6Addr}};
+
+  #[test]
+  fn should_read_hosts() {
+    let hosts = "# Hosts\n127.0.0.1 localhost\n192.168.1.10\tnas.home.lan nas # storage\nfd00::10 nas.home.lan\nfe80::1%lo0 localhost\n\n";
+
+    let records = read_hosts(hosts.as_bytes()).unwrap();
+    assert_eq!(records.len(), 4);
+    assert_eq!(records[1], LocalRecord { name: DnsDomainName::from_str("nas.home.lan.").unwrap(), rdata: DnsRData::A(Ipv4Addr::new(192, 168, 1, 10)) });
+    assert_eq!(records[2].name, DnsDomainName::from_str("nas.").unwrap());
+    assert_eq!(records[3].rdata, DnsRData::AAAA(Ipv6Addr::from_str("fd00::10").unwrap()));
+  }
+
+  #[test]
+  fn should_split_lines() {
+    assert_eq!(split_line("192.168.1.10\tnas.home.lan  nas # storage"), Some(("192.168.1.10".parse().unwrap(), vec!["nas.home.lan", "nas"])));
+    assert_eq!(split_line(":: ads.example.com"), Some(("::".parse().unwrap(), vec!["ads.example.com"])));
+    assert_eq!(split_line("127.0.0.1"), Some(("127.0.0.1".parse().unwrap(), vec![])));
+    assert!(split_line("fe80::1%lo0 localhost").is_none());
+    assert!(split_line("nas.home.lan 192.168.1.10").is_none());
+    assert!(split_line("# 127.0.0.1 localhost").is_none());
+    assert!(split_line("").is_none());
+  }
+}
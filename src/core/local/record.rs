@@ -0,0 +1,154 @@
+//! Local records, as defined in the configuration (ex. `nas.home.lan A 192.168.1.10`)
+
+use crate::dns::protocol::*;
+
+use std::{fmt, str::FromStr, net::{Ipv4Addr, Ipv6Addr}};
+
+const LOCAL_RECORD_TYPES: &[DnsRecordType] = &[
+  DnsRecordType::A,
+  DnsRecordType::AAAA,
+  DnsRecordType::CNAME,
+  DnsRecordType::PTR,
+  DnsRecordType::TXT,
+  DnsRecordType::SRV,
+  DnsRecordType::MX,
+];
+
+/// A record to answer with locally, in the format `NAME TYPE DATA`
+///
+/// The `DATA` depends on the `TYPE`:
+///
+/// * `A` / `AAAA` - IPv4 / IPv6 (ex. `nas.home.lan A 192.168.1.10`)
+/// * `CNAME` / `PTR` - domain name (ex. `www.home.lan CNAME nas.home.lan`)
+/// * `TXT` - text, optionally as one or more quoted strings (ex. `home.lan TXT "v=spf1 -all"`)
+/// * `SRV` - priority, weight, port and target (ex. `_http._tcp.home.lan SRV 10 5 80 nas.home.lan`)
+/// * `MX` - preference and exchange (ex. `home.lan MX 10 mail.home.lan`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalRecord {
+  pub name: DnsDomainName,
+  pub rdata: DnsRData,
+}
+
+impl LocalRecord {
+
+  /// Builds the `DnsRecord` of this local record, with the given TTL
+  pub fn to_record(&self, ttl: u32) -> DnsRecord {
+    DnsRecord::from_rdata(self.name.clone(), ttl, self.rdata.clone())
+  }
+
+}
+
+/// Parses a domain name, always as fully qualified (i.e. relative to the root)
+pub fn parse_name(raw_name: &str) -> Option<DnsDomainName> {
+  if raw_name.is_empty() {
+    return None;
+  }
+
+  let mut name = DnsDomainName::from_ascii(raw_name).ok()?;
+  name.set_fqdn(true);
+
+  Some(name)
+}
+
+/// Parses the text of a `TXT` record: a sequence of quoted strings, or the text as it is
+fn parse_txt(raw_txt: &str) -> Option<Vec<String>> {
+  if !raw_txt.starts_with('"') {
+    return Some(vec![raw_txt.to_string()]);
+  }
+
+  let mut strings = Vec::new();
+  let mut rest = raw_txt;
+  while !rest.is_empty() {
+    let string = rest.strip_prefix('"')?;
+    let end = string.find('"')?;
+    strings.push(string[..end].to_string());
+    rest = string[end + 1..].trim_start();
+  }
+
+  Some(strings)
+}
+
+impl FromStr for LocalRecord {
+  type Err = LocalRecordParseError;
+
+  fn from_str(raw_record: &str) -> Result<Self, Self::Err> {
+    let err = || LocalRecordParseError::new(raw_record);
+
+    let raw_record = raw_record.trim();
+    let mut parts = raw_record.splitn(3, char::is_whitespace);
+    let name = parts.next().and_then(parse_name).ok_or_else(err)?;
+    let record_type = parts.next()
+      .and_then(|raw_type| DnsRecordType::from_str(&raw_type.to_ascii_uppercase()).ok())
+      .filter(|record_type| LOCAL_RECORD_TYPES.contains(record_type))
+      .ok_or_else(err)?;
+    let raw_data = parts.next().map(str::trim).filter(|raw_data| !raw_data.is_empty()).ok_or_else(err)?;
+    let fields: Vec<&str> = raw_data.split_whitespace().collect();
+
+    let rdata = match (record_type, fields.as_slice()) {
+      (DnsRecordType::A, [ip]) => ip.parse::<Ipv4Addr>().ok().map(DnsRData::A),
+      (DnsRecordType::AAAA, [ip]) => ip.parse::<Ipv6Addr>().ok().map(DnsRData::AAAA),
+      (DnsRecordType::CNAME, [target]) => parse_name(target).map(DnsRData::CNAME),
+      (DnsRecordType::PTR, [target]) => parse_name(target).map(DnsRData::PTR),
+      (DnsRecordType::TXT, _) => parse_txt(raw_data).map(|strings| DnsRData::TXT(DnsRDataTXT::new(strings))),
+      (DnsRecordType::SRV, [priority, weight, port, target]) => match (priority.parse(), weight.parse(), port.parse(), parse_name(target)) {
+        (Ok(priority), Ok(weight), Ok(port), Some(target)) => Some(DnsRData::SRV(DnsRDataSRV::new(priority, weight, port, target))),
+        _ => None,
+      },
+      (DnsRecordType::MX, [preference, exchange]) => match (preference.parse(), parse_name(exchange)) {
+        (Ok(preference), Some(exchange)) => Some(DnsRData::MX(DnsRDataMX::new(preference, exchange))),
+        _ => None,
+      },
+      _ => None,
+    };
+
+    rdata.map(|rdata| LocalRecord { name, rdata }).ok_or_else(err)
+  }
+}
+
+/// Error that happens when parsing a `LocalRecord` fails
+#[derive(Debug, Clone)]
+pub struct LocalRecordParseError {
+  token: String
+}
+
+impl LocalRecordParseError {
+  fn new(token: &str) -> Self {
+    Self {
+      token: token.to_string()
+    }
+  }
+}
+
+impl fmt::Display for LocalRecordParseError {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    let types: Vec<String> = LOCAL_RECORD_TYPES.iter().map(DnsRecordType::to_string).collect();
+    write!(fmtr, "Invalid Local Record (expected 'NAME TYPE DATA', with TYPE one of {}): {}", types.join(", "), self.token)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn should_parse_local_record() {
+    let record = LocalRecord::from_str("NAS.home.lan a 192.168.1.10").unwrap();
+    assert_eq!(record.name, DnsDomainName::from_str("nas.home.lan.").unwrap());
+    assert_eq!(record.rdata, DnsRData::A(Ipv4Addr::new(192, 168, 1, 10)));
+
+    let record = LocalRecord::from_str("_http._tcp.home.lan SRV 10 5 80 nas.home.lan").unwrap();
+    assert_eq!(record.rdata, DnsRData::SRV(DnsRDataSRV::new(10, 5, 80, DnsDomainName::from_str("nas.home.lan.").unwrap())));
+
+    let record = LocalRecord::from_str("home.lan TXT \"v=spf1 -all\" \"second\"").unwrap();
+    assert_eq!(record.rdata, DnsRData::TXT(DnsRDataTXT::new(vec!["v=spf1 -all".into(), "second".into()])));
+    let record = LocalRecord::from_str("home.lan TXT hello world").unwrap();
+    assert_eq!(record.rdata, DnsRData::TXT(DnsRDataTXT::new(vec!["hello world".into()])));
+
+    assert!(LocalRecord::from_str("nas.home.lan A fd00::10").is_err());
+    assert!(LocalRecord::from_str("nas.home.lan NS ns.home.lan").is_err());
+    assert!(LocalRecord::from_str("home.lan MX mail.home.lan").is_err());
+    assert!(LocalRecord::from_str("home.lan TXT \"unterminated").is_err());
+    assert!(LocalRecord::from_str("nas.home.lan A").is_err());
+    assert!(LocalRecord::from_str("").is_err());
+  }
+}
@@ -0,0 +1,113 @@
+//! Enum of possible DNS-over-HTTPS request modes
+
+use std::{fmt, convert::From, str::FromStr};
+
+const REQUEST_MODE_NAME_GET: &'static str = "get";
+const REQUEST_MODE_NAME_POST: &'static str = "post";
+
+/// DNS-over-HTTPS request mode
+///
+/// Defines how a DNS query is carried by the HTTP request (see
+/// [RFC 8484](https://tools.ietf.org/html/rfc8484#section-4.1)):
+///
+/// * `GET` - the DNS query is base64url-encoded into the `dns` query parameter: responses can be cached by HTTP caches
+/// * `POST` - the DNS query is the binary body of the request: avoids URL length limits (ex. large EDNS queries)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DoHRequestMode {
+  GET,
+  POST
+}
+
+impl From<DoHRequestMode> for &'static str {
+  fn from(request_mode: DoHRequestMode) -> Self {
+    match request_mode {
+      DoHRequestMode::GET => REQUEST_MODE_NAME_GET,
+      DoHRequestMode::POST => REQUEST_MODE_NAME_POST,
+    }
+  }
+}
+
+impl From<&str> for DoHRequestMode {
+  fn from(raw_request_mode: &str) -> Self {
+    match DoHRequestMode::from_str(raw_request_mode) {
+      Ok(request_mode) => request_mode,
+      Err(err) => panic!("{}", err)
+    }
+  }
+}
+
+impl FromStr for DoHRequestMode {
+  type Err = DoHRequestModeParseError;
+
+  fn from_str(raw_request_mode: &str) -> Result<Self, Self::Err> {
+    match raw_request_mode {
+      REQUEST_MODE_NAME_GET => Ok(DoHRequestMode::GET),
+      REQUEST_MODE_NAME_POST => Ok(DoHRequestMode::POST),
+      _ => Err(DoHRequestModeParseError::new(raw_request_mode))
+    }
+  }
+}
+
+impl fmt::Display for DoHRequestMode {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match *self {
+      DoHRequestMode::GET => write!(fmtr, "{}", REQUEST_MODE_NAME_GET),
+      DoHRequestMode::POST => write!(fmtr, "{}", REQUEST_MODE_NAME_POST),
+    }
+  }
+}
+
+/// Error that happens when parsing a `DoHRequestMode` fails
+#[derive(Debug, Clone)]
+pub struct DoHRequestModeParseError {
+  token: String
+}
+
+impl DoHRequestModeParseError {
+  fn new(token: &str) -> Self {
+    Self {
+      token: token.to_string()
+    }
+  }
+}
+
+impl fmt::Display for DoHRequestModeParseError {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmtr, "Invalid DNS-over-HTTPS Request Mode: {}", self.token)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn should_parse_request_modes() {
+    assert_eq!(DoHRequestMode::from_str("get").unwrap(), DoHRequestMode::GET);
+    assert_eq!(DoHRequestMode::from_str("post").unwrap(), DoHRequestMode::POST);
+    assert_eq!(DoHRequestMode::from("post"), DoHRequestMode::POST);
+
+    assert!(DoHRequestMode::from_str("GET").is_err());
+    assert!(DoHRequestMode::from_str("put").is_err());
+    assert!(DoHRequestMode::from_str("").is_err());
+    assert_eq!(DoHRequestMode::from_str("put").unwrap_err().to_string(), "Invalid DNS-over-HTTPS Request Mode: put");
+  }
+
+  #[test]
+  #[should_panic(expected = "Invalid DNS-over-HTTPS Request Mode: put")]
+  fn should_panic_converting_invalid_request_mode() {
+    DoHRequestMode::from("put");
+  }
+
+  #[test]
+  fn should_name_request_modes() {
+    for request_mode in &[DoHRequestMode::GET, DoHRequestMode::POST] {
+      let name: &'static str = (*request_mode).into();
+      assert_eq!(request_mode.to_string(), name);
+      assert_eq!(DoHRequestMode::from_str(name).unwrap(), *request_mode);
+    }
+
+    assert_eq!(DoHRequestMode::GET.to_string(), "get");
+    assert_eq!(DoHRequestMode::POST.to_string(), "post");
+  }
+}
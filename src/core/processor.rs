@@ -1,7 +1,11 @@
 //! Processing of received requests
 
 use crate::net::request::Request;
-use super::resolver::DoHResolver;
+use crate::dns::protocol::dns_error_response;
+use crate::metrics;
+use crate::querylog::QueryLog;
+use crate::dnstap::Dnstap;
+use super::{resolver::DoHResolver, context};
 
 use log::*;
 use threadpool::{Builder as ThreadPoolBuilder, ThreadPool};
@@ -24,10 +28,13 @@ const PROCESSOR_RECEIVER_TIMEOUT_SEC: u64 = 10;
 /// resolved via the `DoHResolver`.
 ///
 /// The service is agnostic to what kind of DNS-over-HTTPS resolution is configured: it just
-/// uses the provided `DoHResolver`.
+/// uses the provided `DoHResolver`. If a `QueryLog` is provided, every response is logged to it;
+/// if a `Dnstap` is provided, every query and response is emitted to it.
 pub struct Processor {
   receiver: XBeamReceiver<Request>,
   resolver: Box<DoHResolver + Send>,
+  query_log: Option<QueryLog>,
+  dnstap: Option<Dnstap>,
   status: srvzio::ServiceStatusFlag,
   receiver_thread: Option<thread::JoinHandle<()>>,
 }
@@ -39,10 +46,14 @@ impl Processor {
   /// # Parameters
   /// * `receiver`: a `crossbeam::channel::Receiver` that delivers `Request` data
   /// * `resolver`: a struct that implements the `DoHResolver`, wrapped in a `Box`
-  pub fn new(receiver: XBeamReceiver<Request>, resolver: Box<DoHResolver + Send>) -> Processor {
+  /// * `query_log`: where to log the responses (if anywhere)
+  /// * `dnstap`: where to emit queries and responses (if anywhere)
+  pub fn new(receiver: XBeamReceiver<Request>, resolver: Box<DoHResolver + Send>, query_log: Option<QueryLog>, dnstap: Option<Dnstap>) -> Processor {
     Processor {
       receiver,
       resolver,
+      query_log,
+      dnstap,
       status: srvzio::ServiceStatusFlag::default(),
       receiver_thread: None,
     }
@@ -62,6 +73,8 @@ impl srvzio::Service for Processor {
 
     let receiver = self.receiver.clone();
     let resolver = self.resolver.clone();
+    let query_log = self.query_log.clone();
+    let dnstap = self.dnstap.clone();
     let status = self.status.clone();
 
     // Launch a 'request receiving' thread
@@ -80,10 +93,16 @@ impl srvzio::Service for Processor {
                 let q = req.dns_query();
                 let s = req.source();
                 debug!("Received: id={} type={:?} source={} queries={:?}", q.id(), q.message_type(), s, q.queries());
+
+                for query in q.queries() {
+                  metrics::inc_counter(&metrics::QUERIES_RECEIVED, &[req.transport(), &query.query_type().to_string()]);
+                }
               }
 
               let resolver = resolver.clone();
-              pool.execute(move || resolve_and_respond(req, resolver));
+              let query_log = query_log.clone();
+              let dnstap = dnstap.clone();
+              pool.execute(move || resolve_and_respond(req, resolver, query_log, dnstap));
             },
             Err(XBeamRecvTimeoutError::Timeout) => {
               if status.is_stopping() {
@@ -138,14 +157,32 @@ fn crate_thread_pool() -> ThreadPool {
     .build()
 }
 
-fn resolve_and_respond(req: Request, resolver: Box<DoHResolver>) -> () {
-  match resolver.resolve(req.dns_query()) {
+fn resolve_and_respond(req: Request, resolver: Box<DoHResolver>, query_log: Option<QueryLog>, dnstap: Option<Dnstap>) -> () {
+  if let Some(dnstap) = &dnstap {
+    dnstap.client_query(&req);
+  }
+  context::reset();
+
+  let res_msg = match resolver.resolve(req.dns_query()) {
     Ok(res_msg) => {
       debug!("Responding: id={} type={:?} answers={:?}", res_msg.id(), res_msg.message_type(), res_msg.answers());
-      req.respond(res_msg);
+      res_msg
     },
     Err(err) => {
       error!("Unable to resolve request: {}", err);
+
+      // Always respond, so the client doesn't have to wait for its own timeout
+      let res_msg = dns_error_response(req.dns_query(), err.response_code());
+      debug!("Responding: id={} type={:?} response_code={:?}", res_msg.id(), res_msg.message_type(), res_msg.response_code());
+      res_msg
     }
+  };
+
+  if let Some(query_log) = query_log {
+    query_log.log(&req, &res_msg, context::take());
+  }
+  if let Some(dnstap) = dnstap {
+    dnstap.client_response(&req, &res_msg);
   }
+  req.respond(res_msg);
 }
\ No newline at end of file
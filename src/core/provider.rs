@@ -1,12 +1,15 @@
 //! Trait definition for DNS-over-HTTPS provider
 
-use super::protocol::DoHProtocol;
-use crate::dns::protocol::DnsQuery;
+use super::{protocol::DoHProtocol, mode::DoHRequestMode};
+use crate::dns::{protocol::DnsQuery, client};
 
+use log::*;
 use downcast_rs::*;
-use http::{Result, Request};
+use http::{Result, Request, Uri, uri::{Scheme, Authority, PathAndQuery}};
 
-use std::{collections::HashMap, fmt};
+use std::{collections::HashMap, fmt, net::{IpAddr, SocketAddr}, time::Duration};
+
+const BOOTSTRAP_RESOLUTION_TIMEOUT_SEC: u64 = 5;
 
 /// Trait defining a provider of DNS-over-HTTPS services
 pub trait DoHProvider: Downcast {
@@ -17,16 +20,58 @@ pub trait DoHProvider: Downcast {
   /// Protocol supported by the Provider
   fn protocol(&self) -> DoHProtocol;
 
+  /// Request mode preferred by the Provider (i.e. HTTP `GET` or `POST`)
+  fn request_mode(&self) -> DoHRequestMode;
+
+  /// Hostname of the Provider (i.e. the host part of its URI)
+  fn hostname(&self) -> &str;
+
+  /// Addresses the Provider can be reached at, without having to resolve its hostname
+  ///
+  /// Connections go to these addresses, while the hostname is still used for TLS (SNI) and
+  /// for the `Host` header. This avoids a circular dependency when Mooncell is the system resolver.
+  fn bootstrap_addresses(&self) -> &[IpAddr];
+
+  /// Replaces the addresses the Provider can be reached at (see `DoHProvider::bootstrap_addresses()`)
+  fn set_bootstrap_addresses(&mut self, addresses: Vec<IpAddr>);
+
+  /// Resolves the addresses of the Provider via the given "plain" DNS server, and uses them from now on
+  ///
+  /// If the resolution fails, or returns no address, the current bootstrap addresses are kept.
+  ///
+  /// # Parameters
+  ///
+  /// * `dns_server` - Address of a "plain" DNS server (ex. "9.9.9.9:53")
+  fn resolve_bootstrap_addresses(&mut self, dns_server: &SocketAddr) {
+    let timeout = Duration::from_secs(BOOTSTRAP_RESOLUTION_TIMEOUT_SEC);
+
+    match client::lookup_ip(dns_server, self.hostname(), timeout) {
+      Ok(ref addresses) if addresses.is_empty() => {
+        warn!("No addresses found for '{}' via '{}': keeping {:?}", self.hostname(), dns_server, self.bootstrap_addresses());
+      },
+      Ok(addresses) => {
+        info!("Resolved '{}' via '{}': {:?}", self.hostname(), dns_server, addresses);
+        self.set_bootstrap_addresses(addresses);
+      },
+      Err(err) => {
+        warn!("Unable to resolve '{}' via '{}' ({}): keeping {:?}", self.hostname(), dns_server, err, self.bootstrap_addresses());
+      }
+    }
+  }
+
   /// Builds an HTTP request combining the information of the `DoHProvider` with the given `DnsQuery`
   ///
   /// This is the important part of this type: taking a "standard" `DnsQuery` and turning it into
   /// an actual HTTP request that we can send to the give `DoHProvider` and, hopefully, get
   /// a DNS resolution back.
   ///
+  /// Depending on the `DoHProvider::request_mode()`, the DNS query is carried either by the URI
+  /// or by the body of the request.
+  ///
   /// # Parameters
   ///
   /// * `dns_query` - `DnsQuery` that we need to turn into an HTTP request towards the Provider
-  fn build_http_request(&self, dns_query: &DnsQuery) -> Result<Request<()>>;
+  fn build_http_request(&self, dns_query: &DnsQuery) -> Result<Request<Vec<u8>>>;
 
   /// Available Providers of DoH services
   ///
@@ -53,8 +98,71 @@ pub trait DoHProvider: Downcast {
 
 impl fmt::Debug for DoHProvider {
   fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
-    write!(fmtr, "DoHProvider: {{ protocol: {}, id: {} }}", self.protocol(), self.id())
+    write!(fmtr, "DoHProvider: {{ protocol: {}, request_mode: {}, id: {}, bootstrap: {:?} }}", self.protocol(), self.request_mode(), self.id(), self.bootstrap_addresses())
   }
 }
 
-impl_downcast!(DoHProvider);
\ No newline at end of file
+impl_downcast!(DoHProvider);
+
+/// Splits the URL of a DNS-over-HTTPS Provider into the parts needed to build HTTP requests to it
+///
+/// The URL must be absolute (ex. "https://doh.example.com/dns-query"): if it has no path, `/` is used.
+///
+/// # Parameters
+///
+/// * `raw_url` - `&str` representing the URL of a Provider
+pub fn parse_provider_url(raw_url: &str) -> std::result::Result<(Scheme, Authority, PathAndQuery), DoHProviderUrlError> {
+  let uri: Uri = raw_url.parse().map_err(|err| DoHProviderUrlError::new(raw_url, &format!("{}", err)))?;
+  let parts = uri.into_parts();
+
+  match (parts.scheme, parts.authority) {
+    (Some(scheme), Some(authority)) => {
+      let path_query = parts.path_and_query.unwrap_or_else(|| PathAndQuery::from_static("/"));
+      Ok((scheme, authority, path_query))
+    },
+    _ => Err(DoHProviderUrlError::new(raw_url, "scheme and host are required")),
+  }
+}
+
+/// Error that happens when parsing the URL of a `DoHProvider` fails
+#[derive(Debug, Clone)]
+pub struct DoHProviderUrlError {
+  url: String,
+  reason: String,
+}
+
+impl DoHProviderUrlError {
+  fn new(url: &str, reason: &str) -> Self {
+    Self {
+      url: url.to_string(),
+      reason: reason.to_string(),
+    }
+  }
+}
+
+impl fmt::Display for DoHProviderUrlError {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmtr, "Invalid DNS-over-HTTPS Provider URL '{}': {}", self.url, self.reason)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn should_parse_provider_url() {
+    let (scheme, authority, path_query) = parse_provider_url("https://doh.internal.example:8443/dns-query?ct").unwrap();
+    assert_eq!(scheme.as_str(), "https");
+    assert_eq!(authority.host(), "doh.internal.example");
+    assert_eq!(authority.port_part().unwrap().as_u16(), 8443);
+    assert_eq!(path_query.as_str(), "/dns-query?ct");
+
+    let (_, _, path_query) = parse_provider_url("https://doh.internal.example").unwrap();
+    assert_eq!(path_query.as_str(), "/");
+
+    assert!(parse_provider_url("/dns-query").is_err());
+    assert!(parse_provider_url("doh.internal.example").is_err());
+    assert!(parse_provider_url("https://bad host/").is_err());
+  }
+}
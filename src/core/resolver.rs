@@ -1,57 +1,206 @@
 //! Trait definition for resolver of `DnsMessage` requests via DNS-over-HTTPS
 
-use crate::dns::protocol::{DnsMessage, DnsMessageType};
+use super::response::DoHResponse;
+use crate::dns::protocol::{DnsMessage, DnsMessageType, DnsOpCode, DnsQuery, DnsResponseCode};
+use crate::net::http::{self as net_http, HttpResponseError};
 
-use http::Error as HttpError;
+use log::*;
+use http::{Error as HttpError, Request as HttpRequest};
 use curl::Error as CurlError;
 use downcast_rs::*;
 use serde_json::Error as SerdeJsonError;
+use threadpool::ThreadPool;
+use crossbeam_channel::bounded;
 
-use std::{fmt, convert};
+use std::{fmt, convert, error::Error, sync::Arc};
 
 type Result<T> = std::result::Result<T, DoHResolutionError>;
 
+/// Kind of a `DoHResolutionError`: what went wrong, so that callers can act accordingly
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DoHResolutionErrorKind {
+  /// The query can't be resolved as it is (ex. not of type `Query`, or an unsupported op code)
+  InvalidQuery,
+  /// The upstream couldn't be reached, or the connection failed (ex. connection refused)
+  Transport,
+  /// The upstream didn't respond in time
+  Timeout,
+  /// The TLS handshake with the upstream failed (ex. invalid certificate)
+  Tls,
+  /// The upstream responded with an unexpected HTTP status
+  HttpStatus(u16),
+  /// The response of the upstream couldn't be decoded (ex. invalid JSON or DNS message)
+  Decode,
+  /// The upstream responded with an error response code (ex. `DnsResponseCode::ServFail`)
+  Upstream(DnsResponseCode),
+  /// Something went wrong on our side (ex. a HTTP request that couldn't be built)
+  Internal,
+}
+
+impl DoHResolutionErrorKind {
+
+  /// Short name of the kind, without parameters (ex. to label metrics)
+  pub fn name(self) -> &'static str {
+    match self {
+      DoHResolutionErrorKind::InvalidQuery => "invalid_query",
+      DoHResolutionErrorKind::Transport => "transport",
+      DoHResolutionErrorKind::Timeout => "timeout",
+      DoHResolutionErrorKind::Tls => "tls",
+      DoHResolutionErrorKind::HttpStatus(_) => "http_status",
+      DoHResolutionErrorKind::Decode => "decode",
+      DoHResolutionErrorKind::Upstream(_) => "upstream",
+      DoHResolutionErrorKind::Internal => "internal",
+    }
+  }
+
+  /// `true` if resolving the same query again (via the same or another upstream) may succeed
+  ///
+  /// Errors caused by the query itself, or by a bug on our side, are not retryable; neither are
+  /// HTTP client errors (`4xx`), except for timeouts (`408`) and rate limiting (`429`).
+  pub fn is_retryable(self) -> bool {
+    match self {
+      DoHResolutionErrorKind::InvalidQuery | DoHResolutionErrorKind::Internal => false,
+      DoHResolutionErrorKind::HttpStatus(status) => !(400..500).contains(&status) || status == 408 || status == 429,
+      _ => true,
+    }
+  }
+
+  /// `true` if the error is the upstream's fault (i.e. it should count against its health)
+  pub fn is_upstream_failure(self) -> bool {
+    match self {
+      DoHResolutionErrorKind::InvalidQuery | DoHResolutionErrorKind::Internal => false,
+      _ => true,
+    }
+  }
+
+}
+
+impl fmt::Display for DoHResolutionErrorKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DoHResolutionErrorKind::HttpStatus(status) => write!(f, "{} {}", self.name(), status),
+      DoHResolutionErrorKind::Upstream(response_code) => write!(f, "{} {:?}", self.name(), response_code),
+      _ => write!(f, "{}", self.name()),
+    }
+  }
+}
+
 /// A type of `Error` emitted by `Resolver`
 ///
-/// It contains a description and an optional `HttpError` that might have caused it
+/// It contains the kind of error, a description and the `DnsResponseCode` that should be returned
+/// to the client. It can also carry the error that caused it, and the identifier of the provider
+/// (or upstream) that was used.
 #[derive(Debug, Clone)]
 pub struct DoHResolutionError {
+  kind: DoHResolutionErrorKind,
   desc: String,
+  response_code: DnsResponseCode,
+  provider_id: Option<String>,
+  source: Option<Arc<Error + Send + Sync>>,
 }
 
 impl DoHResolutionError {
-  pub fn new(desc: String) -> DoHResolutionError {
-    DoHResolutionError { desc }
+  /// Constructor for an error that should be returned to the client as `DnsResponseCode::ServFail`
+  pub fn new(kind: DoHResolutionErrorKind, desc: String) -> DoHResolutionError {
+    DoHResolutionError::with_response_code(kind, desc, DnsResponseCode::ServFail)
+  }
+
+  /// Constructor for an error that should be returned to the client with the given `DnsResponseCode`
+  pub fn with_response_code(kind: DoHResolutionErrorKind, desc: String, response_code: DnsResponseCode) -> DoHResolutionError {
+    DoHResolutionError { kind, desc, response_code, provider_id: None, source: None }
+  }
+
+  /// Sets the error that caused this one
+  pub fn with_source<E: Error + Send + Sync + 'static>(mut self, source: E) -> DoHResolutionError {
+    self.source = Some(Arc::new(source));
+    self
+  }
+
+  /// Sets the identifier of the provider (or upstream) that was used, unless already set
+  pub fn with_provider_id(mut self, provider_id: &str) -> DoHResolutionError {
+    if self.provider_id.is_none() {
+      self.provider_id = Some(provider_id.to_string());
+    }
+    self
+  }
+
+  /// The kind of error
+  pub fn kind(&self) -> DoHResolutionErrorKind {
+    self.kind
+  }
+
+  /// The `DnsResponseCode` to return to the client (ex. `DnsResponseCode::ServFail`)
+  pub fn response_code(&self) -> DnsResponseCode {
+    self.response_code
+  }
+
+  /// Identifier of the provider (or upstream) that was used, if known
+  pub fn provider_id(&self) -> Option<&str> {
+    self.provider_id.as_deref()
+  }
+
+  /// `true` if resolving the same query again may succeed (see `DoHResolutionErrorKind::is_retryable()`)
+  pub fn is_retryable(&self) -> bool {
+    self.kind.is_retryable()
   }
 }
 
 impl fmt::Display for DoHResolutionError {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "ResolutionError: {}", self.desc)
+    write!(f, "ResolutionError ({}", self.kind)?;
+    if let Some(provider_id) = &self.provider_id {
+      write!(f, ", via '{}'", provider_id)?;
+    }
+    write!(f, "): {}", self.desc)
+  }
+}
+
+impl Error for DoHResolutionError {
+  fn source(&self) -> Option<&(Error + 'static)> {
+    self.source.as_ref().map(|source| source.as_ref() as &(Error + 'static))
   }
 }
 
 impl convert::From<HttpError> for DoHResolutionError {
   fn from(http_error: HttpError) -> Self {
-    DoHResolutionError {
-      desc: format!("Failed to execute HTTP request (http): {}", http_error),
-    }
+    DoHResolutionError::new(DoHResolutionErrorKind::Internal, format!("Failed to build HTTP request (http): {}", http_error))
+      .with_source(http_error)
   }
 }
 
 impl convert::From<SerdeJsonError> for DoHResolutionError {
   fn from(serde_json_error: SerdeJsonError) -> Self {
-    DoHResolutionError {
-      desc: format!("Failed to parse JSON (serde_json): {}", serde_json_error),
-    }
+    DoHResolutionError::new(DoHResolutionErrorKind::Decode, format!("Failed to parse JSON (serde_json): {}", serde_json_error))
+      .with_source(serde_json_error)
   }
 }
 
 impl convert::From<CurlError> for DoHResolutionError {
   fn from(curl_error: CurlError) -> Self {
-    DoHResolutionError {
-      desc: format!("Failed to execute HTTP request (cURL): {}", curl_error),
-    }
+    let kind = if curl_error.is_operation_timedout() {
+      DoHResolutionErrorKind::Timeout
+    } else if curl_error.is_ssl_connect_error() || curl_error.is_peer_failed_verification() || curl_error.is_ssl_certproblem()
+      || curl_error.is_ssl_cacert() || curl_error.is_ssl_cipher() || curl_error.is_ssl_issuer_error() {
+      DoHResolutionErrorKind::Tls
+    } else {
+      DoHResolutionErrorKind::Transport
+    };
+
+    DoHResolutionError::new(kind, format!("Failed to execute HTTP request (cURL): {}", curl_error))
+      .with_source(curl_error)
+  }
+}
+
+impl convert::From<HttpResponseError> for DoHResolutionError {
+  fn from(http_response_error: HttpResponseError) -> Self {
+    let kind = match http_response_error {
+      HttpResponseError::Curl(curl_error) => return DoHResolutionError::from(curl_error),
+      HttpResponseError::Redirect(status, _) | HttpResponseError::Status(status, _) => DoHResolutionErrorKind::HttpStatus(status.as_u16()),
+      HttpResponseError::ContentType(_) | HttpResponseError::TooLarge(_) => DoHResolutionErrorKind::Decode,
+    };
+
+    DoHResolutionError::new(kind, format!("Invalid HTTP response: {}", http_response_error))
+      .with_source(http_response_error)
   }
 }
 
@@ -71,8 +220,9 @@ pub trait DoHResolver: Downcast {
 
   /// Resolves a DNS Query and returns a DNS Response
   ///
-  /// It checks that the `DnsMessage` is of type `DnsMessageType::Query`: if not, it
-  /// throws an error of type `ResolutionError`.
+  /// It checks that the `DnsMessage` is of type `DnsMessageType::Query`, and that its op code
+  /// is `DnsOpCode::Query`: if not, it throws an error of type `ResolutionError`
+  /// (with response code `FormErr` or `NotImp` respectively).
   ///
   /// The actual resolution is then delegated to the specific implementation of
   /// `Resolver::resolve_message_query()`.
@@ -82,10 +232,12 @@ pub trait DoHResolver: Downcast {
   /// * `dns_message` - A `DnsMessage` that we assume is of type `DnsMessageType::Query`
   fn resolve(&self, dns_message: &DnsMessage) -> Result<DnsMessage> {
     // Before resolving, check the type is right
-    if dns_message.message_type() == DnsMessageType::Query {
-      self.resolve_query(dns_message)
+    if dns_message.message_type() != DnsMessageType::Query {
+      Err(DoHResolutionError::with_response_code(DoHResolutionErrorKind::InvalidQuery, "Invalid input: `DnsMessage` was not of type `Query`".into(), DnsResponseCode::FormErr))
+    } else if dns_message.op_code() != DnsOpCode::Query {
+      Err(DoHResolutionError::with_response_code(DoHResolutionErrorKind::InvalidQuery, format!("Unsupported op code: {:?}", dns_message.op_code()), DnsResponseCode::NotImp))
     } else {
-      Err(DoHResolutionError::new("Invalid input: `DnsMessage` was not of type `Query`".into()))
+      self.resolve_query(dns_message)
     }
   }
 
@@ -100,4 +252,77 @@ impl Clone for Box<DoHResolver + Send> {
   fn clone(&self) -> Self {
     self.box_clone()
   }
-}
\ No newline at end of file
+}
+
+/// Resolves a DNS Query by sending each of its `DnsQuery` in its own HTTP Request, in parallel
+///
+/// This is the HTTP round-trip shared by the DoH protocols: they only differ in how the
+/// HTTP Request is built, and how the body of the HTTP Response is parsed (i.e. the `DoHResponse`).
+/// Responses are applied to a DNS Response that has the "authoritative" bit on: they are always
+/// coming from the provider, not a cache.
+///
+/// # Parameters
+///
+/// * `pool` - Where to execute the HTTP Requests
+/// * `req_dns_msg` - The DNS Query to resolve
+/// * `build_http_request` - Builds the HTTP Request for one of the `DnsQuery` of `req_dns_msg`
+pub fn resolve_over_http<R, F>(pool: &ThreadPool, req_dns_msg: &DnsMessage, build_http_request: F) -> Result<DnsMessage>
+  where R: DoHResponse + fmt::Debug + Send + 'static, F: Fn(&DnsQuery) -> Result<HttpRequest<Vec<u8>>> {
+  // Begin preparing response DNS Message
+  let mut res_dns_msg = DnsMessage::new();
+  res_dns_msg.set_id(req_dns_msg.id());
+  res_dns_msg.set_op_code(req_dns_msg.op_code());
+  res_dns_msg.set_message_type(DnsMessageType::Response);
+  res_dns_msg.set_authoritative(true);
+
+  // Execute all queries in parallel
+  let queries_count = req_dns_msg.queries().len();
+  let (tx, rx) = bounded(queries_count);
+  for query in req_dns_msg.queries() {
+    let tx = tx.clone();
+    let req_http = build_http_request(query)?;
+
+    pool.execute(move || {
+      let res_doh = execute_http_request::<R>(&req_http);
+
+      trace!("DoH response: {:?}", res_doh);
+
+      // Delivery fails only if the resolution was already given up (another query failed)
+      if tx.send(res_doh).is_err() {
+        debug!("DoH HTTP Request completed after the resolution failed");
+      }
+    });
+  }
+  // Only the workers hold a sender now: if one dies, the results stop instead of hanging
+  drop(tx);
+
+  // Wait for all the parallel requests to return a `Result`, then apply them to the `DnsMessage` response:
+  // if any has failed, the resolution fails (so the client can be told, instead of receiving a partial response)
+  let mut results_count = 0;
+  for res_doh_result in rx.iter().take(queries_count) {
+    results_count += 1;
+    // TODO Provide the correct edns_client_subnet_prefix if present in `req_dns_msg`
+    if let Err(err) = res_doh_result.and_then(|res_doh| res_doh.apply(0, &mut res_dns_msg)) {
+      error!("A DoH HTTP Request failed: {}", err);
+      return Err(err);
+    }
+  }
+  if results_count < queries_count {
+    return Err(DoHResolutionError::new(DoHResolutionErrorKind::Internal, "A DoH HTTP Request was lost: its worker thread died".into()));
+  }
+
+  Ok(res_dns_msg)
+}
+
+/// Executes a (synchronous) HTTP Request and parses the body of its HTTP Response
+///
+/// # Parameters
+///
+/// * `req_http`: An `http::Request`, created by a `DoHProvider`
+fn execute_http_request<R: DoHResponse>(req_http: &HttpRequest<Vec<u8>>) -> Result<R> {
+  let res_curl_buf = net_http::execute_http_request(req_http)?;
+
+  trace!("Raw DoH response: {:?}", String::from_utf8_lossy(&res_curl_buf));
+
+  R::from_http_body(&res_curl_buf)
+}
@@ -1,18 +1,27 @@
 //! Trait definition for response to DNS-over-HTTPS requests
 
+use super::resolver::DoHResolutionError;
 use crate::dns::protocol::*;
 
-use std::{str::FromStr, string::ToString};
-
 /// Trait defining a _response_ to a DNS Message query
-pub trait DoHResponse: FromStr + ToString + Default {
+pub trait DoHResponse: Default {
+
+  /// Parses the body of the HTTP Response to a DNS-over-HTTPS request
+  ///
+  /// # Parameters
+  ///
+  /// * `body`: body of the HTTP Response
+  fn from_http_body(body: &[u8]) -> Result<Self, DoHResolutionError>;
 
   /// Apply the response to the given `DnsMessage`
   ///
+  /// It fails if the response carries data that can't be converted (ex. an invalid domain name):
+  /// the `DnsMessage` should then be discarded.
+  ///
   /// # Parameters
   ///
   /// * `req_edns_client_subnet_prefix_len`: length of EDNS Client Subnet used in the request
   /// * `res_dns_msg`: Response DNS Message
-  fn apply(&self, req_edns_client_subnet_prefix_len: u8, res_dns_msg: &mut DnsMessage);
+  fn apply(&self, req_edns_client_subnet_prefix_len: u8, res_dns_msg: &mut DnsMessage) -> Result<(), DoHResolutionError>;
 
 }
\ No newline at end of file
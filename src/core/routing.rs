@@ -0,0 +1,225 @@
+//! Routing of DNS queries to different resolvers, by domain suffix (a.k.a. "split DNS")
+
+use super::resolver::{DoHResolver, DoHResolutionError};
+use crate::dns::{protocol::*, client, resolver::DnsTransport};
+
+use log::*;
+
+use std::{fmt, str::FromStr, net::SocketAddr};
+
+const ROUTE_SEPARATOR: char = '=';
+const ROUTE_TARGET_PREFIX_UDP: &'static str = "udp:";
+const ROUTE_TARGET_PREFIX_TCP: &'static str = "tcp:";
+
+type Result<T> = std::result::Result<T, DoHResolutionError>;
+
+/// Where the queries of a route are sent to
+#[derive(Debug, Clone, PartialEq)]
+pub enum DoHRouteTarget {
+  /// A DNS-over-HTTPS Provider, built-in or user-defined (format: `PROVIDER`)
+  Provider(String),
+  /// A "plain" DNS server (format: `udp:IP[:PORT]`, `tcp:IP[:PORT]` or just `IP[:PORT]` for UDP)
+  Plain(SocketAddr, DnsTransport),
+}
+
+impl FromStr for DoHRouteTarget {
+  type Err = DoHRouteParseError;
+
+  fn from_str(raw_target: &str) -> std::result::Result<Self, Self::Err> {
+    let (raw_server, transport) = if let Some(raw_server) = raw_target.strip_prefix(ROUTE_TARGET_PREFIX_UDP) {
+      (raw_server, Some(DnsTransport::Udp))
+    } else if let Some(raw_server) = raw_target.strip_prefix(ROUTE_TARGET_PREFIX_TCP) {
+      (raw_server, Some(DnsTransport::Tcp))
+    } else {
+      (raw_target, None)
+    };
+
+    match (client::parse_server_address(raw_server), transport) {
+      (Ok(server), transport) => Ok(DoHRouteTarget::Plain(server, transport.unwrap_or(DnsTransport::Udp))),
+      (Err(_), None) if !raw_target.is_empty() => Ok(DoHRouteTarget::Provider(raw_target.to_string())),
+      _ => Err(DoHRouteParseError::new(raw_target)),
+    }
+  }
+}
+
+impl fmt::Display for DoHRouteTarget {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DoHRouteTarget::Provider(id) => write!(fmtr, "{}", id),
+      DoHRouteTarget::Plain(server, transport) => write!(fmtr, "{}:{}", transport, server),
+    }
+  }
+}
+
+/// A route: queries for the suffix (and its subdomains) are sent to the target
+///
+/// Format: `SUFFIX=TARGET` (ex. `corp.example=tcp:10.0.0.53`, `lan=192.168.1.1`, `example.org=quad9`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoHRoute {
+  pub suffix: DnsDomainName,
+  pub target: DoHRouteTarget,
+}
+
+impl FromStr for DoHRoute {
+  type Err = DoHRouteParseError;
+
+  fn from_str(raw_route: &str) -> std::result::Result<Self, Self::Err> {
+    let separator = raw_route.find(ROUTE_SEPARATOR).ok_or_else(|| DoHRouteParseError::new(raw_route))?;
+    let raw_suffix = raw_route[..separator].trim().trim_end_matches('.');
+    if raw_suffix.is_empty() {
+      return Err(DoHRouteParseError::new(raw_route));
+    }
+
+    let mut suffix = DnsDomainName::from_ascii(raw_suffix).map_err(|_| DoHRouteParseError::new(raw_route))?;
+    suffix.set_fqdn(true);
+
+    Ok(DoHRoute {
+      suffix,
+      target: raw_route[separator + 1..].trim().parse().map_err(|_| DoHRouteParseError::new(raw_route))?,
+    })
+  }
+}
+
+impl fmt::Display for DoHRoute {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmtr, "{}{}{}", self.suffix.to_ascii().trim_end_matches('.'), ROUTE_SEPARATOR, self.target)
+  }
+}
+
+/// Error that happens when parsing a `DoHRoute` (or its `DoHRouteTarget`) fails
+#[derive(Debug, Clone)]
+pub struct DoHRouteParseError {
+  token: String
+}
+
+impl DoHRouteParseError {
+  fn new(token: &str) -> Self {
+    Self {
+      token: token.to_string()
+    }
+  }
+}
+
+impl fmt::Display for DoHRouteParseError {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmtr, "Invalid Route (expected 'SUFFIX=PROVIDER', 'SUFFIX=udp:IP[:PORT]' or 'SUFFIX=tcp:IP[:PORT]'): {}", self.token)
+  }
+}
+
+/// Resolver that sends queries to a different resolver, depending on the suffix of the name queried
+///
+/// The route with the longest suffix matching the name wins; queries matching no route are sent
+/// to the default resolver. Messages with multiple queries are routed by the first one.
+#[derive(Clone)]
+pub struct DoHRoutingResolver {
+  default: Box<DoHResolver + Send>,
+  routes: Vec<(DnsDomainName, Box<DoHResolver + Send>)>,
+}
+
+impl DoHRoutingResolver {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  ///
+  /// * `default` - the `DoHResolver` for the queries that match no route
+  pub fn new(default: Box<DoHResolver + Send>) -> DoHRoutingResolver {
+    DoHRoutingResolver {
+      default,
+      routes: Vec::new(),
+    }
+  }
+
+  /// Adds a route
+  ///
+  /// # Parameters
+  ///
+  /// * `suffix` - the domain suffix to route (including the name itself)
+  /// * `resolver` - the `DoHResolver` to send the queries for the suffix to
+  pub fn add_route(&mut self, suffix: DnsDomainName, resolver: Box<DoHResolver + Send>) {
+    self.routes.push((suffix, resolver));
+  }
+
+  /// The resolver for the given name
+  fn route(&self, name: &DnsDomainName) -> &Box<DoHResolver + Send> {
+    self.routes.iter()
+      .filter(|(suffix, _)| suffix.zone_of(name))
+      .max_by_key(|(suffix, _)| suffix.num_labels())
+      .map(|(suffix, resolver)| {
+        debug!("Routing '{}' via route '{}'", name, suffix);
+        resolver
+      })
+      .unwrap_or(&self.default)
+  }
+
+}
+
+impl DoHResolver for DoHRoutingResolver {
+
+  fn resolve_query(&self, req_dns_msg: &DnsMessage) -> Result<DnsMessage> {
+    match req_dns_msg.queries().first() {
+      Some(query) => self.route(query.name()).resolve_query(req_dns_msg),
+      None => self.default.resolve_query(req_dns_msg),
+    }
+  }
+
+  fn box_clone(&self) -> Box<DoHResolver + Send> {
+    Box::new((*self).clone())
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::resolver::DoHResolutionErrorKind;
+  use crate::test_support::query_message;
+
+  /// Resolver that always fails, with its name as description (to tell which one was used)
+  #[derive(Debug, Clone)]
+  struct NamedResolver(&'static str);
+
+  impl DoHResolver for NamedResolver {
+    fn resolve_query(&self, _: &DnsMessage) -> Result<DnsMessage> {
+      Err(DoHResolutionError::new(DoHResolutionErrorKind::Transport, self.0.into()))
+    }
+
+    fn box_clone(&self) -> Box<DoHResolver + Send> {
+      Box::new((*self).clone())
+    }
+  }
+
+  fn resolved_by(resolver: &DoHRoutingResolver, name: &str) -> String {
+    resolver.resolve_query(&query_message(1, name, DnsRecordType::A)).unwrap_err().to_string()
+  }
+
+  #[test]
+  fn should_parse_route() {
+    let route = DoHRoute::from_str("Corp.Example.=tcp:10.0.0.53").unwrap();
+    assert_eq!(route.suffix, DnsDomainName::from_str("corp.example.").unwrap());
+    assert_eq!(route.target, DoHRouteTarget::Plain("10.0.0.53:53".parse().unwrap(), DnsTransport::Tcp));
+    assert_eq!(route.to_string(), "Corp.Example=tcp:10.0.0.53:53");
+
+    assert_eq!(DoHRoute::from_str("lan=192.168.1.1:5353").unwrap().target, DoHRouteTarget::Plain("192.168.1.1:5353".parse().unwrap(), DnsTransport::Udp));
+    assert_eq!(DoHRoute::from_str("example.org = quad9").unwrap().target, DoHRouteTarget::Provider("quad9".into()));
+    assert!(DoHRoute::from_str("corp.example=udp:intranet").is_err());
+    assert!(DoHRoute::from_str("corp.example=").is_err());
+    assert!(DoHRoute::from_str("=quad9").is_err());
+    assert!(DoHRoute::from_str("corp.example").is_err());
+  }
+
+  #[test]
+  fn should_route_by_longest_suffix() {
+    let mut resolver = DoHRoutingResolver::new(Box::new(NamedResolver("default")));
+    resolver.add_route(DoHRoute::from_str("corp.example=10.0.0.53").unwrap().suffix, Box::new(NamedResolver("corp")));
+    resolver.add_route(DoHRoute::from_str("lab.corp.example=10.1.0.53").unwrap().suffix, Box::new(NamedResolver("lab")));
+    resolver.add_route(DoHRoute::from_str("10.in-addr.arpa=10.0.0.53").unwrap().suffix, Box::new(NamedResolver("reverse")));
+
+    assert!(resolved_by(&resolver, "corp.example.").contains("corp"));
+    assert!(resolved_by(&resolver, "intranet.CORP.example.").contains("corp"));
+    assert!(resolved_by(&resolver, "host.lab.corp.example.").contains("lab"));
+    assert!(resolved_by(&resolver, "1.0.0.10.in-addr.arpa.").contains("reverse"));
+    assert!(resolved_by(&resolver, "notcorp.example.").contains("default"));
+    assert!(resolved_by(&resolver, "example.com.").contains("default"));
+  }
+}
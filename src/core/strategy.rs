@@ -0,0 +1,92 @@
+//! Enum of possible strategies to spread DNS queries across upstream providers
+
+use std::{fmt, convert::From, str::FromStr};
+
+const STRATEGY_NAME_FAILOVER: &'static str = "failover";
+const STRATEGY_NAME_ROUND_ROBIN: &'static str = "round-robin";
+const STRATEGY_NAME_WEIGHTED: &'static str = "weighted";
+const STRATEGY_NAME_FASTEST: &'static str = "fastest";
+const STRATEGY_NAME_RACE: &'static str = "race";
+
+/// Strategy used by a `DoHUpstreamGroup` to pick the upstream(s) to send a query to
+///
+/// Whatever the strategy, if an upstream fails the next one (in the order chosen by the strategy)
+/// is tried:
+///
+/// * `FAILOVER` - upstreams are always tried in order of preference
+/// * `ROUND_ROBIN` - each query starts from the next upstream: spreads queries evenly (privacy)
+/// * `WEIGHTED` - each query starts from an upstream picked at random, proportionally to its weight
+/// * `FASTEST` - upstreams are tried from the lowest observed latency (EWMA): minimizes latency
+/// * `RACE` - the first N upstreams are queried at once, and the first valid response is used (reliability)
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DoHUpstreamStrategy {
+  FAILOVER,
+  ROUND_ROBIN,
+  WEIGHTED,
+  FASTEST,
+  RACE
+}
+
+impl From<DoHUpstreamStrategy> for &'static str {
+  fn from(strategy: DoHUpstreamStrategy) -> Self {
+    match strategy {
+      DoHUpstreamStrategy::FAILOVER => STRATEGY_NAME_FAILOVER,
+      DoHUpstreamStrategy::ROUND_ROBIN => STRATEGY_NAME_ROUND_ROBIN,
+      DoHUpstreamStrategy::WEIGHTED => STRATEGY_NAME_WEIGHTED,
+      DoHUpstreamStrategy::FASTEST => STRATEGY_NAME_FASTEST,
+      DoHUpstreamStrategy::RACE => STRATEGY_NAME_RACE,
+    }
+  }
+}
+
+impl From<&str> for DoHUpstreamStrategy {
+  fn from(raw_strategy: &str) -> Self {
+    match DoHUpstreamStrategy::from_str(raw_strategy) {
+      Ok(strategy) => strategy,
+      Err(err) => panic!("{}", err)
+    }
+  }
+}
+
+impl FromStr for DoHUpstreamStrategy {
+  type Err = DoHUpstreamStrategyParseError;
+
+  fn from_str(raw_strategy: &str) -> Result<Self, Self::Err> {
+    match raw_strategy {
+      STRATEGY_NAME_FAILOVER => Ok(DoHUpstreamStrategy::FAILOVER),
+      STRATEGY_NAME_ROUND_ROBIN => Ok(DoHUpstreamStrategy::ROUND_ROBIN),
+      STRATEGY_NAME_WEIGHTED => Ok(DoHUpstreamStrategy::WEIGHTED),
+      STRATEGY_NAME_FASTEST => Ok(DoHUpstreamStrategy::FASTEST),
+      STRATEGY_NAME_RACE => Ok(DoHUpstreamStrategy::RACE),
+      _ => Err(DoHUpstreamStrategyParseError::new(raw_strategy))
+    }
+  }
+}
+
+impl fmt::Display for DoHUpstreamStrategy {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    let name: &'static str = (*self).into();
+    write!(fmtr, "{}", name)
+  }
+}
+
+/// Error that happens when parsing a `DoHUpstreamStrategy` fails
+#[derive(Debug, Clone)]
+pub struct DoHUpstreamStrategyParseError {
+  token: String
+}
+
+impl DoHUpstreamStrategyParseError {
+  fn new(token: &str) -> Self {
+    Self {
+      token: token.to_string()
+    }
+  }
+}
+
+impl fmt::Display for DoHUpstreamStrategyParseError {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmtr, "Invalid Upstream Strategy: {}", self.token)
+  }
+}
@@ -0,0 +1,717 @@
+//! Group of upstream `DoHResolver`, one per DNS-over-HTTPS provider, with failover
+//!
+//! How queries are spread across the upstreams is decided by a `DoHUpstreamStrategy`.
+
+use super::{resolver::{DoHResolver, DoHResolutionError, DoHResolutionErrorKind}, strategy::DoHUpstreamStrategy, context};
+use crate::dns::protocol::{DnsMessage, DnsResponseCode};
+use crate::dnstap::Dnstap;
+use crate::metrics;
+
+use log::*;
+use rand::{self, Rng};
+use threadpool::{ThreadPool, Builder as ThreadPoolBuilder};
+use num_cpus;
+use crossbeam_channel::bounded;
+
+use std::{cmp, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, time::{Duration, Instant, SystemTime}};
+
+const UPSTREAM_BACKOFF_MIN_SEC: u64 = 5;
+const UPSTREAM_BACKOFF_MAX_SEC: u64 = 300;
+const UPSTREAM_LATENCY_EWMA_ALPHA: f64 = 0.3;
+const UPSTREAM_WEIGHT_DEFAULT: u32 = 1;
+const DOH_UPSTREAM_RACE_THREAD_NAME: &'static str = "doh_upstream_race_thread";
+
+type Result<T> = std::result::Result<T, DoHResolutionError>;
+
+/// Availability and observed latency of an upstream
+///
+/// After a failure, an upstream is taken out of rotation for a period of time that grows
+/// (exponentially) with the consecutive failures. Once that period is over, it's tried again:
+/// a success puts it back in rotation.
+///
+/// The latency is an exponentially weighted moving average (EWMA) of the successful resolutions.
+#[derive(Debug, Clone, Default)]
+pub struct DoHUpstreamState {
+  consecutive_failures: u32,
+  down_until: Option<Instant>,
+  latency: Option<Duration>,
+}
+
+impl DoHUpstreamState {
+
+  /// Returns `true` if the upstream is in rotation at the given instant
+  pub fn is_available(&self, now: Instant) -> bool {
+    match self.down_until {
+      Some(down_until) => now >= down_until,
+      None => true,
+    }
+  }
+
+  /// Returns `true` if the last resolution (or probe) was successful
+  pub fn is_up(&self) -> bool {
+    self.consecutive_failures == 0
+  }
+
+  /// Number of consecutive failures since the last success
+  pub fn consecutive_failures(&self) -> u32 {
+    self.consecutive_failures
+  }
+
+  /// Observed latency (EWMA): `None` until the first success
+  pub fn latency(&self) -> Option<Duration> {
+    self.latency
+  }
+
+  /// Records a success, and how long it took: the upstream is (back) in rotation
+  pub fn record_success(&mut self, latency: Duration) {
+    self.consecutive_failures = 0;
+    self.down_until = None;
+    self.latency = Some(match self.latency {
+      Some(ewma) => Duration::from_secs_f64(
+        UPSTREAM_LATENCY_EWMA_ALPHA * latency.as_secs_f64() + (1.0 - UPSTREAM_LATENCY_EWMA_ALPHA) * ewma.as_secs_f64()
+      ),
+      None => latency,
+    });
+  }
+
+  /// Records a failure: the upstream is out of rotation for a while
+  pub fn record_failure(&mut self, now: Instant) {
+    self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+
+    let backoff_exp = cmp::min(self.consecutive_failures - 1, 16);
+    let backoff_sec = cmp::min(UPSTREAM_BACKOFF_MIN_SEC << backoff_exp, UPSTREAM_BACKOFF_MAX_SEC);
+    self.down_until = Some(now + Duration::from_secs(backoff_sec));
+  }
+
+}
+
+/// An upstream: a `DoHResolver` bound to a specific provider, its weight and its state
+///
+/// The state is shared by all the clones of this upstream.
+#[derive(Clone)]
+pub struct DoHUpstream {
+  id: String,
+  resolver: Box<DoHResolver + Send>,
+  weight: u32,
+  state: Arc<Mutex<DoHUpstreamState>>,
+}
+
+impl DoHUpstream {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  ///
+  /// * `id` - Identifier of the upstream (usually, the one of the provider)
+  /// * `resolver` - the `DoHResolver` bound to the provider
+  pub fn new(id: &str, resolver: Box<DoHResolver + Send>) -> DoHUpstream {
+    DoHUpstream::with_weight(id, resolver, UPSTREAM_WEIGHT_DEFAULT)
+  }
+
+  /// Constructor, with a weight (only used by `DoHUpstreamStrategy::WEIGHTED`)
+  ///
+  /// # Parameters
+  ///
+  /// * `id` - Identifier of the upstream (usually, the one of the provider)
+  /// * `resolver` - the `DoHResolver` bound to the provider
+  /// * `weight` - how often the upstream is picked, relative to the others (`0` means "only as a fallback")
+  pub fn with_weight(id: &str, resolver: Box<DoHResolver + Send>, weight: u32) -> DoHUpstream {
+    DoHUpstream {
+      id: id.to_string(),
+      resolver,
+      weight,
+      state: Arc::new(Mutex::new(DoHUpstreamState::default())),
+    }
+  }
+
+  /// Identifier of the upstream
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  /// Weight of the upstream
+  pub fn weight(&self) -> u32 {
+    self.weight
+  }
+
+  /// Shared state of the upstream
+  pub fn state(&self) -> Arc<Mutex<DoHUpstreamState>> {
+    self.state.clone()
+  }
+
+  fn is_available(&self, now: Instant) -> bool {
+    self.state.lock().unwrap().is_available(now)
+  }
+
+  fn latency(&self) -> Option<Duration> {
+    self.state.lock().unwrap().latency()
+  }
+
+  /// Resolves via this upstream, recording the outcome in its state
+  ///
+  /// A response with code `DnsResponseCode::ServFail` is returned as-is, but counts as a failure.
+  /// Errors that are not the upstream's fault (see `DoHResolutionErrorKind::is_upstream_failure()`)
+  /// don't count as failures. Errors carry the identifier of the upstream.
+  pub fn resolve_query(&self, req_dns_msg: &DnsMessage) -> Result<DnsMessage> {
+    let start = Instant::now();
+    let res = self.resolver.resolve_query(req_dns_msg)
+      .map_err(|err| err.with_provider_id(&self.id));
+
+    match &res {
+      Ok(res_dns_msg) if res_dns_msg.response_code() != DnsResponseCode::ServFail => {
+        self.state.lock().unwrap().record_success(start.elapsed());
+      },
+      Ok(res_dns_msg) => {
+        warn!("Upstream '{}' responded with {:?}", self.id, res_dns_msg.response_code());
+        metrics::inc_counter(&metrics::UPSTREAM_ERRORS, &[&self.id, DoHResolutionErrorKind::Upstream(res_dns_msg.response_code()).name()]);
+        self.state.lock().unwrap().record_failure(Instant::now());
+      },
+      Err(err) => {
+        warn!("Upstream '{}' failed: {}", self.id, err);
+        metrics::inc_counter(&metrics::UPSTREAM_ERRORS, &[&self.id, err.kind().name()]);
+        if err.kind().is_upstream_failure() {
+          self.state.lock().unwrap().record_failure(Instant::now());
+        }
+      },
+    }
+
+    res
+  }
+
+}
+
+/// Returns `true` if the result of an upstream resolution doesn't need to fail over
+fn is_valid(res: &Result<DnsMessage>) -> bool {
+  match res {
+    Ok(res_dns_msg) => res_dns_msg.response_code() != DnsResponseCode::ServFail,
+    Err(_) => false,
+  }
+}
+
+/// Resolves the query via the upstream, emitting the exchange to the `Dnstap` (if any)
+fn exchange(upstream: &DoHUpstream, dnstap: Option<&Dnstap>, req_dns_msg: &DnsMessage) -> Result<DnsMessage> {
+  let query_time = SystemTime::now();
+  if let Some(dnstap) = dnstap {
+    dnstap.forwarder_query(req_dns_msg, query_time);
+  }
+
+  let res = upstream.resolve_query(req_dns_msg);
+
+  if let (Some(dnstap), Ok(res_dns_msg)) = (dnstap, &res) {
+    dnstap.forwarder_response(res_dns_msg, query_time);
+  }
+
+  res
+}
+
+/// Resolver that delegates to a group of upstreams, failing over to the next one
+///
+/// The order upstreams are tried in is decided by the `DoHUpstreamStrategy`, skipping the ones
+/// out of rotation (unless all of them are). An upstream fails when its `DoHResolver` returns an
+/// error (ex. transport or HTTP error), or a response with code `DnsResponseCode::ServFail`. Errors
+/// that aren't retryable (see `DoHResolutionErrorKind::is_retryable()`) are returned right away.
+///
+/// If a fallback is set, it's tried only after all the upstreams have failed (ex. when DNS-over-HTTPS
+/// is blocked by a captive portal).
+///
+/// If a `Dnstap` is set, every exchange with an upstream is emitted as `FORWARDER_QUERY`/`FORWARDER_RESPONSE`.
+#[derive(Clone)]
+pub struct DoHUpstreamGroup {
+  upstreams: Vec<DoHUpstream>,
+  fallback: Option<DoHUpstream>,
+  strategy: DoHUpstreamStrategy,
+  race_count: usize,
+  next: Arc<AtomicUsize>,
+  pool: Option<ThreadPool>,
+  dnstap: Option<Dnstap>,
+}
+
+impl DoHUpstreamGroup {
+
+  /// Constructor, with strategy `DoHUpstreamStrategy::FAILOVER`
+  ///
+  /// # Parameters
+  ///
+  /// * `upstreams` - the upstreams, in order of preference
+  pub fn new(upstreams: Vec<DoHUpstream>) -> DoHUpstreamGroup {
+    DoHUpstreamGroup::with_strategy(upstreams, DoHUpstreamStrategy::FAILOVER, 0)
+  }
+
+  /// Constructor, with the given strategy
+  ///
+  /// # Parameters
+  ///
+  /// * `upstreams` - the upstreams, in order of preference
+  /// * `strategy` - how to spread the queries across the upstreams
+  /// * `race_count` - how many upstreams to query at once (only used by `DoHUpstreamStrategy::RACE`)
+  pub fn with_strategy(upstreams: Vec<DoHUpstream>, strategy: DoHUpstreamStrategy, race_count: usize) -> DoHUpstreamGroup {
+    let race_count = cmp::max(race_count, 1);
+
+    let pool = match strategy {
+      DoHUpstreamStrategy::RACE => Some(ThreadPoolBuilder::new()
+        .num_threads(num_cpus::get() * race_count)
+        .thread_name(DOH_UPSTREAM_RACE_THREAD_NAME.into())
+        .build()),
+      _ => None,
+    };
+
+    DoHUpstreamGroup {
+      upstreams,
+      fallback: None,
+      strategy,
+      race_count,
+      next: Arc::new(AtomicUsize::new(0)),
+      pool,
+      dnstap: None,
+    }
+  }
+
+  /// Sets the upstream of last resort, tried only after all the others have failed
+  pub fn set_fallback(&mut self, fallback: DoHUpstream) {
+    self.fallback = Some(fallback);
+  }
+
+  /// Emits every exchange with the upstreams to the given `Dnstap`
+  pub fn set_dnstap(&mut self, dnstap: Dnstap) {
+    self.dnstap = Some(dnstap);
+  }
+
+  /// The upstreams, in order of preference
+  pub fn upstreams(&self) -> &[DoHUpstream] {
+    &self.upstreams
+  }
+
+  /// Orders the upstreams to try, according to the strategy
+  ///
+  /// Only upstreams in rotation are returned: if none is, all of them are.
+  fn candidates(&self) -> Vec<&DoHUpstream> {
+    let now = Instant::now();
+    let mut candidates: Vec<&DoHUpstream> = self.upstreams.iter().filter(|upstream| upstream.is_available(now)).collect();
+    if candidates.is_empty() {
+      warn!("No upstream available: trying all of them");
+      candidates = self.upstreams.iter().collect();
+    }
+
+    if candidates.len() > 1 {
+      match self.strategy {
+        DoHUpstreamStrategy::FAILOVER | DoHUpstreamStrategy::RACE => {},
+        DoHUpstreamStrategy::ROUND_ROBIN => {
+          let first = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+          candidates.rotate_left(first);
+        },
+        DoHUpstreamStrategy::WEIGHTED => {
+          let total_weight: u64 = candidates.iter().map(|upstream| u64::from(upstream.weight())).sum();
+          if total_weight > 0 {
+            let mut pick = rand::thread_rng().gen_range(0, total_weight);
+            let first = candidates.iter()
+              .position(|upstream| {
+                let weight = u64::from(upstream.weight());
+                if pick < weight { true } else { pick -= weight; false }
+              })
+              .unwrap_or(0);
+            let upstream = candidates.remove(first);
+            candidates.insert(0, upstream);
+          }
+        },
+        DoHUpstreamStrategy::FASTEST => {
+          // Upstreams without an observed latency come first, so they get one
+          candidates.sort_by_key(|upstream| upstream.latency().unwrap_or_default());
+        },
+      }
+    }
+
+    candidates
+  }
+
+  /// Queries the given upstreams all at once, and returns the first valid result (if any), with the upstream that provided it
+  ///
+  /// If none is valid, the last result received is returned. Racers whose worker thread died
+  /// return no result: if none returned one, `None` is returned.
+  fn race(&self, pool: &ThreadPool, racers: &[&DoHUpstream], req_dns_msg: &DnsMessage) -> Option<(String, Result<DnsMessage>)> {
+    let (tx, rx) = bounded(racers.len());
+    for racer in racers {
+      let tx = tx.clone();
+      let racer = (*racer).clone();
+      let dnstap = self.dnstap.clone();
+      let req_dns_msg = req_dns_msg.clone();
+
+      pool.execute(move || {
+        // The receiver is gone once a valid result was received: the others are ignored
+        let _ = tx.send((racer.id().to_string(), exchange(&racer, dnstap.as_ref(), &req_dns_msg)));
+      });
+    }
+    // Only the racers hold a sender now: if one dies, the results stop instead of hanging
+    drop(tx);
+
+    let mut last_res = None;
+    let mut results_count = 0;
+    for (id, res) in rx.iter().take(racers.len()) {
+      if is_valid(&res) {
+        return Some((id, res));
+      }
+      results_count += 1;
+      last_res = Some((id, res));
+    }
+    if results_count < racers.len() {
+      error!("{} racing upstream(s) lost: their worker thread died", racers.len() - results_count);
+    }
+
+    last_res
+  }
+
+}
+
+impl DoHResolver for DoHUpstreamGroup {
+
+  fn resolve_query(&self, req_dns_msg: &DnsMessage) -> Result<DnsMessage> {
+    let candidates = self.candidates();
+
+    // When racing, the first upstreams are queried at once: the others are tried in order after that
+    let (racers, others) = match &self.pool {
+      Some(pool) if candidates.len() > 1 => {
+        let race_count = cmp::min(self.race_count, candidates.len());
+        (Some((pool, &candidates[..race_count])), &candidates[race_count..])
+      },
+      _ => (None, &candidates[..]),
+    };
+
+    let mut last_servfail: Option<(String, DnsMessage)> = None;
+    let mut last_err: Option<DoHResolutionError> = None;
+    let fallback = self.fallback.iter().map(|fallback| {
+      warn!("All upstreams failed: falling back to '{}'", fallback.id());
+      (fallback.id().to_string(), exchange(fallback, self.dnstap.as_ref(), req_dns_msg))
+    });
+    let results = racers
+      .and_then(|(pool, racers)| self.race(pool, racers, req_dns_msg))
+      .into_iter()
+      .chain(others.iter().map(|upstream| (upstream.id().to_string(), exchange(upstream, self.dnstap.as_ref(), req_dns_msg))))
+      .chain(fallback);
+    for (id, res) in results {
+      if is_valid(&res) {
+        context::set_upstream(&id);
+        return res;
+      }
+
+      match res {
+        Ok(res_dns_msg) => last_servfail = Some((id, res_dns_msg)),
+        // Resolving the query again can't succeed: no other upstream would do better
+        Err(err) => if !err.is_retryable() {
+          return Err(err);
+        } else {
+          last_err = Some(err);
+        },
+      }
+      debug!("Failing over to the next upstream (if any)");
+    }
+
+    // All upstreams failed: a SERVFAIL response is still a response
+    match (last_servfail, last_err) {
+      (Some((id, res_dns_msg)), _) => {
+        context::set_upstream(&id);
+        Ok(res_dns_msg)
+      },
+      (None, Some(err)) => Err(err),
+      (None, None) if self.upstreams.is_empty() => Err(DoHResolutionError::new(DoHResolutionErrorKind::Internal, "No upstream configured".into())),
+      (None, None) => Err(DoHResolutionError::new(DoHResolutionErrorKind::Internal, "No upstream returned a result".into())),
+    }
+  }
+
+  fn box_clone(&self) -> Box<DoHResolver + Send> {
+    Box::new((*self).clone())
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::dns::protocol::*;
+  use crate::doh_wire::{provider::DoHWireProvider, resolver::DoHWireResolver};
+  use crate::net::http::HTTP_DOH_REQUEST_TIMEOUT_SEC;
+  use crate::test_support::{CountingResolver, query_message};
+  use http::HeaderMap;
+  use std::net::TcpListener;
+
+  /// Resolver that always fails with an error of the given kind
+  #[derive(Debug, Clone)]
+  struct RejectingResolver(DoHResolutionErrorKind);
+
+  impl DoHResolver for RejectingResolver {
+    fn resolve_query(&self, _: &DnsMessage) -> Result<DnsMessage> {
+      match self.0 {
+        DoHResolutionErrorKind::InvalidQuery => Err(DoHResolutionError::with_response_code(self.0, "Unsupported query".into(), DnsResponseCode::NotImp)),
+        kind => Err(DoHResolutionError::new(kind, "Rejected query".into())),
+      }
+    }
+
+    fn box_clone(&self) -> Box<DoHResolver + Send> {
+      Box::new((*self).clone())
+    }
+  }
+
+  /// Resolver that always panics (ex. a bug in a `DoHResolver`)
+  #[derive(Debug, Clone)]
+  struct PanickingResolver;
+
+  impl DoHResolver for PanickingResolver {
+    fn resolve_query(&self, _: &DnsMessage) -> Result<DnsMessage> {
+      panic!("Resolver bug")
+    }
+
+    fn box_clone(&self) -> Box<DoHResolver + Send> {
+      Box::new((*self).clone())
+    }
+  }
+
+  /// Builds an upstream with the given outcome: `None` means "error"
+  fn upstream(id: &str, outcome: Option<DnsResponseCode>) -> (DoHUpstream, Arc<AtomicUsize>) {
+    slow_upstream(id, outcome, Duration::from_millis(0), 1)
+  }
+
+  /// Builds an upstream with the given outcome, that takes the given time to resolve
+  fn slow_upstream(id: &str, outcome: Option<DnsResponseCode>, delay: Duration, weight: u32) -> (DoHUpstream, Arc<AtomicUsize>) {
+    let resolver = CountingResolver::new(outcome).with_delay(delay);
+    let count = resolver.count();
+
+    (DoHUpstream::with_weight(id, Box::new(resolver), weight), count)
+  }
+
+  #[test]
+  fn should_use_first_upstream() {
+    let (first, first_count) = upstream("first", Some(DnsResponseCode::NoError));
+    let (second, second_count) = upstream("second", Some(DnsResponseCode::NoError));
+    let group = DoHUpstreamGroup::new(vec![first, second]);
+
+    assert_eq!(group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap().response_code(), DnsResponseCode::NoError);
+    assert_eq!(first_count.load(Ordering::SeqCst), 1);
+    assert_eq!(second_count.load(Ordering::SeqCst), 0);
+  }
+
+  #[test]
+  fn should_fail_over_on_error_and_servfail() {
+    let (failing, failing_count) = upstream("failing", None);
+    let (servfail, servfail_count) = upstream("servfail", Some(DnsResponseCode::ServFail));
+    let (working, working_count) = upstream("working", Some(DnsResponseCode::NXDomain));
+    let group = DoHUpstreamGroup::new(vec![failing, servfail, working]);
+
+    context::reset();
+    assert_eq!(group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap().response_code(), DnsResponseCode::NXDomain);
+    assert_eq!(failing_count.load(Ordering::SeqCst), 1);
+    assert_eq!(servfail_count.load(Ordering::SeqCst), 1);
+    assert_eq!(working_count.load(Ordering::SeqCst), 1);
+    assert_eq!(context::take().upstream, Some("working".to_string()));
+
+    // Failed upstreams are out of rotation
+    assert_eq!(group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap().response_code(), DnsResponseCode::NXDomain);
+    assert_eq!(failing_count.load(Ordering::SeqCst), 1);
+    assert_eq!(servfail_count.load(Ordering::SeqCst), 1);
+    assert_eq!(working_count.load(Ordering::SeqCst), 2);
+  }
+
+  #[test]
+  fn should_fall_back_only_when_all_upstreams_fail() {
+    let (working, working_count) = upstream("working", Some(DnsResponseCode::NoError));
+    let (fallback, fallback_count) = upstream("fallback", Some(DnsResponseCode::NoError));
+    let mut group = DoHUpstreamGroup::new(vec![working]);
+    group.set_fallback(fallback.clone());
+
+    assert_eq!(group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap().response_code(), DnsResponseCode::NoError);
+    assert_eq!(working_count.load(Ordering::SeqCst), 1);
+    assert_eq!(fallback_count.load(Ordering::SeqCst), 0);
+
+    let (failing, failing_count) = upstream("failing", None);
+    let mut group = DoHUpstreamGroup::new(vec![failing]);
+    group.set_fallback(fallback);
+
+    context::reset();
+    assert_eq!(group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap().response_code(), DnsResponseCode::NoError);
+    assert_eq!(failing_count.load(Ordering::SeqCst), 1);
+    assert_eq!(fallback_count.load(Ordering::SeqCst), 1);
+    assert_eq!(context::take().upstream, Some("fallback".to_string()));
+  }
+
+  #[test]
+  fn should_try_all_upstreams_when_none_is_available() {
+    let (servfail, servfail_count) = upstream("servfail", Some(DnsResponseCode::ServFail));
+    let (failing, failing_count) = upstream("failing", None);
+    let group = DoHUpstreamGroup::new(vec![servfail, failing]);
+
+    assert_eq!(group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap().response_code(), DnsResponseCode::ServFail);
+    assert_eq!(group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap().response_code(), DnsResponseCode::ServFail);
+    assert_eq!(servfail_count.load(Ordering::SeqCst), 2);
+    assert_eq!(failing_count.load(Ordering::SeqCst), 2);
+
+    let (failing, _) = upstream("failing", None);
+    assert!(DoHUpstreamGroup::new(vec![failing]).resolve(&query_message(1, "example.com.", DnsRecordType::A)).is_err());
+    assert!(DoHUpstreamGroup::new(vec![]).resolve(&query_message(1, "example.com.", DnsRecordType::A)).is_err());
+  }
+
+  #[test]
+  fn should_fail_over_from_unresponsive_upstream_in_time() {
+    // A provider that accepts connections, but never responds
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+    let provider = DoHWireProvider::from_url("unresponsive", &url, HeaderMap::new(), Vec::new()).unwrap();
+    let unresponsive = DoHUpstream::new("unresponsive", Box::new(DoHWireResolver::new(provider)));
+    let (working, working_count) = upstream("working", Some(DnsResponseCode::NoError));
+    let group = DoHUpstreamGroup::new(vec![unresponsive, working]);
+
+    // Clients retry after a few seconds (ex. 5 for glibc): the response must still be there for them
+    let start = Instant::now();
+    assert_eq!(group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap().response_code(), DnsResponseCode::NoError);
+    assert!(start.elapsed() < Duration::from_secs(HTTP_DOH_REQUEST_TIMEOUT_SEC + 1));
+    assert_eq!(working_count.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn should_return_upstream_to_rotation() {
+    let now = Instant::now();
+    let mut state = DoHUpstreamState::default();
+    assert!(state.is_available(now));
+    assert!(state.is_up());
+
+    state.record_failure(now);
+    assert!(!state.is_available(now));
+    assert!(!state.is_up());
+    assert!(state.is_available(now + Duration::from_secs(UPSTREAM_BACKOFF_MIN_SEC)));
+
+    // Backoff grows with consecutive failures, up to a maximum
+    state.record_failure(now);
+    assert_eq!(state.consecutive_failures(), 2);
+    assert!(!state.is_available(now + Duration::from_secs(UPSTREAM_BACKOFF_MIN_SEC)));
+    for _ in 0..100 {
+      state.record_failure(now);
+    }
+    assert!(state.is_available(now + Duration::from_secs(UPSTREAM_BACKOFF_MAX_SEC)));
+
+    state.record_success(Duration::from_millis(100));
+    assert_eq!(state.consecutive_failures(), 0);
+    assert!(state.is_available(now));
+  }
+
+  #[test]
+  fn should_track_latency_ewma() {
+    let mut state = DoHUpstreamState::default();
+    assert_eq!(state.latency(), None);
+
+    state.record_success(Duration::from_millis(100));
+    assert_eq!(state.latency(), Some(Duration::from_millis(100)));
+
+    state.record_success(Duration::from_millis(200));
+    let latency = state.latency().unwrap();
+    assert!(latency > Duration::from_millis(129) && latency < Duration::from_millis(131));
+  }
+
+  #[test]
+  fn should_spread_queries_round_robin() {
+    let (first, first_count) = upstream("first", Some(DnsResponseCode::NoError));
+    let (second, second_count) = upstream("second", Some(DnsResponseCode::NoError));
+    let (third, third_count) = upstream("third", Some(DnsResponseCode::NoError));
+    let group = DoHUpstreamGroup::with_strategy(vec![first, second, third], DoHUpstreamStrategy::ROUND_ROBIN, 0);
+
+    for _ in 0..6 {
+      group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap();
+    }
+    assert_eq!(first_count.load(Ordering::SeqCst), 2);
+    assert_eq!(second_count.load(Ordering::SeqCst), 2);
+    assert_eq!(third_count.load(Ordering::SeqCst), 2);
+  }
+
+  #[test]
+  fn should_spread_queries_by_weight() {
+    let (heavy, heavy_count) = slow_upstream("heavy", Some(DnsResponseCode::NoError), Duration::from_millis(0), 1);
+    let (fallback, fallback_count) = slow_upstream("fallback", Some(DnsResponseCode::NoError), Duration::from_millis(0), 0);
+    let group = DoHUpstreamGroup::with_strategy(vec![fallback, heavy], DoHUpstreamStrategy::WEIGHTED, 0);
+
+    for _ in 0..10 {
+      group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap();
+    }
+    assert_eq!(heavy_count.load(Ordering::SeqCst), 10);
+    assert_eq!(fallback_count.load(Ordering::SeqCst), 0);
+  }
+
+  #[test]
+  fn should_prefer_fastest_upstream() {
+    let (slow, slow_count) = slow_upstream("slow", Some(DnsResponseCode::NoError), Duration::from_millis(20), 1);
+    let (fast, fast_count) = upstream("fast", Some(DnsResponseCode::NoError));
+    let group = DoHUpstreamGroup::with_strategy(vec![slow, fast], DoHUpstreamStrategy::FASTEST, 0);
+
+    // Until all latencies are known, the unknown ones come first
+    for _ in 0..5 {
+      group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap();
+    }
+    assert_eq!(slow_count.load(Ordering::SeqCst), 1);
+    assert_eq!(fast_count.load(Ordering::SeqCst), 4);
+  }
+
+  #[test]
+  fn should_race_upstreams() {
+    let (slow, slow_count) = slow_upstream("slow", Some(DnsResponseCode::NoError), Duration::from_millis(500), 1);
+    let (fast, fast_count) = upstream("fast", Some(DnsResponseCode::NXDomain));
+    let (spare, spare_count) = upstream("spare", Some(DnsResponseCode::NoError));
+    let group = DoHUpstreamGroup::with_strategy(vec![slow, fast, spare], DoHUpstreamStrategy::RACE, 2);
+
+    let start = Instant::now();
+    context::reset();
+    assert_eq!(group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap().response_code(), DnsResponseCode::NXDomain);
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert_eq!(context::take().upstream, Some("fast".to_string()));
+    assert_eq!(slow_count.load(Ordering::SeqCst), 1);
+    assert_eq!(fast_count.load(Ordering::SeqCst), 1);
+    assert_eq!(spare_count.load(Ordering::SeqCst), 0);
+
+    // If all the racers fail, the others are tried in order
+    let (failing, _) = upstream("failing", None);
+    let (servfail, _) = upstream("servfail", Some(DnsResponseCode::ServFail));
+    let (spare, spare_count) = upstream("spare", Some(DnsResponseCode::NoError));
+    let group = DoHUpstreamGroup::with_strategy(vec![failing, servfail, spare], DoHUpstreamStrategy::RACE, 2);
+
+    assert_eq!(group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap().response_code(), DnsResponseCode::NoError);
+    assert_eq!(spare_count.load(Ordering::SeqCst), 1);
+
+    // A racer that panics doesn't hang the resolution
+    let panicking = DoHUpstream::new("panicking", Box::new(PanickingResolver));
+    let (failing, _) = upstream("failing", None);
+    let (spare, spare_count) = upstream("spare", Some(DnsResponseCode::NoError));
+    let group = DoHUpstreamGroup::with_strategy(vec![panicking.clone(), failing, spare], DoHUpstreamStrategy::RACE, 2);
+
+    assert_eq!(group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap().response_code(), DnsResponseCode::NoError);
+    assert_eq!(spare_count.load(Ordering::SeqCst), 1);
+
+    let group = DoHUpstreamGroup::with_strategy(vec![panicking.clone(), panicking], DoHUpstreamStrategy::RACE, 2);
+    assert_eq!(group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap_err().kind(), DoHResolutionErrorKind::Internal);
+  }
+
+  #[test]
+  fn should_fail_with_typed_errors() {
+    let (failing, _) = upstream("failing", None);
+    let group = DoHUpstreamGroup::new(vec![failing]);
+
+    let err = group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap_err();
+    assert_eq!(err.kind(), DoHResolutionErrorKind::Transport);
+    assert_eq!(err.provider_id(), Some("failing"));
+    assert!(err.is_retryable());
+
+    // An invalid query doesn't fail over, and doesn't count against the upstream
+    let rejecting = DoHUpstream::new("rejecting", Box::new(RejectingResolver(DoHResolutionErrorKind::InvalidQuery)));
+    let (working, working_count) = upstream("working", Some(DnsResponseCode::NoError));
+    let group = DoHUpstreamGroup::new(vec![rejecting.clone(), working]);
+
+    let err = group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap_err();
+    assert_eq!(err.kind(), DoHResolutionErrorKind::InvalidQuery);
+    assert_eq!(err.response_code(), DnsResponseCode::NotImp);
+    assert!(!err.is_retryable());
+    assert_eq!(working_count.load(Ordering::SeqCst), 0);
+    assert!(rejecting.state().lock().unwrap().is_up());
+
+    // Neither does an internal error
+    let broken = DoHUpstream::new("broken", Box::new(RejectingResolver(DoHResolutionErrorKind::Internal)));
+    let (working, working_count) = upstream("working", Some(DnsResponseCode::NoError));
+    let group = DoHUpstreamGroup::new(vec![broken, working]);
+
+    let err = group.resolve(&query_message(1, "example.com.", DnsRecordType::A)).unwrap_err();
+    assert_eq!(err.kind(), DoHResolutionErrorKind::Internal);
+    assert!(!err.is_retryable());
+    assert_eq!(working_count.load(Ordering::SeqCst), 0);
+  }
+}
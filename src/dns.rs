@@ -3,4 +3,6 @@
 //! This is mostly based on re-exporting [trust-dns-proto](https://crates.io/crates/trust-dns-proto)
 //! crate types and add some utility functions.
 
-pub mod protocol;
\ No newline at end of file
+pub mod protocol;
+pub mod client;
+pub mod resolver;
\ No newline at end of file
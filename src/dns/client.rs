@@ -0,0 +1,246 @@
+//! Minimal client for "plain" DNS (i.e. not over HTTPS), used where DNS-over-HTTPS can't be used
+//!
+//! For example, to resolve the hostname of a DNS-over-HTTPS provider at launch.
+
+use super::protocol::*;
+
+use log::*;
+use rand;
+
+use std::{
+  io::{self, Read, Write},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket, TcpStream, AddrParseError},
+  time::{Duration, Instant},
+  str::FromStr,
+};
+
+/// Port where "plain" DNS servers listen, unless otherwise specified
+pub const DNS_DEFAULT_PORT: u16 = 53;
+
+const DNS_UDP_MESSAGE_MAX_SIZE: usize = 4096;
+
+/// Parses the address of a DNS server, in the format `IP[:PORT]`
+///
+/// If the port is omitted, `DNS_DEFAULT_PORT` is used. IPv6 addresses with a port
+/// must be enclosed in square brackets (ex. "[2620:fe::fe]:53").
+///
+/// # Parameters
+///
+/// * `raw_server` - `&str` representing the address (ex. "9.9.9.9" or "9.9.9.9:5353")
+pub fn parse_server_address(raw_server: &str) -> Result<SocketAddr, AddrParseError> {
+  raw_server.parse::<SocketAddr>()
+    .or_else(|_| raw_server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, DNS_DEFAULT_PORT)))
+}
+
+/// Sends a DNS query over UDP and waits for the matching response
+///
+/// Responses that don't match the ID and the questions of the query (or that can't be parsed) are ignored.
+///
+/// # Parameters
+///
+/// * `server` - Address of the DNS server to query
+/// * `dns_query` - `DnsMessage` of type `DnsMessageType::Query`
+/// * `timeout` - How long to wait for a response, overall
+pub fn query_udp(server: &SocketAddr, dns_query: &DnsMessage, timeout: Duration) -> Result<DnsMessage, DnsProtoError> {
+  let deadline = Instant::now() + timeout;
+
+  // Bind to "any" address, of the same family of the server
+  let local_addr: SocketAddr = match server {
+    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+  };
+  let socket = UdpSocket::bind(local_addr)?;
+
+  socket.send_to(&dns_message_to_bytes(dns_query)?, server)?;
+
+  let mut buf: [u8; DNS_UDP_MESSAGE_MAX_SIZE] = [0; DNS_UDP_MESSAGE_MAX_SIZE];
+  loop {
+    // Ignored messages must not extend the wait: only what's left until the deadline is waited for
+    socket.set_read_timeout(Some(remaining(deadline)?))?;
+    let (amount, src) = socket.recv_from(&mut buf).map_err(timed_out)?;
+    if src != *server {
+      warn!("Ignoring DNS message from unexpected source '{}'", src);
+      continue;
+    }
+
+    let dns_response = match dns_message_from_bytes(&buf[..amount]) {
+      Ok(dns_response) => dns_response,
+      Err(err) => {
+        warn!("Ignoring invalid DNS message from '{}': {}", src, err);
+        continue;
+      }
+    };
+    if is_response_to(&dns_response, dns_query) {
+      return Ok(dns_response);
+    }
+
+    warn!("Ignoring unexpected DNS message from '{}': id={} type={:?}", src, dns_response.id(), dns_response.message_type());
+  }
+}
+
+/// Sends a DNS query over TCP and waits for the matching response
+///
+/// Messages are prefixed by their length, as per [RFC 1035](https://tools.ietf.org/html/rfc1035#section-4.2.2).
+/// Responses that don't match the ID and the questions of the query (or that can't be parsed) are ignored.
+///
+/// # Parameters
+///
+/// * `server` - Address of the DNS server to query
+/// * `dns_query` - `DnsMessage` of type `DnsMessageType::Query`
+/// * `timeout` - How long to wait to connect and then for a response, overall
+pub fn query_tcp(server: &SocketAddr, dns_query: &DnsMessage, timeout: Duration) -> Result<DnsMessage, DnsProtoError> {
+  let deadline = Instant::now() + timeout;
+
+  let mut stream = TcpStream::connect_timeout(server, timeout)?;
+  stream.set_write_timeout(Some(remaining(deadline)?))?;
+
+  let raw_query = dns_message_to_bytes(dns_query)?;
+  let mut buf = Vec::with_capacity(raw_query.len() + 2);
+  buf.extend_from_slice(&(raw_query.len() as u16).to_be_bytes());
+  buf.extend_from_slice(&raw_query);
+  stream.write_all(&buf)?;
+
+  loop {
+    // Ignored messages must not extend the wait: only what's left until the deadline is waited for
+    let mut len_buf: [u8; 2] = [0; 2];
+    stream.set_read_timeout(Some(remaining(deadline)?))?;
+    stream.read_exact(&mut len_buf).map_err(timed_out)?;
+    let mut buf = vec![0; u16::from_be_bytes(len_buf) as usize];
+    stream.set_read_timeout(Some(remaining(deadline)?))?;
+    stream.read_exact(&mut buf).map_err(timed_out)?;
+
+    let dns_response = match dns_message_from_bytes(&buf) {
+      Ok(dns_response) => dns_response,
+      Err(err) => {
+        warn!("Ignoring invalid DNS message from '{}': {}", server, err);
+        continue;
+      }
+    };
+    if is_response_to(&dns_response, dns_query) {
+      return Ok(dns_response);
+    }
+
+    warn!("Ignoring unexpected DNS message from '{}': id={} type={:?}", server, dns_response.id(), dns_response.message_type());
+  }
+}
+
+/// Looks up the IPv4 and IPv6 addresses of the given hostname
+///
+/// # Parameters
+///
+/// * `server` - Address of the DNS server to query
+/// * `hostname` - Name to look up (ex. "cloudflare-dns.com")
+/// * `timeout` - How long to wait for each response
+pub fn lookup_ip(server: &SocketAddr, hostname: &str, timeout: Duration) -> Result<Vec<IpAddr>, DnsProtoError> {
+  let name = DnsDomainName::from_str(hostname)?;
+  let mut addresses = Vec::new();
+
+  for record_type in &[DnsRecordType::A, DnsRecordType::AAAA] {
+    let mut dns_query = DnsMessage::new();
+    dns_query.set_id(query_id());
+    dns_query.set_message_type(DnsMessageType::Query);
+    dns_query.set_op_code(DnsOpCode::Query);
+    dns_query.set_recursion_desired(true);
+    dns_query.add_query(DnsQuery::query(name.clone(), *record_type));
+
+    let dns_response = query_udp(server, &dns_query, timeout)?;
+    for answer in dns_response.answers() {
+      match answer.rdata() {
+        DnsRData::A(ipv4) => addresses.push(IpAddr::V4(*ipv4)),
+        DnsRData::AAAA(ipv6) => addresses.push(IpAddr::V6(*ipv6)),
+        _ => {},
+      }
+    }
+  }
+
+  Ok(addresses)
+}
+
+/// Generates a random ID for a DNS query
+///
+/// IDs must be unpredictable, to make it harder to spoof responses (see [RFC 5452](https://tools.ietf.org/html/rfc5452#section-9.2)).
+pub fn query_id() -> u16 {
+  rand::random()
+}
+
+/// Returns `true` if the DNS message is the response to the given query (same ID and questions)
+fn is_response_to(dns_response: &DnsMessage, dns_query: &DnsMessage) -> bool {
+  dns_response.message_type() == DnsMessageType::Response
+    && dns_response.id() == dns_query.id()
+    && dns_response.queries() == dns_query.queries()
+}
+
+/// Time left until the given deadline, or a "timed out" error if it has passed already
+fn remaining(deadline: Instant) -> io::Result<Duration> {
+  let now = Instant::now();
+  if now < deadline {
+    Ok(deadline - now)
+  } else {
+    Err(timed_out(io::Error::from(io::ErrorKind::TimedOut)))
+  }
+}
+
+/// Reports a read that timed out as such (depending on the platform, it's `WouldBlock` instead)
+fn timed_out(err: io::Error) -> io::Error {
+  match err.kind() {
+    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for a response"),
+    _ => err,
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_support::query_message;
+  use std::thread;
+
+  /// Binds a "server" that receives a query, then responds to it with the given function
+  fn udp_server<F>(respond: F) -> SocketAddr where F: FnOnce(&UdpSocket, &DnsMessage, SocketAddr) + Send + 'static {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let server = socket.local_addr().unwrap();
+
+    thread::spawn(move || {
+      let mut buf = [0u8; DNS_UDP_MESSAGE_MAX_SIZE];
+      let (amount, src) = socket.recv_from(&mut buf).unwrap();
+      respond(&socket, &dns_message_from_bytes(&buf[..amount]).unwrap(), src);
+    });
+
+    server
+  }
+
+  #[test]
+  fn should_ignore_invalid_messages() {
+    let server = udp_server(|socket, dns_query, src| {
+      socket.send_to(&[0x12, 0x34, 0x80], src).unwrap();
+      socket.send_to(&dns_message_to_bytes(&dns_error_response(dns_query, DnsResponseCode::NoError)).unwrap(), src).unwrap();
+    });
+
+    let dns_query = query_message(query_id(), "example.com.", DnsRecordType::A);
+    let dns_response = query_udp(&server, &dns_query, Duration::from_secs(5)).unwrap();
+    assert_eq!(dns_response.id(), dns_query.id());
+  }
+
+  #[test]
+  fn should_time_out_despite_ignored_messages() {
+    let server = udp_server(|socket, _, src| {
+      // Keep sending something to ignore, for longer than the timeout
+      for _ in 0..50 {
+        let _ = socket.send_to(&[0x12, 0x34, 0x80], src);
+        thread::sleep(Duration::from_millis(20));
+      }
+    });
+
+    let start = Instant::now();
+    assert!(query_udp(&server, &query_message(query_id(), "example.com.", DnsRecordType::A), Duration::from_millis(200)).is_err());
+    assert!(start.elapsed() < Duration::from_millis(800));
+  }
+
+  #[test]
+  fn should_parse_server_address() {
+    assert_eq!(parse_server_address("9.9.9.9").unwrap(), "9.9.9.9:53".parse().unwrap());
+    assert_eq!(parse_server_address("9.9.9.9:5353").unwrap(), "9.9.9.9:5353".parse().unwrap());
+    assert_eq!(parse_server_address("2620:fe::fe").unwrap(), "[2620:fe::fe]:53".parse().unwrap());
+    assert_eq!(parse_server_address("[2620:fe::fe]:5353").unwrap(), "[2620:fe::fe]:5353".parse().unwrap());
+    assert!(parse_server_address("dns.quad9.net").is_err());
+  }
+}
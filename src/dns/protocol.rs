@@ -22,8 +22,13 @@ pub use trust_dns_proto::{
     resource::Record as DnsRecord,
     record_data::RData as DnsRData,
     rdata::opt::{OPT as DnsRDataOPT, EdnsCode as DnsRDataOPTCode, EdnsOption as DnsRDataOPTOption},
+    rdata::soa::SOA as DnsRDataSOA,
+    rdata::txt::TXT as DnsRDataTXT,
+    rdata::srv::SRV as DnsRDataSRV,
+    rdata::mx::MX as DnsRDataMX,
+    rdata::null::NULL as DnsRDataNULL,
   },
-  serialize::binary::{BinDecodable, BinEncodable},
+  serialize::binary::{BinDecodable, BinEncodable, BinDecoder, Restrict},
   error::{
     ProtoError as DnsProtoError,
     ProtoErrorKind as DnsProtoErrorKind,
@@ -31,6 +36,9 @@ pub use trust_dns_proto::{
 };
 use serde::{ser::Serializer, de::{Deserialize, Deserializer}};
 
+/// Size of the header of a DNS message (see [RFC 1035](https://tools.ietf.org/html/rfc1035#section-4.1.1))
+const DNS_HEADER_SIZE: usize = 12;
+
 /// Converts an array of raw bytes into a `DnsMessage`
 pub fn dns_message_from_bytes(bytes: &[u8]) -> Result<DnsMessage, DnsProtoError> {
   DnsMessage::from_bytes(bytes)
@@ -41,6 +49,51 @@ pub fn dns_message_to_bytes(message: &DnsMessage) -> Result<Vec<u8>, DnsProtoErr
   message.to_bytes()
 }
 
+/// Builds an "error" response to the given DNS query, with the given `DnsResponseCode`
+///
+/// The response carries the same ID, op code and questions of the query, so that the client
+/// can match it and stop waiting (or retry) straight away.
+///
+/// # Parameters
+///
+/// * `dns_query` - DNS Message of type `DnsMessageType::Query` to respond to
+/// * `response_code` - `DnsResponseCode` of the response (ex. `DnsResponseCode::ServFail`)
+pub fn dns_error_response(dns_query: &DnsMessage, response_code: DnsResponseCode) -> DnsMessage {
+  let mut dns_response = DnsMessage::error_msg(dns_query.id(), dns_query.op_code(), response_code);
+  dns_response.set_recursion_desired(dns_query.recursion_desired());
+  dns_response.set_recursion_available(true);
+  dns_response.set_checking_disabled(dns_query.checking_disabled());
+  dns_response.add_queries(dns_query.queries().to_vec());
+
+  dns_response
+}
+
+/// Builds an "error" response to a DNS query that couldn't be parsed
+///
+/// The response code is `DnsResponseCode::NotImp` if the op code is unknown, `DnsResponseCode::FormErr`
+/// otherwise. Only the ID can be echoed back.
+///
+/// Returns `None` unless the raw bytes contain a full header of a query (i.e. the `QR` bit is not set):
+/// responding to anything else would allow for loops between servers (ex. by spoofing the source),
+/// or for amplification of small packets.
+///
+/// # Parameters
+///
+/// * `raw_dns_query` - Raw bytes received, that failed to parse into a `DnsMessage`
+pub fn dns_error_response_from_bytes(raw_dns_query: &[u8]) -> Option<DnsMessage> {
+  if raw_dns_query.len() < DNS_HEADER_SIZE || raw_dns_query[2] & 0x80 != 0 {
+    return None;
+  }
+
+  let id = u16::from_be_bytes([raw_dns_query[0], raw_dns_query[1]]);
+  let raw_op_code = (raw_dns_query[2] >> 3) & 0x0F;
+
+  Some(match DnsOpCode::from_u8(raw_op_code) {
+    Ok(op_code) => DnsMessage::error_msg(id, op_code, DnsResponseCode::FormErr),
+    Err(_) => DnsMessage::error_msg(id, DnsOpCode::Query, DnsResponseCode::NotImp),
+  })
+}
+
 /// Serializes a `DnsResponseCode` into a `u16`
 ///
 /// Useful for Serde's `serialize_with` attribute
@@ -134,6 +187,47 @@ mod test {
     assert!(dns_req.edns().is_none());
   }
 
+  #[test]
+  fn should_build_error_response() {
+    let buf = read_file_to_vec("./test/fixtures/dns_udp_query_A-example.com-packet.bin");
+    let dns_req = dns_message_from_bytes(&buf).unwrap();
+
+    let dns_res = dns_error_response(&dns_req, DnsResponseCode::ServFail);
+    assert_eq!(dns_res.message_type(), DnsMessageType::Response);
+    assert_eq!(dns_res.id(), dns_req.id());
+    assert_eq!(dns_res.op_code(), dns_req.op_code());
+    assert_eq!(dns_res.response_code(), DnsResponseCode::ServFail);
+    assert_eq!(dns_res.queries(), dns_req.queries());
+    assert_eq!(dns_res.answer_count(), 0);
+    assert!(dns_message_to_bytes(&dns_res).is_ok());
+  }
+
+  #[test]
+  fn should_build_error_response_from_bytes() {
+    let mut buf = read_file_to_vec("./test/fixtures/dns_udp_query_A-example.com-packet.bin");
+    let dns_req = dns_message_from_bytes(&buf).unwrap();
+
+    // Truncated in the middle of the question
+    let dns_res = dns_error_response_from_bytes(&buf[..14]).unwrap();
+    assert_eq!(dns_res.message_type(), DnsMessageType::Response);
+    assert_eq!(dns_res.id(), dns_req.id());
+    assert_eq!(dns_res.response_code(), DnsResponseCode::FormErr);
+
+    // Unknown op code
+    buf[2] |= 0x0F << 3;
+    let dns_res = dns_error_response_from_bytes(&buf).unwrap();
+    assert_eq!(dns_res.id(), dns_req.id());
+    assert_eq!(dns_res.response_code(), DnsResponseCode::NotImp);
+
+    // Not even a full header
+    assert!(dns_error_response_from_bytes(&buf[..1]).is_none());
+    assert!(dns_error_response_from_bytes(&buf[..11]).is_none());
+
+    // Not a query
+    buf[2] |= 0x80;
+    assert!(dns_error_response_from_bytes(&buf).is_none());
+  }
+
   #[test]
   fn should_deserialize_udp_query_aaaa_www_ivandemarino_me() {
     let buf = read_file_to_vec("./test/fixtures/dns_udp_query_AAAA-www.ivandemarino.me-packet.bin");
@@ -0,0 +1,208 @@
+//! Implementation of `DoHResolver` for "plain" DNS (i.e. not over HTTPS), over UDP or TCP
+//!
+//! This is used where a DNS-over-HTTPS provider can't be used (ex. an internal resolver, only
+//! reachable via VPN).
+
+use super::{protocol::*, client};
+use crate::core::resolver::*;
+
+use log::*;
+
+use std::{fmt, net::SocketAddr, time::Duration};
+
+const DNS_PLAIN_RESOLVER_TIMEOUT_SEC: u64 = 5;
+
+type Result<T> = std::result::Result<T, DoHResolutionError>;
+
+/// Transport protocol to reach a "plain" DNS server with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DnsTransport {
+  Udp,
+  Tcp,
+}
+
+impl fmt::Display for DnsTransport {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DnsTransport::Udp => write!(fmtr, "udp"),
+      DnsTransport::Tcp => write!(fmtr, "tcp"),
+    }
+  }
+}
+
+/// Resolver that forwards queries to a "plain" DNS server
+///
+/// Queries are forwarded with a random ID (restored in the response), and only a response
+/// matching the ID and the questions is accepted. Over UDP, a truncated response is retried over
+/// TCP (as per [RFC 7766](https://tools.ietf.org/html/rfc7766#section-5)).
+#[derive(Debug, Clone)]
+pub struct DnsPlainResolver {
+  server: SocketAddr,
+  transport: DnsTransport,
+  timeout: Duration,
+}
+
+impl DnsPlainResolver {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  ///
+  /// * `server` - Address of the DNS server
+  /// * `transport` - Transport protocol to reach the DNS server with
+  pub fn new(server: SocketAddr, transport: DnsTransport) -> DnsPlainResolver {
+    DnsPlainResolver::with_timeout(server, transport, Duration::from_secs(DNS_PLAIN_RESOLVER_TIMEOUT_SEC))
+  }
+
+  /// Constructor, with a timeout
+  ///
+  /// # Parameters
+  ///
+  /// * `server` - Address of the DNS server
+  /// * `transport` - Transport protocol to reach the DNS server with
+  /// * `timeout` - How long to wait for a response (and, over TCP, to connect)
+  pub fn with_timeout(server: SocketAddr, transport: DnsTransport, timeout: Duration) -> DnsPlainResolver {
+    DnsPlainResolver {
+      server,
+      transport,
+      timeout,
+    }
+  }
+
+}
+
+impl DoHResolver for DnsPlainResolver {
+
+  fn resolve_query(&self, req_dns_msg: &DnsMessage) -> Result<DnsMessage> {
+    let mut fwd_dns_msg = req_dns_msg.clone();
+    fwd_dns_msg.set_id(client::query_id());
+
+    let res = match self.transport {
+      DnsTransport::Udp => client::query_udp(&self.server, &fwd_dns_msg, self.timeout)
+        .and_then(|res_dns_msg| if res_dns_msg.truncated() {
+          debug!("Truncated response from '{}': retrying over TCP", self.server);
+          client::query_tcp(&self.server, &fwd_dns_msg, self.timeout)
+        } else {
+          Ok(res_dns_msg)
+        }),
+      DnsTransport::Tcp => client::query_tcp(&self.server, &fwd_dns_msg, self.timeout),
+    };
+
+    let mut res_dns_msg = res.map_err(|err| {
+      let kind = match err.kind() {
+        DnsProtoErrorKind::Timeout => DoHResolutionErrorKind::Timeout,
+        DnsProtoErrorKind::Io => DoHResolutionErrorKind::Transport,
+        _ => DoHResolutionErrorKind::Decode,
+      };
+      DoHResolutionError::new(kind, format!("Failed to query DNS server '{}' over {}: {}", self.server, self.transport, err))
+    })?;
+    res_dns_msg.set_id(req_dns_msg.id());
+
+    Ok(res_dns_msg)
+  }
+
+  fn box_clone(&self) -> Box<DoHResolver + Send> {
+    Box::new((*self).clone())
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_support::query_message;
+  use std::{str::FromStr, thread, io::{Read, Write}, net::{TcpListener, UdpSocket, Ipv4Addr}};
+
+  fn response_message(req_dns_msg: &DnsMessage, truncated: bool) -> Vec<u8> {
+    let mut res_dns_msg = dns_error_response(req_dns_msg, DnsResponseCode::NoError);
+    if truncated {
+      // The TC flag is only set by the encoder if it runs out of space: set it on the raw bytes
+      let mut raw_res_dns_msg = dns_message_to_bytes(&res_dns_msg).unwrap();
+      raw_res_dns_msg[2] |= 0x02;
+      return raw_res_dns_msg;
+    }
+
+    let name = req_dns_msg.queries()[0].name().clone();
+    res_dns_msg.add_answer(DnsRecord::from_rdata(name, 60, DnsRData::A(Ipv4Addr::new(10, 0, 0, 80))));
+
+    dns_message_to_bytes(&res_dns_msg).unwrap()
+  }
+
+  #[test]
+  fn should_retry_truncated_response_over_tcp() {
+    // Same port for UDP and TCP, like a real DNS server
+    let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = tcp_listener.local_addr().unwrap();
+    let udp_socket = UdpSocket::bind(server).unwrap();
+
+    let udp_thread = thread::spawn(move || {
+      let mut buf = [0; 512];
+      let (amount, src) = udp_socket.recv_from(&mut buf).unwrap();
+      let req_dns_msg = dns_message_from_bytes(&buf[..amount]).unwrap();
+      udp_socket.send_to(&response_message(&req_dns_msg, true), src).unwrap();
+    });
+    let tcp_thread = thread::spawn(move || {
+      let (mut stream, _) = tcp_listener.accept().unwrap();
+      let mut len_buf = [0; 2];
+      stream.read_exact(&mut len_buf).unwrap();
+      let mut buf = vec![0; u16::from_be_bytes(len_buf) as usize];
+      stream.read_exact(&mut buf).unwrap();
+
+      let raw_res = response_message(&dns_message_from_bytes(&buf).unwrap(), false);
+      stream.write_all(&(raw_res.len() as u16).to_be_bytes()).unwrap();
+      stream.write_all(&raw_res).unwrap();
+    });
+
+    let resolver = DnsPlainResolver::new(server, DnsTransport::Udp);
+    let res = resolver.resolve(&query_message(42, "intranet.corp.example.", DnsRecordType::A)).unwrap();
+    assert_eq!(res.id(), 42);
+    assert!(!res.truncated());
+    assert_eq!(res.answers()[0].rdata(), &DnsRData::A(Ipv4Addr::new(10, 0, 0, 80)));
+
+    udp_thread.join().unwrap();
+    tcp_thread.join().unwrap();
+  }
+
+  #[test]
+  fn should_accept_only_matching_response() {
+    let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = udp_socket.local_addr().unwrap();
+
+    let udp_thread = thread::spawn(move || {
+      let mut buf = [0; 512];
+      let (amount, src) = udp_socket.recv_from(&mut buf).unwrap();
+      let req_dns_msg = dns_message_from_bytes(&buf[..amount]).unwrap();
+
+      // The ID was randomized: a response with the original one (ex. spoofed), or for another question, is ignored
+      let mut spoofed_dns_msg = req_dns_msg.clone();
+      spoofed_dns_msg.set_id(42);
+      udp_socket.send_to(&response_message(&spoofed_dns_msg, false), src).unwrap();
+      let mut other_dns_msg = DnsMessage::new();
+      other_dns_msg.set_id(req_dns_msg.id());
+      other_dns_msg.add_query(DnsQuery::query(DnsDomainName::from_str("other.corp.example.").unwrap(), DnsRecordType::A));
+      udp_socket.send_to(&response_message(&other_dns_msg, false), src).unwrap();
+
+      udp_socket.send_to(&response_message(&req_dns_msg, false), src).unwrap();
+      req_dns_msg.id()
+    });
+
+    let resolver = DnsPlainResolver::new(server, DnsTransport::Udp);
+    let res = resolver.resolve(&query_message(42, "intranet.corp.example.", DnsRecordType::A)).unwrap();
+    assert_ne!(udp_thread.join().unwrap(), 42);
+    assert_eq!(res.id(), 42);
+    assert_eq!(res.queries(), query_message(42, "intranet.corp.example.", DnsRecordType::A).queries());
+    assert_eq!(res.answers().len(), 1);
+  }
+
+  #[test]
+  fn should_time_out() {
+    // Nothing ever responds
+    let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let resolver = DnsPlainResolver::with_timeout(udp_socket.local_addr().unwrap(), DnsTransport::Udp, Duration::from_millis(100));
+    let err = resolver.resolve(&query_message(42, "intranet.corp.example.", DnsRecordType::A)).unwrap_err();
+    assert_eq!(err.kind(), DoHResolutionErrorKind::Timeout);
+    assert!(err.is_retryable());
+    assert!(err.to_string().contains("timed out"));
+  }
+}
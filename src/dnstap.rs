@@ -0,0 +1,202 @@
+//! [dnstap](http://dnstap.info/) output: DNS messages, as received and sent, for analytics
+//!
+//! Messages are emitted via a `Dnstap` by the `Processor` (client queries and responses) and by the
+//! `DoHUpstreamGroup` (queries forwarded to, and responses received from, DNS-over-HTTPS providers).
+//! They are handed over to a `DnstapWriter` (see `writer`) that writes them as Frame Streams to the
+//! configured `DnstapDestination`, so that resolution is never slowed down by the writing.
+
+pub mod message;
+pub mod writer;
+
+use crate::dns::protocol::{DnsMessage, dns_message_to_bytes};
+use crate::net::request::Request;
+use crate::metrics;
+use self::message::{DnstapMessage, DnstapMessageType, DnstapSocketProtocol};
+
+use crossbeam_channel::{Sender as XBeamSender, TrySendError as XBeamTrySendError};
+use log::*;
+
+use std::{fmt, str::FromStr, path::PathBuf, time::SystemTime};
+
+const DESTINATION_PREFIX_FILE: &'static str = "file:";
+const DESTINATION_PREFIX_UNIX: &'static str = "unix:";
+
+/// Messages waiting to be written, past which new messages are dropped (see `Dnstap`)
+pub const DNSTAP_QUEUE_CAPACITY: usize = 4096;
+
+/// Where the dnstap output is written to
+#[derive(Debug, Clone, PartialEq)]
+pub enum DnstapDestination {
+  /// A file, overwritten when starting (format: `file:PATH`, or just `PATH`)
+  File(PathBuf),
+  /// A Unix (stream) socket, ex. of a dnstap collector (format: `unix:PATH`)
+  UnixSocket(PathBuf),
+}
+
+impl FromStr for DnstapDestination {
+  type Err = DnstapDestinationParseError;
+
+  fn from_str(raw_destination: &str) -> Result<Self, Self::Err> {
+    let destination = if let Some(path) = raw_destination.strip_prefix(DESTINATION_PREFIX_UNIX) {
+      DnstapDestination::UnixSocket(PathBuf::from(path))
+    } else if let Some(path) = raw_destination.strip_prefix(DESTINATION_PREFIX_FILE) {
+      DnstapDestination::File(PathBuf::from(path))
+    } else {
+      DnstapDestination::File(PathBuf::from(raw_destination))
+    };
+
+    match &destination {
+      DnstapDestination::File(path) | DnstapDestination::UnixSocket(path) if path.as_os_str().is_empty() => {
+        Err(DnstapDestinationParseError::new(raw_destination))
+      },
+      _ => Ok(destination),
+    }
+  }
+}
+
+impl fmt::Display for DnstapDestination {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DnstapDestination::File(path) => write!(fmtr, "{}{}", DESTINATION_PREFIX_FILE, path.display()),
+      DnstapDestination::UnixSocket(path) => write!(fmtr, "{}{}", DESTINATION_PREFIX_UNIX, path.display()),
+    }
+  }
+}
+
+/// Error that happens when parsing a `DnstapDestination` fails
+#[derive(Debug, Clone)]
+pub struct DnstapDestinationParseError {
+  token: String
+}
+
+impl DnstapDestinationParseError {
+  fn new(token: &str) -> Self {
+    Self {
+      token: token.to_string()
+    }
+  }
+}
+
+impl fmt::Display for DnstapDestinationParseError {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmtr, "Invalid dnstap destination (expected '{}PATH' or '{}PATH'): {}", DESTINATION_PREFIX_FILE, DESTINATION_PREFIX_UNIX, self.token)
+  }
+}
+
+/// Handle used to emit dnstap messages
+///
+/// It's cheap to clone: all the clones send to the same `DnstapWriter`.
+#[derive(Debug, Clone)]
+pub struct Dnstap {
+  sender: XBeamSender<DnstapMessage>,
+}
+
+impl Dnstap {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  ///
+  /// * `sender` - Channel sender to "emit" the messages to the `DnstapWriter`
+  pub fn new(sender: XBeamSender<DnstapMessage>) -> Dnstap {
+    Dnstap {
+      sender,
+    }
+  }
+
+  /// Emits a `CLIENT_QUERY` message, for a request received from a client
+  pub fn client_query(&self, req: &Request) {
+    self.emit(DnstapMessage {
+      message_type: DnstapMessageType::ClientQuery,
+      socket_protocol: socket_protocol(req),
+      query_address: Some(*req.source()),
+      query_time: received_time(req),
+      query_message: to_bytes(req.dns_query()),
+      response_time: None,
+      response_message: None,
+    });
+  }
+
+  /// Emits a `CLIENT_RESPONSE` message, for the response about to be sent to a client
+  pub fn client_response(&self, req: &Request, res_dns_msg: &DnsMessage) {
+    self.emit(DnstapMessage {
+      message_type: DnstapMessageType::ClientResponse,
+      socket_protocol: socket_protocol(req),
+      query_address: Some(*req.source()),
+      query_time: received_time(req),
+      query_message: None,
+      response_time: Some(SystemTime::now()),
+      response_message: to_bytes(res_dns_msg),
+    });
+  }
+
+  /// Emits a `FORWARDER_QUERY` message, for a query about to be sent to a DNS-over-HTTPS provider
+  pub fn forwarder_query(&self, req_dns_msg: &DnsMessage, query_time: SystemTime) {
+    self.emit(DnstapMessage {
+      message_type: DnstapMessageType::ForwarderQuery,
+      socket_protocol: DnstapSocketProtocol::Doh,
+      query_address: None,
+      query_time,
+      query_message: to_bytes(req_dns_msg),
+      response_time: None,
+      response_message: None,
+    });
+  }
+
+  /// Emits a `FORWARDER_RESPONSE` message, for a response received from a DNS-over-HTTPS provider
+  pub fn forwarder_response(&self, res_dns_msg: &DnsMessage, query_time: SystemTime) {
+    self.emit(DnstapMessage {
+      message_type: DnstapMessageType::ForwarderResponse,
+      socket_protocol: DnstapSocketProtocol::Doh,
+      query_address: None,
+      query_time,
+      query_message: None,
+      response_time: Some(SystemTime::now()),
+      response_message: to_bytes(res_dns_msg),
+    });
+  }
+
+  /// Emits a message to the `DnstapWriter`: it never blocks, and drops (and counts) the message if the writer can't keep up
+  fn emit(&self, message: DnstapMessage) {
+    match self.sender.try_send(message) {
+      Ok(_) => {},
+      Err(XBeamTrySendError::Full(_)) => metrics::inc_counter(&metrics::LOG_ENTRIES_DROPPED, &["dnstap"]),
+      Err(XBeamTrySendError::Disconnected(_)) => error!("Unable to emit dnstap message: writer is gone"),
+    }
+  }
+
+}
+
+fn socket_protocol(req: &Request) -> DnstapSocketProtocol {
+  match req.transport() {
+    "tcp" => DnstapSocketProtocol::Tcp,
+    _ => DnstapSocketProtocol::Udp,
+  }
+}
+
+/// When the request was received, as wall-clock time
+fn received_time(req: &Request) -> SystemTime {
+  SystemTime::now() - req.received().elapsed()
+}
+
+fn to_bytes(dns_msg: &DnsMessage) -> Option<Vec<u8>> {
+  dns_message_to_bytes(dns_msg)
+    .map_err(|err| warn!("Unable to serialize DNS message for dnstap: {}", err))
+    .ok()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn should_parse_destination() {
+    assert_eq!(DnstapDestination::from_str("/var/log/mooncell.dnstap").unwrap(), DnstapDestination::File("/var/log/mooncell.dnstap".into()));
+    assert_eq!(DnstapDestination::from_str("file:mooncell.dnstap").unwrap(), DnstapDestination::File("mooncell.dnstap".into()));
+    assert_eq!(DnstapDestination::from_str("unix:/run/dnstap.sock").unwrap(), DnstapDestination::UnixSocket("/run/dnstap.sock".into()));
+    assert!(DnstapDestination::from_str("").is_err());
+    assert!(DnstapDestination::from_str("unix:").is_err());
+
+    assert_eq!(DnstapDestination::from_str("unix:/run/dnstap.sock").unwrap().to_string(), "unix:/run/dnstap.sock");
+  }
+}
@@ -0,0 +1,175 @@
+//! Minimal implementation of the [dnstap](http://dnstap.info/) message schema, encoded as protobuf
+//!
+//! Only the fields that mooncell can populate are supported: see `dnstap.proto` for the full schema.
+
+use std::{net::{IpAddr, SocketAddr}, time::{SystemTime, UNIX_EPOCH}};
+
+const WIRE_TYPE_VARINT: u32 = 0;
+const WIRE_TYPE_LENGTH_DELIMITED: u32 = 2;
+const WIRE_TYPE_FIXED32: u32 = 5;
+
+// Fields of `Dnstap`
+const DNSTAP_FIELD_IDENTITY: u32 = 1;
+const DNSTAP_FIELD_VERSION: u32 = 2;
+const DNSTAP_FIELD_MESSAGE: u32 = 14;
+const DNSTAP_FIELD_TYPE: u32 = 15;
+const DNSTAP_TYPE_MESSAGE: u64 = 1;
+
+// Fields of `Message`
+const MESSAGE_FIELD_TYPE: u32 = 1;
+const MESSAGE_FIELD_SOCKET_FAMILY: u32 = 2;
+const MESSAGE_FIELD_SOCKET_PROTOCOL: u32 = 3;
+const MESSAGE_FIELD_QUERY_ADDRESS: u32 = 4;
+const MESSAGE_FIELD_QUERY_PORT: u32 = 6;
+const MESSAGE_FIELD_QUERY_TIME_SEC: u32 = 8;
+const MESSAGE_FIELD_QUERY_TIME_NSEC: u32 = 9;
+const MESSAGE_FIELD_QUERY_MESSAGE: u32 = 10;
+const MESSAGE_FIELD_RESPONSE_TIME_SEC: u32 = 12;
+const MESSAGE_FIELD_RESPONSE_TIME_NSEC: u32 = 13;
+const MESSAGE_FIELD_RESPONSE_MESSAGE: u32 = 14;
+
+const SOCKET_FAMILY_INET: u64 = 1;
+const SOCKET_FAMILY_INET6: u64 = 2;
+
+/// Type of a dnstap `Message` (only the ones mooncell emits)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DnstapMessageType {
+  ClientQuery = 5,
+  ClientResponse = 6,
+  ForwarderQuery = 7,
+  ForwarderResponse = 8,
+}
+
+/// Protocol of the socket a DNS message was exchanged over
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DnstapSocketProtocol {
+  Udp = 1,
+  Tcp = 2,
+  Doh = 4,
+}
+
+/// A dnstap `Message`
+#[derive(Debug, Clone)]
+pub struct DnstapMessage {
+  pub message_type: DnstapMessageType,
+  pub socket_protocol: DnstapSocketProtocol,
+  /// Address of the "initiator" of the exchange (ex. the client, for `DnstapMessageType::ClientQuery`)
+  pub query_address: Option<SocketAddr>,
+  pub query_time: SystemTime,
+  /// The query, in DNS wire format
+  pub query_message: Option<Vec<u8>>,
+  pub response_time: Option<SystemTime>,
+  /// The response, in DNS wire format
+  pub response_message: Option<Vec<u8>>,
+}
+
+impl DnstapMessage {
+
+  /// Encodes the message, wrapped in a `Dnstap` envelope
+  ///
+  /// # Parameters
+  ///
+  /// * `identity` - Identity of the server (if any)
+  /// * `version` - Version of the server
+  pub fn encode(&self, identity: Option<&str>, version: &str) -> Vec<u8> {
+    let mut message = Vec::new();
+    encode_varint_field(&mut message, MESSAGE_FIELD_TYPE, self.message_type as u64);
+    let query_address = self.query_address.map(|query_address| match query_address.ip() {
+      IpAddr::V4(ipv4) => (SOCKET_FAMILY_INET, ipv4.octets().to_vec(), query_address.port()),
+      IpAddr::V6(ipv6) => (SOCKET_FAMILY_INET6, ipv6.octets().to_vec(), query_address.port()),
+    });
+    if let Some((family, _, _)) = &query_address {
+      encode_varint_field(&mut message, MESSAGE_FIELD_SOCKET_FAMILY, *family);
+    }
+    encode_varint_field(&mut message, MESSAGE_FIELD_SOCKET_PROTOCOL, self.socket_protocol as u64);
+    if let Some((_, address, port)) = &query_address {
+      encode_bytes_field(&mut message, MESSAGE_FIELD_QUERY_ADDRESS, address);
+      encode_varint_field(&mut message, MESSAGE_FIELD_QUERY_PORT, u64::from(*port));
+    }
+    encode_time_fields(&mut message, MESSAGE_FIELD_QUERY_TIME_SEC, MESSAGE_FIELD_QUERY_TIME_NSEC, self.query_time);
+    if let Some(query_message) = &self.query_message {
+      encode_bytes_field(&mut message, MESSAGE_FIELD_QUERY_MESSAGE, query_message);
+    }
+    if let Some(response_time) = self.response_time {
+      encode_time_fields(&mut message, MESSAGE_FIELD_RESPONSE_TIME_SEC, MESSAGE_FIELD_RESPONSE_TIME_NSEC, response_time);
+    }
+    if let Some(response_message) = &self.response_message {
+      encode_bytes_field(&mut message, MESSAGE_FIELD_RESPONSE_MESSAGE, response_message);
+    }
+
+    let mut dnstap = Vec::with_capacity(message.len() + 32);
+    if let Some(identity) = identity {
+      encode_bytes_field(&mut dnstap, DNSTAP_FIELD_IDENTITY, identity.as_bytes());
+    }
+    encode_bytes_field(&mut dnstap, DNSTAP_FIELD_VERSION, version.as_bytes());
+    encode_bytes_field(&mut dnstap, DNSTAP_FIELD_MESSAGE, &message);
+    encode_varint_field(&mut dnstap, DNSTAP_FIELD_TYPE, DNSTAP_TYPE_MESSAGE);
+
+    dnstap
+  }
+
+}
+
+fn encode_varint(buf: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    buf.push((value as u8 & 0x7F) | 0x80);
+    value >>= 7;
+  }
+  buf.push(value as u8);
+}
+
+fn encode_key(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+  encode_varint(buf, u64::from(field << 3 | wire_type));
+}
+
+fn encode_varint_field(buf: &mut Vec<u8>, field: u32, value: u64) {
+  encode_key(buf, field, WIRE_TYPE_VARINT);
+  encode_varint(buf, value);
+}
+
+fn encode_bytes_field(buf: &mut Vec<u8>, field: u32, value: &[u8]) {
+  encode_key(buf, field, WIRE_TYPE_LENGTH_DELIMITED);
+  encode_varint(buf, value.len() as u64);
+  buf.extend_from_slice(value);
+}
+
+fn encode_time_fields(buf: &mut Vec<u8>, sec_field: u32, nsec_field: u32, time: SystemTime) {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  encode_varint_field(buf, sec_field, since_epoch.as_secs());
+  encode_key(buf, nsec_field, WIRE_TYPE_FIXED32);
+  buf.extend_from_slice(&since_epoch.subsec_nanos().to_le_bytes());
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn should_encode_message() {
+    let message = DnstapMessage {
+      message_type: DnstapMessageType::ClientQuery,
+      socket_protocol: DnstapSocketProtocol::Udp,
+      query_address: Some("192.168.1.42:5353".parse().unwrap()),
+      query_time: UNIX_EPOCH + Duration::new(300, 1),
+      query_message: Some(vec![0xAB, 0xCD]),
+      response_time: None,
+      response_message: None,
+    };
+
+    assert_eq!(message.encode(Some("ns1"), "mc"), vec![
+      0x0A, 3, b'n', b's', b'1',                  // identity
+      0x12, 2, b'm', b'c',                        // version
+      0x72, 27,                                   // message
+        0x08, 5,                                  // type: CLIENT_QUERY
+        0x10, 1,                                  // socket_family: INET
+        0x18, 1,                                  // socket_protocol: UDP
+        0x22, 4, 192, 168, 1, 42,                 // query_address
+        0x30, 0xE9, 0x29,                         // query_port: 5353
+        0x40, 0xAC, 0x02,                         // query_time_sec: 300
+        0x4D, 1, 0, 0, 0,                         // query_time_nsec: 1
+        0x52, 2, 0xAB, 0xCD,                      // query_message
+      0x78, 1,                                    // type: MESSAGE
+    ]);
+  }
+}
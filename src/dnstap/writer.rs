@@ -0,0 +1,430 @@
+//! Implementation of the service that writes the dnstap output, as [Frame Streams](https://github.com/farsightsec/fstrm)
+//!
+//! A file is written with the unidirectional protocol (`START`, data frames, `STOP`). A Unix socket
+//! is written with the bidirectional one, that collectors expect: the content type is agreed upon
+//! (`READY`/`ACCEPT`) before `START`, and the collector acknowledges `STOP` with `FINISH`.
+//!
+//! A file whose Frame Stream broke (ex. the disk was full) is never appended to: it's set aside,
+//! and a new file is started in its place.
+
+use super::{DnstapDestination, message::DnstapMessage};
+use crate::output::{OutputReopener, connect_unix_socket};
+
+use log::*;
+use crossbeam_channel::{Receiver as XBeamReceiver, RecvTimeoutError as XBeamRecvTimeoutError};
+use srvzio;
+
+use std::{thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}, fs::{self, File}, io::{self, Read, Write, BufWriter}, fmt::Display, os::unix::net::UnixStream, path::{Path, PathBuf}};
+
+const DNSTAP_WRITER_SERVICE_NAME: &'static str = "DnstapWriter";
+const DNSTAP_WRITER_THREAD_NAME: &'static str = "dnstap_writer_thread";
+const DNSTAP_WRITER_RECEIVER_TIMEOUT_SEC: u64 = 1;
+const DNSTAP_WRITER_HANDSHAKE_TIMEOUT_SEC: u64 = 5;
+const DNSTAP_VERSION: &'static str = concat!("mooncell ", env!("CARGO_PKG_VERSION"));
+
+const FSTRM_CONTENT_TYPE: &'static str = "protobuf:dnstap.Dnstap";
+const FSTRM_CONTROL_ACCEPT: u32 = 0x01;
+const FSTRM_CONTROL_START: u32 = 0x02;
+const FSTRM_CONTROL_STOP: u32 = 0x03;
+const FSTRM_CONTROL_READY: u32 = 0x04;
+const FSTRM_CONTROL_FINISH: u32 = 0x05;
+const FSTRM_CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+const FSTRM_CONTROL_FRAME_MAX_SIZE: usize = 512;
+
+/// DnstapWriter is a service that writes dnstap messages, as Frame Streams
+///
+/// The messages are received via the `Receiver` (see `crossbeam::channel::Receiver`), and written
+/// to the `DnstapDestination`. If writing fails, the destination is opened again later, backing off
+/// while it keeps failing (see `OutputReopener`): messages that can't be written are dropped.
+pub struct DnstapWriter {
+  destination: DnstapDestination,
+  identity: Option<String>,
+  receiver: XBeamReceiver<DnstapMessage>,
+  status: srvzio::ServiceStatusFlag,
+  writer_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl DnstapWriter {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  /// * `destination`: where to write the dnstap output to
+  /// * `identity`: identity of this server, added to every message (if any)
+  /// * `receiver`: a `crossbeam::channel::Receiver` that delivers `DnstapMessage` data
+  pub fn new(destination: DnstapDestination, identity: Option<String>, receiver: XBeamReceiver<DnstapMessage>) -> DnstapWriter {
+    DnstapWriter {
+      destination,
+      identity,
+      receiver,
+      status: srvzio::ServiceStatusFlag::default(),
+      writer_thread: None,
+    }
+  }
+
+}
+
+impl srvzio::Service for DnstapWriter {
+
+  fn name(&self) -> &'static str {
+    DNSTAP_WRITER_SERVICE_NAME
+  }
+
+  fn start(&mut self) {
+    self.status.starting();
+
+    let destination = self.destination.clone();
+    let identity = self.identity.clone();
+    let receiver = self.receiver.clone();
+    let status = self.status.clone();
+
+    // Launch a 'writing' thread
+    self.writer_thread = Some(thread::Builder::new()
+      .name(DNSTAP_WRITER_THREAD_NAME.into())
+      .spawn(move || {
+        let mut output = DnstapOutput::new(destination);
+        output.reopen(Instant::now());
+
+        status.started();
+
+        // Receive messages to write, but interrupt at regular intervals to check if DnstapWriter was stopped
+        loop {
+          match receiver.recv_timeout(Duration::from_secs(DNSTAP_WRITER_RECEIVER_TIMEOUT_SEC)) {
+            Ok(message) => output.write_message(&message.encode(identity.as_ref().map(String::as_str), DNSTAP_VERSION)),
+            Err(XBeamRecvTimeoutError::Timeout) => {
+              if status.is_stopping() {
+                trace!("{} is done running: stop writing dnstap output", DNSTAP_WRITER_SERVICE_NAME);
+                break;
+              }
+
+              // Nothing to write for a while: make sure what was written so far is not sitting in a buffer
+              output.flush();
+            },
+            Err(XBeamRecvTimeoutError::Disconnected) => break,
+          }
+        }
+
+        // Write any message still pending, then close the stream
+        for message in receiver.try_iter() {
+          output.write_message(&message.encode(identity.as_ref().map(String::as_str), DNSTAP_VERSION));
+        }
+        output.close();
+
+        status.stopped();
+      })
+      .expect(format!("Unable to spawn thread: {}", DNSTAP_WRITER_THREAD_NAME).as_ref())
+    );
+  }
+
+  fn await_started(&mut self) {
+    while !self.status.is_started() {}
+  }
+
+  fn stop(&mut self) {
+    trace!("{} should now stop...", DNSTAP_WRITER_SERVICE_NAME);
+    self.status.stopping();
+  }
+
+  fn await_stopped(&mut self) {
+    while !self.status.is_stopped() {}
+
+    // Wait for writer thread to stop (if it's actually set)
+    if self.writer_thread.is_some() {
+      self.writer_thread
+        .take()
+        .unwrap()
+        .join()
+        .expect(format!("Panicked upon termination: {}", DNSTAP_WRITER_THREAD_NAME).as_ref());
+    }
+  }
+
+}
+
+/// The destination of the dnstap output, (re)opened when needed
+///
+/// If the Frame Stream written to a file broke, the file is set aside before being opened again.
+struct DnstapOutput {
+  destination: DnstapDestination,
+  stream: Option<FrameStream>,
+  broken_file: bool,
+  reopener: OutputReopener,
+}
+
+impl DnstapOutput {
+
+  fn new(destination: DnstapDestination) -> DnstapOutput {
+    DnstapOutput {
+      reopener: OutputReopener::new(format!("dnstap output '{}'", destination)),
+      destination,
+      stream: None,
+      broken_file: false,
+    }
+  }
+
+  /// (Re)opens the destination, unless it's too early to try again
+  fn reopen(&mut self, now: Instant) {
+    let destination = &self.destination;
+    let broken_file = &mut self.broken_file;
+    let stream = self.reopener.reopen(now, || {
+      if let (true, DnstapDestination::File(path)) = (*broken_file, destination) {
+        let aside_path = set_aside(path).map_err(|err| io::Error::new(err.kind(), format!("Unable to set it aside: {}", err)))?;
+        warn!("Dnstap output '{}' was set aside as '{}'", destination, aside_path.display());
+        *broken_file = false;
+      }
+
+      FrameStream::open(destination)
+    });
+
+    if stream.is_some() {
+      self.stream = stream;
+    }
+  }
+
+  /// Forgets the broken stream: it will be reopened for the next message, if possible
+  fn forget_stream<E: Display>(&mut self, err: E) {
+    self.stream = None;
+    self.broken_file = match self.destination {
+      DnstapDestination::File(_) => true,
+      DnstapDestination::UnixSocket(_) => false,
+    };
+    self.reopener.broken(err);
+  }
+
+  /// Writes an (encoded) message to the destination, (re)opening it if needed
+  fn write_message(&mut self, payload: &[u8]) {
+    if self.stream.is_none() {
+      self.reopen(Instant::now());
+    }
+
+    if let Some(Err(err)) = self.stream.as_mut().map(|s| s.write_data_frame(payload)) {
+      self.forget_stream(err);
+    }
+  }
+
+  /// Flushes what was written so far to the destination (if open)
+  fn flush(&mut self) {
+    if let Some(Err(err)) = self.stream.as_mut().map(FrameStream::flush) {
+      self.forget_stream(err);
+    }
+  }
+
+  /// Stops the Frame Stream (if open)
+  fn close(&mut self) {
+    if let Some(Err(err)) = self.stream.take().map(FrameStream::close) {
+      warn!("Unable to close dnstap output '{}' cleanly: {}", self.destination, err);
+    }
+  }
+
+}
+
+/// Moves a file aside (i.e. renames it, with the current time as extension), returning its new path
+fn set_aside(path: &Path) -> io::Result<PathBuf> {
+  let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or_default();
+  let mut aside_path = path.as_os_str().to_owned();
+  aside_path.push(format!(".{}", millis));
+  let aside_path = PathBuf::from(aside_path);
+
+  fs::rename(path, &aside_path)?;
+  Ok(aside_path)
+}
+
+/// An open Frame Stream, started and ready to receive data frames
+enum FrameStream {
+  File(BufWriter<File>),
+  UnixSocket(UnixStream),
+}
+
+impl FrameStream {
+
+  /// Opens the destination and starts the Frame Stream (with a handshake, if bidirectional)
+  ///
+  /// A file is truncated: a Frame Stream is never started after whatever the file already contains.
+  fn open(destination: &DnstapDestination) -> io::Result<FrameStream> {
+    match destination {
+      DnstapDestination::File(path) => {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&control_frame(FSTRM_CONTROL_START, true))?;
+
+        Ok(FrameStream::File(file))
+      },
+      DnstapDestination::UnixSocket(path) => {
+        let mut socket = connect_unix_socket(path)?;
+        socket.set_read_timeout(Some(Duration::from_secs(DNSTAP_WRITER_HANDSHAKE_TIMEOUT_SEC)))?;
+
+        socket.write_all(&control_frame(FSTRM_CONTROL_READY, true))?;
+        expect_control_frame(&mut socket, FSTRM_CONTROL_ACCEPT)?;
+        socket.write_all(&control_frame(FSTRM_CONTROL_START, true))?;
+
+        Ok(FrameStream::UnixSocket(socket))
+      },
+    }
+  }
+
+  fn write_data_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+    // Length and payload are written at once, so that a frame is never left half-written in the buffer
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+
+    match self {
+      FrameStream::File(file) => file.write_all(&frame),
+      FrameStream::UnixSocket(socket) => socket.write_all(&frame),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      FrameStream::File(file) => file.flush(),
+      FrameStream::UnixSocket(socket) => socket.flush(),
+    }
+  }
+
+  /// Stops the Frame Stream (waiting for acknowledgement, if bidirectional)
+  fn close(self) -> io::Result<()> {
+    match self {
+      FrameStream::File(mut file) => {
+        file.write_all(&control_frame(FSTRM_CONTROL_STOP, false))?;
+        file.flush()
+      },
+      FrameStream::UnixSocket(mut socket) => {
+        socket.write_all(&control_frame(FSTRM_CONTROL_STOP, false))?;
+        expect_control_frame(&mut socket, FSTRM_CONTROL_FINISH)
+      },
+    }
+  }
+
+}
+
+/// Builds a control frame, optionally with the content type field
+fn control_frame(control_type: u32, with_content_type: bool) -> Vec<u8> {
+  let mut control = control_type.to_be_bytes().to_vec();
+  if with_content_type {
+    control.extend_from_slice(&FSTRM_CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+    control.extend_from_slice(&(FSTRM_CONTENT_TYPE.len() as u32).to_be_bytes());
+    control.extend_from_slice(FSTRM_CONTENT_TYPE.as_bytes());
+  }
+
+  // Control frames are "escaped" by a zero length
+  let mut frame = 0u32.to_be_bytes().to_vec();
+  frame.extend_from_slice(&(control.len() as u32).to_be_bytes());
+  frame.extend_from_slice(&control);
+
+  frame
+}
+
+/// Reads a control frame, and checks it's of the expected type
+fn expect_control_frame<R: Read>(reader: &mut R, expected_control_type: u32) -> io::Result<()> {
+  let mut word = [0u8; 4];
+
+  reader.read_exact(&mut word)?;
+  if u32::from_be_bytes(word) != 0 {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "Expected a control frame, received a data frame"));
+  }
+
+  reader.read_exact(&mut word)?;
+  let control_len = u32::from_be_bytes(word) as usize;
+  if control_len < 4 || control_len > FSTRM_CONTROL_FRAME_MAX_SIZE {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid control frame length: {}", control_len)));
+  }
+
+  let mut control = vec![0u8; control_len];
+  reader.read_exact(&mut control)?;
+  let control_type = u32::from_be_bytes([control[0], control[1], control[2], control[3]]);
+  if control_type != expected_control_type {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected control frame of type {}, received {}", expected_control_type, control_type)));
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::{fs, env, os::unix::net::UnixListener};
+
+  #[test]
+  fn should_write_frame_stream_to_file() {
+    let path = env::temp_dir().join(format!("mooncell-dnstap-test-{}.fstrm", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let destination = DnstapDestination::File(path.clone());
+
+    let mut output = DnstapOutput::new(destination);
+    output.write_message(b"first");
+    output.write_message(b"second");
+    output.stream.take().unwrap().close().unwrap();
+
+    let mut expected = control_frame(FSTRM_CONTROL_START, true);
+    expected.extend_from_slice(&[0, 0, 0, 5]);
+    expected.extend_from_slice(b"first");
+    expected.extend_from_slice(&[0, 0, 0, 6]);
+    expected.extend_from_slice(b"second");
+    expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, FSTRM_CONTROL_STOP as u8]);
+    assert_eq!(fs::read(&path).unwrap(), expected);
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn should_set_aside_broken_file_when_reopening() {
+    let dir = env::temp_dir().join(format!("mooncell-dnstap-test-reopen-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    let path = dir.join("dnstap.fstrm");
+    let destination = DnstapDestination::File(path.clone());
+
+    let mut output = DnstapOutput::new(destination);
+    output.write_message(b"before");
+    output.forget_stream("disk full");
+    output.write_message(b"after");
+    output.close();
+
+    // The new file holds a Frame Stream of its own
+    let mut expected = control_frame(FSTRM_CONTROL_START, true);
+    expected.extend_from_slice(&[0, 0, 0, 5]);
+    expected.extend_from_slice(b"after");
+    expected.extend_from_slice(&control_frame(FSTRM_CONTROL_STOP, false));
+    assert_eq!(fs::read(&path).unwrap(), expected);
+
+    // The broken one is kept as it was, without a new `START` appended to it
+    let aside_paths: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).filter(|p| p != &path).collect();
+    assert_eq!(aside_paths.len(), 1);
+    let mut expected = control_frame(FSTRM_CONTROL_START, true);
+    expected.extend_from_slice(&[0, 0, 0, 6]);
+    expected.extend_from_slice(b"before");
+    assert_eq!(fs::read(&aside_paths[0]).unwrap(), expected);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn should_handshake_with_unix_socket_collector() {
+    let path = env::temp_dir().join(format!("mooncell-dnstap-test-{}.sock", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+
+    // A minimal collector: accepts, then reads everything until STOP and acknowledges with FINISH
+    let collector = thread::spawn(move || {
+      let (mut socket, _) = listener.accept().unwrap();
+      expect_control_frame(&mut socket, FSTRM_CONTROL_READY).unwrap();
+      socket.write_all(&control_frame(FSTRM_CONTROL_ACCEPT, true)).unwrap();
+      expect_control_frame(&mut socket, FSTRM_CONTROL_START).unwrap();
+
+      let mut len = [0u8; 4];
+      socket.read_exact(&mut len).unwrap();
+      let mut payload = vec![0u8; u32::from_be_bytes(len) as usize];
+      socket.read_exact(&mut payload).unwrap();
+
+      expect_control_frame(&mut socket, FSTRM_CONTROL_STOP).unwrap();
+      socket.write_all(&control_frame(FSTRM_CONTROL_FINISH, false)).unwrap();
+
+      payload
+    });
+
+    let mut output = DnstapOutput::new(DnstapDestination::UnixSocket(path.clone()));
+    output.write_message(b"message");
+    output.stream.take().unwrap().close().unwrap();
+
+    assert_eq!(collector.join().unwrap(), b"message".to_vec());
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -9,8 +9,9 @@
 //!
 //! As I type this, standardization work has began _only_ for
 //! ["raw" DNS-over-HTTPS](https://datatracker.ietf.org/doc/rfc8484/): sending DNS message as binary
-//! body in an HTTPS request. This modules is ignoring this specification (for now).
+//! body in an HTTPS request. That specification is implemented in `doh_wire`.
 
 pub mod response;
+pub mod rdata;
 pub mod provider;
 pub mod resolver;
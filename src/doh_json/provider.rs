@@ -1,18 +1,19 @@
 //! DoH JSON provider(s)
 
-use crate::core::{provider::DoHProvider, protocol::DoHProtocol};
+use crate::core::{provider::{DoHProvider, DoHProviderUrlError, parse_provider_url}, protocol::DoHProtocol, mode::DoHRequestMode};
 use crate::dns::protocol::*;
+use crate::net::http::{HttpBootstrapAddresses, HttpProviderId, HttpExpectedContentTypes};
 
 use http::{
   method::Method,
   version::Version,
   uri::{Builder as UriBuilder, Scheme, Authority, PathAndQuery},
-  header::{HeaderMap, self},
+  header::{HeaderMap, HeaderValue, self},
   request::{Request, Builder as RequestBuilder},
   Result,
 };
 
-use std::{collections::HashMap, str::FromStr};
+use std::{collections::HashMap, str::FromStr, net::IpAddr};
 
 // TODO Add support for optional parameters: hopefully Google and the others have compatible,
 //  optional parameters
@@ -20,11 +21,12 @@ use std::{collections::HashMap, str::FromStr};
 /// Describes a provider of DNS-over-HTTPS services
 #[derive(Debug, Clone)]
 pub struct DoHJsonProvider {
-  id: &'static str,
+  id: String,
   scheme: Scheme,
   authority: Authority,
   path_query: PathAndQuery,
   headers: HeaderMap,
+  bootstrap: Vec<IpAddr>,
 }
 
 impl DoHJsonProvider {
@@ -36,13 +38,15 @@ impl DoHJsonProvider {
   /// * `raw_scheme` - `&str` representing the scheme of a URI (ex. "http", "https" or others)
   /// * `raw_authority` - `&str` representing the authority of a URI (ex. "example.com" or "other-example.com:8081")
   /// * `raw_path_query` - `&str` representing the path and query of a URI (ex. "/path/to/file?q1=v1&q2=v2")
-  fn from_raw_parts(id: &'static str, raw_scheme: &str, raw_authority: &str, raw_path_query: &str) -> DoHJsonProvider {
+  /// * `raw_bootstrap` - `&str`s representing the IP addresses the authority can be reached at (ex. "1.1.1.1")
+  fn from_raw_parts(id: &'static str, raw_scheme: &str, raw_authority: &str, raw_path_query: &str, raw_bootstrap: &[&str]) -> DoHJsonProvider {
     DoHJsonProvider::from_parts(
-      id,
+      id.to_string(),
       raw_scheme.parse().unwrap(),
       raw_authority.parse().unwrap(),
       raw_path_query.parse().unwrap(),
-      HeaderMap::default()
+      HeaderMap::default(),
+      raw_bootstrap.iter().map(|raw_ip| raw_ip.parse().unwrap()).collect()
     )
   }
 
@@ -55,13 +59,15 @@ impl DoHJsonProvider {
   /// * `raw_authority` - `&str` representing the authority of a URI (ex. "example.com" or "other-example.com:8081")
   /// * `raw_path_query` - `&str` representing the path and query of a URI (ex. "/path/to/file?q1=v1&q2=v2")
   /// * `headers` - an `HeaderMap` as defined by the `http` crate
-  fn from_raw_parts_with_headers(id: &'static str, raw_scheme: &str, raw_authority: &str, raw_path_query: &str, headers: HeaderMap) -> DoHJsonProvider {
+  /// * `raw_bootstrap` - `&str`s representing the IP addresses the authority can be reached at (ex. "1.1.1.1")
+  fn from_raw_parts_with_headers(id: &'static str, raw_scheme: &str, raw_authority: &str, raw_path_query: &str, headers: HeaderMap, raw_bootstrap: &[&str]) -> DoHJsonProvider {
     DoHJsonProvider::from_parts(
-      id,
+      id.to_string(),
       raw_scheme.parse().unwrap(),
       raw_authority.parse().unwrap(),
       raw_path_query.parse().unwrap(),
-      headers
+      headers,
+      raw_bootstrap.iter().map(|raw_ip| raw_ip.parse().unwrap()).collect()
     )
   }
 
@@ -74,17 +80,51 @@ impl DoHJsonProvider {
   /// * `authority` - `Authority` of a URI (ex. "example.com" or "other-example.com:8081")
   /// * `path_query` - `PathAndQuery` of a URI (ex. "/path/to/file?q1=v1&q2=v2")
   /// * `headers` - an `HeaderMap` as defined by the `http` crate
-  fn from_parts(id: &'static str, scheme: Scheme, authority: Authority, path_query: PathAndQuery, headers: HeaderMap) -> DoHJsonProvider {
+  /// * `bootstrap` - IP addresses the authority can be reached at
+  fn from_parts(id: String, scheme: Scheme, authority: Authority, path_query: PathAndQuery, headers: HeaderMap, bootstrap: Vec<IpAddr>) -> DoHJsonProvider {
     DoHJsonProvider {
       id,
       scheme,
       authority,
       path_query,
-      headers
+      headers,
+      bootstrap
     }
   }
+
+  /// Constructor for a user-defined Provider, from its URL
+  ///
+  /// Unless already in the given headers, `Accept: application/dns-json` is added, as some
+  /// providers require it to respond in JSON.
+  ///
+  /// # Parameters
+  ///
+  /// * `id` - Identifier of this Provider
+  /// * `raw_url` - `&str` representing the URL of the Provider (ex. "https://doh.example.com/resolve")
+  /// * `headers` - an `HeaderMap` as defined by the `http` crate
+  /// * `bootstrap` - IP addresses the Provider can be reached at (can be empty)
+  pub fn from_url(id: &str, raw_url: &str, mut headers: HeaderMap, bootstrap: Vec<IpAddr>) -> std::result::Result<DoHJsonProvider, DoHProviderUrlError> {
+    let (scheme, authority, path_query) = parse_provider_url(raw_url)?;
+
+    if !headers.contains_key(header::ACCEPT) {
+      headers.insert(header::ACCEPT, HeaderValue::from_static(DNS_JSON_MEDIA_TYPE));
+    }
+
+    Ok(DoHJsonProvider::from_parts(id.to_string(), scheme, authority, path_query, headers, bootstrap))
+  }
 }
 
+/// Media type of DNS messages exchanged in JSON format
+pub const DNS_JSON_MEDIA_TYPE: &'static str = "application/dns-json";
+
+/// Media types a DoH JSON response can declare: providers don't all use `DNS_JSON_MEDIA_TYPE`
+pub const DNS_JSON_RESPONSE_MEDIA_TYPES: &'static [&'static str] = &[
+  DNS_JSON_MEDIA_TYPE,
+  "application/json",
+  "application/x-javascript",
+  "text/javascript",
+];
+
 /// Static `&str` identifier for [Google Public DNS-over-HTTPS](https://developers.google.com/speed/public-dns/docs/dns-over-https) provider
 pub const PROVIDER_NAME_GOOGLE: &'static str = "google";
 /// Static `&str` identifier for [Cloudflare DNS-over-HTTPS](https://developers.cloudflare.com/1.1.1.1/dns-over-https/json-format/) provider
@@ -100,17 +140,49 @@ pub const PROVIDER_NAME_RUBYFISH: &'static str = "rubyfish";
 /// Static `&str` identifier for [BlahDNS DNS-over-HTTPS](https://blahdns.com/) provider (Preferable in Japan)
 pub const PROVIDER_NAME_BLAHDNS:  &'static str = "blahdns";
 
+/// Addresses the Google Public DNS DNS-over-HTTPS provider can be reached at, without resolving its hostname
+pub const PROVIDER_BOOTSTRAP_GOOGLE: &[&str] = &["8.8.8.8", "8.8.4.4", "2001:4860:4860::8888", "2001:4860:4860::8844"];
+/// Addresses the Cloudflare DNS DNS-over-HTTPS provider can be reached at, without resolving its hostname
+pub const PROVIDER_BOOTSTRAP_CLOUDFLARE: &[&str] = &["1.1.1.1", "1.0.0.1", "2606:4700:4700::1111", "2606:4700:4700::1001"];
+/// Addresses the Quad9 "Recommended" DNS-over-HTTPS provider can be reached at, without resolving its hostname
+pub const PROVIDER_BOOTSTRAP_QUAD9: &[&str] = &["9.9.9.9", "149.112.112.112", "2620:fe::fe", "2620:fe::9"];
+/// Addresses the Quad9 "Secured" DNS-over-HTTPS provider can be reached at, without resolving its hostname
+pub const PROVIDER_BOOTSTRAP_QUAD9_SECURED: &[&str] = &["9.9.9.9", "149.112.112.9", "2620:fe::9", "2620:fe::fe:9"];
+/// Addresses the Quad9 "Unsecured" DNS-over-HTTPS provider can be reached at, without resolving its hostname
+pub const PROVIDER_BOOTSTRAP_QUAD9_UNSECURED: &[&str] = &["9.9.9.10", "149.112.112.10", "2620:fe::10", "2620:fe::fe:10"];
+/// Addresses the Rubyfish DNS-over-HTTPS provider can be reached at, without resolving its hostname
+pub const PROVIDER_BOOTSTRAP_RUBYFISH: &[&str] = &["118.89.110.78", "47.96.179.163"];
+/// Addresses the BlahDNS DNS-over-HTTPS provider can be reached at, without resolving its hostname
+pub const PROVIDER_BOOTSTRAP_BLAHDNS: &[&str] = &["159.69.198.101", "2a01:4f8:1c1c:6b4b::1"];
+
 impl DoHProvider for DoHJsonProvider {
 
-  fn id(&self) -> &'static str {
-    self.id
+  fn id(&self) -> &str {
+    &self.id
   }
 
   fn protocol(&self) -> DoHProtocol {
     DoHProtocol::JSON
   }
 
-  fn build_http_request(&self, dns_query: &DnsQuery) -> Result<Request<()>> {
+  /// DoH JSON only supports `GET` requests
+  fn request_mode(&self) -> DoHRequestMode {
+    DoHRequestMode::GET
+  }
+
+  fn hostname(&self) -> &str {
+    self.authority.host()
+  }
+
+  fn bootstrap_addresses(&self) -> &[IpAddr] {
+    &self.bootstrap
+  }
+
+  fn set_bootstrap_addresses(&mut self, addresses: Vec<IpAddr>) {
+    self.bootstrap = addresses;
+  }
+
+  fn build_http_request(&self, dns_query: &DnsQuery) -> Result<Request<Vec<u8>>> {
     // Prepare Path and Query parts of the request, combining the Provider "required" parts
     // with the actual DNS Query
     let query_type: &str = dns_query.query_type().into();
@@ -141,7 +213,14 @@ impl DoHProvider for DoHJsonProvider {
       req_builder.header(hkey, hval);
     }
 
-    req_builder.body(())
+    // Adding addresses to reach the Provider at (if any)
+    if !self.bootstrap.is_empty() {
+      req_builder.extension(HttpBootstrapAddresses(self.bootstrap.clone()));
+    }
+    req_builder.extension(HttpProviderId(self.id.clone()));
+    req_builder.extension(HttpExpectedContentTypes(DNS_JSON_RESPONSE_MEDIA_TYPES));
+
+    req_builder.body(Vec::new())
   }
 
   fn available() -> HashMap<&'static str, Self> {
@@ -152,52 +231,59 @@ impl DoHProvider for DoHJsonProvider {
       PROVIDER_NAME_GOOGLE,
       "https",
       "dns.google.com",
-      "/resolve"
+      "/resolve",
+      PROVIDER_BOOTSTRAP_GOOGLE
     ));
     // Cloudflare
     let mut cloudflare_headers = HeaderMap::with_capacity(1);
-    cloudflare_headers.insert(header::ACCEPT, "application/dns-json".parse().unwrap());
+    cloudflare_headers.insert(header::ACCEPT, HeaderValue::from_static(DNS_JSON_MEDIA_TYPE));
     providers.insert(PROVIDER_NAME_CLOUDFLARE, DoHJsonProvider::from_raw_parts_with_headers(
       PROVIDER_NAME_CLOUDFLARE,
       "https",
       "cloudflare-dns.com",
       "/dns-query",
-      cloudflare_headers
+      cloudflare_headers,
+      PROVIDER_BOOTSTRAP_CLOUDFLARE
     ));
     // Quad9 recommended
     providers.insert(PROVIDER_NAME_QUAD9, DoHJsonProvider::from_raw_parts(
       PROVIDER_NAME_QUAD9,
       "https",
       "dns.quad9.net",
-      "/dns-query"
+      "/dns-query",
+      PROVIDER_BOOTSTRAP_QUAD9
     ));
     // Quad9 secured
     providers.insert(PROVIDER_NAME_QUAD9_SECURED, DoHJsonProvider::from_raw_parts(
       PROVIDER_NAME_QUAD9_SECURED,
       "https",
       "dns9.quad9.net",
-      "/dns-query"
+      "/dns-query",
+      PROVIDER_BOOTSTRAP_QUAD9_SECURED
     ));
     // Quad9 unsecured
     providers.insert(PROVIDER_NAME_QUAD9_UNSECURED, DoHJsonProvider::from_raw_parts(
       PROVIDER_NAME_QUAD9_UNSECURED,
       "https",
       "dns10.quad9.net",
-      "/dns-query"
+      "/dns-query",
+      PROVIDER_BOOTSTRAP_QUAD9_UNSECURED
     ));
     // Rubyfish
     providers.insert(PROVIDER_NAME_RUBYFISH, DoHJsonProvider::from_raw_parts(
       PROVIDER_NAME_RUBYFISH,
       "https",
       "dns.rubyfish.cn",
-      "/dns-query"
+      "/dns-query",
+      PROVIDER_BOOTSTRAP_RUBYFISH
     ));
     // BlahDNS
     providers.insert(PROVIDER_NAME_BLAHDNS, DoHJsonProvider::from_raw_parts(
       PROVIDER_NAME_BLAHDNS,
       "https",
       "doh-de.blahdns.com",
-      "/dns-query"
+      "/dns-query",
+      PROVIDER_BOOTSTRAP_BLAHDNS
     ));
 
     providers
@@ -229,10 +315,11 @@ mod test {
       assert_eq!(http_request.version(), Version::HTTP_11);
       assert_eq!(http_request.uri().to_string(), "https://cloudflare-dns.com/dns-query?type=AAAA&name=ivandemarino.me.");
       assert_eq!(http_request.extensions().get::<bool>(), None);
+      assert_eq!(http_request.extensions().get::<HttpBootstrapAddresses>().unwrap().0, provider.bootstrap_addresses());
       assert!(http_request.headers().contains_key(header::ACCEPT));
       assert_eq!(http_request.headers().get(header::ACCEPT).unwrap(), &"application/dns-json");
       assert_eq!(http_request.headers().len(), 1);
-      assert_eq!(http_request.body(), &());
+      assert!(http_request.body().is_empty());
     }
   }
 
@@ -249,7 +336,7 @@ mod test {
     assert_eq!(http_request.uri().to_string(), "https://dns.google.com/resolve?type=A&name=github.com.");
     assert_eq!(http_request.extensions().get::<bool>(), None);
     assert_eq!(http_request.headers().len(), 0);
-    assert_eq!(http_request.body(), &());
+    assert!(http_request.body().is_empty());
   }
 
   #[test]
@@ -265,7 +352,7 @@ mod test {
     assert_eq!(http_request.uri().to_string(), "https://dns.quad9.net/dns-query?type=A&name=github.com.");
     assert_eq!(http_request.extensions().get::<bool>(), None);
     assert_eq!(http_request.headers().len(), 0);
-    assert_eq!(http_request.body(), &());
+    assert!(http_request.body().is_empty());
   }
 
   #[test]
@@ -281,7 +368,7 @@ mod test {
     assert_eq!(http_request.uri().to_string(), "https://dns9.quad9.net/dns-query?type=A&name=github.com.");
     assert_eq!(http_request.extensions().get::<bool>(), None);
     assert_eq!(http_request.headers().len(), 0);
-    assert_eq!(http_request.body(), &());
+    assert!(http_request.body().is_empty());
   }
 
   #[test]
@@ -297,7 +384,7 @@ mod test {
     assert_eq!(http_request.uri().to_string(), "https://dns10.quad9.net/dns-query?type=A&name=github.com.");
     assert_eq!(http_request.extensions().get::<bool>(), None);
     assert_eq!(http_request.headers().len(), 0);
-    assert_eq!(http_request.body(), &());
+    assert!(http_request.body().is_empty());
   }
 
   #[test]
@@ -313,7 +400,7 @@ mod test {
     assert_eq!(http_request.uri().to_string(), "https://dns.rubyfish.cn/dns-query?type=A&name=apple.com.");
     assert_eq!(http_request.extensions().get::<bool>(), None);
     assert_eq!(http_request.headers().len(), 0);
-    assert_eq!(http_request.body(), &());
+    assert!(http_request.body().is_empty());
   }
 
   #[test]
@@ -329,7 +416,42 @@ mod test {
     assert_eq!(http_request.uri().to_string(), "https://doh-de.blahdns.com/dns-query?type=A&name=apple.com.");
     assert_eq!(http_request.extensions().get::<bool>(), None);
     assert_eq!(http_request.headers().len(), 0);
-    assert_eq!(http_request.body(), &());
+    assert!(http_request.body().is_empty());
+  }
+
+  #[test]
+  fn should_provide_bootstrap_addresses() {
+    for (_, mut provider) in DoHJsonProvider::available() {
+      assert!(!provider.bootstrap_addresses().is_empty());
+
+      provider.set_bootstrap_addresses(vec![]);
+      let example_query = DnsQuery::query(DnsDomainName::from_str("apple.com.").unwrap(), DnsRecordType::A);
+      let http_request = provider.build_http_request(&example_query).unwrap();
+      assert_eq!(http_request.extensions().get::<HttpBootstrapAddresses>(), None);
+    }
+
+    let provider = DoHJsonProvider::default();
+    assert_eq!(provider.hostname(), "cloudflare-dns.com");
+    assert!(provider.bootstrap_addresses().contains(&"1.1.1.1".parse().unwrap()));
+  }
+
+  #[test]
+  fn should_build_custom_provider_from_url() {
+    let example_query = DnsQuery::query(DnsDomainName::from_str("github.com.").unwrap(), DnsRecordType::A);
+    let mut headers = HeaderMap::new();
+    headers.insert("x-api-key", HeaderValue::from_static("secret"));
+
+    let provider = DoHJsonProvider::from_url("internal", "https://doh.internal.example/resolve", headers, vec!["10.0.0.53".parse().unwrap()]).unwrap();
+    assert_eq!(provider.id(), "internal");
+    assert_eq!(provider.hostname(), "doh.internal.example");
+
+    let http_request = provider.build_http_request(&example_query).unwrap();
+    assert_eq!(http_request.uri().to_string(), "https://doh.internal.example/resolve?type=A&name=github.com.");
+    assert_eq!(http_request.headers().get(header::ACCEPT).unwrap(), &DNS_JSON_MEDIA_TYPE);
+    assert_eq!(http_request.headers().get("x-api-key").unwrap(), &"secret");
+    assert_eq!(http_request.extensions().get::<HttpBootstrapAddresses>().unwrap().0, provider.bootstrap_addresses());
+
+    assert!(DoHJsonProvider::from_url("internal", "doh.internal.example", HeaderMap::new(), vec![]).is_err());
   }
 
   #[test]
@@ -0,0 +1,562 @@
+//! Conversion of the `data` of DoH JSON records, in DNS presentation format, into `DnsRData`
+//!
+//! The presentation format (ex. `10 mail.example.com.` for a `MX` record) is first converted to
+//! the wire format, then decoded by Trust-DNS: this way all the record types it supports are
+//! covered, with the same validation applied to responses from DoH Wire providers. Data in the
+//! [RFC 3597](https://tools.ietf.org/html/rfc3597#section-5) generic format (`\# LEN HEX`) is
+//! accepted for every record type.
+
+use crate::dns::protocol::*;
+
+use log::*;
+use chrono::NaiveDateTime;
+
+use std::{fmt, str::FromStr, net::{Ipv4Addr, Ipv6Addr}};
+
+const GENERIC_RDATA_PREFIX: &'static str = "\\#";
+const CHARACTER_STRING_MAX_LEN: usize = 255;
+const BASE32HEX_ALPHABET: &'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+/// Record types that Trust-DNS doesn't know about, but whose presentation format is supported
+const RECORD_TYPE_DNAME: u16 = 39;
+const RECORD_TYPE_SVCB: u16 = 64;
+const RECORD_TYPE_HTTPS: u16 = 65;
+
+/// Mnemonics of the record types that `DnsRecordType::from_str` doesn't parse
+const RECORD_TYPE_MNEMONICS: &[(&'static str, u16)] = &[
+  ("KEY", 25),
+  ("SIG", 24),
+  ("DNAME", RECORD_TYPE_DNAME),
+  ("DS", 43),
+  ("RRSIG", 46),
+  ("NSEC", 47),
+  ("DNSKEY", 48),
+  ("NSEC3", 50),
+  ("NSEC3PARAM", 51),
+  ("CDS", 59),
+  ("CDNSKEY", 60),
+  ("SVCB", RECORD_TYPE_SVCB),
+  ("HTTPS", RECORD_TYPE_HTTPS),
+];
+
+/// Keys of the `SVCB` / `HTTPS` service parameters (see [RFC 9460](https://tools.ietf.org/html/rfc9460#section-14.3.2))
+const SVC_PARAM_KEYS: &[&'static str] = &["mandatory", "alpn", "no-default-alpn", "port", "ipv4hint", "ech", "ipv6hint"];
+
+/// Converts the `data` of a DoH JSON record, in presentation format, into `DnsRData`
+///
+/// If the data can be converted to the wire format, but Trust-DNS can't decode it (ex. a `DNSKEY`
+/// with an algorithm it doesn't support), the raw data is kept as it is.
+///
+/// # Parameters
+///
+/// * `record_type` - the type of the record
+/// * `data` - the data of the record, in presentation format
+pub fn parse_rdata(record_type: DnsRecordType, data: &str) -> Result<DnsRData, RDataParseError> {
+  let err = || RDataParseError::new(record_type, data);
+
+  match record_type {
+    DnsRecordType::ANY | DnsRecordType::AXFR | DnsRecordType::IXFR | DnsRecordType::OPT | DnsRecordType::ZERO => return Err(err()),
+    _ => {},
+  };
+
+  let wire = if data.trim_start().starts_with(GENERIC_RDATA_PREFIX) {
+    generic_to_wire(&data.trim_start()[GENERIC_RDATA_PREFIX.len()..])
+  } else {
+    presentation_to_wire(record_type, data)
+  }.filter(|wire| wire.len() <= u16::max_value() as usize).ok_or_else(err)?;
+
+  let mut decoder = BinDecoder::new(&wire);
+  match DnsRData::read(&mut decoder, record_type, Restrict::new(wire.len() as u16)) {
+    Ok(rdata) => Ok(rdata),
+    Err(decode_err) => {
+      debug!("Keeping raw data of DNS Record of type {} ({}): {}", record_type, decode_err, data);
+      Ok(DnsRData::Unknown { code: record_type.into(), rdata: DnsRDataNULL::with(wire) })
+    }
+  }
+}
+
+/// Error that happens when the `data` of a DoH JSON record can't be converted into `DnsRData`
+#[derive(Debug, Clone)]
+pub struct RDataParseError {
+  record_type: DnsRecordType,
+  token: String
+}
+
+impl RDataParseError {
+  fn new(record_type: DnsRecordType, token: &str) -> Self {
+    Self {
+      record_type,
+      token: token.to_string()
+    }
+  }
+}
+
+impl fmt::Display for RDataParseError {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmtr, "Invalid data for DNS Record of type {}: {}", self.record_type, self.token)
+  }
+}
+
+/// Converts data in the generic format (`LEN HEX`, after the `\#` prefix) to the wire format
+fn generic_to_wire(raw_generic: &str) -> Option<Vec<u8>> {
+  let mut parts = raw_generic.split_whitespace();
+  let len: usize = parts.next()?.parse().ok()?;
+  let wire = parse_hex(&parts.collect::<String>())?;
+
+  Some(wire).filter(|wire| wire.len() == len)
+}
+
+/// Converts data in presentation format to the wire format
+fn presentation_to_wire(record_type: DnsRecordType, data: &str) -> Option<Vec<u8>> {
+  let mut wire = Vec::new();
+
+  // Unquoted text is taken as it is, split in as many strings as needed
+  if record_type == DnsRecordType::TXT && !data.trim_start().starts_with('"') {
+    for chunk in data.trim().as_bytes().chunks(CHARACTER_STRING_MAX_LEN) {
+      push_character_string(&mut wire, chunk)?;
+    }
+    if wire.is_empty() {
+      push_character_string(&mut wire, &[])?;
+    }
+    return Some(wire);
+  }
+
+  let mut fields = Fields::new(data)?;
+  match record_type {
+    DnsRecordType::A => wire.extend_from_slice(&fields.number::<Ipv4Addr>()?.octets()),
+    DnsRecordType::AAAA => wire.extend_from_slice(&fields.number::<Ipv6Addr>()?.octets()),
+    DnsRecordType::NS | DnsRecordType::CNAME | DnsRecordType::PTR | DnsRecordType::ANAME => push_name(&mut wire, &fields.name()?),
+    DnsRecordType::MX => {
+      push_u16(&mut wire, fields.number()?);
+      push_name(&mut wire, &fields.name()?);
+    },
+    DnsRecordType::SOA => {
+      push_name(&mut wire, &fields.name()?);                  //< Primary name server
+      push_name(&mut wire, &fields.name()?);                  //< Responsible mailbox
+      for _ in 0..5 {                                         //< Serial, refresh, retry, expire and minimum TTL
+        push_u32(&mut wire, fields.number()?);
+      }
+    },
+    DnsRecordType::SRV => {
+      for _ in 0..3 {                                         //< Priority, weight and port
+        push_u16(&mut wire, fields.number()?);
+      }
+      push_name(&mut wire, &fields.name()?);
+    },
+    DnsRecordType::TXT => {
+      while let Some(string) = fields.bytes() {
+        push_character_string(&mut wire, &string)?;
+      }
+    },
+    DnsRecordType::CAA => {
+      wire.push(fields.number()?);                            //< Flags
+      push_character_string(&mut wire, &fields.bytes()?)?;    //< Tag
+      wire.extend(fields.bytes()?);                           //< Value
+    },
+    DnsRecordType::NAPTR => {
+      push_u16(&mut wire, fields.number()?);                  //< Order
+      push_u16(&mut wire, fields.number()?);                  //< Preference
+      for _ in 0..3 {                                         //< Flags, services and regexp
+        push_character_string(&mut wire, &fields.bytes()?)?;
+      }
+      push_name(&mut wire, &fields.name()?);                  //< Replacement
+    },
+    DnsRecordType::SSHFP => {
+      wire.push(fields.number()?);                            //< Algorithm
+      wire.push(fields.number()?);                            //< Fingerprint type
+      wire.extend(parse_hex(&fields.rest())?);
+    },
+    DnsRecordType::TLSA => {
+      for _ in 0..3 {                                         //< Certificate usage, selector and matching type
+        wire.push(fields.number()?);
+      }
+      wire.extend(parse_hex(&fields.rest())?);
+    },
+    DnsRecordType::OPENPGPKEY => wire.extend(base64::decode(&fields.rest()).ok()?),
+    DnsRecordType::DNSSEC(dnssec_record_type) => match dnssec_record_type {
+      DnsDNSSECRecordType::DS => {
+        push_u16(&mut wire, fields.number()?);                //< Key tag
+        wire.push(fields.number()?);                          //< Algorithm
+        wire.push(fields.number()?);                          //< Digest type
+        wire.extend(parse_hex(&fields.rest())?);
+      },
+      DnsDNSSECRecordType::DNSKEY | DnsDNSSECRecordType::KEY => {
+        push_u16(&mut wire, fields.number()?);                //< Flags
+        wire.push(fields.number()?);                          //< Protocol
+        wire.push(fields.number()?);                          //< Algorithm
+        wire.extend(base64::decode(&fields.rest()).ok()?);
+      },
+      DnsDNSSECRecordType::RRSIG | DnsDNSSECRecordType::SIG => {
+        push_u16(&mut wire, parse_record_type(&fields.text()?)?);
+        wire.push(fields.number()?);                          //< Algorithm
+        wire.push(fields.number()?);                          //< Labels
+        push_u32(&mut wire, fields.number()?);                //< Original TTL
+        push_u32(&mut wire, parse_signature_time(&fields.text()?)?);
+        push_u32(&mut wire, parse_signature_time(&fields.text()?)?);
+        push_u16(&mut wire, fields.number()?);                //< Key tag
+        push_name(&mut wire, &fields.name()?);                //< Signer
+        wire.extend(base64::decode(&fields.rest()).ok()?);
+      },
+      DnsDNSSECRecordType::NSEC => {
+        push_name(&mut wire, &fields.name()?);                //< Next domain name
+        push_type_bitmaps(&mut wire, &mut fields)?;
+      },
+      DnsDNSSECRecordType::NSEC3 => {
+        push_nsec3_params(&mut wire, &mut fields)?;
+        let next_hashed_owner = parse_base32hex(&fields.text()?)?;
+        wire.push(next_hashed_owner.len() as u8);
+        wire.extend(next_hashed_owner);
+        push_type_bitmaps(&mut wire, &mut fields)?;
+      },
+      DnsDNSSECRecordType::NSEC3PARAM => push_nsec3_params(&mut wire, &mut fields)?,
+      DnsDNSSECRecordType::Unknown(_) => return None,
+    },
+    DnsRecordType::Unknown(RECORD_TYPE_DNAME) => push_name(&mut wire, &fields.name()?),
+    DnsRecordType::Unknown(RECORD_TYPE_SVCB) | DnsRecordType::Unknown(RECORD_TYPE_HTTPS) => {
+      push_u16(&mut wire, fields.number()?);                  //< Priority
+      push_name(&mut wire, &fields.name()?);                  //< Target
+      push_svc_params(&mut wire, &mut fields)?;
+    },
+    _ => return None,
+  };
+
+  // All the fields must have been used
+  if fields.bytes().is_some() {
+    return None;
+  }
+
+  Some(wire)
+}
+
+/// The fields of data in presentation format
+///
+/// Fields are separated by whitespaces, unless quoted; escapes (`\X` and `\DDD`) are resolved.
+struct Fields {
+  fields: std::vec::IntoIter<Vec<u8>>,
+}
+
+impl Fields {
+
+  fn new(data: &str) -> Option<Fields> {
+    let mut fields = Vec::new();
+    let mut field: Option<Vec<u8>> = None;
+    let mut quoted = false;
+
+    let mut bytes = data.bytes();
+    while let Some(byte) = bytes.next() {
+      match byte {
+        b'"' => {
+          quoted = !quoted;
+          field.get_or_insert_with(Vec::new);
+        },
+        b'\\' => {
+          let escaped = bytes.next()?;
+          let byte = if escaped.is_ascii_digit() {
+            let digits = [escaped, bytes.next()?, bytes.next()?];
+            std::str::from_utf8(&digits).ok()?.parse().ok()?
+          } else {
+            escaped
+          };
+          field.get_or_insert_with(Vec::new).push(byte);
+        },
+        byte if byte.is_ascii_whitespace() && !quoted => fields.extend(field.take()),
+        byte => field.get_or_insert_with(Vec::new).push(byte),
+      }
+    }
+    if quoted {
+      return None;
+    }
+    fields.extend(field);
+
+    Some(Fields {
+      fields: fields.into_iter(),
+    })
+  }
+
+  fn bytes(&mut self) -> Option<Vec<u8>> {
+    self.fields.next()
+  }
+
+  fn text(&mut self) -> Option<String> {
+    String::from_utf8(self.bytes()?).ok()
+  }
+
+  fn number<T: FromStr>(&mut self) -> Option<T> {
+    self.text()?.parse().ok()
+  }
+
+  fn name(&mut self) -> Option<DnsDomainName> {
+    let mut name = DnsDomainName::from_ascii(self.text()?).ok()?;
+    name.set_fqdn(true);
+
+    Some(name)
+  }
+
+  /// All the remaining fields, concatenated (ex. base64 split in multiple fields)
+  fn rest(&mut self) -> String {
+    self.fields.by_ref().filter_map(|field| String::from_utf8(field).ok()).collect()
+  }
+
+}
+
+fn push_u16(wire: &mut Vec<u8>, value: u16) {
+  wire.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_u32(wire: &mut Vec<u8>, value: u32) {
+  wire.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Pushes a domain name, uncompressed and preserving the case
+fn push_name(wire: &mut Vec<u8>, name: &DnsDomainName) {
+  for label in name.iter() {
+    wire.push(label.len() as u8);
+    wire.extend_from_slice(label);
+  }
+  wire.push(0);
+}
+
+fn push_character_string(wire: &mut Vec<u8>, string: &[u8]) -> Option<()> {
+  if string.len() > CHARACTER_STRING_MAX_LEN {
+    return None;
+  }
+  wire.push(string.len() as u8);
+  wire.extend_from_slice(string);
+
+  Some(())
+}
+
+/// Pushes the hash algorithm, flags, iterations and salt of `NSEC3` and `NSEC3PARAM`
+fn push_nsec3_params(wire: &mut Vec<u8>, fields: &mut Fields) -> Option<()> {
+  wire.push(fields.number()?);
+  wire.push(fields.number()?);
+  push_u16(wire, fields.number()?);
+
+  let salt = match fields.text()?.as_ref() {
+    "-" => Vec::new(),
+    raw_salt => parse_hex(raw_salt)?,
+  };
+  wire.push(salt.len() as u8);
+  wire.extend(salt);
+
+  Some(())
+}
+
+/// Pushes the remaining fields, record types, as type bitmaps (see [RFC 4034](https://tools.ietf.org/html/rfc4034#section-4.1.2))
+fn push_type_bitmaps(wire: &mut Vec<u8>, fields: &mut Fields) -> Option<()> {
+  let mut record_types = Vec::new();
+  while let Some(raw_record_type) = fields.text() {
+    record_types.push(parse_record_type(&raw_record_type)?);
+  }
+  record_types.sort();
+  record_types.dedup();
+
+  let mut record_types = record_types.into_iter().peekable();
+  while let Some(&first) = record_types.peek() {
+    let window = (first >> 8) as u8;
+    let mut bitmap = [0u8; 32];
+    while let Some(record_type) = record_types.peek().cloned().filter(|record_type| (record_type >> 8) as u8 == window) {
+      let bit = (record_type & 0xFF) as usize;
+      bitmap[bit / 8] |= 0x80 >> (bit % 8);
+      record_types.next();
+    }
+
+    let bitmap_len = bitmap.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+    wire.push(window);
+    wire.push(bitmap_len as u8);
+    wire.extend_from_slice(&bitmap[..bitmap_len]);
+  }
+
+  Some(())
+}
+
+/// Pushes the remaining fields, `KEY[=VALUE]` service parameters (see [RFC 9460](https://tools.ietf.org/html/rfc9460#section-2.2))
+fn push_svc_params(wire: &mut Vec<u8>, fields: &mut Fields) -> Option<()> {
+  let mut params: Vec<(u16, Vec<u8>)> = Vec::new();
+  while let Some(raw_param) = fields.text() {
+    let mut parts = raw_param.splitn(2, '=');
+    let key = parse_svc_param_key(parts.next()?)?;
+    let raw_value = parts.next().unwrap_or_default();
+
+    let mut value = Vec::new();
+    match key {
+      0 => for raw_key in raw_value.split(',') {
+        push_u16(&mut value, parse_svc_param_key(raw_key)?);
+      },
+      1 => for alpn_id in raw_value.split(',') {
+        push_character_string(&mut value, alpn_id.as_bytes())?;
+      },
+      2 if raw_value.is_empty() => {},
+      2 => return None,
+      3 => push_u16(&mut value, raw_value.parse().ok()?),
+      4 => for ip in raw_value.split(',') {
+        value.extend_from_slice(&ip.parse::<Ipv4Addr>().ok()?.octets());
+      },
+      5 => value.extend(base64::decode(raw_value).ok()?),
+      6 => for ip in raw_value.split(',') {
+        value.extend_from_slice(&ip.parse::<Ipv6Addr>().ok()?.octets());
+      },
+      _ => value.extend_from_slice(raw_value.as_bytes()),
+    };
+    params.push((key, value));
+  }
+
+  // Parameters must be in increasing order of key, with no duplicates
+  params.sort_by_key(|(key, _)| *key);
+  if params.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+    return None;
+  }
+  for (key, value) in params {
+    push_u16(wire, key);
+    push_u16(wire, value.len() as u16);
+    wire.extend(value);
+  }
+
+  Some(())
+}
+
+fn parse_svc_param_key(raw_key: &str) -> Option<u16> {
+  SVC_PARAM_KEYS.iter()
+    .position(|key| *key == raw_key)
+    .map(|key| key as u16)
+    .or_else(|| raw_key.strip_prefix("key").and_then(|raw_number| raw_number.parse().ok()))
+}
+
+/// Parses a record type, by mnemonic (ex. `MX`) or in the generic format (ex. `TYPE15`)
+fn parse_record_type(raw_record_type: &str) -> Option<u16> {
+  let raw_record_type = raw_record_type.to_ascii_uppercase();
+
+  RECORD_TYPE_MNEMONICS.iter()
+    .find(|(mnemonic, _)| *mnemonic == raw_record_type)
+    .map(|(_, code)| *code)
+    .or_else(|| raw_record_type.strip_prefix("TYPE").and_then(|raw_code| raw_code.parse().ok()))
+    .or_else(|| DnsRecordType::from_str(&raw_record_type).ok().map(u16::from))
+}
+
+/// Parses a signature expiration/inception time, as `YYYYMMDDHHmmSS` or seconds since the epoch
+fn parse_signature_time(raw_time: &str) -> Option<u32> {
+  if raw_time.len() == 14 {
+    NaiveDateTime::parse_from_str(raw_time, "%Y%m%d%H%M%S").ok().map(|time| time.timestamp() as u32)
+  } else {
+    raw_time.parse().ok()
+  }
+}
+
+fn parse_hex(raw_hex: &str) -> Option<Vec<u8>> {
+  if raw_hex.len() % 2 != 0 || !raw_hex.is_ascii() {
+    return None;
+  }
+
+  (0..raw_hex.len()).step_by(2)
+    .map(|i| u8::from_str_radix(&raw_hex[i..i + 2], 16).ok())
+    .collect()
+}
+
+/// Parses "base 32 encoding with extended hex alphabet", without padding (see [RFC 5155](https://tools.ietf.org/html/rfc5155#section-3.3))
+fn parse_base32hex(raw_base32hex: &str) -> Option<Vec<u8>> {
+  let mut bytes = Vec::new();
+  let mut buffer = 0u32;
+  let mut buffer_bits = 0;
+  for byte in raw_base32hex.bytes() {
+    let value = BASE32HEX_ALPHABET.iter().position(|symbol| *symbol == byte.to_ascii_uppercase())?;
+    buffer = (buffer << 5) | value as u32;
+    buffer_bits += 5;
+    if buffer_bits >= 8 {
+      buffer_bits -= 8;
+      bytes.push((buffer >> buffer_bits) as u8);
+    }
+  }
+
+  Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use trust_dns_proto::rr::dnssec::rdata::DNSSECRData as DnsRDataDNSSEC;
+
+  fn record_type(raw_record_type: &str) -> DnsRecordType {
+    DnsRecordType::from(parse_record_type(raw_record_type).unwrap())
+  }
+
+  fn name(raw_name: &str) -> DnsDomainName {
+    DnsDomainName::from_str(raw_name).unwrap()
+  }
+
+  #[test]
+  fn should_parse_common_records() {
+    assert_eq!(parse_rdata(DnsRecordType::MX, "10 mail.example.com.").unwrap(), DnsRData::MX(DnsRDataMX::new(10, name("mail.example.com."))));
+    assert_eq!(parse_rdata(DnsRecordType::SRV, "0 5 88 kdc.example.com.").unwrap(), DnsRData::SRV(DnsRDataSRV::new(0, 5, 88, name("kdc.example.com."))));
+    assert_eq!(parse_rdata(DnsRecordType::TXT, "\"v=spf1 -all\" \"say \\\"hi\\\"\\033\"").unwrap(), DnsRData::TXT(DnsRDataTXT::new(vec!["v=spf1 -all".into(), "say \"hi\"!".into()])));
+    assert_eq!(parse_rdata(DnsRecordType::TXT, "v=spf1 -all").unwrap(), DnsRData::TXT(DnsRDataTXT::new(vec!["v=spf1 -all".into()])));
+
+    match parse_rdata(DnsRecordType::SOA, "ns1.example.com. hostmaster.example.com. 2019061301 3600 600 604800 60").unwrap() {
+      DnsRData::SOA(soa) => {
+        assert_eq!(soa.rname(), &name("hostmaster.example.com."));
+        assert_eq!(soa.serial(), 2019061301);
+        assert_eq!(soa.minimum(), 60);
+      },
+      rdata => panic!("Unexpected rdata: {:?}", rdata),
+    };
+    match parse_rdata(DnsRecordType::CAA, "0 issue \"letsencrypt.org\"").unwrap() {
+      DnsRData::CAA(caa) => assert!(caa.tag().is_issue()),
+      rdata => panic!("Unexpected rdata: {:?}", rdata),
+    };
+    assert!(match parse_rdata(DnsRecordType::NAPTR, "100 10 \"S\" \"SIP+D2U\" \"\" _sip._udp.example.com.").unwrap() { DnsRData::NAPTR(_) => true, _ => false });
+    assert!(match parse_rdata(DnsRecordType::TLSA, "3 1 1 0C72AC70B745AC19998811B131D662C9AC69DBDBE7CB23E5B514B56664C5D3D6").unwrap() { DnsRData::TLSA(_) => true, _ => false });
+
+    assert!(parse_rdata(DnsRecordType::MX, "mail.example.com.").is_err());
+    assert!(parse_rdata(DnsRecordType::MX, "10 mail.example.com. extra").is_err());
+    assert!(parse_rdata(DnsRecordType::A, "fd00::10").is_err());
+    assert!(parse_rdata(DnsRecordType::TXT, "\"unterminated").is_err());
+    assert!(parse_rdata(DnsRecordType::OPT, "\\# 0").is_err());
+  }
+
+  #[test]
+  fn should_parse_dnssec_records() {
+    match parse_rdata(record_type("DS"), "2371 13 2 1F987CC6583E92DF0890718C42 49 76 BE B1 E8 B2 27 D1 4D 3F 1D A7 CD 50 4F 53 0B 4B 2B").unwrap() {
+      DnsRData::DNSSEC(DnsRDataDNSSEC::DS(ds)) => assert_eq!(ds.key_tag(), 2371),
+      rdata => panic!("Unexpected rdata: {:?}", rdata),
+    };
+    match parse_rdata(record_type("RRSIG"), "A 13 2 300 20190701000000 1561939200 34505 example.com. dGVzdA==").unwrap() {
+      DnsRData::DNSSEC(DnsRDataDNSSEC::SIG(sig)) => {
+        assert_eq!(sig.type_covered(), DnsRecordType::A);
+        assert_eq!(sig.sig_expiration(), 1561939200);
+        assert_eq!(sig.sig_inception(), 1561939200);
+        assert_eq!(sig.signer_name(), &name("example.com."));
+        assert_eq!(sig.sig(), b"test");
+      },
+      rdata => panic!("Unexpected rdata: {:?}", rdata),
+    };
+    match parse_rdata(record_type("NSEC"), "www.example.com. A MX RRSIG NSEC TYPE1234").unwrap() {
+      DnsRData::DNSSEC(DnsRDataDNSSEC::NSEC(nsec)) => assert_eq!(nsec.type_bit_maps().len(), 5),
+      rdata => panic!("Unexpected rdata: {:?}", rdata),
+    };
+    match parse_rdata(record_type("NSEC3"), "1 0 10 AABBCCDD 2VPTU5TIMAMQTTGL4LUU9KG21E0AOR3S A RRSIG").unwrap() {
+      DnsRData::DNSSEC(DnsRDataDNSSEC::NSEC3(nsec3)) => {
+        assert_eq!(nsec3.salt(), &[0xAA, 0xBB, 0xCC, 0xDD]);
+        assert_eq!(nsec3.next_hashed_owner_name().len(), 20);
+      },
+      rdata => panic!("Unexpected rdata: {:?}", rdata),
+    };
+  }
+
+  #[test]
+  fn should_parse_other_records_to_raw_data() {
+    // Generic format, for any type
+    let rdata = parse_rdata(DnsRecordType::from(0xFF00), "\\# 4 0A00 0050").unwrap();
+    assert_eq!(rdata, DnsRData::Unknown { code: 0xFF00, rdata: DnsRDataNULL::with(vec![10, 0, 0, 80]) });
+    assert_eq!(parse_rdata(DnsRecordType::A, "\\# 4 0A000050").unwrap(), DnsRData::A(Ipv4Addr::new(10, 0, 0, 80)));
+    assert!(parse_rdata(DnsRecordType::A, "\\# 5 0A000050").is_err());
+    assert!(parse_rdata(DnsRecordType::from(0xFF00), "10.0.0.80").is_err());
+
+    // HTTPS / SVCB
+    let rdata = parse_rdata(record_type("HTTPS"), "1 . alpn=\"h3,h2\" port=443 ipv4hint=10.0.0.80").unwrap();
+    let mut wire = vec![0, 1, 0];
+    wire.extend_from_slice(&[0, 1, 0, 6, 2, b'h', b'3', 2, b'h', b'2']);
+    wire.extend_from_slice(&[0, 3, 0, 2, 1, 187]);
+    wire.extend_from_slice(&[0, 4, 0, 4, 10, 0, 0, 80]);
+    assert_eq!(rdata, DnsRData::Unknown { code: RECORD_TYPE_HTTPS, rdata: DnsRDataNULL::with(wire) });
+    assert!(parse_rdata(record_type("SVCB"), "1 svc.example.com. port=443 port=8443").is_err());
+  }
+}
@@ -2,16 +2,17 @@
 //!
 //! Based on [Serde JSON](https://crates.io/crates/serde_json).
 
-use crate::core::response::DoHResponse;
+use super::rdata::{parse_rdata, RDataParseError};
+use crate::core::{response::DoHResponse, resolver::{DoHResolutionError, DoHResolutionErrorKind}};
 use crate::dns::protocol::*;
 
 use log::*;
 use serde::{ser::{Serializer}, de::{Deserialize, Deserializer}};
 use serde_derive::{Serialize, Deserialize};
-use serde_json::{self, Value};
+use serde_json;
 use ipnet::IpNet;
 
-use std::{str::FromStr, string::ToString, collections::HashMap, net::{Ipv4Addr, Ipv6Addr}};
+use std::{fmt, convert, str::FromStr, string::ToString, collections::HashMap};
 
 /// Represents the deserialized response body for a DNS-over-HTTPS JSON request
 #[derive(Serialize, Deserialize, Debug)]
@@ -30,17 +31,16 @@ pub struct DoHJsonResponse {
   pub checking_disabled: bool,                  //< Whether the client asked to disable DNSSEC
   #[serde(rename = "Question")]
   pub question: Vec<DoHJsonQuestion>,           //< See `DoHResponseQuestion` above
-  #[serde(rename = "Answer")]
-  pub answer: Vec<DoHJsonAnswer>,               //< See `DoHResponseAnswer` above
+  #[serde(rename = "Answer", default)]
+  pub answer: Vec<DoHJsonAnswer>,               //< See `DoHResponseAnswer` above (omitted by some providers, if empty)
+  #[serde(rename = "Authority", default, skip_serializing_if = "Vec::is_empty")]
+  pub authority: Vec<DoHJsonAnswer>,            //< Same as `answer` (ex. the `SOA` of a `NXDOMAIN`)
   #[serde(rename = "Additional", default)]
-  pub additional: Vec<Value>,
+  pub additional: Vec<DoHJsonAnswer>,           //< Same as `answer`
   #[serde(default, serialize_with = "DoHJsonResponse::edns_client_subnet_serialize", deserialize_with = "DoHJsonResponse::edns_client_subnet_deserialize")]
   pub edns_client_subnet: Option<IpNet>,        //< IP address / scope prefix-length
   #[serde(rename = "Comment", default)]
   pub comment: String,
-
-  // TODO Add support for "Authority" field (CloudFlare has it). Example:
-  //   ".., "Authority":[{"name": "github.io.", "type": 6, "TTL": 60, "data": "ns1.p16.dynect.net. hostmaster.github.com. 92 3600 600 604800 60"}] }"
 }
 
 impl DoHJsonResponse {
@@ -79,7 +79,11 @@ impl DoHJsonResponse {
 
 impl DoHResponse for DoHJsonResponse {
 
-  fn apply(&self, req_edns_client_subnet_prefix_len: u8, res_dns_msg: &mut DnsMessage) {
+  fn from_http_body(body: &[u8]) -> Result<Self, DoHResolutionError> {
+    DoHJsonResponse::from_slice(body).map_err(DoHResolutionError::from)
+  }
+
+  fn apply(&self, req_edns_client_subnet_prefix_len: u8, res_dns_msg: &mut DnsMessage) -> Result<(), DoHResolutionError> {
     // Set control fields
     res_dns_msg.set_truncated(self.truncated);
     res_dns_msg.set_recursion_desired(self.recursion_desired);
@@ -90,49 +94,30 @@ impl DoHResponse for DoHJsonResponse {
 
     // Add question fields
     for question in self.question.iter() {
-      let q_name = DnsDomainName::from_str(question.name.as_ref()).unwrap();
-      res_dns_msg.add_query(DnsQuery::query(q_name, question.question_type));
+      res_dns_msg.add_query(DnsQuery::query(parse_name(&question.name)?, question.question_type));
     }
 
-    // Add answer fields
+    // Add answer, authority and additional fields
     for answer in self.answer.iter() {
-      let r_name = DnsDomainName::from_str(answer.name.as_ref()).unwrap();
-      let r_ttl = answer.ttl;
-      let r_type = answer.answer_type;
-
-      match r_type {
-        DnsRecordType::A => {
-          let r_ipv4addr = Ipv4Addr::from_str(answer.data.as_ref()).unwrap();
-          res_dns_msg.add_answer(DnsRecord::from_rdata(r_name, r_ttl, DnsRData::A(r_ipv4addr)));
-        },
-        DnsRecordType::AAAA => {
-          let r_ipv6addr = Ipv6Addr::from_str(answer.data.as_ref()).unwrap();
-          res_dns_msg.add_answer(DnsRecord::from_rdata(r_name, r_ttl, DnsRData::AAAA(r_ipv6addr)));
-        },
-        DnsRecordType::CNAME => {
-          let r_cname = DnsDomainName::from_str(answer.data.as_ref()).unwrap();
-          res_dns_msg.add_answer(DnsRecord::from_rdata(r_name, r_ttl, DnsRData::CNAME(r_cname)));
-        },
-        DnsRecordType::NS => {
-          // TODO ?
-          let r_cname = DnsDomainName::from_str(answer.data.as_ref()).unwrap();
-          res_dns_msg.add_answer(DnsRecord::from_rdata(r_name, r_ttl, DnsRData::NS(r_cname)));
-        },
-        DnsRecordType::PTR => {
-          // TODO ?
-          let r_cname = DnsDomainName::from_str(answer.data.as_ref()).unwrap();
-          res_dns_msg.add_answer(DnsRecord::from_rdata(r_name, r_ttl, DnsRData::PTR(r_cname)));
-        },
-        _ => {
-          error!("Unsupported DNS Answer Record type: {}", r_type);
-        }
-      };
+      res_dns_msg.add_answer(answer.to_record()?);
+    }
+    for authority in self.authority.iter() {
+      res_dns_msg.add_name_server(authority.to_record()?);
+    }
+    for additional in self.additional.iter() {
+      // Additional records are just "hints": one that can't be converted is not worth failing for
+      match additional.to_record() {
+        Ok(record) => { res_dns_msg.add_additional(record); },
+        Err(err) => warn!("Ignoring additional record '{}' (type {}): {}", additional.name, additional.answer_type, err),
+      }
     }
 
     // Add additional "EDNS Client Subnet OPT" if present
     if let Some(client_subnet) = self.edns_client_subnet {
       res_dns_msg.set_edns(edns_from_client_subnet(req_edns_client_subnet_prefix_len, &client_subnet));
     }
+
+    Ok(())
   }
 
 }
@@ -163,6 +148,7 @@ impl Default for DoHJsonResponse {
       checking_disabled: false,
       question: vec![],
       answer: vec![],
+      authority: vec![],
       additional: vec![],
       edns_client_subnet: Option::default(),
       comment: String::default()
@@ -242,7 +228,7 @@ impl DoHJsonQuestion {
   }
 }
 
-/// Answer part of a `DoHResponse` type
+/// Answer part of a `DoHResponse` type (also used for the authority and additional parts)
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DoHJsonAnswer {
   pub name: String,                             //< FQDN with trailing dot
@@ -257,6 +243,52 @@ impl DoHJsonAnswer {
   fn answer_type_default() -> DnsRecordType {
     DnsRecordType::A
   }
+
+  /// Converts to a `DnsRecord`
+  pub fn to_record(&self) -> Result<DnsRecord, DoHJsonConversionError> {
+    let r_name = parse_name(&self.name)?;
+    let r_data = parse_rdata(self.answer_type, self.data.as_ref())?;
+
+    // The record type is set explicitly: Trust-DNS would turn a `RRSIG` into a `SIG`
+    let mut record = DnsRecord::from_rdata(r_name, self.ttl, r_data);
+    record.set_rr_type(self.answer_type);
+
+    Ok(record)
+  }
+}
+
+fn parse_name(raw_name: &str) -> Result<DnsDomainName, DoHJsonConversionError> {
+  DnsDomainName::from_str(raw_name).map_err(|_| DoHJsonConversionError::InvalidName(raw_name.to_string()))
+}
+
+/// Error that happens when a `DoHJsonResponse` carries data that can't be converted for a `DnsMessage`
+#[derive(Debug, Clone)]
+pub enum DoHJsonConversionError {
+  /// A question or record name that is not a valid domain name
+  InvalidName(String),
+  /// Record data that can't be converted to the record type
+  InvalidRData(RDataParseError),
+}
+
+impl fmt::Display for DoHJsonConversionError {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DoHJsonConversionError::InvalidName(name) => write!(fmtr, "Invalid domain name: {}", name),
+      DoHJsonConversionError::InvalidRData(err) => write!(fmtr, "{}", err),
+    }
+  }
+}
+
+impl convert::From<RDataParseError> for DoHJsonConversionError {
+  fn from(rdata_parse_error: RDataParseError) -> Self {
+    DoHJsonConversionError::InvalidRData(rdata_parse_error)
+  }
+}
+
+impl convert::From<DoHJsonConversionError> for DoHResolutionError {
+  fn from(conversion_error: DoHJsonConversionError) -> Self {
+    DoHResolutionError::new(DoHResolutionErrorKind::Decode, format!("Failed to convert DoH JSON response: {}", conversion_error))
+  }
 }
 
 pub use serde_json::Error as DoHParseError;
@@ -264,6 +296,7 @@ pub use serde_json::Error as DoHParseError;
 #[cfg(test)]
 mod test {
   use super::*;
+  use std::net::Ipv4Addr;
 
   const EXAMPLE_JSON_RESPONSE: &'static str = r#"{
     "Status": 0,
@@ -361,7 +394,7 @@ mod test {
     assert_eq!(dns_msg.additional_count(), 0u16);
 
     let dns_resp = EXAMPLE_JSON_RESPONSE.parse::<DoHJsonResponse>().unwrap();
-    dns_resp.apply(10, &mut dns_msg);
+    dns_resp.apply(10, &mut dns_msg).unwrap();
 
     // Check DNS Message control
     assert_eq!(dns_msg.truncated(), false);
@@ -414,4 +447,76 @@ mod test {
     assert_eq!(dns_msg.additionals().len(), 0);
   }
 
+  #[test]
+  fn should_apply_all_record_types() {
+    let dns_resp_json = r#"{"Status":0,"TC":false,"RD":true,"RA":true,"AD":true,"CD":false,"Question":[{"name":"example.com.","type":15}],"Answer":[
+      {"name":"example.com.","type":15,"TTL":300,"data":"10 mail.example.com."},
+      {"name":"example.com.","type":46,"TTL":300,"data":"MX 13 2 300 20190701000000 20190624000000 34505 example.com. dGVzdA=="},
+      {"name":"example.com.","type":65,"TTL":300,"data":"1 . alpn=h2"}
+    ]}"#;
+    let dns_resp = dns_resp_json.parse::<DoHJsonResponse>().unwrap();
+
+    let mut dns_msg = DnsMessage::new();
+    dns_resp.apply(0, &mut dns_msg).unwrap();
+    assert_eq!(dns_msg.answers().len(), 3);
+
+    // Record types survive the conversion to the wire format
+    let dns_msg = dns_message_from_bytes(&dns_message_to_bytes(&dns_msg).unwrap()).unwrap();
+    let record_types: Vec<u16> = dns_msg.answers().iter().map(|record| record.rr_type().into()).collect();
+    assert_eq!(record_types, vec![15, 46, 65]);
+    assert_eq!(dns_msg.answers()[0].rdata(), &DnsRData::MX(DnsRDataMX::new(10, DnsDomainName::from_str("mail.example.com.").unwrap())));
+  }
+
+  #[test]
+  fn should_apply_authority_and_additional() {
+    let dns_resp_json = r#"{"Status":3,"TC":false,"RD":true,"RA":true,"AD":false,"CD":false,"Question":[{"name":"nope.github.io.","type":1}],"Answer":[],"Authority":[{"name":"github.io.","type":6,"TTL":60,"data":"ns1.p16.dynect.net. hostmaster.github.com. 92 3600 600 604800 60"}],"Additional":[{"name":"ns1.p16.dynect.net.","type":1,"TTL":3600,"data":"208.78.70.16"}],"edns_client_subnet":"","Comment":""}"#;
+    let dns_resp = dns_resp_json.parse::<DoHJsonResponse>().unwrap();
+    assert_eq!(dns_resp.to_string(), dns_resp_json);
+
+    // Some providers omit "Answer" altogether for NXDOMAIN/NODATA
+    let dns_resp_no_answer = dns_resp_json.replace(r#""Answer":[],"#, "").parse::<DoHJsonResponse>().unwrap();
+    assert!(dns_resp_no_answer.answer.is_empty());
+    assert_eq!(dns_resp_no_answer.authority.len(), 1);
+
+    let mut dns_msg = DnsMessage::new();
+    dns_resp.apply(0, &mut dns_msg).unwrap();
+
+    assert_eq!(dns_msg.response_code(), DnsResponseCode::NXDomain);
+    assert_eq!(dns_msg.answers().len(), 0);
+    assert_eq!(dns_msg.name_servers().len(), 1);
+    match dns_msg.name_servers()[0].rdata() {
+      DnsRData::SOA(soa) => assert_eq!(soa.minimum(), 60),
+      rdata => panic!("Unexpected rdata: {:?}", rdata),
+    };
+    assert_eq!(dns_msg.additionals().len(), 1);
+    assert_eq!(dns_msg.additionals()[0].rdata(), &DnsRData::A(Ipv4Addr::new(208, 78, 70, 16)));
+  }
+
+  #[test]
+  fn should_ignore_invalid_additional() {
+    let dns_resp_json = r#"{"Status":0,"TC":false,"RD":true,"RA":true,"AD":false,"CD":false,"Question":[{"name":"github.com.","type":1}],"Answer":[{"name":"github.com.","type":1,"TTL":60,"data":"140.82.118.4"}],"Additional":[{"name":"github.com.","type":1,"TTL":60,"data":"not an IP"}]}"#;
+    let dns_resp = dns_resp_json.parse::<DoHJsonResponse>().unwrap();
+
+    let mut dns_msg = DnsMessage::new();
+    dns_resp.apply(0, &mut dns_msg).unwrap();
+    assert_eq!(dns_msg.answers().len(), 1);
+    assert_eq!(dns_msg.additionals().len(), 0);
+  }
+
+  #[test]
+  fn should_fail_to_apply_invalid_data() {
+    let invalid_jsons = [
+      r#"{"Status":0,"TC":false,"RD":true,"RA":true,"AD":false,"CD":false,"Question":[{"name":"example..com.","type":1}],"Answer":[]}"#,
+      r#"{"Status":0,"TC":false,"RD":true,"RA":true,"AD":false,"CD":false,"Question":[],"Answer":[{"name":"example.com.","type":1,"TTL":60,"data":"not an IP"}]}"#,
+      r#"{"Status":0,"TC":false,"RD":true,"RA":true,"AD":false,"CD":false,"Question":[],"Answer":[],"Authority":[{"name":"example.com.","type":6,"TTL":60,"data":"ns1.example.com."}]}"#,
+    ];
+
+    for invalid_json in invalid_jsons.iter() {
+      let dns_resp = invalid_json.parse::<DoHJsonResponse>().unwrap();
+      let err = dns_resp.apply(0, &mut DnsMessage::new()).unwrap_err();
+      assert_eq!(err.response_code(), DnsResponseCode::ServFail);
+      assert_eq!(err.kind(), DoHResolutionErrorKind::Decode);
+    }
+  }
+
 }
@@ -1,3 +1,12 @@
-//! DNS-over-HTTPS Wire implementation
-//!
-//! TODO Implement this after 1.0 is out
\ No newline at end of file
+//! DNS-over-HTTPS Wire implementation
+//!
+//! This module is _exclusively_ about the ["raw" DNS-over-HTTPS](https://tools.ietf.org/html/rfc8484)
+//! standard (RFC 8484): DNS messages are sent, and received, in their binary wire-format,
+//! as body of HTTPS requests, using the media type `application/dns-message`.
+//!
+//! Compared to the JSON dialect (see `doh_json`), nothing is lost in translation: the response
+//! DNS message is exactly what the provider sent back.
+
+pub mod response;
+pub mod provider;
+pub mod resolver;
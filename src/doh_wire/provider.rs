@@ -0,0 +1,414 @@
+//! DoH Wire provider(s)
+
+use crate::core::{provider::{DoHProvider, DoHProviderUrlError, parse_provider_url}, protocol::DoHProtocol, mode::DoHRequestMode};
+use crate::dns::protocol::*;
+use crate::doh_json::provider::{
+  PROVIDER_NAME_GOOGLE,
+  PROVIDER_NAME_CLOUDFLARE,
+  PROVIDER_NAME_QUAD9,
+  PROVIDER_NAME_QUAD9_SECURED,
+  PROVIDER_NAME_QUAD9_UNSECURED,
+  PROVIDER_NAME_RUBYFISH,
+  PROVIDER_NAME_BLAHDNS,
+  PROVIDER_BOOTSTRAP_GOOGLE,
+  PROVIDER_BOOTSTRAP_CLOUDFLARE,
+  PROVIDER_BOOTSTRAP_QUAD9,
+  PROVIDER_BOOTSTRAP_QUAD9_SECURED,
+  PROVIDER_BOOTSTRAP_QUAD9_UNSECURED,
+  PROVIDER_BOOTSTRAP_RUBYFISH,
+  PROVIDER_BOOTSTRAP_BLAHDNS,
+};
+use crate::net::http::{HttpBootstrapAddresses, HttpProviderId, HttpExpectedContentTypes};
+
+use http::{
+  method::Method,
+  version::Version,
+  uri::{Builder as UriBuilder, Scheme, Authority, PathAndQuery},
+  header::{HeaderMap, HeaderValue, self},
+  request::{Request, Builder as RequestBuilder},
+  Result,
+};
+
+use base64;
+
+use std::{collections::HashMap, str::FromStr, net::IpAddr};
+
+/// Media type of DNS messages exchanged in wire-format, as defined by [RFC 8484](https://tools.ietf.org/html/rfc8484#section-6)
+pub const DNS_MESSAGE_MEDIA_TYPE: &'static str = "application/dns-message";
+
+/// Describes a provider of DNS-over-HTTPS services, that supports RFC 8484 wire-format
+#[derive(Debug, Clone)]
+pub struct DoHWireProvider {
+  id: String,
+  scheme: Scheme,
+  authority: Authority,
+  path_query: PathAndQuery,
+  headers: HeaderMap,
+  request_mode: DoHRequestMode,
+  bootstrap: Vec<IpAddr>,
+}
+
+impl DoHWireProvider {
+  /// Constructor from "raw" parts
+  ///
+  /// # Parameters
+  ///
+  /// * `id` - Identifier of this Provider
+  /// * `raw_scheme` - `&str` representing the scheme of a URI (ex. "http", "https" or others)
+  /// * `raw_authority` - `&str` representing the authority of a URI (ex. "example.com" or "other-example.com:8081")
+  /// * `raw_path_query` - `&str` representing the path and query of a URI (ex. "/dns-query")
+  /// * `request_mode` - `DoHRequestMode` preferred by this Provider
+  /// * `raw_bootstrap` - `&str`s representing the IP addresses the authority can be reached at (ex. "1.1.1.1")
+  fn from_raw_parts(id: &'static str, raw_scheme: &str, raw_authority: &str, raw_path_query: &str, request_mode: DoHRequestMode, raw_bootstrap: &[&str]) -> DoHWireProvider {
+    DoHWireProvider::from_parts(
+      id.to_string(),
+      raw_scheme.parse().unwrap(),
+      raw_authority.parse().unwrap(),
+      raw_path_query.parse().unwrap(),
+      HeaderMap::default(),
+      request_mode,
+      raw_bootstrap.iter().map(|raw_ip| raw_ip.parse().unwrap()).collect()
+    )
+  }
+
+  /// Constructor from parts
+  ///
+  /// # Parameters
+  ///
+  /// * `id` - Identifier of this Provider
+  /// * `scheme` - `Scheme` of a URI (ex. "http", "https" or others)
+  /// * `authority` - `Authority` of a URI (ex. "example.com" or "other-example.com:8081")
+  /// * `path_query` - `PathAndQuery` of a URI (ex. "/dns-query")
+  /// * `headers` - an `HeaderMap` as defined by the `http` crate
+  /// * `request_mode` - `DoHRequestMode` preferred by this Provider
+  /// * `bootstrap` - IP addresses the authority can be reached at
+  fn from_parts(id: String, scheme: Scheme, authority: Authority, path_query: PathAndQuery, headers: HeaderMap, request_mode: DoHRequestMode, bootstrap: Vec<IpAddr>) -> DoHWireProvider {
+    DoHWireProvider {
+      id,
+      scheme,
+      authority,
+      path_query,
+      headers,
+      request_mode,
+      bootstrap
+    }
+  }
+
+  /// Constructor for a user-defined Provider, from its URL
+  ///
+  /// The Provider prefers `DoHRequestMode::POST` (see `DoHWireProvider::set_request_mode()` to change it).
+  ///
+  /// # Parameters
+  ///
+  /// * `id` - Identifier of this Provider
+  /// * `raw_url` - `&str` representing the URL of the Provider (ex. "https://doh.example.com/dns-query")
+  /// * `headers` - an `HeaderMap` as defined by the `http` crate
+  /// * `bootstrap` - IP addresses the Provider can be reached at (can be empty)
+  pub fn from_url(id: &str, raw_url: &str, headers: HeaderMap, bootstrap: Vec<IpAddr>) -> std::result::Result<DoHWireProvider, DoHProviderUrlError> {
+    let (scheme, authority, path_query) = parse_provider_url(raw_url)?;
+
+    Ok(DoHWireProvider::from_parts(id.to_string(), scheme, authority, path_query, headers, DoHRequestMode::POST, bootstrap))
+  }
+
+  /// Overrides the `DoHRequestMode` preferred by this Provider
+  ///
+  /// # Parameters
+  ///
+  /// * `request_mode` - `DoHRequestMode` to use from now on
+  pub fn set_request_mode(&mut self, request_mode: DoHRequestMode) {
+    self.request_mode = request_mode;
+  }
+
+  /// Builds an HTTP request carrying the given DNS message, already serialized in wire-format
+  ///
+  /// This is what `DoHProvider::build_http_request()` uses under the hood, but it allows the
+  /// caller to send a fully formed DNS message (ex. with EDNS and flags of the original client
+  /// request), instead of just a `DnsQuery`.
+  ///
+  /// # Parameters
+  ///
+  /// * `raw_dns_message` - DNS message, in wire-format, to send to the Provider
+  pub fn build_http_request_for_raw_message(&self, raw_dns_message: Vec<u8>) -> Result<Request<Vec<u8>>> {
+    // Depending on the request mode, the DNS message goes either in the query or in the body
+    let (method, path_query, body) = match self.request_mode {
+      DoHRequestMode::GET => {
+        let encoded_dns_message = encode_base64url(&raw_dns_message);
+        let path_query = if let Some(provider_required_query) = self.path_query.query() {
+          PathAndQuery::from_str(&format!("{}?dns={}&{}", self.path_query.path(), encoded_dns_message, provider_required_query))?
+        } else {
+          PathAndQuery::from_str(&format!("{}?dns={}", self.path_query.path(), encoded_dns_message))?
+        };
+
+        (Method::GET, path_query, Vec::new())
+      },
+      DoHRequestMode::POST => (Method::POST, self.path_query.clone(), raw_dns_message),
+    };
+
+    // Compose the request URI by assembling all it's parts
+    let uri = UriBuilder::new()
+      .scheme(self.scheme.clone())
+      .authority(self.authority.clone())
+      .path_and_query(path_query)
+      .build()?;
+
+    // Using a Request builder to assemble the final HTTP Request
+    let mut req_builder = RequestBuilder::new();
+    // Adding some defaults as well as URI
+    req_builder
+      .version(Version::HTTP_11)
+      .method(method.clone())
+      .uri(uri)
+      .header(header::ACCEPT, HeaderValue::from_static(DNS_MESSAGE_MEDIA_TYPE));
+    if method == Method::POST {
+      req_builder.header(header::CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE_MEDIA_TYPE));
+    }
+
+    // Adding extra headers (if any)
+    for (hkey, hval) in self.headers.iter() {
+      req_builder.header(hkey, hval);
+    }
+
+    // Adding addresses to reach the Provider at (if any)
+    if !self.bootstrap.is_empty() {
+      req_builder.extension(HttpBootstrapAddresses(self.bootstrap.clone()));
+    }
+    req_builder.extension(HttpProviderId(self.id.clone()));
+    req_builder.extension(HttpExpectedContentTypes(&[DNS_MESSAGE_MEDIA_TYPE]));
+
+    req_builder.body(body)
+  }
+}
+
+impl DoHProvider for DoHWireProvider {
+
+  fn id(&self) -> &str {
+    &self.id
+  }
+
+  fn protocol(&self) -> DoHProtocol {
+    DoHProtocol::WIRE
+  }
+
+  fn request_mode(&self) -> DoHRequestMode {
+    self.request_mode
+  }
+
+  fn hostname(&self) -> &str {
+    self.authority.host()
+  }
+
+  fn bootstrap_addresses(&self) -> &[IpAddr] {
+    &self.bootstrap
+  }
+
+  fn set_bootstrap_addresses(&mut self, addresses: Vec<IpAddr>) {
+    self.bootstrap = addresses;
+  }
+
+  /// Builds the HTTP request that will carry the DNS query
+  ///
+  /// The `DnsQuery` is wrapped in a DNS message with ID `0` (as recommended by
+  /// [RFC 8484](https://tools.ietf.org/html/rfc8484#section-4.1)) and "recursion desired" on.
+  fn build_http_request(&self, dns_query: &DnsQuery) -> Result<Request<Vec<u8>>> {
+    let mut dns_message = DnsMessage::new();
+    dns_message.set_id(0);
+    dns_message.set_message_type(DnsMessageType::Query);
+    dns_message.set_recursion_desired(true);
+    dns_message.add_query(dns_query.clone());
+
+    let raw_dns_message = dns_message_to_bytes(&dns_message)
+      .expect("A DNS message carrying a single, valid DnsQuery should always serialize: this should never happen!");
+
+    self.build_http_request_for_raw_message(raw_dns_message)
+  }
+
+  fn available() -> HashMap<&'static str, Self> {
+    let mut providers = HashMap::new();
+
+    // Google
+    providers.insert(PROVIDER_NAME_GOOGLE, DoHWireProvider::from_raw_parts(
+      PROVIDER_NAME_GOOGLE,
+      "https",
+      "dns.google",
+      "/dns-query",
+      DoHRequestMode::GET,
+      PROVIDER_BOOTSTRAP_GOOGLE
+    ));
+    // Cloudflare
+    providers.insert(PROVIDER_NAME_CLOUDFLARE, DoHWireProvider::from_raw_parts(
+      PROVIDER_NAME_CLOUDFLARE,
+      "https",
+      "cloudflare-dns.com",
+      "/dns-query",
+      DoHRequestMode::POST,
+      PROVIDER_BOOTSTRAP_CLOUDFLARE
+    ));
+    // Quad9 recommended
+    providers.insert(PROVIDER_NAME_QUAD9, DoHWireProvider::from_raw_parts(
+      PROVIDER_NAME_QUAD9,
+      "https",
+      "dns.quad9.net",
+      "/dns-query",
+      DoHRequestMode::POST,
+      PROVIDER_BOOTSTRAP_QUAD9
+    ));
+    // Quad9 secured
+    providers.insert(PROVIDER_NAME_QUAD9_SECURED, DoHWireProvider::from_raw_parts(
+      PROVIDER_NAME_QUAD9_SECURED,
+      "https",
+      "dns9.quad9.net",
+      "/dns-query",
+      DoHRequestMode::POST,
+      PROVIDER_BOOTSTRAP_QUAD9_SECURED
+    ));
+    // Quad9 unsecured
+    providers.insert(PROVIDER_NAME_QUAD9_UNSECURED, DoHWireProvider::from_raw_parts(
+      PROVIDER_NAME_QUAD9_UNSECURED,
+      "https",
+      "dns10.quad9.net",
+      "/dns-query",
+      DoHRequestMode::POST,
+      PROVIDER_BOOTSTRAP_QUAD9_UNSECURED
+    ));
+    // Rubyfish
+    providers.insert(PROVIDER_NAME_RUBYFISH, DoHWireProvider::from_raw_parts(
+      PROVIDER_NAME_RUBYFISH,
+      "https",
+      "dns.rubyfish.cn",
+      "/dns-query",
+      DoHRequestMode::POST,
+      PROVIDER_BOOTSTRAP_RUBYFISH
+    ));
+    // BlahDNS
+    providers.insert(PROVIDER_NAME_BLAHDNS, DoHWireProvider::from_raw_parts(
+      PROVIDER_NAME_BLAHDNS,
+      "https",
+      "doh-de.blahdns.com",
+      "/dns-query",
+      DoHRequestMode::POST,
+      PROVIDER_BOOTSTRAP_BLAHDNS
+    ));
+
+    providers
+  }
+
+  fn default_id() -> &'static str {
+    PROVIDER_NAME_CLOUDFLARE
+  }
+
+}
+
+/// Encodes a DNS message in wire-format to base64url (no padding), as used in RFC 8484 `GET` requests
+///
+/// # Parameters
+///
+/// * `raw_dns_message` - DNS message, in wire-format
+fn encode_base64url(raw_dns_message: &[u8]) -> String {
+  base64::encode_config(raw_dns_message, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn should_encode_base64url() {
+    // Bytes that are `+` and `/` in standard base64, and a length that requires padding
+    let encoded = encode_base64url(&[0xfb, 0xff, 0xbf, 0x00]);
+    assert_eq!(encoded, "-_-_AA");
+    assert_eq!(base64::decode_config(&encoded, base64::URL_SAFE_NO_PAD).unwrap(), vec![0xfb, 0xff, 0xbf, 0x00]);
+  }
+
+  #[test]
+  fn should_provide_cloudflare_provider() {
+    let example_query = DnsQuery::query(DnsDomainName::from_str("ivandemarino.me.").unwrap(), DnsRecordType::AAAA);
+
+    let default_provider: DoHWireProvider = DoHWireProvider::default();
+    let cloudflare_provider = DoHWireProvider::available().get(PROVIDER_NAME_CLOUDFLARE).unwrap().to_owned();
+
+    // Cloudflare is also the default provider
+    let providers = vec![default_provider, cloudflare_provider];
+
+    for provider in providers {
+      let http_request = provider.build_http_request(&example_query).unwrap();
+      assert_eq!(http_request.method(), Method::POST);
+      assert_eq!(http_request.version(), Version::HTTP_11);
+      assert_eq!(http_request.uri().to_string(), "https://cloudflare-dns.com/dns-query");
+      assert_eq!(http_request.headers().get(header::ACCEPT).unwrap(), &DNS_MESSAGE_MEDIA_TYPE);
+      assert_eq!(http_request.headers().get(header::CONTENT_TYPE).unwrap(), &DNS_MESSAGE_MEDIA_TYPE);
+      assert_eq!(http_request.headers().len(), 2);
+      assert_eq!(http_request.extensions().get::<HttpBootstrapAddresses>().unwrap().0, provider.bootstrap_addresses());
+      assert_eq!(dns_message_from_bytes(http_request.body()).unwrap().queries(), &[example_query.clone()]);
+    }
+  }
+
+  #[test]
+  fn should_provide_google_provider() {
+    let example_query = DnsQuery::query(DnsDomainName::from_str("github.com.").unwrap(), DnsRecordType::A);
+    let default_providers = DoHWireProvider::available();
+
+    let provider = default_providers.get(PROVIDER_NAME_GOOGLE).unwrap();
+
+    let http_request = provider.build_http_request(&example_query).unwrap();
+    assert_eq!(http_request.method(), Method::GET);
+    assert_eq!(http_request.version(), Version::HTTP_11);
+    assert!(http_request.uri().to_string().starts_with("https://dns.google/dns-query?dns="));
+    assert_eq!(http_request.headers().get(header::ACCEPT).unwrap(), &DNS_MESSAGE_MEDIA_TYPE);
+    assert_eq!(http_request.headers().len(), 1);
+    assert!(http_request.body().is_empty());
+
+    // The `dns` parameter is the base64url encoded DNS message
+    let encoded_dns_message = http_request.uri().query().unwrap().trim_start_matches("dns=");
+    let raw_dns_message = base64::decode_config(encoded_dns_message, base64::URL_SAFE_NO_PAD).unwrap();
+    let dns_message = dns_message_from_bytes(&raw_dns_message).unwrap();
+    assert_eq!(dns_message.id(), 0);
+    assert_eq!(dns_message.queries(), &[example_query]);
+  }
+
+  #[test]
+  fn should_override_request_mode() {
+    let example_query = DnsQuery::query(DnsDomainName::from_str("github.com.").unwrap(), DnsRecordType::A);
+    let mut provider = DoHWireProvider::available().remove(PROVIDER_NAME_GOOGLE).unwrap();
+    assert_eq!(provider.request_mode(), DoHRequestMode::GET);
+
+    provider.set_request_mode(DoHRequestMode::POST);
+    assert_eq!(provider.request_mode(), DoHRequestMode::POST);
+
+    let http_request = provider.build_http_request(&example_query).unwrap();
+    assert_eq!(http_request.method(), Method::POST);
+    assert_eq!(http_request.uri().to_string(), "https://dns.google/dns-query");
+    assert_eq!(http_request.headers().get(header::CONTENT_TYPE).unwrap(), &DNS_MESSAGE_MEDIA_TYPE);
+    assert!(!http_request.body().is_empty());
+  }
+
+  #[test]
+  fn should_build_custom_provider_from_url() {
+    let example_query = DnsQuery::query(DnsDomainName::from_str("github.com.").unwrap(), DnsRecordType::A);
+    let mut headers = HeaderMap::new();
+    headers.insert("x-api-key", HeaderValue::from_static("secret"));
+
+    let provider = DoHWireProvider::from_url("internal", "https://doh.internal.example/dns-query", headers, vec![]).unwrap();
+    assert_eq!(provider.id(), "internal");
+    assert_eq!(provider.request_mode(), DoHRequestMode::POST);
+    assert!(provider.bootstrap_addresses().is_empty());
+
+    let http_request = provider.build_http_request(&example_query).unwrap();
+    assert_eq!(http_request.method(), Method::POST);
+    assert_eq!(http_request.uri().to_string(), "https://doh.internal.example/dns-query");
+    assert_eq!(http_request.headers().get("x-api-key").unwrap(), &"secret");
+    assert_eq!(http_request.extensions().get::<HttpBootstrapAddresses>(), None);
+  }
+
+  #[test]
+  fn should_provide_default_provider_ids() {
+    assert_eq!(DoHWireProvider::available_ids().len(), 7);
+    assert!(DoHWireProvider::available_ids().contains(&PROVIDER_NAME_CLOUDFLARE));
+    assert!(DoHWireProvider::available_ids().contains(&PROVIDER_NAME_GOOGLE));
+    assert!(DoHWireProvider::available_ids().contains(&PROVIDER_NAME_QUAD9));
+    assert!(DoHWireProvider::available_ids().contains(&PROVIDER_NAME_QUAD9_SECURED));
+    assert!(DoHWireProvider::available_ids().contains(&PROVIDER_NAME_QUAD9_UNSECURED));
+    assert!(DoHWireProvider::available_ids().contains(&PROVIDER_NAME_RUBYFISH));
+    assert!(DoHWireProvider::available_ids().contains(&PROVIDER_NAME_BLAHDNS));
+  }
+
+}
@@ -0,0 +1,143 @@
+//! Implementation of `DoHResolver` for the DoH Wire Protocol.
+
+use super::{response::*, provider::DoHWireProvider};
+use crate::core::resolver::*;
+use crate::dns::protocol::{DnsMessage, DnsMessageType, DnsQuery, dns_message_to_bytes};
+
+use threadpool::{ThreadPool, Builder as ThreadPoolBuilder};
+use num_cpus;
+
+const DOH_WIRE_RESOLVER_THREAD_NAME: &'static str = "doh_wire_resolver_thread";
+
+type Result<T> = std::result::Result<T, DoHResolutionError>;
+
+/// DNS-over-HTTPS resolver that implements the DoH Wire protocol
+///
+/// This is an "Authoritative" resolver: responses are always coming from the given provider,
+/// not the cache. As such, responses will always have the "authoritative" bit on.
+#[derive(Debug, Clone)]
+pub struct DoHWireResolver {
+  provider: DoHWireProvider,
+  pool: ThreadPool
+}
+
+impl DoHWireResolver {
+
+  pub fn new(provider: DoHWireProvider) -> DoHWireResolver {
+    let pool = ThreadPoolBuilder::new()
+      .num_threads(num_cpus::get())
+      .thread_name(DOH_WIRE_RESOLVER_THREAD_NAME.into())
+      .build();
+
+    DoHWireResolver {
+      provider,
+      pool,
+    }
+  }
+
+}
+
+impl DoHResolver for DoHWireResolver {
+
+  fn resolve_query(&self, req_dns_msg: &DnsMessage) -> Result<DnsMessage> {
+    resolve_over_http::<DoHWireResponse, _>(&self.pool, req_dns_msg, |query| {
+      let raw_query_dns_msg = dns_message_to_bytes(&build_query_dns_message(req_dns_msg, query))
+        .map_err(|err| DoHResolutionError::new(DoHResolutionErrorKind::Internal, format!("Failed to serialize DNS query: {}", err)))?;
+
+      Ok(self.provider.build_http_request_for_raw_message(raw_query_dns_msg)?)
+    })
+  }
+
+  fn box_clone(&self) -> Box<DoHResolver + Send> {
+    Box::new((*self).clone())
+  }
+
+}
+
+/// Builds the DNS Message, carrying a single `DnsQuery`, that is sent to the provider
+///
+/// As recommended by [RFC 8484](https://tools.ietf.org/html/rfc8484#section-4.1), the
+/// DNS Message ID is set to `0`: this makes responses more friendly to HTTP caches.
+///
+/// # Parameters
+///
+/// * `req_dns_msg`: the DNS Message received from the client, carrying flags and EDNS to preserve
+/// * `query`: the specific query to send
+fn build_query_dns_message(req_dns_msg: &DnsMessage, query: &DnsQuery) -> DnsMessage {
+  let mut query_dns_msg = DnsMessage::new();
+  query_dns_msg.set_id(0);
+  query_dns_msg.set_op_code(req_dns_msg.op_code());
+  query_dns_msg.set_message_type(DnsMessageType::Query);
+  query_dns_msg.set_recursion_desired(req_dns_msg.recursion_desired());
+  query_dns_msg.set_checking_disabled(req_dns_msg.checking_disabled());
+  query_dns_msg.add_query(query.clone());
+
+  if let Some(edns) = req_dns_msg.edns() {
+    query_dns_msg.set_edns(edns.clone());
+  }
+
+  query_dns_msg
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::provider::DoHProvider;
+  use crate::doh_json::provider as doh_json_provider;
+  use crate::dns::protocol::{DnsMessage, DnsRecordType, DnsClass, dns_message_from_bytes};
+  use std::{io::Read, fs::File, path::Path};
+
+  fn read_file_to_vec<P: AsRef<Path>>(path: P) -> Vec<u8> {
+    let mut f = File::open(path).unwrap();
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf).unwrap();
+
+    buf
+  }
+
+  fn force_msg_finalization(dns_msg: DnsMessage) -> DnsMessage {
+    let bytes = dns_message_to_bytes(&dns_msg).unwrap();
+    dns_message_from_bytes(&bytes).unwrap()
+  }
+
+  #[test]
+  fn should_build_query_dns_message() {
+    let buf = read_file_to_vec("./test/fixtures/dns_udp_query_A-example.com-packet.bin");
+    let dns_req = DnsMessage::from_vec(&buf).unwrap();
+
+    let query_dns_msg = build_query_dns_message(&dns_req, &dns_req.queries()[0]);
+    assert_eq!(query_dns_msg.id(), 0);
+    assert_eq!(query_dns_msg.message_type(), DnsMessageType::Query);
+    assert_eq!(query_dns_msg.recursion_desired(), dns_req.recursion_desired());
+    assert_eq!(query_dns_msg.queries(), dns_req.queries());
+    assert!(query_dns_msg.edns().is_some());
+  }
+
+  #[test]
+  fn should_resolve_udp_query_example_com() {
+    let provider = DoHWireProvider::available().remove(doh_json_provider::PROVIDER_NAME_CLOUDFLARE).unwrap();
+
+    let resolver = DoHWireResolver::new(provider);
+
+    let buf = read_file_to_vec("./test/fixtures/dns_udp_query_A-example.com-packet.bin");
+    let dns_req = DnsMessage::from_vec(&buf).unwrap();
+
+    let dns_res_result = resolver.resolve_query(&dns_req);
+    assert!(dns_res_result.is_ok());
+    let dns_res = force_msg_finalization(dns_res_result.unwrap());
+
+    assert_eq!(dns_res.message_type(), DnsMessageType::Response);
+    assert_eq!(dns_res.id(), dns_req.id());
+    assert_eq!(dns_res.query_count(), 1);
+    assert!(dns_res.answer_count() >= 1);
+
+    let dns_query = &(dns_res.queries())[0];
+    assert_eq!(dns_query.query_type(), DnsRecordType::A);
+    assert_eq!(dns_query.query_class(), DnsClass::IN);
+    assert_eq!(dns_query.name().to_utf8(), "example.com.");
+
+    let dns_answer = &(dns_res.answers())[0];
+    assert_eq!(dns_answer.record_type(), DnsRecordType::A);
+    assert_eq!(dns_answer.name().to_utf8(), "example.com.");
+  }
+}
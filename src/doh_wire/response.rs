@@ -0,0 +1,137 @@
+//! Implementation of `DoHResponse` for the DoH Wire Protocol.
+//!
+//! The response body is a DNS message in binary wire-format (see
+//! [RFC 8484](https://tools.ietf.org/html/rfc8484#section-4.2)).
+
+use crate::core::{response::DoHResponse, resolver::{DoHResolutionError, DoHResolutionErrorKind}};
+use crate::dns::protocol::*;
+
+/// Represents the deserialized response body for a DNS-over-HTTPS Wire request
+#[derive(Debug, Clone)]
+pub struct DoHWireResponse {
+  pub message: DnsMessage,
+}
+
+impl DoHWireResponse {
+
+  /// Deserializes a slice of bytes (`&[u8]`) into a `DoHWireResponse`
+  ///
+  /// # Parameters
+  ///
+  /// * `bytes`: slice of bytes that can be deserialized to `DoHWireResponse`
+  pub fn from_slice(bytes: &[u8]) -> Result<Self, DoHParseError> {
+    Ok(DoHWireResponse {
+      message: dns_message_from_bytes(bytes)?
+    })
+  }
+
+}
+
+impl DoHResponse for DoHWireResponse {
+
+  fn from_http_body(body: &[u8]) -> Result<Self, DoHResolutionError> {
+    DoHWireResponse::from_slice(body)
+      .map_err(|proto_error| DoHResolutionError::new(DoHResolutionErrorKind::Decode, format!("Failed to parse DNS message (wire): {}", proto_error)))
+  }
+
+  fn apply(&self, _req_edns_client_subnet_prefix_len: u8, res_dns_msg: &mut DnsMessage) -> Result<(), DoHResolutionError> {
+    // Set control fields
+    res_dns_msg.set_truncated(self.message.truncated());
+    res_dns_msg.set_recursion_desired(self.message.recursion_desired());
+    res_dns_msg.set_recursion_available(self.message.recursion_available());
+    res_dns_msg.set_authentic_data(self.message.authentic_data());
+    res_dns_msg.set_checking_disabled(self.message.checking_disabled());
+    res_dns_msg.set_response_code(self.message.response_code());
+
+    // Copy over all the sections, as they were received
+    res_dns_msg.add_queries(self.message.queries().to_vec());
+    res_dns_msg.add_answers(self.message.answers().to_vec());
+    res_dns_msg.add_name_servers(self.message.name_servers().to_vec());
+    for additional in self.message.additionals() {
+      res_dns_msg.add_additional(additional.clone());
+    }
+
+    // Carry over EDNS (if present)
+    if let Some(edns) = self.message.edns() {
+      res_dns_msg.set_edns(edns.clone());
+    }
+
+    Ok(())
+  }
+
+}
+
+impl Default for DoHWireResponse {
+  /// Useful for testing
+  fn default() -> Self {
+    let mut message = DnsMessage::new();
+    message.set_message_type(DnsMessageType::Response);
+    message.set_recursion_desired(true);
+    message.set_recursion_available(true);
+
+    DoHWireResponse {
+      message
+    }
+  }
+}
+
+pub use crate::dns::protocol::DnsProtoError as DoHParseError;
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::{str::FromStr, net::Ipv4Addr};
+
+  fn example_response_message() -> DnsMessage {
+    let name = DnsDomainName::from_str("apple.com.").unwrap();
+
+    let mut message = DnsMessage::new();
+    message.set_message_type(DnsMessageType::Response);
+    message.set_recursion_desired(true);
+    message.set_recursion_available(true);
+    message.add_query(DnsQuery::query(name.clone(), DnsRecordType::A));
+    message.add_answer(DnsRecord::from_rdata(name.clone(), 3599, DnsRData::A(Ipv4Addr::new(17, 178, 96, 59))));
+    message.add_answer(DnsRecord::from_rdata(name.clone(), 3599, DnsRData::A(Ipv4Addr::new(17, 172, 224, 47))));
+
+    message
+  }
+
+  #[test]
+  fn should_deserialize_response() {
+    let bytes = dns_message_to_bytes(&example_response_message()).unwrap();
+    let dns_resp = DoHWireResponse::from_slice(&bytes).unwrap();
+
+    assert_eq!(dns_resp.message.message_type(), DnsMessageType::Response);
+    assert_eq!(dns_resp.message.response_code(), DnsResponseCode::NoError);
+    assert_eq!(dns_resp.message.queries().len(), 1);
+    assert_eq!(dns_resp.message.answers().len(), 2);
+
+    assert!(DoHWireResponse::from_slice(&[0u8, 1u8, 2u8]).is_err());
+  }
+
+  #[test]
+  fn should_apply_to_dns_message() {
+    let mut dns_msg = DnsMessage::new();
+
+    let dns_resp = DoHWireResponse { message: example_response_message() };
+    dns_resp.apply(0, &mut dns_msg).unwrap();
+
+    // Check DNS Message control
+    assert_eq!(dns_msg.truncated(), false);
+    assert_eq!(dns_msg.recursion_desired(), true);
+    assert_eq!(dns_msg.recursion_available(), true);
+    assert_eq!(dns_msg.response_code(), DnsResponseCode::NoError);
+
+    // Check queries and answers
+    assert_eq!(dns_msg.queries().len(), 1);
+    assert_eq!(dns_msg.queries()[0].name().to_utf8(), "apple.com.");
+    assert_eq!(dns_msg.answers().len(), 2);
+    assert_eq!(dns_msg.answers()[0].rdata(), &DnsRData::A(Ipv4Addr::new(17, 178, 96, 59)));
+    assert_eq!(dns_msg.answers()[1].rdata(), &DnsRData::A(Ipv4Addr::new(17, 172, 224, 47)));
+
+    assert!(dns_msg.edns().is_none());
+    assert_eq!(dns_msg.name_servers().len(), 0);
+    assert_eq!(dns_msg.additionals().len(), 0);
+  }
+
+}
@@ -5,17 +5,26 @@ mod doh_json;
 mod doh_wire;
 mod net;
 mod logging;
+mod metrics;
+mod output;
+mod querylog;
+mod dnstap;
+#[cfg(test)]
+mod test_support;
 
 use crate::net::{server::Server, request::Request};
 use crate::config::{cli::CLI, config::Config};
-use crate::core::processor::Processor;
+use crate::core::{processor::Processor, health::HealthChecker, upstream::{DoHUpstream, DoHUpstreamGroup}, routing::DoHRoute, filter::{SharedDomainLists, updater::DomainListUpdater}};
+use crate::metrics::server::MetricsServer;
+use crate::querylog::{QueryLog, QueryLogEntry, QUERY_LOG_QUEUE_CAPACITY, writer::QueryLogWriter};
+use crate::dnstap::{Dnstap, DNSTAP_QUEUE_CAPACITY, message::DnstapMessage, writer::DnstapWriter};
 
 use log::*;
 use crossbeam_channel::{Sender as XBeamSender, Receiver as XBeamReceiver, self as xbeam_channel};
 use srvzio::Service;
 use exitcode;
 
-use std::process;
+use std::{iter, process, time::Duration};
 
 fn main() {
   // Load CLI configuration and initialize logging
@@ -26,8 +35,8 @@ fn main() {
   if cli.is_list_providers() {
     // Sub-command "list providers" was invoked
     cli.list_providers();
-  } else if cli.provider().is_none() {
-    // No provider was configured: wrong usage
+  } else if cli.providers().is_empty() || !cli.has_valid_routes() {
+    // No (valid) provider was configured, for all queries or for a route: wrong usage
     process::exit(exitcode::USAGE);
   } else {
     info!("Starting...");
@@ -36,8 +45,57 @@ fn main() {
     let (sender, receiver): (XBeamSender<Request>, XBeamReceiver<Request>) = xbeam_channel::unbounded();
     let mut srv_mgr = srvzio::ServiceManager::new();
 
+    // The upstreams, of all the queries and of the routes, are shared by the resolver and the health checker
+    let mut upstream_group = cli.upstream_group();
+    let mut route_upstream_groups = cli.route_upstream_groups(&upstream_group);
+    let upstreams = distinct_upstreams(&upstream_group, &route_upstream_groups);
+
+    // Create MetricsServer (if configured): exposes what the other services are doing
+    if let Some(metrics_address) = cli.metrics_address() {
+      srv_mgr.register(Box::new(MetricsServer::new(metrics_address)));
+      register_gauges(&receiver, &upstreams);
+    }
+
+    // Create HealthChecker: probes the upstreams, so the resolver can skip the ones that are down
+    if cli.health_check_interval() > 0 {
+      let interval = Duration::from_secs(cli.health_check_interval());
+      srv_mgr.register(Box::new(HealthChecker::new(upstreams, interval)));
+    }
+    // Create QueryLogWriter (if configured): the "consumer" of query log entries
+    let query_log = cli.query_log().map(|destination| {
+      let (query_log_sender, query_log_receiver): (XBeamSender<QueryLogEntry>, XBeamReceiver<QueryLogEntry>) = xbeam_channel::bounded(QUERY_LOG_QUEUE_CAPACITY);
+      srv_mgr.register(Box::new(QueryLogWriter::new(destination, query_log_receiver)));
+
+      QueryLog::new(query_log_sender, cli.query_log_anonymize())
+    });
+    // Create DnstapWriter (if configured): the "consumer" of dnstap messages
+    let dnstap = cli.dnstap().map(|destination| {
+      let (dnstap_sender, dnstap_receiver): (XBeamSender<DnstapMessage>, XBeamReceiver<DnstapMessage>) = xbeam_channel::bounded(DNSTAP_QUEUE_CAPACITY);
+      srv_mgr.register(Box::new(DnstapWriter::new(destination, cli.dnstap_identity(), dnstap_receiver)));
+
+      Dnstap::new(dnstap_sender)
+    });
+    if let Some(dnstap) = &dnstap {
+      upstream_group.set_dnstap(dnstap.clone());
+      for (_, route_upstream_group) in &mut route_upstream_groups {
+        route_upstream_group.set_dnstap(dnstap.clone());
+      }
+    }
+    // Load the domain lists (if any) and create DomainListUpdater: keeps them up to date while in use
+    let domain_lists = cli.domain_list_loader().map(|mut loader| {
+      let lists = loader.load().unwrap_or_default();
+      info!("Blocking {} domains, allowing {} domains (and their subdomains)", lists.blocklist.len(), lists.allowlist.len());
+
+      let domain_lists = SharedDomainLists::new(lists);
+      if cli.list_refresh_interval() > 0 {
+        let interval = Duration::from_secs(cli.list_refresh_interval());
+        srv_mgr.register(Box::new(DomainListUpdater::new(loader, domain_lists.clone(), interval)));
+      }
+
+      domain_lists
+    });
     // Create Processor: the "consumer" of requests
-    srv_mgr.register(Box::new(Processor::new(receiver, cli.resolver())));
+    srv_mgr.register(Box::new(Processor::new(receiver, cli.resolver(upstream_group, route_upstream_groups, domain_lists), query_log, dnstap)));
     // Create Server: the "producer" of requests
     srv_mgr.register(Box::new(Server::new(&cli, sender)));
 
@@ -48,3 +106,33 @@ fn main() {
     info!("... Terminated.");
   }
 }
+
+/// The upstreams of all the given groups, each once (groups can share upstreams)
+fn distinct_upstreams(upstream_group: &DoHUpstreamGroup, route_upstream_groups: &[(DoHRoute, DoHUpstreamGroup)]) -> Vec<DoHUpstream> {
+  let mut upstreams: Vec<DoHUpstream> = Vec::new();
+  let groups = iter::once(upstream_group).chain(route_upstream_groups.iter().map(|(_, group)| group));
+  for upstream in groups.flat_map(|group| group.upstreams()) {
+    if upstreams.iter().all(|distinct| distinct.id() != upstream.id()) {
+      upstreams.push(upstream.clone());
+    }
+  }
+
+  upstreams
+}
+
+/// Registers the gauges about the Server -> Processor channel, and about the upstreams
+fn register_gauges(receiver: &XBeamReceiver<Request>, upstreams: &[DoHUpstream]) {
+  let receiver = receiver.clone();
+  metrics::register_gauge(&metrics::PROCESSOR_QUEUE_DEPTH, &[], move || receiver.len() as f64);
+
+  for upstream in upstreams {
+    let state = upstream.state();
+    metrics::register_gauge(&metrics::UPSTREAM_UP, &[upstream.id()], move || if state.lock().unwrap().is_up() { 1.0 } else { 0.0 });
+
+    let state = upstream.state();
+    metrics::register_gauge(&metrics::UPSTREAM_CONSECUTIVE_FAILURES, &[upstream.id()], move || f64::from(state.lock().unwrap().consecutive_failures()));
+
+    let state = upstream.state();
+    metrics::register_gauge(&metrics::UPSTREAM_LATENCY, &[upstream.id()], move || state.lock().unwrap().latency().map(|latency| latency.as_secs_f64()).unwrap_or(std::f64::NAN));
+  }
+}
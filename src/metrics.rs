@@ -0,0 +1,365 @@
+//! Metrics, collected in a global registry and exposed in [Prometheus text format](https://prometheus.io/docs/instrumenting/exposition_formats/)
+//!
+//! Metrics are described by `MetricDesc` constants, and recorded via `inc_counter()` and
+//! `observe_histogram()`: the values of the labels are given in the same order as the names in
+//! the description. Gauges are computed when rendering, via the functions given to `register_gauge()`.
+//!
+//! Collection is opt-in (see `enable()`): until then, recording a metric does nothing.
+
+pub mod server;
+
+use lazy_static::lazy_static;
+
+use std::{fmt::Write, collections::BTreeMap, sync::{Mutex, atomic::{AtomicBool, Ordering}}};
+
+const HISTOGRAM_BUCKETS_SEC: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Kind of a metric
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MetricKind {
+  Counter,
+  Histogram,
+  Gauge,
+}
+
+impl MetricKind {
+  fn name(self) -> &'static str {
+    match self {
+      MetricKind::Counter => "counter",
+      MetricKind::Histogram => "histogram",
+      MetricKind::Gauge => "gauge",
+    }
+  }
+}
+
+/// Description of a metric
+#[derive(Debug)]
+pub struct MetricDesc {
+  pub name: &'static str,
+  pub help: &'static str,
+  pub kind: MetricKind,
+  pub label_names: &'static [&'static str],
+}
+
+pub const QUERIES_RECEIVED: MetricDesc = MetricDesc {
+  name: "mooncell_queries_received_total",
+  help: "DNS queries received, by transport and query type",
+  kind: MetricKind::Counter,
+  label_names: &["transport", "qtype"],
+};
+
+pub const RESPONSES_SENT: MetricDesc = MetricDesc {
+  name: "mooncell_responses_sent_total",
+  help: "DNS responses sent, by transport and response code",
+  kind: MetricKind::Counter,
+  label_names: &["transport", "rcode"],
+};
+
+pub const REQUEST_DURATION: MetricDesc = MetricDesc {
+  name: "mooncell_request_duration_seconds",
+  help: "Time from receiving a DNS query to responding to it (end-to-end), by transport",
+  kind: MetricKind::Histogram,
+  label_names: &["transport"],
+};
+
+pub const UPSTREAM_REQUESTS: MetricDesc = MetricDesc {
+  name: "mooncell_upstream_requests_total",
+  help: "HTTP requests sent to DNS-over-HTTPS providers, by provider and HTTP status (\"error\" if no response)",
+  kind: MetricKind::Counter,
+  label_names: &["provider", "status"],
+};
+
+pub const UPSTREAM_REQUEST_DURATION: MetricDesc = MetricDesc {
+  name: "mooncell_upstream_request_duration_seconds",
+  help: "Duration of the HTTP requests sent to DNS-over-HTTPS providers, by provider",
+  kind: MetricKind::Histogram,
+  label_names: &["provider"],
+};
+
+pub const UPSTREAM_ERRORS: MetricDesc = MetricDesc {
+  name: "mooncell_upstream_errors_total",
+  help: "Failed resolutions via the upstreams (including SERVFAIL responses), by provider and kind of error",
+  kind: MetricKind::Counter,
+  label_names: &["provider", "kind"],
+};
+
+pub const CACHE_HITS: MetricDesc = MetricDesc {
+  name: "mooncell_cache_hits_total",
+  help: "DNS queries responded from the cache",
+  kind: MetricKind::Counter,
+  label_names: &[],
+};
+
+pub const CACHE_MISSES: MetricDesc = MetricDesc {
+  name: "mooncell_cache_misses_total",
+  help: "DNS queries not found in the cache",
+  kind: MetricKind::Counter,
+  label_names: &[],
+};
+
+pub const QUERIES_BLOCKED: MetricDesc = MetricDesc {
+  name: "mooncell_queries_blocked_total",
+  help: "DNS queries blocked by the blocklist",
+  kind: MetricKind::Counter,
+  label_names: &[],
+};
+
+pub const QUERIES_LOCAL: MetricDesc = MetricDesc {
+  name: "mooncell_queries_local_total",
+  help: "DNS queries answered with local records",
+  kind: MetricKind::Counter,
+  label_names: &[],
+};
+
+pub const LOG_ENTRIES_DROPPED: MetricDesc = MetricDesc {
+  name: "mooncell_log_entries_dropped_total",
+  help: "Entries dropped because the writer couldn't keep up, by log (query_log or dnstap)",
+  kind: MetricKind::Counter,
+  label_names: &["log"],
+};
+
+pub const PROCESSOR_QUEUE_DEPTH: MetricDesc = MetricDesc {
+  name: "mooncell_processor_queue_depth",
+  help: "DNS requests received by the Server, waiting to be picked up by the Processor",
+  kind: MetricKind::Gauge,
+  label_names: &[],
+};
+
+pub const UPSTREAM_UP: MetricDesc = MetricDesc {
+  name: "mooncell_upstream_up",
+  help: "Whether the last resolution (or probe) via the DNS-over-HTTPS provider was successful, by provider",
+  kind: MetricKind::Gauge,
+  label_names: &["provider"],
+};
+
+pub const UPSTREAM_CONSECUTIVE_FAILURES: MetricDesc = MetricDesc {
+  name: "mooncell_upstream_consecutive_failures",
+  help: "Consecutive failed resolutions (or probes) via the DNS-over-HTTPS provider, by provider",
+  kind: MetricKind::Gauge,
+  label_names: &["provider"],
+};
+
+pub const UPSTREAM_LATENCY: MetricDesc = MetricDesc {
+  name: "mooncell_upstream_latency_seconds",
+  help: "Observed latency (EWMA) of the resolutions via the DNS-over-HTTPS provider, by provider",
+  kind: MetricKind::Gauge,
+  label_names: &["provider"],
+};
+
+/// Values of a metric, for a specific combination of label values
+#[derive(Debug, Clone)]
+enum Series {
+  Counter(u64),
+  Histogram { buckets: Vec<u64>, sum: f64, count: u64 },
+}
+
+/// A function that computes the value of a gauge
+type GaugeFn = Box<Fn() -> f64 + Send + Sync>;
+
+#[derive(Default)]
+struct Registry {
+  series: BTreeMap<&'static str, (&'static MetricDesc, BTreeMap<Vec<String>, Series>)>,
+  gauges: BTreeMap<&'static str, (&'static MetricDesc, Vec<(Vec<String>, GaugeFn)>)>,
+}
+
+lazy_static! {
+  static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables the collection of metrics
+pub fn enable() {
+  ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns `true` if metrics are being collected
+pub fn is_enabled() -> bool {
+  ENABLED.load(Ordering::Relaxed)
+}
+
+/// Applies a change to the series of a metric, for the given label values (creating it if missing)
+fn update_series<F>(desc: &'static MetricDesc, label_values: &[&str], init: Series, update: F) where F: FnOnce(&mut Series) {
+  debug_assert_eq!(desc.label_names.len(), label_values.len(), "Wrong number of labels for metric {}", desc.name);
+
+  let mut registry = REGISTRY.lock().unwrap();
+  let (_, family) = registry.series.entry(desc.name).or_insert_with(|| (desc, BTreeMap::new()));
+  let series = family.entry(label_values.iter().map(|value| value.to_string()).collect()).or_insert(init);
+
+  update(series);
+}
+
+/// Increments a counter
+///
+/// # Parameters
+///
+/// * `desc` - Description of the counter
+/// * `label_values` - Values of the labels, in the order of `MetricDesc::label_names`
+pub fn inc_counter(desc: &'static MetricDesc, label_values: &[&str]) {
+  if !is_enabled() {
+    return;
+  }
+
+  update_series(desc, label_values, Series::Counter(0), |series| {
+    if let Series::Counter(value) = series {
+      *value += 1;
+    }
+  });
+}
+
+/// Records an observation in a histogram
+///
+/// # Parameters
+///
+/// * `desc` - Description of the histogram
+/// * `label_values` - Values of the labels, in the order of `MetricDesc::label_names`
+/// * `value` - The observed value (ex. duration in seconds)
+pub fn observe_histogram(desc: &'static MetricDesc, label_values: &[&str], value: f64) {
+  if !is_enabled() {
+    return;
+  }
+
+  let init = Series::Histogram { buckets: vec![0; HISTOGRAM_BUCKETS_SEC.len()], sum: 0.0, count: 0 };
+  update_series(desc, label_values, init, |series| {
+    if let Series::Histogram { buckets, sum, count } = series {
+      for (bucket, upper_bound) in buckets.iter_mut().zip(HISTOGRAM_BUCKETS_SEC) {
+        if value <= *upper_bound {
+          *bucket += 1;
+        }
+      }
+      *sum += value;
+      *count += 1;
+    }
+  });
+}
+
+/// Registers a gauge, that is computed every time metrics are rendered
+///
+/// # Parameters
+///
+/// * `desc` - Description of the gauge
+/// * `label_values` - Values of the labels, in the order of `MetricDesc::label_names`
+/// * `gauge_fn` - Computes the value of the gauge
+pub fn register_gauge<F>(desc: &'static MetricDesc, label_values: &[&str], gauge_fn: F) where F: Fn() -> f64 + Send + Sync + 'static {
+  debug_assert_eq!(desc.label_names.len(), label_values.len(), "Wrong number of labels for metric {}", desc.name);
+
+  let mut registry = REGISTRY.lock().unwrap();
+  let (_, family) = registry.gauges.entry(desc.name).or_insert_with(|| (desc, Vec::new()));
+  family.push((label_values.iter().map(|value| value.to_string()).collect(), Box::new(gauge_fn)));
+}
+
+/// Renders all the metrics in Prometheus text format
+pub fn render() -> String {
+  let registry = REGISTRY.lock().unwrap();
+  let mut out = String::new();
+
+  for (desc, family) in registry.series.values() {
+    write_header(&mut out, desc);
+
+    for (label_values, series) in family {
+      let labels: Vec<(&str, &str)> = desc.label_names.iter().cloned().zip(label_values.iter().map(String::as_str)).collect();
+
+      match series {
+        Series::Counter(value) => {
+          writeln!(out, "{}{} {}", desc.name, format_labels(&labels), value).unwrap();
+        },
+        Series::Histogram { buckets, sum, count } => {
+          for (bucket, upper_bound) in buckets.iter().zip(HISTOGRAM_BUCKETS_SEC) {
+            let upper_bound = upper_bound.to_string();
+            let mut bucket_labels = labels.clone();
+            bucket_labels.push(("le", &upper_bound));
+            writeln!(out, "{}_bucket{} {}", desc.name, format_labels(&bucket_labels), bucket).unwrap();
+          }
+          let mut inf_labels = labels.clone();
+          inf_labels.push(("le", "+Inf"));
+          writeln!(out, "{}_bucket{} {}", desc.name, format_labels(&inf_labels), count).unwrap();
+          writeln!(out, "{}_sum{} {}", desc.name, format_labels(&labels), sum).unwrap();
+          writeln!(out, "{}_count{} {}", desc.name, format_labels(&labels), count).unwrap();
+        },
+      }
+    }
+  }
+
+  for (desc, family) in registry.gauges.values() {
+    write_header(&mut out, desc);
+
+    for (label_values, gauge_fn) in family {
+      let labels: Vec<(&str, &str)> = desc.label_names.iter().cloned().zip(label_values.iter().map(String::as_str)).collect();
+      writeln!(out, "{}{} {}", desc.name, format_labels(&labels), gauge_fn()).unwrap();
+    }
+  }
+
+  out
+}
+
+fn write_header(out: &mut String, desc: &MetricDesc) {
+  writeln!(out, "# HELP {} {}", desc.name, desc.help).unwrap();
+  writeln!(out, "# TYPE {} {}", desc.name, desc.kind.name()).unwrap();
+}
+
+/// Formats labels as `{name="value",...}` (empty if there are no labels), escaping the values
+fn format_labels(labels: &[(&str, &str)]) -> String {
+  if labels.is_empty() {
+    return String::new();
+  }
+
+  let labels: Vec<String> = labels.iter()
+    .map(|(name, value)| format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+    .collect();
+
+  format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const TEST_COUNTER: MetricDesc = MetricDesc {
+    name: "mooncell_test_total",
+    help: "Test counter",
+    kind: MetricKind::Counter,
+    label_names: &["name"],
+  };
+
+  const TEST_GAUGE: MetricDesc = MetricDesc {
+    name: "mooncell_test_gauge",
+    help: "Test gauge",
+    kind: MetricKind::Gauge,
+    label_names: &["name"],
+  };
+
+  const TEST_HISTOGRAM: MetricDesc = MetricDesc {
+    name: "mooncell_test_seconds",
+    help: "Test histogram",
+    kind: MetricKind::Histogram,
+    label_names: &[],
+  };
+
+  #[test]
+  fn should_render_metrics() {
+    enable();
+
+    inc_counter(&TEST_COUNTER, &["a\"b"]);
+    inc_counter(&TEST_COUNTER, &["a\"b"]);
+    inc_counter(&TEST_COUNTER, &["c"]);
+    observe_histogram(&TEST_HISTOGRAM, &[], 0.02);
+    observe_histogram(&TEST_HISTOGRAM, &[], 20.0);
+    register_gauge(&TEST_GAUGE, &["a"], || 1.5);
+    register_gauge(&TEST_GAUGE, &["b"], || 3.0);
+
+    let rendered = render();
+    assert!(rendered.contains("# TYPE mooncell_test_total counter\n"));
+    assert!(rendered.contains("mooncell_test_total{name=\"a\\\"b\"} 2\n"));
+    assert!(rendered.contains("mooncell_test_total{name=\"c\"} 1\n"));
+    assert!(rendered.contains("# TYPE mooncell_test_seconds histogram\n"));
+    assert!(rendered.contains("mooncell_test_seconds_bucket{le=\"0.01\"} 0\n"));
+    assert!(rendered.contains("mooncell_test_seconds_bucket{le=\"0.025\"} 1\n"));
+    assert!(rendered.contains("mooncell_test_seconds_bucket{le=\"10\"} 1\n"));
+    assert!(rendered.contains("mooncell_test_seconds_bucket{le=\"+Inf\"} 2\n"));
+    assert!(rendered.contains("mooncell_test_seconds_sum 20.02\n"));
+    assert!(rendered.contains("mooncell_test_seconds_count 2\n"));
+    assert_eq!(rendered.matches("# TYPE mooncell_test_gauge gauge\n").count(), 1);
+    assert!(rendered.contains("mooncell_test_gauge{name=\"a\"} 1.5\n"));
+    assert!(rendered.contains("mooncell_test_gauge{name=\"b\"} 3\n"));
+  }
+}
@@ -0,0 +1,180 @@
+//! Implementation of a (minimal) HTTP server, that exposes the metrics to Prometheus
+//!
+//! It only serves `GET /metrics`, each connection on its own thread (up to a few at once): it's
+//! meant to be scraped, not browsed.
+
+use crate::net::utils::wake_tcp_listener;
+
+use log::*;
+use srvzio;
+
+use std::{net::{SocketAddr, TcpListener, TcpStream}, thread, time::Duration, io::{Read, Write}};
+
+const METRICS_SERVER_SERVICE_NAME: &'static str = "MetricsServer";
+const METRICS_SERVER_THREAD_NAME: &'static str = "metrics_server_thread";
+const METRICS_SERVER_WAKE_TIMEOUT_MS: u64 = 500;
+const METRICS_SERVER_IO_TIMEOUT_SEC: u64 = 5;
+const METRICS_SERVER_MAX_CONNECTIONS: usize = 8;
+const METRICS_SERVER_REQUEST_MAX_SIZE: usize = 8192;
+const METRICS_PATH: &'static str = "/metrics";
+const METRICS_CONTENT_TYPE: &'static str = "text/plain; version=0.0.4";
+
+/// MetricsServer is a service that serves the metrics over HTTP, at `/metrics`
+///
+/// It also enables the collection of metrics (see `metrics::enable()`).
+pub struct MetricsServer {
+  address: SocketAddr,
+  local_address: Option<SocketAddr>,
+  status: srvzio::ServiceStatusFlag,
+  server_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MetricsServer {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  /// * `address`: the address to listen on (ex. `127.0.0.1:9153`)
+  pub fn new(address: SocketAddr) -> MetricsServer {
+    super::enable();
+
+    MetricsServer {
+      address,
+      local_address: None,
+      status: srvzio::ServiceStatusFlag::default(),
+      server_thread: None,
+    }
+  }
+
+}
+
+impl srvzio::Service for MetricsServer {
+
+  fn name(&self) -> &'static str {
+    METRICS_SERVER_SERVICE_NAME
+  }
+
+  fn start(&mut self) {
+    self.status.starting();
+
+    let tcp_listener = TcpListener::bind(self.address).expect(&format!("Could not bind to TCP: {}", self.address));
+    self.local_address = tcp_listener.local_addr().ok();
+    let status = self.status.clone();
+
+    // Launch a thread to accept connections, each responded to by a thread of its own
+    self.server_thread = Some(thread::Builder::new()
+      .name(METRICS_SERVER_THREAD_NAME.into())
+      .spawn(move || {
+        let mut connection_threads: Vec<thread::JoinHandle<()>> = Vec::new();
+
+        status.started();
+
+        loop {
+          // `accept` blocks: when the `MetricsServer` is stopped, a connection is made to wake this thread up
+          let accept_result = tcp_listener.accept();
+          if status.is_stopping() {
+            trace!("{} is done running: stop accepting connections", METRICS_SERVER_SERVICE_NAME);
+            break;
+          }
+
+          // Forget about connection threads that are done
+          connection_threads.retain(|t| !t.is_finished());
+
+          match accept_result {
+            Ok((tcp_stream, src)) => {
+              if connection_threads.len() >= METRICS_SERVER_MAX_CONNECTIONS {
+                warn!("Too many metrics connections ({}): closing connection from '{}'", connection_threads.len(), src);
+                continue;
+              }
+
+              trace!("Accepted metrics connection from '{}'", src);
+              let connection_thread = thread::Builder::new()
+                .name(format!("{}_{}", METRICS_SERVER_THREAD_NAME, src))
+                .spawn(move || if let Err(err) = handle_http_connection(tcp_stream) {
+                  warn!("Unable to serve metrics to '{}': {}", src, err);
+                });
+
+              match connection_thread {
+                Ok(t) => connection_threads.push(t),
+                Err(err) => error!("Unable to spawn thread for metrics connection from '{}': {}", src, err),
+              }
+            },
+            Err(err) => {
+              error!("Error accepting: {:?} {}", err.kind(), err);
+            }
+          }
+        }
+
+        // Wait for all the connections to be done
+        while let Some(t) = connection_threads.pop() {
+          if t.join().is_err() {
+            error!("A metrics connection thread panicked upon termination");
+          }
+        }
+
+        status.stopped();
+      })
+      .expect(format!("Unable to spawn thread: {}", METRICS_SERVER_THREAD_NAME).as_ref())
+    );
+  }
+
+  fn await_started(&mut self) {
+    while !self.status.is_started() {}
+  }
+
+  fn stop(&mut self) {
+    trace!("{} should now stop...", METRICS_SERVER_SERVICE_NAME);
+    self.status.stopping();
+
+    // The listener blocks on `accept`: connect to it, so it notices it should stop
+    if let Some(local_address) = self.local_address {
+      if let Err(err) = wake_tcp_listener(local_address, Duration::from_millis(METRICS_SERVER_WAKE_TIMEOUT_MS)) {
+        error!("Unable to wake metrics listener on '{}': {}", local_address, err);
+      }
+    }
+  }
+
+  fn await_stopped(&mut self) {
+    while !self.status.is_stopped() {}
+
+    // Wait for server thread to stop (if it's actually set)
+    if self.server_thread.is_some() {
+      self.server_thread
+        .take()
+        .unwrap()
+        .join()
+        .expect(format!("Panicked upon termination: {}", METRICS_SERVER_THREAD_NAME).as_ref());
+    }
+  }
+
+}
+
+/// Reads an HTTP request from the connection, and responds to it (then closes the connection)
+fn handle_http_connection(mut tcp_stream: TcpStream) -> std::io::Result<()> {
+  tcp_stream.set_read_timeout(Some(Duration::from_secs(METRICS_SERVER_IO_TIMEOUT_SEC)))?;
+  tcp_stream.set_write_timeout(Some(Duration::from_secs(METRICS_SERVER_IO_TIMEOUT_SEC)))?;
+
+  // Read until the end of the headers: the request line is all that matters
+  let mut buf: Vec<u8> = Vec::new();
+  let mut chunk: [u8; 1024] = [0; 1024];
+  while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < METRICS_SERVER_REQUEST_MAX_SIZE {
+    let amount = tcp_stream.read(&mut chunk)?;
+    if amount == 0 {
+      break;
+    }
+    buf.extend_from_slice(&chunk[..amount]);
+  }
+
+  let request = String::from_utf8_lossy(&buf);
+  let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+  let (status, content_type, body) = match (request_line.next(), request_line.next()) {
+    (Some("GET"), Some(path)) if path == METRICS_PATH || path.starts_with(&format!("{}?", METRICS_PATH)) => {
+      ("200 OK", METRICS_CONTENT_TYPE, super::render())
+    },
+    (Some("GET"), Some(_)) => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+    _ => ("405 Method Not Allowed", "text/plain", "Method Not Allowed\n".to_string()),
+  };
+
+  write!(tcp_stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, content_type, body.len(), body)?;
+  tcp_stream.flush()
+}
@@ -1,7 +1,9 @@
 //! Module concerning with "low level" networking
 //!
-//! It provides the UDP and TCP interface of the `server::DnsServer`.
+//! It provides the UDP and TCP interface of the `server::DnsServer`, as well as the HTTP client
+//! used to talk to DNS-over-HTTPS providers.
 
 pub mod utils;
+pub mod http;
 pub mod server;
 pub mod request;
@@ -0,0 +1,396 @@
+//! Utility methods to execute HTTP requests
+//!
+//! It's based on [cURL](https://crates.io/crates/curl) and it's shared by all the DNS-over-HTTPS
+//! protocol implementations.
+
+use crate::metrics;
+
+use log::*;
+use curl::{Error as CurlError, easy::{Easy as CurlEasy, HttpVersion as CurlHttpVersion, List as CurlList}};
+use http::{Version as HttpVersion, Method as HttpMethod, Request as HttpRequest, Response as HttpResponse, HeaderMap as HttpHeaderMap, StatusCode as HttpStatusCode, header::{self, HeaderName, HeaderValue}};
+
+use std::{fmt, error::Error, net::IpAddr, time::{Duration, Instant}};
+
+const HTTP_REQUEST_TIMEOUT_SEC: u64 = 60;
+const HTTP_CONNECT_TIMEOUT_SEC: u64 = 10;
+const HTTP_DOH_CONNECT_TIMEOUT_SEC: u64 = 2;
+const HTTP_DEFAULT_PORT: u16 = 80;
+const HTTPS_DEFAULT_PORT: u16 = 443;
+const HTTP_MAX_REDIRECTIONS: u32 = 5;
+
+/// Maximum size of the body of a response from a DNS-over-HTTPS provider
+///
+/// A DNS message is at most 64KB in wire-format: this leaves plenty of room for its JSON form.
+pub const HTTP_DOH_RESPONSE_MAX_SIZE_BYTES: usize = 1024 * 1024;
+
+/// Maximum time a request to a DNS-over-HTTPS provider can take (connection included)
+///
+/// Past it, the request fails and the next upstream (if any) is tried: this has to fit in the
+/// time clients wait before retrying (usually 5 seconds), like for plain DNS.
+pub const HTTP_DOH_REQUEST_TIMEOUT_SEC: u64 = 5;
+
+/// Addresses to connect to, instead of resolving the host of the request URI
+///
+/// When added to the extensions of an `http::Request`, the connection goes to one of these
+/// addresses, while the original host is still used for TLS (SNI) and for the `Host` header.
+/// This is how a DNS-over-HTTPS provider can be reached without using DNS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpBootstrapAddresses(pub Vec<IpAddr>);
+
+/// Identifier of the DNS-over-HTTPS provider an `http::Request` is for
+///
+/// When added to the extensions of an `http::Request`, it's used to label the metrics about it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpProviderId(pub String);
+
+/// Follow redirects (i.e. `3xx` responses with a `Location` header)
+///
+/// When added to the extensions of an `http::Request`, redirects are followed: the response is
+/// the one at the end of the redirects. By default, redirects are not followed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpFollowRedirects;
+
+/// Media types accepted in the `Content-Type` of the response
+///
+/// When added to the extensions of an `http::Request`, `execute_http_request()` fails if the
+/// response doesn't declare a `Content-Type` among these (ex. the HTML page of a captive portal).
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpExpectedContentTypes(pub &'static [&'static str]);
+
+/// Error returned when an HTTP request fails, or its response is not acceptable
+#[derive(Debug)]
+pub enum HttpResponseError {
+  /// The request couldn't be executed (see `curl::Error`)
+  Curl(CurlError),
+  /// The response was a redirect, to the given `Location` (if any), that was not followed
+  Redirect(HttpStatusCode, Option<String>),
+  /// The response had a non-successful status, and an optional `Retry-After` header
+  Status(HttpStatusCode, Option<String>),
+  /// The response had an unexpected `Content-Type`, or none at all
+  ContentType(Option<String>),
+  /// The body of the response was bigger than the given maximum size (in bytes)
+  TooLarge(usize),
+}
+
+impl HttpResponseError {
+  /// HTTP status of the response, if a response was received
+  pub fn status(&self) -> Option<HttpStatusCode> {
+    match self {
+      HttpResponseError::Redirect(status, _) | HttpResponseError::Status(status, _) => Some(*status),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for HttpResponseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      HttpResponseError::Curl(curl_error) => write!(f, "{}", curl_error),
+      HttpResponseError::Redirect(status, Some(location)) => write!(f, "Unexpected redirect ({}) to '{}'", status, location),
+      HttpResponseError::Redirect(status, None) => write!(f, "Unexpected redirect ({})", status),
+      HttpResponseError::Status(status, Some(retry_after)) => write!(f, "Unexpected HTTP status {} (Retry-After: {})", status, retry_after),
+      HttpResponseError::Status(status, None) => write!(f, "Unexpected HTTP status {}", status),
+      HttpResponseError::ContentType(Some(content_type)) => write!(f, "Unexpected Content-Type '{}'", content_type),
+      HttpResponseError::ContentType(None) => write!(f, "Missing Content-Type"),
+      HttpResponseError::TooLarge(max_size) => write!(f, "Response bigger than {} bytes", max_size),
+    }
+  }
+}
+
+impl Error for HttpResponseError {
+  fn source(&self) -> Option<&(Error + 'static)> {
+    match self {
+      HttpResponseError::Curl(curl_error) => Some(curl_error),
+      _ => None,
+    }
+  }
+}
+
+impl From<CurlError> for HttpResponseError {
+  fn from(curl_error: CurlError) -> Self {
+    HttpResponseError::Curl(curl_error)
+  }
+}
+
+/// Converts an `http::Version` to the corresponding value in `curl::HttpVersion`
+///
+/// # Parameters
+///
+/// * `version`: The HTTP Version to convert
+fn http_version_to_curl(version: HttpVersion) -> CurlHttpVersion {
+  match version {
+    HttpVersion::HTTP_2 => CurlHttpVersion::V2,
+    HttpVersion::HTTP_10 => CurlHttpVersion::V10,
+    HttpVersion::HTTP_11 | _ => CurlHttpVersion::V11,
+  }
+}
+
+/// Converts an `http:HeaderMap` to a `curl::List` (used to carry headers)
+///
+/// # Parameters
+///
+/// * `header_map`: A map of headers
+fn http_headers_to_curl(header_map: &HttpHeaderMap) -> Result<CurlList, CurlError> {
+  let mut curl_headers = CurlList::new();
+
+  for (name, value) in header_map.iter() {
+    curl_headers.append(format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes())).as_ref())?;
+  }
+
+  Ok(curl_headers)
+}
+
+/// Converts an `HttpBootstrapAddresses` to a `curl::List` of "resolve overrides"
+///
+/// Each entry is in the format `HOST:PORT:ADDRESS[,ADDRESS]...`, as expected by
+/// [CURLOPT_RESOLVE](https://curl.haxx.se/libcurl/c/CURLOPT_RESOLVE.html).
+///
+/// # Parameters
+///
+/// * `req_http`: The HTTP request to connect for
+/// * `bootstrap`: The addresses to connect to
+fn http_bootstrap_to_curl(req_http: &HttpRequest<Vec<u8>>, bootstrap: &HttpBootstrapAddresses) -> Option<CurlList> {
+  let uri = req_http.uri();
+  let host = uri.host()?;
+  let port = uri.port_part()
+    .map(|port| port.as_u16())
+    .unwrap_or_else(|| if uri.scheme_str() == Some("http") { HTTP_DEFAULT_PORT } else { HTTPS_DEFAULT_PORT });
+  let addresses: Vec<String> = bootstrap.0.iter()
+    .map(|address| match address {
+      IpAddr::V4(ipv4) => ipv4.to_string(),
+      IpAddr::V6(ipv6) => format!("[{}]", ipv6),
+    })
+    .collect();
+
+  if addresses.is_empty() {
+    return None;
+  }
+
+  let mut curl_resolve = CurlList::new();
+  curl_resolve.append(&format!("{}:{}:{}", host, port, addresses.join(","))).ok()?;
+
+  Some(curl_resolve)
+}
+
+/// Executes a (synchronous) HTTP Request and returns the raw body of the response
+///
+/// If the request method is `POST`, the request body is sent as-is.
+/// If the request carries `HttpBootstrapAddresses` in its extensions, those are used to connect.
+/// Metrics are recorded about the request, labeled with its `HttpProviderId` (or host).
+///
+/// The response is validated (see `validate_http_response()`) and its body can't be bigger than
+/// `HTTP_DOH_RESPONSE_MAX_SIZE_BYTES`. The request fails if it takes longer than `HTTP_DOH_REQUEST_TIMEOUT_SEC`.
+///
+/// # Parameters
+///
+/// * `req_http`: An `http::Request`, usually created by a `DoHProvider`
+pub fn execute_http_request(req_http: &HttpRequest<Vec<u8>>) -> Result<Vec<u8>, HttpResponseError> {
+  let start = Instant::now();
+  let mut req_curl = CurlEasy::new();
+  let timeouts = (Duration::from_secs(HTTP_DOH_REQUEST_TIMEOUT_SEC), Duration::from_secs(HTTP_DOH_CONNECT_TIMEOUT_SEC));
+  let res = perform_http_request(&mut req_curl, req_http, Some(HTTP_DOH_RESPONSE_MAX_SIZE_BYTES), timeouts);
+
+  let provider_id = req_http.extensions().get::<HttpProviderId>()
+    .map(|provider_id| provider_id.0.as_str())
+    .or_else(|| req_http.uri().host())
+    .unwrap_or_default();
+  let status = match &res {
+    Ok(res_http) => res_http.status().as_u16().to_string(),
+    Err(_) => "error".to_string(),
+  };
+  metrics::inc_counter(&metrics::UPSTREAM_REQUESTS, &[provider_id, &status]);
+  metrics::observe_histogram(&metrics::UPSTREAM_REQUEST_DURATION, &[provider_id], start.elapsed().as_secs_f64());
+
+  let res_http = res?;
+  if let Err(err) = validate_http_response(req_http, &res_http) {
+    match err.status() {
+      Some(HttpStatusCode::TOO_MANY_REQUESTS) => warn!("Rate limited by '{}': {}", provider_id, err),
+      _ => debug!("Rejected HTTP response from '{}': {}", provider_id, err),
+    }
+    return Err(err);
+  }
+
+  Ok(res_http.into_body())
+}
+
+/// Validates an HTTP response, before its body is handed over to be parsed
+///
+/// The response must have a successful (`2xx`) status and, if the request carries
+/// `HttpExpectedContentTypes` in its extensions, a `Content-Type` among those (a response without
+/// `Content-Type` is then rejected).
+///
+/// # Parameters
+///
+/// * `req_http`: The `http::Request` the response is for
+/// * `res_http`: The `http::Response` to validate
+pub fn validate_http_response(req_http: &HttpRequest<Vec<u8>>, res_http: &HttpResponse<Vec<u8>>) -> Result<(), HttpResponseError> {
+  let header_str = |name: HeaderName| res_http.headers().get(name)
+    .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+
+  let status = res_http.status();
+  if status.is_redirection() {
+    return Err(HttpResponseError::Redirect(status, header_str(header::LOCATION)));
+  }
+  if !status.is_success() {
+    return Err(HttpResponseError::Status(status, header_str(header::RETRY_AFTER)));
+  }
+
+  if let Some(expected) = req_http.extensions().get::<HttpExpectedContentTypes>() {
+    let content_type = header_str(header::CONTENT_TYPE).ok_or(HttpResponseError::ContentType(None))?;
+    // Parameters (ex. "; charset=UTF-8") are irrelevant here
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    if !expected.0.iter().any(|expected_media_type| expected_media_type.eq_ignore_ascii_case(media_type)) {
+      return Err(HttpResponseError::ContentType(Some(content_type)));
+    }
+  }
+
+  Ok(())
+}
+
+/// Executes a (synchronous) HTTP Request and returns the response (status, headers and raw body)
+///
+/// Unlike `execute_http_request()`, no metrics are recorded: this is meant for requests that are
+/// not sent to DNS-over-HTTPS providers (ex. to download lists).
+///
+/// # Parameters
+///
+/// * `req_http`: An `http::Request`
+pub fn fetch_http_response(req_http: &HttpRequest<Vec<u8>>) -> Result<HttpResponse<Vec<u8>>, HttpResponseError> {
+  let timeouts = (Duration::from_secs(HTTP_REQUEST_TIMEOUT_SEC), Duration::from_secs(HTTP_CONNECT_TIMEOUT_SEC));
+  perform_http_request(&mut CurlEasy::new(), req_http, None, timeouts)
+}
+
+/// Sets up the given cURL request to execute the given HTTP Request, and performs it
+///
+/// If `max_size` is given, the transfer is aborted as soon as the body of the response exceeds it.
+/// The `timeouts` are the ones of the whole request, and of establishing the connection.
+fn perform_http_request(req_curl: &mut CurlEasy, req_http: &HttpRequest<Vec<u8>>, max_size: Option<usize>, timeouts: (Duration, Duration)) -> Result<HttpResponse<Vec<u8>>, HttpResponseError> {
+  let mut res_curl_buf: Vec<u8> = Vec::new();
+  let mut res_curl_headers: Vec<(String, String)> = Vec::new();
+  let mut res_too_large = false;
+
+  // Setup the cURL Request by adapting the given HTTP Request
+  let (timeout, connect_timeout) = timeouts;
+  req_curl.timeout(timeout)?;
+  req_curl.connect_timeout(connect_timeout)?;
+  req_curl.http_version(http_version_to_curl(req_http.version()))?;
+  req_curl.url(format!("{}", req_http.uri()).as_ref())?;
+  req_curl.http_headers(http_headers_to_curl(req_http.headers())?)?;
+  if let Some(bootstrap) = req_http.extensions().get::<HttpBootstrapAddresses>() {
+    if let Some(curl_resolve) = http_bootstrap_to_curl(req_http, bootstrap) {
+      req_curl.resolve(curl_resolve)?;
+    }
+  }
+  if req_http.extensions().get::<HttpFollowRedirects>().is_some() {
+    req_curl.follow_location(true)?;
+    req_curl.max_redirections(HTTP_MAX_REDIRECTIONS)?;
+  }
+  if let Some(max_size) = max_size {
+    // Only effective when the size is known upfront (i.e. `Content-Length`): see `write_function()` below
+    req_curl.max_filesize(max_size as u64)?;
+  }
+  if req_http.method() == HttpMethod::POST {
+    req_curl.post(true)?;
+    req_curl.post_fields_copy(req_http.body())?;
+  }
+
+  // Execute the request and wait for data to be written in the response buffer
+  let res_curl_buf_max = max_size.unwrap_or(std::usize::MAX);
+  let res_curl = {
+    let mut req_curl_transfer = req_curl.transfer();
+    req_curl_transfer.write_function(|data| {
+      if res_curl_buf.len() + data.len() > res_curl_buf_max {
+        // Writing less than given makes cURL abort the transfer
+        res_too_large = true;
+        return Ok(0);
+      }
+      res_curl_buf.extend_from_slice(data);
+      Ok(data.len())
+    })?;
+    req_curl_transfer.header_function(|line| {
+      let line = String::from_utf8_lossy(line);
+      if line.starts_with("HTTP/") {
+        // Status line: the headers of any previous response (ex. a redirect) don't apply
+        res_curl_headers.clear();
+      } else if let Some(colon) = line.find(':') {
+        res_curl_headers.push((line[..colon].trim().to_string(), line[colon + 1..].trim().to_string()));
+      }
+      true
+    })?;
+    req_curl_transfer.perform()
+  };
+  match (res_curl, max_size) {
+    (Err(ref curl_error), Some(max_size)) if res_too_large || curl_error.is_filesize_exceeded() => {
+      return Err(HttpResponseError::TooLarge(max_size));
+    },
+    (res_curl, _) => res_curl?,
+  }
+
+  trace!("Received {} bytes in HTTP response from '{}'", res_curl_buf.len(), req_http.uri());
+
+  let mut res_http = HttpResponse::new(res_curl_buf);
+  if let Ok(status) = HttpStatusCode::from_u16(req_curl.response_code()? as u16) {
+    *res_http.status_mut() = status;
+  }
+  for (raw_name, raw_value) in res_curl_headers {
+    if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(raw_name.as_bytes()), HeaderValue::from_str(&raw_value)) {
+      res_http.headers_mut().append(name, value);
+    }
+  }
+
+  Ok(res_http)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn response(status: HttpStatusCode, headers: &[(HeaderName, &'static str)]) -> HttpResponse<Vec<u8>> {
+    let mut res_http = HttpResponse::new(Vec::new());
+    *res_http.status_mut() = status;
+    for (name, value) in headers {
+      res_http.headers_mut().insert(name.clone(), HeaderValue::from_static(value));
+    }
+
+    res_http
+  }
+
+  #[test]
+  fn should_validate_status() {
+    let req_http = HttpRequest::get("https://doh.example.com/").body(Vec::new()).unwrap();
+
+    assert!(validate_http_response(&req_http, &response(HttpStatusCode::OK, &[])).is_ok());
+    match validate_http_response(&req_http, &response(HttpStatusCode::TOO_MANY_REQUESTS, &[(header::RETRY_AFTER, "30")])) {
+      Err(HttpResponseError::Status(HttpStatusCode::TOO_MANY_REQUESTS, Some(retry_after))) => assert_eq!(retry_after, "30"),
+      other => panic!("Unexpected: {:?}", other),
+    }
+    match validate_http_response(&req_http, &response(HttpStatusCode::FOUND, &[(header::LOCATION, "http://portal.example.com/")])) {
+      Err(HttpResponseError::Redirect(HttpStatusCode::FOUND, Some(location))) => assert_eq!(location, "http://portal.example.com/"),
+      other => panic!("Unexpected: {:?}", other),
+    }
+    assert_eq!(validate_http_response(&req_http, &response(HttpStatusCode::SERVICE_UNAVAILABLE, &[])).unwrap_err().status(), Some(HttpStatusCode::SERVICE_UNAVAILABLE));
+  }
+
+  #[test]
+  fn should_validate_content_type() {
+    let mut req_http = HttpRequest::get("https://doh.example.com/").body(Vec::new()).unwrap();
+
+    // Anything goes, if no Content-Type is expected
+    assert!(validate_http_response(&req_http, &response(HttpStatusCode::OK, &[(header::CONTENT_TYPE, "text/html")])).is_ok());
+    assert!(validate_http_response(&req_http, &response(HttpStatusCode::OK, &[])).is_ok());
+
+    req_http.extensions_mut().insert(HttpExpectedContentTypes(&["application/dns-message"]));
+    assert!(validate_http_response(&req_http, &response(HttpStatusCode::OK, &[(header::CONTENT_TYPE, "application/dns-message")])).is_ok());
+    assert!(validate_http_response(&req_http, &response(HttpStatusCode::OK, &[(header::CONTENT_TYPE, "Application/DNS-Message; charset=UTF-8")])).is_ok());
+    match validate_http_response(&req_http, &response(HttpStatusCode::OK, &[(header::CONTENT_TYPE, "text/html; charset=UTF-8")])) {
+      Err(HttpResponseError::ContentType(content_type)) => assert_eq!(content_type, Some("text/html; charset=UTF-8".to_string())),
+      other => panic!("Unexpected: {:?}", other),
+    }
+
+    // Once a Content-Type is expected, it must be there
+    match validate_http_response(&req_http, &response(HttpStatusCode::OK, &[])) {
+      Err(HttpResponseError::ContentType(content_type)) => assert_eq!(content_type, None),
+      other => panic!("Unexpected: {:?}", other),
+    }
+  }
+}
@@ -2,11 +2,13 @@
 //!
 //! It's role is to wrap the received DNS query and provide a network-abstract way to respond back
 
-use crate::dns::protocol::{DnsMessage, DnsMessageType, dns_message_to_bytes};
+use crate::dns::protocol::{DnsMessage, DnsMessageType, DnsResponseCode, DnsProtoError, dns_message_to_bytes, dns_error_response};
+use crate::metrics;
+use super::utils::{tcp_message_with_length, TCP_MESSAGE_MAX_SIZE};
 
 use log::*;
 
-use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::{net::{SocketAddr, TcpStream, UdpSocket}, io::Write, time::Instant, sync::{Arc, Mutex}};
 
 /// The type of `DnsRequest`
 #[derive(Debug)]
@@ -25,8 +27,9 @@ pub struct Request {
   source: SocketAddr,
   dns_query: DnsMessage,
   req_type: RequestType,
-  tcp_stream: Option<TcpStream>,
+  tcp_stream: Option<Arc<Mutex<TcpStream>>>,
   udp_socket: Option<UdpSocket>,
+  received: Instant,
 }
 
 impl Request {
@@ -46,7 +49,8 @@ impl Request {
       dns_query,
       req_type: RequestType::UdpRequest,
       tcp_stream: None,
-      udp_socket: Some(socket)
+      udp_socket: Some(socket),
+      received: Instant::now(),
     }
   }
 
@@ -58,14 +62,16 @@ impl Request {
   ///
   /// * `source` - Socket address source
   /// * `dns_query` - DNS DnsMessage received from the given source
-  /// * `stream` - TCP Stream representing a live and established connection with the source
-  pub fn from_tcp(source: SocketAddr, dns_query: DnsMessage, stream: TcpStream) -> Request {
+  /// * `stream` - TCP Stream representing a live and established connection with the source,
+  ///   shared by all the requests received over it (so their responses are written one at a time)
+  pub fn from_tcp(source: SocketAddr, dns_query: DnsMessage, stream: Arc<Mutex<TcpStream>>) -> Request {
     Request {
       source,
       dns_query,
       req_type: RequestType::TcpRequest,
       tcp_stream: Some(stream),
-      udp_socket: None
+      udp_socket: None,
+      received: Instant::now(),
     }
   }
 
@@ -79,42 +85,82 @@ impl Request {
     &self.dns_query
   }
 
+  /// Return when the request was received
+  pub fn received(&self) -> Instant {
+    self.received
+  }
+
+  /// Return the transport the request was received over (i.e. "udp" or "tcp")
+  pub fn transport(&self) -> &'static str {
+    match self.req_type {
+      RequestType::UdpRequest => "udp",
+      RequestType::TcpRequest => "tcp",
+    }
+  }
+
   /// Respond to the request with the given DNS response
   ///
-  /// NOTE: Assumes the DNS Message Type is a Response, otherwise it will panic!
+  /// If the given DNS response is not of type `Response`, or it can't be serialized, the request
+  /// is responded with a `DnsResponseCode::ServFail` instead: the client should never be left waiting.
   ///
   /// # Parameters
   /// * `dns_res`: `DnsMessage` of type `DnsMessageType::Response`, that this call will send to the original requestor
   pub fn respond(self, dns_res: DnsMessage) {
-    // Before resolving, check the type is right
-    if dns_res.message_type() == DnsMessageType::Query {
+    // Before responding, check the type is right
+    let raw_dns_res_result = if dns_res.message_type() == DnsMessageType::Query {
       error!("A DNS Query was provided instead of a DNS Response: this is clearly a bug that needs fixing!");
-      // TODO Send a empty/error DNS response (or something sensible)
-    }
+      Err(DnsProtoError::from("DNS Message is not a Response"))
+    } else {
+      dns_message_to_bytes(&dns_res).and_then(|raw_dns_res| match self.req_type {
+        RequestType::TcpRequest if raw_dns_res.len() > TCP_MESSAGE_MAX_SIZE => {
+          Err(DnsProtoError::from(format!("DNS Message is too big for TCP ({} bytes)", raw_dns_res.len())))
+        },
+        _ => Ok(raw_dns_res),
+      })
+    };
 
-    match dns_message_to_bytes(&dns_res) {
-      Ok(raw_dns_res) => {
-        match self.req_type {
-          // Send response over UDP
-          RequestType::UdpRequest => {
-            if let Err(err) = self.udp_socket.unwrap().send_to(raw_dns_res.as_ref(), self.source) {
-              error!("Unable to send response back over UDP socket: {}", err);
-              // TODO Send a empty/error DNS response (or something sensible)
-            };
-          },
-
-          // Send response over TCP
-          RequestType::TcpRequest => {
-            // TODO Send message back on the TCP stream
-            unimplemented!();
-          }
-        };
-      },
+    let (raw_dns_res, response_code) = match raw_dns_res_result {
+      Ok(raw_dns_res) => (raw_dns_res, dns_res.response_code()),
       Err(err) => {
         error!("Unable to serialize response: {}", err);
-        // TODO Send a empty/error DNS response (or something sensible)
+        match dns_message_to_bytes(&dns_error_response(&self.dns_query, DnsResponseCode::ServFail)) {
+          Ok(raw_dns_res) => (raw_dns_res, DnsResponseCode::ServFail),
+          Err(err) => {
+            error!("Unable to serialize error response: {}", err);
+            return;
+          }
+        }
       },
     };
+
+    metrics::inc_counter(&metrics::RESPONSES_SENT, &[self.transport(), &format!("{:?}", response_code)]);
+    metrics::observe_histogram(&metrics::REQUEST_DURATION, &[self.transport()], self.received.elapsed().as_secs_f64());
+
+    match self.req_type {
+      // Send response over UDP
+      RequestType::UdpRequest => {
+        if let Err(err) = self.udp_socket.unwrap().send_to(raw_dns_res.as_ref(), self.source) {
+          error!("Unable to send response back over UDP socket: {}", err);
+        };
+      },
+
+      // Send response over TCP
+      RequestType::TcpRequest => {
+        // Over TCP, the message is prefixed by 2 bytes length field (RFC 1035, section 4.2.2).
+        // Length and message are written at once, holding the lock on the stream shared by concurrent responses.
+        let raw_tcp_dns_res = match tcp_message_with_length(&raw_dns_res) {
+          Some(raw_tcp_dns_res) => raw_tcp_dns_res,
+          None => {
+            error!("Unable to send response of {} bytes over TCP stream: too big", raw_dns_res.len());
+            return;
+          }
+        };
+
+        if let Err(err) = self.tcp_stream.unwrap().lock().unwrap().write_all(raw_tcp_dns_res.as_ref()) {
+          error!("Unable to send response back over TCP stream: {}", err);
+        };
+      }
+    };
   }
 
 }
@@ -3,16 +3,20 @@
 //! It's role is to handle the networking part of receiving a DNS queries
 
 use crate::config::config::Config;
-use super::{utils::{bind_udp_sockets, /*bind_tcp_listeners*/}, request::Request};
+use super::{utils::{bind_udp_sockets, bind_tcp_listeners, wake_tcp_listener, tcp_message_with_length, TCP_MESSAGE_MAX_SIZE}, request::Request};
 use crate::dns;
 
 use log::*;
 use crossbeam_channel::Sender as XBeamSender;
 use srvzio;
 
-use std::{net::{Ipv4Addr, Ipv6Addr, UdpSocket}, thread, time::Duration, io::ErrorKind};
+use std::{net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket, TcpListener, TcpStream}, thread, time::{Duration, Instant}, io::{self, ErrorKind, Read, Write}, sync::{Arc, Mutex}};
 
 const SERVER_SERVICE_NAME: &'static str = "Server";
+const SERVER_TCP_WAKE_TIMEOUT_MS: u64 = 500;
+const SERVER_TCP_IDLE_TIMEOUT_SEC: u64 = 10;
+const SERVER_TCP_MESSAGE_TIMEOUT_SEC: u64 = 5;
+const SERVER_TCP_MAX_CONNECTIONS_PER_LISTENER: usize = 128;
 
 /// The DNS Server that listens for DNS queries over UDP or TCP requests.
 #[derive(Debug)]
@@ -20,6 +24,7 @@ pub struct Server {
   ip4s: Vec<Ipv4Addr>,
   ip6s: Vec<Ipv6Addr>,
   port: u16,
+  tcp_addrs: Vec<SocketAddr>,
   threads: Vec<thread::JoinHandle<()>>,
   sender: XBeamSender<Request>,
   status: srvzio::ServiceStatusFlag,
@@ -36,14 +41,14 @@ impl srvzio::Service for Server {
     self.status.starting();
 
     // Bind TCP listeners and start dedicated threads to handle requests (one thread per listener)
-    // TODO Implement TCP support
-//    let tcp_listeners = bind_tcp_listeners(&self.ip4s, &self.ip6s, &self.port);
-//    let threads = self.start_tcp_threads(tcp_listeners);
+    let tcp_listeners = bind_tcp_listeners(&self.ip4s, &self.ip6s, &self.port);
+    self.tcp_addrs = tcp_listeners.iter().filter_map(|tcp_listener| tcp_listener.local_addr().ok()).collect();
+    let threads = self.start_tcp_threads(tcp_listeners);
+    self.threads.extend(threads);
 
     // Bind UDP sockets and start dedicated threads to listen for requests (one thread per socket)
     let udp_sockets = bind_udp_sockets(&self.ip4s, &self.ip6s, &self.port);
     let threads = self.start_udp_threads(udp_sockets);
-
     self.threads.extend(threads);
   }
 
@@ -54,6 +59,13 @@ impl srvzio::Service for Server {
   fn stop(&mut self) {
     trace!("Server should now stop...");
     self.status.stopping();
+
+    // TCP listeners block on `accept`: connect to each of them, so they notice they should stop
+    for tcp_addr in &self.tcp_addrs {
+      if let Err(e) = wake_tcp_listener(*tcp_addr, Duration::from_millis(SERVER_TCP_WAKE_TIMEOUT_MS)) {
+        error!("Unable to wake TCP listener on '{}': {}", tcp_addr, e);
+      }
+    }
   }
 
   fn await_stopped(&mut self) {
@@ -79,7 +91,8 @@ impl Server {
       ip4s: config.ipv4(),
       ip6s: config.ipv6(),
       port: config.port(),
-      threads: Vec::with_capacity(config.ipv4().len() + config.ipv6().len()),
+      tcp_addrs: Vec::new(),
+      threads: Vec::with_capacity((config.ipv4().len() + config.ipv6().len()) * 2),
       sender,
       status: srvzio::ServiceStatusFlag::default(),
     }
@@ -123,7 +136,7 @@ impl Server {
             Ok((amount, src)) => {
               debug!("Received {} bytes via UDP datagram from '{}'", amount, src);
 
-              match dns::protocol::dns_message_from_bytes(&buf) {
+              match dns::protocol::dns_message_from_bytes(&buf[..amount]) {
                 Ok(dns_message) => {
                   if dns_message.message_type() == dns::protocol::DnsMessageType::Query {
                     let u_sock = thread_udp_sock.try_clone().unwrap();
@@ -134,7 +147,14 @@ impl Server {
                     warn!("Received unexpected DNS message of type {:?}: ignoring", dns_message.message_type());
                   }
                 },
-                Err(e) => error!("Unable to parse DNS message: {}", e)
+                Err(e) => {
+                  error!("Unable to parse DNS message: {}", e);
+                  if let Some(raw_dns_res) = parse_error_response(&buf[..amount]) {
+                    if let Err(e) = thread_udp_sock.send_to(&raw_dns_res, src) {
+                      error!("Unable to send response back over UDP socket: {}", e);
+                    }
+                  }
+                }
               };
             }
             Err(e) => match e.kind() {
@@ -159,4 +179,368 @@ impl Server {
     }).collect()
   }
 
+  /// Spawn threads dedicated to handle `TcpListener` traffic
+  ///
+  /// This method will allocate 1 `Thread` per `TcpListener` given as input: its purpose is to
+  /// accept incoming connections. Each accepted connection is then handled by its own thread,
+  /// that reads DNS messages until the client closes the connection (or it goes idle for
+  /// `SERVER_TCP_IDLE_TIMEOUT_SEC`, or it takes longer than `SERVER_TCP_MESSAGE_TIMEOUT_SEC` to send
+  /// a whole message once it started). At most `SERVER_TCP_MAX_CONNECTIONS_PER_LISTENER` connections
+  /// are handled at once: past that, new connections are closed right away.
+  ///
+  /// Over TCP, every DNS message is prefixed by a 2 bytes length field
+  /// (see [RFC 1035](https://tools.ietf.org/html/rfc1035#section-4.2.2)): once read, the message
+  /// is deserialized into a `DnsMessage` and then emitted on the given internal `self.sender`
+  /// channel for further processing, exactly like for UDP.
+  fn start_tcp_threads(&mut self, tcp_listeners: Vec<TcpListener>) -> Vec<thread::JoinHandle<()>> {
+    // Map the bound listeners to threads, so we can later on use their `JoinHandle` to terminate them
+    tcp_listeners.into_iter().enumerate().map(|(idx, tcp_listener)| {
+
+      let thread_tcp_sender = self.sender.clone();
+      let status = self.status.clone();
+
+      // Launch a thread per listener we are accepting connections on
+      thread::Builder::new().name(format!("tcp_listener_thread_{}", idx)).spawn(move || {
+        let mut connection_threads: Vec<thread::JoinHandle<()>> = Vec::new();
+
+        status.started();
+
+        trace!("Waiting for TCP connections...");
+        loop {
+          // `accept` blocks: when the `Server` is stopped, a connection is made to wake this thread up
+          let accept_result = tcp_listener.accept();
+          if status.is_stopping() {
+            trace!("Server is done running: stop accepting connections");
+            break;
+          }
+
+          // Forget about connection threads that are done
+          connection_threads.retain(|t| !t.is_finished());
+
+          match accept_result {
+            Ok((tcp_stream, src)) => {
+              if connection_threads.len() >= SERVER_TCP_MAX_CONNECTIONS_PER_LISTENER {
+                warn!("Too many TCP connections ({}): closing connection from '{}'", connection_threads.len(), src);
+                drop(tcp_stream);
+                continue;
+              }
+
+              debug!("Accepted TCP connection from '{}'", src);
+
+              let sender = thread_tcp_sender.clone();
+              let status = status.clone();
+              let connection_thread = thread::Builder::new()
+                .name(format!("tcp_connection_thread_{}_{}", idx, src))
+                .spawn(move || {
+                  let timeouts = (Duration::from_secs(SERVER_TCP_IDLE_TIMEOUT_SEC), Duration::from_secs(SERVER_TCP_MESSAGE_TIMEOUT_SEC));
+                  handle_tcp_connection(tcp_stream, sender, status, timeouts)
+                });
+
+              match connection_thread {
+                Ok(t) => connection_threads.push(t),
+                Err(e) => error!("Unable to spawn thread for TCP connection from '{}': {}", src, e),
+              }
+            },
+            Err(e) => {
+              error!("Error accepting: {:?} {}", e.kind(), e);
+            }
+          }
+        }
+
+        // Wait for all the connections to be done
+        while let Some(t) = connection_threads.pop() {
+          if t.join().is_err() {
+            error!("A TCP connection thread panicked upon termination");
+          }
+        }
+      }).expect("Unable to spawn thread for TCP bound listener")
+    }).collect()
+  }
+
+}
+
+/// Handles a single TCP connection, reading length-prefixed DNS messages until the connection is closed
+///
+/// # Parameters
+///
+/// * `tcp_stream` - Established TCP connection with the client
+/// * `sender` - Channel sender to "emit" `DnsRequest` after been received and parsed
+/// * `status` - Status of the `Server`, to know when to stop
+/// * `timeouts` - How long the connection can be idle, and how long a client can take to send a
+///   whole message once it started (so that a client trickling bytes can't hold the connection forever)
+fn handle_tcp_connection(mut tcp_stream: TcpStream, sender: XBeamSender<Request>, status: srvzio::ServiceStatusFlag, timeouts: (Duration, Duration)) {
+  let (idle_timeout, message_timeout) = timeouts;
+  let src = match tcp_stream.peer_addr() {
+    Ok(src) => src,
+    Err(e) => {
+      error!("Unable to determine source of TCP connection: {}", e);
+      return;
+    }
+  };
+
+  // Clone the TCP stream for writing: responses to pipelined queries are written by different threads, one at a time
+  let tcp_writer = match tcp_stream.try_clone() {
+    Ok(tcp_writer) => Arc::new(Mutex::new(tcp_writer)),
+    Err(e) => {
+      error!("Unable to setup TCP connection from '{}': {}", src, e);
+      return;
+    }
+  };
+
+  let mut len_buf: [u8; 2] = [0; 2];
+  let mut buf: Vec<u8> = Vec::with_capacity(TCP_MESSAGE_MAX_SIZE);
+
+  loop {
+    // Wait for the next message (with a read timeout, so we can actually stop this thread), then read
+    // the rest of its 2 bytes length prefix and the DNS message itself, before the deadline
+    let read_result = tcp_stream.set_read_timeout(Some(idle_timeout))
+      .and_then(|_| tcp_stream.read_exact(&mut len_buf[..1]))
+      .and_then(|_| {
+        let deadline = Instant::now() + message_timeout;
+        read_exact_until(&mut tcp_stream, &mut len_buf[1..], deadline)?;
+
+        let len = u16::from_be_bytes(len_buf) as usize;
+        buf.resize(len, 0);
+        read_exact_until(&mut tcp_stream, &mut buf, deadline)
+      });
+
+    match read_result {
+      Ok(_) => {
+        debug!("Received {} bytes via TCP connection from '{}'", buf.len(), src);
+
+        match dns::protocol::dns_message_from_bytes(&buf) {
+          Ok(dns_message) => {
+            if dns_message.message_type() == dns::protocol::DnsMessageType::Query {
+              let dns_request = Request::from_tcp(src, dns_message, tcp_writer.clone());
+              sender.send(dns_request).expect("Unable to pass on DNS Request for processing");
+            } else {
+              warn!("Received unexpected DNS message of type {:?}: ignoring", dns_message.message_type());
+            }
+          },
+          Err(e) => {
+            error!("Unable to parse DNS message: {}", e);
+            if let Some(raw_tcp_dns_res) = parse_error_response(&buf).and_then(|raw_dns_res| tcp_message_with_length(&raw_dns_res)) {
+              if let Err(e) = tcp_writer.lock().unwrap().write_all(&raw_tcp_dns_res) {
+                error!("Unable to send response back over TCP stream: {}", e);
+              }
+            }
+          }
+        };
+      },
+      Err(e) => match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+          // NOTE: This happens when a read has timed out: the connection is idle, or the client is too slow.
+          // See `start_udp_threads` for why we check for 2 errors.
+          trace!("TCP connection from '{}' is idle, or too slow: closing it", src);
+          break;
+        },
+        ErrorKind::UnexpectedEof => {
+          trace!("TCP connection from '{}' was closed", src);
+          break;
+        },
+        _ => {
+          error!("Error receiving: {:?} {}", e.kind(), e);
+          break;
+        }
+      }
+    }
+
+    if status.is_stopping() {
+      trace!("Server is done running: stop reading from TCP connection");
+      break;
+    }
+  }
+}
+
+/// Reads exactly enough bytes to fill the buffer, failing with `ErrorKind::TimedOut` if that takes past the deadline
+///
+/// # Parameters
+///
+/// * `tcp_stream` - TCP Stream to read from
+/// * `buf` - Buffer to fill
+/// * `deadline` - When to give up
+fn read_exact_until(tcp_stream: &mut TcpStream, mut buf: &mut [u8], deadline: Instant) -> io::Result<()> {
+  while !buf.is_empty() {
+    let now = Instant::now();
+    if now >= deadline {
+      return Err(io::Error::new(ErrorKind::TimedOut, "Message not received in time"));
+    }
+
+    tcp_stream.set_read_timeout(Some(deadline - now))?;
+    match tcp_stream.read(buf) {
+      Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "Connection closed before the end of the message")),
+      Ok(amount) => buf = &mut buf[amount..],
+      Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+      Err(e) => return Err(e),
+    }
+  }
+
+  Ok(())
+}
+
+/// Builds the raw response for a DNS message that couldn't be parsed
+///
+/// The response has the same ID of the received message, and response code `FormErr`
+/// (or `NotImp`, if the op code is unknown). Returns `None` if the message isn't a query with a full
+/// header: it must then be dropped silently (see `dns_error_response_from_bytes()`).
+///
+/// # Parameters
+///
+/// * `raw_dns_message` - Raw bytes received, that failed to parse into a `DnsMessage`
+fn parse_error_response(raw_dns_message: &[u8]) -> Option<Vec<u8>> {
+  let dns_res = dns::protocol::dns_error_response_from_bytes(raw_dns_message)?;
+  debug!("Responding: id={} type={:?} response_code={:?}", dns_res.id(), dns_res.message_type(), dns_res.response_code());
+
+  match dns::protocol::dns_message_to_bytes(&dns_res) {
+    Ok(raw_dns_res) => Some(raw_dns_res),
+    Err(e) => {
+      error!("Unable to serialize error response: {}", e);
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::dns::protocol::*;
+  use crate::test_support::query_message;
+  use crossbeam_channel::{self as xbeam_channel, Receiver as XBeamReceiver};
+
+  /// Accepts a single TCP connection on loopback, and handles it with the given timeouts
+  fn tcp_connection(idle_timeout: Duration, message_timeout: Duration) -> (TcpStream, XBeamReceiver<Request>) {
+    let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (sender, receiver) = xbeam_channel::unbounded();
+
+    let tcp_addr = tcp_listener.local_addr().unwrap();
+    thread::spawn(move || {
+      let (tcp_stream, _) = tcp_listener.accept().unwrap();
+      handle_tcp_connection(tcp_stream, sender, srvzio::ServiceStatusFlag::default(), (idle_timeout, message_timeout));
+    });
+
+    let client = TcpStream::connect(tcp_addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    (client, receiver)
+  }
+
+  /// Responds to the request with an empty response, with the given response code
+  fn respond(req: Request, response_code: DnsResponseCode) {
+    let dns_res = dns_error_response(req.dns_query(), response_code);
+    req.respond(dns_res);
+  }
+
+  /// Responds to the requests, as they are received, with an empty `NoError` response
+  fn respond_all(receiver: XBeamReceiver<Request>) {
+    thread::spawn(move || receiver.iter().for_each(|req| respond(req, DnsResponseCode::NoError)));
+  }
+
+  fn write_query(client: &mut TcpStream, id: u16, name: &str) {
+    let raw_dns_query = dns_message_to_bytes(&query_message(id, name, DnsRecordType::A)).unwrap();
+    client.write_all(&tcp_message_with_length(&raw_dns_query).unwrap()).unwrap();
+  }
+
+  fn read_response(client: &mut TcpStream) -> DnsMessage {
+    let mut len_buf = [0; 2];
+    client.read_exact(&mut len_buf).unwrap();
+    let mut buf = vec![0; u16::from_be_bytes(len_buf) as usize];
+    client.read_exact(&mut buf).unwrap();
+
+    dns_message_from_bytes(&buf).unwrap()
+  }
+
+  /// `true` if the server closed the connection (waiting for it, for at most the client read timeout)
+  fn is_closed(client: &mut TcpStream) -> bool {
+    match client.read(&mut [0; 1]) {
+      Ok(0) => true,
+      Err(ref e) if e.kind() == ErrorKind::ConnectionReset => true,
+      _ => false,
+    }
+  }
+
+  #[test]
+  fn should_respond_to_framed_query() {
+    let (mut client, receiver) = tcp_connection(Duration::from_secs(5), Duration::from_secs(5));
+    respond_all(receiver);
+
+    write_query(&mut client, 1, "example.com.");
+    let res = read_response(&mut client);
+    assert_eq!(res.id(), 1);
+    assert_eq!(res.message_type(), DnsMessageType::Response);
+    assert_eq!(res.queries()[0].name().to_utf8(), "example.com.");
+  }
+
+  #[test]
+  fn should_respond_to_pipelined_queries() {
+    let (mut client, receiver) = tcp_connection(Duration::from_secs(5), Duration::from_secs(5));
+
+    // Both queries in a single write
+    let mut raw_tcp_dns_queries = Vec::new();
+    for (id, name) in &[(1, "example.com."), (2, "example.org.")] {
+      let raw_dns_query = dns_message_to_bytes(&query_message(*id, name, DnsRecordType::A)).unwrap();
+      raw_tcp_dns_queries.extend(tcp_message_with_length(&raw_dns_query).unwrap());
+    }
+    client.write_all(&raw_tcp_dns_queries).unwrap();
+
+    // Respond to them concurrently, and out of order
+    let first = receiver.recv().unwrap();
+    let second = receiver.recv().unwrap();
+    let second_thread = thread::spawn(move || respond(second, DnsResponseCode::NoError));
+    let first_thread = thread::spawn(move || respond(first, DnsResponseCode::NXDomain));
+    second_thread.join().unwrap();
+    first_thread.join().unwrap();
+
+    let mut responses = vec![read_response(&mut client), read_response(&mut client)];
+    responses.sort_by_key(|res| res.id());
+    assert_eq!(responses[0].response_code(), DnsResponseCode::NXDomain);
+    assert_eq!(responses[0].queries()[0].name().to_utf8(), "example.com.");
+    assert_eq!(responses[1].response_code(), DnsResponseCode::NoError);
+    assert_eq!(responses[1].queries()[0].name().to_utf8(), "example.org.");
+  }
+
+  #[test]
+  fn should_respond_formerr_to_malformed_message() {
+    let (mut client, receiver) = tcp_connection(Duration::from_secs(5), Duration::from_secs(5));
+    respond_all(receiver);
+
+    // A query header announcing a question, that isn't there
+    let raw_dns_query = [0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    client.write_all(&tcp_message_with_length(&raw_dns_query).unwrap()).unwrap();
+    let res = read_response(&mut client);
+    assert_eq!(res.id(), 0x1234);
+    assert_eq!(res.response_code(), DnsResponseCode::FormErr);
+
+    // The connection is still usable
+    write_query(&mut client, 2, "example.com.");
+    assert_eq!(read_response(&mut client).id(), 2);
+  }
+
+  #[test]
+  fn should_close_idle_connection() {
+    let (mut client, _receiver) = tcp_connection(Duration::from_millis(200), Duration::from_secs(5));
+
+    let start = Instant::now();
+    assert!(is_closed(&mut client));
+    assert!(start.elapsed() < Duration::from_secs(2));
+  }
+
+  #[test]
+  fn should_close_connection_trickling_message() {
+    let (mut client, _receiver) = tcp_connection(Duration::from_millis(200), Duration::from_millis(500));
+
+    // Each byte comes before the idle timeout, but the message never ends in time
+    let mut trickling_client = client.try_clone().unwrap();
+    thread::spawn(move || {
+      for byte in &[0x00, 0x20, 0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00] {
+        if trickling_client.write_all(&[*byte]).is_err() {
+          break;
+        }
+        thread::sleep(Duration::from_millis(100));
+      }
+    });
+
+    let start = Instant::now();
+    assert!(is_closed(&mut client));
+    assert!(start.elapsed() < Duration::from_millis(1500));
+  }
 }
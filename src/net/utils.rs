@@ -1,6 +1,27 @@
 //! Utility methods for networking
 
-use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket, TcpListener}};
+use std::{io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket, TcpListener, TcpStream}, time::Duration};
+
+/// Maximum size of a DNS message over TCP, as its length has to fit a 2 bytes field
+pub const TCP_MESSAGE_MAX_SIZE: usize = 65535;
+
+/// Prefixes the given raw DNS message with its 2 bytes length, as required over TCP
+/// (see [RFC 1035](https://tools.ietf.org/html/rfc1035#section-4.2.2))
+///
+/// Returns `None` if the message is bigger than `TCP_MESSAGE_MAX_SIZE`.
+///
+/// * `raw_dns_message` - The raw DNS message to send over TCP
+pub fn tcp_message_with_length(raw_dns_message: &[u8]) -> Option<Vec<u8>> {
+  if raw_dns_message.len() > TCP_MESSAGE_MAX_SIZE {
+    return None;
+  }
+
+  let mut raw_tcp_dns_message = Vec::with_capacity(raw_dns_message.len() + 2);
+  raw_tcp_dns_message.extend_from_slice(&(raw_dns_message.len() as u16).to_be_bytes());
+  raw_tcp_dns_message.extend_from_slice(raw_dns_message);
+
+  Some(raw_tcp_dns_message)
+}
 
 /// Binds the given addresses to `UdpSocket`s
 ///
@@ -28,6 +49,22 @@ pub fn bind_tcp_listeners(ipv4_addresses: &Vec<Ipv4Addr>, ipv6_addresses: &Vec<I
     }).collect()
 }
 
+/// Connects to a `TcpListener`, so that a thread blocked on its `accept` wakes up
+///
+/// If the listener is bound to an unspecified address (ex. `0.0.0.0`), the loopback one is connected to instead.
+///
+/// * `tcp_addr` - The address the listener is bound to
+/// * `timeout` - How long to wait for the connection to be established
+pub fn wake_tcp_listener(tcp_addr: SocketAddr, timeout: Duration) -> io::Result<()> {
+  let wake_addr = match tcp_addr.ip() {
+    IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), tcp_addr.port()),
+    IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), tcp_addr.port()),
+    _ => tcp_addr,
+  };
+
+  TcpStream::connect_timeout(&wake_addr, timeout).map(|_| ())
+}
+
 /// Takes IPv4 and IPv6 addresses and maps them to `SocketAddr`
 ///
 /// This method generifies the IP version-specific information into a version-ignostic
@@ -45,3 +82,15 @@ fn map_ips_to_socket_addresses(ipv4_addresses: &Vec<Ipv4Addr>, ipv6_addresses: &
     .flat_map(|addr| (addr, port.clone()).to_socket_addrs().unwrap())
     .collect()
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn should_prefix_tcp_message_with_length() {
+    assert_eq!(tcp_message_with_length(&[1, 2, 3]).unwrap(), vec![0, 3, 1, 2, 3]);
+    assert_eq!(tcp_message_with_length(&vec![0; TCP_MESSAGE_MAX_SIZE]).unwrap()[..2], [0xFF, 0xFF]);
+    assert!(tcp_message_with_length(&vec![0; TCP_MESSAGE_MAX_SIZE + 1]).is_none());
+  }
+}
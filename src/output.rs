@@ -0,0 +1,141 @@
+//! Utilities shared by the services that write an output (ex. the query log, or dnstap)
+//!
+//! Outputs can fail (ex. a collector listening on a Unix socket goes away): they are then reopened
+//! later, via an `OutputReopener`, without ever blocking (or flooding the log of) the service.
+
+use log::*;
+
+use std::{cmp, io, fmt::Display, path::Path, time::{Duration, Instant}, os::unix::net::UnixStream};
+
+const OUTPUT_WRITE_TIMEOUT_SEC: u64 = 5;
+const OUTPUT_REOPEN_BACKOFF_MIN_SEC: u64 = 1;
+const OUTPUT_REOPEN_BACKOFF_MAX_SEC: u64 = 60;
+
+/// Connects to a Unix (stream) socket, to write an output to
+///
+/// Writes time out: a collector that stops reading can't block the writer (and, with it, its queue) forever.
+///
+/// # Parameters
+///
+/// * `path` - Path of the socket
+pub fn connect_unix_socket(path: &Path) -> io::Result<UnixStream> {
+  let stream = UnixStream::connect(path)?;
+  stream.set_write_timeout(Some(Duration::from_secs(OUTPUT_WRITE_TIMEOUT_SEC)))?;
+
+  Ok(stream)
+}
+
+/// Keeps track of the (re)opening of an output
+///
+/// While the output can't be (re)opened, attempts are spaced by an increasing backoff and only
+/// the first failure is logged: this is an outage, that ends once the output is reopened.
+#[derive(Debug)]
+pub struct OutputReopener {
+  name: String,
+  reopen_at: Option<Instant>,
+  reopen_backoff: Duration,
+}
+
+impl OutputReopener {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  ///
+  /// * `name` - Name of the output, used when logging (ex. "query log '/var/log/mooncell.log'")
+  pub fn new(name: String) -> OutputReopener {
+    OutputReopener {
+      name,
+      reopen_at: None,
+      reopen_backoff: Duration::from_secs(OUTPUT_REOPEN_BACKOFF_MIN_SEC),
+    }
+  }
+
+  /// Whether the output is failing (i.e. there is an ongoing outage)
+  pub fn is_failing(&self) -> bool {
+    self.reopen_at.is_some()
+  }
+
+  /// (Re)opens the output with the given function, unless it's too early to try again
+  ///
+  /// Returns what was opened, or `None` if it wasn't (yet).
+  ///
+  /// # Parameters
+  ///
+  /// * `now` - Current time
+  /// * `open` - Opens the output
+  pub fn reopen<T, E, F>(&mut self, now: Instant, open: F) -> Option<T> where F: FnOnce() -> Result<T, E>, E: Display {
+    match self.reopen_at {
+      Some(reopen_at) if now < reopen_at => return None,
+      _ => {},
+    }
+
+    match open() {
+      Ok(opened) => {
+        if self.is_failing() {
+          info!("{} is writable again", self.name);
+        }
+        self.reopen_at = None;
+        self.reopen_backoff = Duration::from_secs(OUTPUT_REOPEN_BACKOFF_MIN_SEC);
+
+        Some(opened)
+      },
+      Err(err) => {
+        if self.is_failing() {
+          debug!("Unable to reopen {}: {}", self.name, err);
+        } else {
+          error!("Unable to open {}: {} (dropping output until it is)", self.name, err);
+        }
+        self.reopen_at = Some(now + self.reopen_backoff);
+        self.reopen_backoff = cmp::min(self.reopen_backoff * 2, Duration::from_secs(OUTPUT_REOPEN_BACKOFF_MAX_SEC));
+
+        None
+      },
+    }
+  }
+
+  /// Records that the (open) output broke: it can be reopened right away
+  ///
+  /// # Parameters
+  ///
+  /// * `err` - Why the output broke
+  pub fn broken<E: Display>(&mut self, err: E) {
+    error!("Unable to write to {}: {} (dropping output until it's reopened)", self.name, err);
+    self.reopen_at = Some(Instant::now());
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn should_back_off_while_output_is_down() {
+    let mut reopener = OutputReopener::new("test output".into());
+    let now = Instant::now();
+    let failing_open = || Err::<(), _>("down");
+
+    assert!(reopener.reopen(now, failing_open).is_none());
+    assert!(reopener.is_failing());
+    assert_eq!(reopener.reopen_backoff, Duration::from_secs(OUTPUT_REOPEN_BACKOFF_MIN_SEC * 2));
+
+    // Too early to try again
+    assert!(reopener.reopen(now, || -> Result<(), &str> { panic!("Too early to reopen") }).is_none());
+    assert_eq!(reopener.reopen_backoff, Duration::from_secs(OUTPUT_REOPEN_BACKOFF_MIN_SEC * 2));
+
+    let later = now + Duration::from_secs(OUTPUT_REOPEN_BACKOFF_MIN_SEC);
+    assert!(reopener.reopen(later, failing_open).is_none());
+    assert_eq!(reopener.reopen_backoff, Duration::from_secs(OUTPUT_REOPEN_BACKOFF_MIN_SEC * 4));
+
+    // Once reopened, the outage is over
+    let later = later + Duration::from_secs(OUTPUT_REOPEN_BACKOFF_MIN_SEC * 2);
+    assert_eq!(reopener.reopen(later, || Ok::<_, &str>(42)), Some(42));
+    assert!(!reopener.is_failing());
+    assert_eq!(reopener.reopen_backoff, Duration::from_secs(OUTPUT_REOPEN_BACKOFF_MIN_SEC));
+
+    reopener.broken("broken pipe");
+    assert!(reopener.is_failing());
+    assert_eq!(reopener.reopen(Instant::now(), || Ok::<_, &str>(42)), Some(42));
+  }
+}
@@ -0,0 +1,215 @@
+//! Query log: one JSON line per query, describing how it was resolved
+//!
+//! Entries are built by the `Processor` via a `QueryLog`, and handed over to a `QueryLogWriter`
+//! (see `writer`) that writes them to the configured `QueryLogDestination`, so that resolution
+//! is never slowed down by the writing.
+
+pub mod writer;
+
+use crate::core::context::ResolutionContext;
+use crate::dns::protocol::DnsMessage;
+use crate::net::request::Request;
+use crate::metrics;
+
+use chrono::{Utc, SecondsFormat};
+use crossbeam_channel::{Sender as XBeamSender, TrySendError as XBeamTrySendError};
+use log::*;
+use serde::Serialize;
+
+use std::{fmt, str::FromStr, path::PathBuf, net::{IpAddr, Ipv4Addr, Ipv6Addr}};
+
+const DESTINATION_STDOUT: &'static str = "stdout";
+const DESTINATION_PREFIX_FILE: &'static str = "file:";
+const DESTINATION_PREFIX_UNIX: &'static str = "unix:";
+
+/// Entries waiting to be written, past which new entries are dropped (see `QueryLog::log()`)
+pub const QUERY_LOG_QUEUE_CAPACITY: usize = 4096;
+
+/// Where the query log is written to
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryLogDestination {
+  /// Standard output (format: `stdout`)
+  Stdout,
+  /// A file, appended to (format: `file:PATH`, or just `PATH`)
+  File(PathBuf),
+  /// A Unix (stream) socket, ex. of a log collector (format: `unix:PATH`)
+  UnixSocket(PathBuf),
+}
+
+impl FromStr for QueryLogDestination {
+  type Err = QueryLogDestinationParseError;
+
+  fn from_str(raw_destination: &str) -> Result<Self, Self::Err> {
+    let destination = if raw_destination == DESTINATION_STDOUT {
+      QueryLogDestination::Stdout
+    } else if let Some(path) = raw_destination.strip_prefix(DESTINATION_PREFIX_UNIX) {
+      QueryLogDestination::UnixSocket(PathBuf::from(path))
+    } else if let Some(path) = raw_destination.strip_prefix(DESTINATION_PREFIX_FILE) {
+      QueryLogDestination::File(PathBuf::from(path))
+    } else {
+      QueryLogDestination::File(PathBuf::from(raw_destination))
+    };
+
+    match &destination {
+      QueryLogDestination::File(path) | QueryLogDestination::UnixSocket(path) if path.as_os_str().is_empty() => {
+        Err(QueryLogDestinationParseError::new(raw_destination))
+      },
+      _ => Ok(destination),
+    }
+  }
+}
+
+impl fmt::Display for QueryLogDestination {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      QueryLogDestination::Stdout => write!(fmtr, "{}", DESTINATION_STDOUT),
+      QueryLogDestination::File(path) => write!(fmtr, "{}{}", DESTINATION_PREFIX_FILE, path.display()),
+      QueryLogDestination::UnixSocket(path) => write!(fmtr, "{}{}", DESTINATION_PREFIX_UNIX, path.display()),
+    }
+  }
+}
+
+/// Error that happens when parsing a `QueryLogDestination` fails
+#[derive(Debug, Clone)]
+pub struct QueryLogDestinationParseError {
+  token: String
+}
+
+impl QueryLogDestinationParseError {
+  fn new(token: &str) -> Self {
+    Self {
+      token: token.to_string()
+    }
+  }
+}
+
+impl fmt::Display for QueryLogDestinationParseError {
+  fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmtr, "Invalid Query Log destination (expected '{}', '{}PATH' or '{}PATH'): {}", DESTINATION_STDOUT, DESTINATION_PREFIX_FILE, DESTINATION_PREFIX_UNIX, self.token)
+  }
+}
+
+/// An entry of the query log
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryLogEntry {
+  /// When the response was sent (RFC 3339, UTC)
+  pub timestamp: String,
+  /// IP of the client (anonymized, if so configured)
+  pub client: IpAddr,
+  /// Transport the query was received over ("udp" or "tcp")
+  pub transport: &'static str,
+  /// Name queried
+  pub qname: String,
+  /// Type of record queried
+  pub qtype: String,
+  /// Code of the response
+  pub rcode: String,
+  /// Number of answers in the response
+  pub answers: usize,
+  /// Identifier of the upstream that provided the response (if any)
+  pub upstream: Option<String>,
+  /// How the cache was involved ("hit", "miss" or "none")
+  pub cache: &'static str,
+  /// Time from receiving the query to responding to it, in milliseconds
+  pub latency_ms: f64,
+}
+
+/// Handle used to add entries to the query log
+///
+/// It's cheap to clone: all the clones send to the same `QueryLogWriter`.
+#[derive(Debug, Clone)]
+pub struct QueryLog {
+  sender: XBeamSender<QueryLogEntry>,
+  anonymize: bool,
+}
+
+impl QueryLog {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  ///
+  /// * `sender` - Channel sender to "emit" the entries to the `QueryLogWriter`
+  /// * `anonymize` - If `true`, client IPs are truncated (see `anonymize_ip()`)
+  pub fn new(sender: XBeamSender<QueryLogEntry>, anonymize: bool) -> QueryLog {
+    QueryLog {
+      sender,
+      anonymize,
+    }
+  }
+
+  /// Adds an entry to the query log, describing the response to a request
+  ///
+  /// This never blocks: if the `QueryLogWriter` can't keep up, the entry is dropped (and counted).
+  ///
+  /// # Parameters
+  ///
+  /// * `req` - The request that was responded
+  /// * `res_dns_msg` - The response
+  /// * `context` - What is known about the resolution
+  pub fn log(&self, req: &Request, res_dns_msg: &DnsMessage, context: ResolutionContext) {
+    let client = if self.anonymize { anonymize_ip(req.source().ip()) } else { req.source().ip() };
+    let query = req.dns_query().queries().first();
+
+    let entry = QueryLogEntry {
+      timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+      client,
+      transport: req.transport(),
+      qname: query.map(|query| query.name().to_string()).unwrap_or_default(),
+      qtype: query.map(|query| query.query_type().to_string()).unwrap_or_default(),
+      rcode: format!("{:?}", res_dns_msg.response_code()),
+      answers: res_dns_msg.answers().len(),
+      upstream: context.upstream,
+      cache: context.cache.name(),
+      latency_ms: req.received().elapsed().as_secs_f64() * 1000.0,
+    };
+
+    match self.sender.try_send(entry) {
+      Ok(_) => {},
+      Err(XBeamTrySendError::Full(_)) => metrics::inc_counter(&metrics::LOG_ENTRIES_DROPPED, &["query_log"]),
+      Err(XBeamTrySendError::Disconnected(_)) => error!("Unable to add entry to the query log: writer is gone"),
+    }
+  }
+
+}
+
+/// Anonymizes an IP, by truncating it: IPv4 to its `/24`, IPv6 to its `/48`
+///
+/// # Parameters
+///
+/// * `ip` - The IP to anonymize
+pub fn anonymize_ip(ip: IpAddr) -> IpAddr {
+  match ip {
+    IpAddr::V4(ipv4) => {
+      let octets = ipv4.octets();
+      IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], 0))
+    },
+    IpAddr::V6(ipv6) => {
+      let segments = ipv6.segments();
+      IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], 0, 0, 0, 0, 0))
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn should_parse_destination() {
+    assert_eq!(QueryLogDestination::from_str("stdout").unwrap(), QueryLogDestination::Stdout);
+    assert_eq!(QueryLogDestination::from_str("/var/log/mooncell.log").unwrap(), QueryLogDestination::File("/var/log/mooncell.log".into()));
+    assert_eq!(QueryLogDestination::from_str("file:queries.log").unwrap(), QueryLogDestination::File("queries.log".into()));
+    assert_eq!(QueryLogDestination::from_str("unix:/run/collector.sock").unwrap(), QueryLogDestination::UnixSocket("/run/collector.sock".into()));
+    assert!(QueryLogDestination::from_str("").is_err());
+    assert!(QueryLogDestination::from_str("unix:").is_err());
+
+    assert_eq!(QueryLogDestination::from_str("unix:/run/collector.sock").unwrap().to_string(), "unix:/run/collector.sock");
+  }
+
+  #[test]
+  fn should_anonymize_ip() {
+    assert_eq!(anonymize_ip("192.168.1.42".parse().unwrap()), "192.168.1.0".parse::<IpAddr>().unwrap());
+    assert_eq!(anonymize_ip("2001:db8:85a3:8d3:1319:8a2e:370:7348".parse().unwrap()), "2001:db8:85a3::".parse::<IpAddr>().unwrap());
+  }
+}
@@ -0,0 +1,213 @@
+//! Implementation of the service that writes the query log
+
+use super::{QueryLogEntry, QueryLogDestination};
+use crate::output::{OutputReopener, connect_unix_socket};
+
+use log::*;
+use crossbeam_channel::{Receiver as XBeamReceiver, RecvTimeoutError as XBeamRecvTimeoutError};
+use serde_json;
+use srvzio;
+
+use std::{thread, time::{Duration, Instant}, fs::OpenOptions, io::{self, Write, LineWriter}};
+
+const QUERY_LOG_WRITER_SERVICE_NAME: &'static str = "QueryLogWriter";
+const QUERY_LOG_WRITER_THREAD_NAME: &'static str = "query_log_writer_thread";
+const QUERY_LOG_WRITER_RECEIVER_TIMEOUT_SEC: u64 = 1;
+
+/// QueryLogWriter is a service that writes the entries of the query log, one JSON per line
+///
+/// The entries are received via the `Receiver` (see `crossbeam::channel::Receiver`), and written
+/// to the `QueryLogDestination`. If writing fails, the destination is opened again later, backing
+/// off while it keeps failing (see `OutputReopener`): entries that can't be written are dropped.
+pub struct QueryLogWriter {
+  destination: QueryLogDestination,
+  receiver: XBeamReceiver<QueryLogEntry>,
+  status: srvzio::ServiceStatusFlag,
+  writer_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl QueryLogWriter {
+
+  /// Constructor
+  ///
+  /// # Parameters
+  /// * `destination`: where to write the query log to
+  /// * `receiver`: a `crossbeam::channel::Receiver` that delivers `QueryLogEntry` data
+  pub fn new(destination: QueryLogDestination, receiver: XBeamReceiver<QueryLogEntry>) -> QueryLogWriter {
+    QueryLogWriter {
+      destination,
+      receiver,
+      status: srvzio::ServiceStatusFlag::default(),
+      writer_thread: None,
+    }
+  }
+
+}
+
+impl srvzio::Service for QueryLogWriter {
+
+  fn name(&self) -> &'static str {
+    QUERY_LOG_WRITER_SERVICE_NAME
+  }
+
+  fn start(&mut self) {
+    self.status.starting();
+
+    let destination = self.destination.clone();
+    let receiver = self.receiver.clone();
+    let status = self.status.clone();
+
+    // Launch a 'writing' thread
+    self.writer_thread = Some(thread::Builder::new()
+      .name(QUERY_LOG_WRITER_THREAD_NAME.into())
+      .spawn(move || {
+        let mut writer = QueryLogOutput::new(destination);
+        writer.reopen(Instant::now());
+
+        status.started();
+
+        // Receive entries to write, but interrupt at regular intervals to check if QueryLogWriter was stopped
+        loop {
+          match receiver.recv_timeout(Duration::from_secs(QUERY_LOG_WRITER_RECEIVER_TIMEOUT_SEC)) {
+            Ok(entry) => writer.write_entry(&entry),
+            Err(XBeamRecvTimeoutError::Timeout) => {
+              if status.is_stopping() {
+                trace!("{} is done running: stop writing the query log", QUERY_LOG_WRITER_SERVICE_NAME);
+                break;
+              }
+            },
+            Err(XBeamRecvTimeoutError::Disconnected) => break,
+          }
+        }
+
+        // Write any entry still pending
+        for entry in receiver.try_iter() {
+          writer.write_entry(&entry);
+        }
+
+        status.stopped();
+      })
+      .expect(format!("Unable to spawn thread: {}", QUERY_LOG_WRITER_THREAD_NAME).as_ref())
+    );
+  }
+
+  fn await_started(&mut self) {
+    while !self.status.is_started() {}
+  }
+
+  fn stop(&mut self) {
+    trace!("{} should now stop...", QUERY_LOG_WRITER_SERVICE_NAME);
+    self.status.stopping();
+  }
+
+  fn await_stopped(&mut self) {
+    while !self.status.is_stopped() {}
+
+    // Wait for writer thread to stop (if it's actually set)
+    if self.writer_thread.is_some() {
+      self.writer_thread
+        .take()
+        .unwrap()
+        .join()
+        .expect(format!("Panicked upon termination: {}", QUERY_LOG_WRITER_THREAD_NAME).as_ref());
+    }
+  }
+
+}
+
+/// Opens the destination of the query log, for writing
+fn open_destination(destination: &QueryLogDestination) -> io::Result<Box<Write + Send>> {
+  Ok(match destination {
+    QueryLogDestination::Stdout => Box::new(LineWriter::new(io::stdout())),
+    QueryLogDestination::File(path) => Box::new(LineWriter::new(OpenOptions::new().create(true).append(true).open(path)?)),
+    QueryLogDestination::UnixSocket(path) => Box::new(connect_unix_socket(path)?),
+  })
+}
+
+/// The destination of the query log, (re)opened when needed
+struct QueryLogOutput {
+  destination: QueryLogDestination,
+  writer: Option<Box<Write + Send>>,
+  reopener: OutputReopener,
+}
+
+impl QueryLogOutput {
+
+  fn new(destination: QueryLogDestination) -> QueryLogOutput {
+    QueryLogOutput {
+      reopener: OutputReopener::new(format!("query log '{}'", destination)),
+      destination,
+      writer: None,
+    }
+  }
+
+  /// (Re)opens the destination, unless it's too early to try again
+  fn reopen(&mut self, now: Instant) {
+    let destination = &self.destination;
+    if let Some(writer) = self.reopener.reopen(now, || open_destination(destination)) {
+      self.writer = Some(writer);
+    }
+  }
+
+  /// Writes an entry to the destination, (re)opening it if needed
+  fn write_entry(&mut self, entry: &QueryLogEntry) {
+    let mut line = match serde_json::to_vec(entry) {
+      Ok(line) => line,
+      Err(err) => {
+        error!("Unable to serialize query log entry: {}", err);
+        return;
+      }
+    };
+    line.push(b'\n');
+
+    if self.writer.is_none() {
+      self.reopen(Instant::now());
+    }
+
+    let written = self.writer.as_mut().map(|w| w.write_all(&line));
+    if let Some(Err(err)) = written {
+      // Forget the broken writer: it will be reopened for the next entry, if possible
+      self.writer = None;
+      self.reopener.broken(err);
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::{fs, env, net::IpAddr};
+
+  #[test]
+  fn should_write_json_lines() {
+    let path = env::temp_dir().join(format!("mooncell-query-log-test-{}.log", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let destination = QueryLogDestination::File(path.clone());
+
+    let entry = QueryLogEntry {
+      timestamp: "2019-06-01T10:00:00.000Z".into(),
+      client: "192.168.1.0".parse::<IpAddr>().unwrap(),
+      transport: "udp",
+      qname: "example.com.".into(),
+      qtype: "A".into(),
+      rcode: "NoError".into(),
+      answers: 1,
+      upstream: Some("cloudflare".into()),
+      cache: "miss",
+      latency_ms: 12.5,
+    };
+
+    let mut writer = QueryLogOutput::new(destination);
+    writer.write_entry(&entry);
+    writer.write_entry(&entry);
+    drop(writer);
+
+    let content = fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], r#"{"timestamp":"2019-06-01T10:00:00.000Z","client":"192.168.1.0","transport":"udp","qname":"example.com.","qtype":"A","rcode":"NoError","answers":1,"upstream":"cloudflare","cache":"miss","latency_ms":12.5}"#);
+
+    fs::remove_file(&path).unwrap();
+  }
+}
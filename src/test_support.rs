@@ -0,0 +1,96 @@
+//! Fixtures shared by the tests of the different modules
+
+use crate::core::resolver::{DoHResolver, DoHResolutionError, DoHResolutionErrorKind};
+use crate::dns::protocol::*;
+
+use std::{str::FromStr, thread, net::Ipv4Addr, time::Duration, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+
+type Result<T> = std::result::Result<T, DoHResolutionError>;
+
+/// Builds a DNS query, for the given name and record type
+///
+/// # Parameters
+///
+/// * `id` - ID of the message
+/// * `name` - Name to query (ex. "example.com.")
+/// * `query_type` - Type of record to query
+pub fn query_message(id: u16, name: &str, query_type: DnsRecordType) -> DnsMessage {
+  let mut dns_msg = DnsMessage::new();
+  dns_msg.set_id(id);
+  dns_msg.set_message_type(DnsMessageType::Query);
+  dns_msg.add_query(DnsQuery::query(DnsDomainName::from_str(name).unwrap(), query_type));
+
+  dns_msg
+}
+
+/// Resolver that always has the same outcome, counting how many times it was used
+///
+/// The outcome is a response with the given response code (answering `1.2.3.4` if it's
+/// `DnsResponseCode::NoError`) or, if there is none, a `DoHResolutionErrorKind::Transport` error.
+#[derive(Debug, Clone)]
+pub struct CountingResolver {
+  outcome: Option<DnsResponseCode>,
+  ttl: u32,
+  delay: Duration,
+  count: Arc<AtomicUsize>,
+}
+
+impl CountingResolver {
+
+  /// Constructor, for a resolver that answers right away, with a TTL of 300 seconds
+  ///
+  /// # Parameters
+  ///
+  /// * `outcome` - Response code to respond with: `None` means "error"
+  pub fn new(outcome: Option<DnsResponseCode>) -> CountingResolver {
+    CountingResolver {
+      outcome,
+      ttl: 300,
+      delay: Duration::from_millis(0),
+      count: Arc::new(AtomicUsize::new(0)),
+    }
+  }
+
+  /// Sets the TTL of the answer
+  pub fn with_ttl(mut self, ttl: u32) -> CountingResolver {
+    self.ttl = ttl;
+    self
+  }
+
+  /// Sets how long resolving takes
+  pub fn with_delay(mut self, delay: Duration) -> CountingResolver {
+    self.delay = delay;
+    self
+  }
+
+  /// Counter of the times the resolver was used (shared by all its clones)
+  pub fn count(&self) -> Arc<AtomicUsize> {
+    self.count.clone()
+  }
+
+}
+
+impl DoHResolver for CountingResolver {
+  fn resolve_query(&self, req_dns_msg: &DnsMessage) -> Result<DnsMessage> {
+    self.count.fetch_add(1, Ordering::SeqCst);
+    thread::sleep(self.delay);
+
+    let response_code = self.outcome
+      .ok_or_else(|| DoHResolutionError::new(DoHResolutionErrorKind::Transport, "Connection refused".into()))?;
+
+    let mut res_dns_msg = DnsMessage::new();
+    res_dns_msg.set_id(req_dns_msg.id());
+    res_dns_msg.set_message_type(DnsMessageType::Response);
+    res_dns_msg.set_response_code(response_code);
+    res_dns_msg.add_queries(req_dns_msg.queries().to_vec());
+    if let (DnsResponseCode::NoError, Some(query)) = (response_code, req_dns_msg.queries().first()) {
+      res_dns_msg.add_answer(DnsRecord::from_rdata(query.name().clone(), self.ttl, DnsRData::A(Ipv4Addr::new(1, 2, 3, 4))));
+    }
+
+    Ok(res_dns_msg)
+  }
+
+  fn box_clone(&self) -> Box<DoHResolver + Send> {
+    Box::new((*self).clone())
+  }
+}